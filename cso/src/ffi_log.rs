@@ -0,0 +1,138 @@
+use std::{ffi::CString, os::raw::{c_char, c_int}, sync::{atomic::{AtomicBool, Ordering}, OnceLock, RwLock}};
+use log::{kv::{Error, Key, Value, VisitSource}, Level, LevelFilter, Log, Metadata, Record};
+
+//宿主的日志回调：level为1~5(error,warn,info,debug,trace)，target为模块名如rwebc::quic_client，msg为消息，
+//kvs为字段的json对象如{"label":"aabbcc00020c","code":"-26"}，值都是字符串，没有字段时为{}
+//字符串都以\0结尾，只在回调期间有效；回调会在rwebc的各个线程上调用，宿主要自己保证线程安全
+pub type LogCallback = extern "C" fn(level:c_int,target:*const c_char,msg:*const c_char,kvs:*const c_char);
+
+static CALLBACK:RwLock<Option<LogCallback>> = RwLock::new(None);
+static LEVEL_SET:AtomicBool = AtomicBool::new(false);//宿主设置过级别后注册回调时不再改级别
+static INSTALLED:OnceLock<bool> = OnceLock::new();
+
+struct FfiLogger;
+
+//按写日志时的顺序收集字段
+struct Kvs(Vec<(String,String)>);
+
+impl<'kvs> VisitSource<'kvs> for Kvs{
+    fn visit_pair(&mut self,key:Key<'kvs>,value:Value<'kvs>)->Result<(),Error>{
+        self.0.push((key.to_string(),value.to_string()));
+        Ok(())
+    }
+}
+
+impl Kvs{
+    fn json(&self)->String{
+        let pairs:Vec<String> = self.0.iter().map(|(k,v)|format!("{}:{}",serde_json::Value::from(k.as_str()),serde_json::Value::from(v.as_str()))).collect();
+        format!("{{{}}}",pairs.join(","))
+    }
+}
+
+impl Log for FfiLogger{
+    fn enabled(&self,metadata:&Metadata)->bool{
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self,record:&Record){
+        if !self.enabled(record.metadata()){
+            return;
+        }
+        let mut kvs = Kvs(vec![]);
+        record.key_values().visit(&mut kvs).unwrap_or_default();
+        //先把函数指针拿出来，回调里再调set_log_callback也不会死锁
+        let callback = *CALLBACK.read().unwrap_or_else(|e|e.into_inner());
+        match callback{
+            Some(callback)=>{
+                let target = CString::new(record.target()).unwrap_or_default();
+                let msg = CString::new(record.args().to_string().replace('\0',"")).unwrap_or_default();
+                let kvs = CString::new(kvs.json().replace('\0',"")).unwrap_or_default();
+                callback(record.level() as c_int, target.as_ptr(), msg.as_ptr(), kvs.as_ptr());
+            },
+            None if cfg!(feature="log")=>{
+                let fields:String = kvs.0.iter().map(|(k,v)|format!(" {}={}",k,v)).collect();
+                eprintln!("[{}] {}: {}{}",record.level(),record.target(),record.args(),fields)
+            },
+            None=>{}
+        }
+    }
+
+    fn flush(&self){}
+}
+
+static LOGGER:FfiLogger = FfiLogger;
+
+//第一次调用时装上日志，编译时带log这个feature时没有回调也输出到stderr；返回是否装上了
+pub(crate) fn init()->bool{
+    *INSTALLED.get_or_init(||{
+        if let Err(e) = log::set_logger(&LOGGER){
+            eprintln!("cso logger not installed: {}",e);
+            return false;
+        }
+        if !LEVEL_SET.load(Ordering::Relaxed){
+            log::set_max_level(if cfg!(feature="log") {LevelFilter::Info} else {LevelFilter::Off});
+        }
+        true
+    })
+}
+
+//注册日志回调，传NULL取消；没有设置过级别时注册后为info；日志没能装上时返回-2
+#[unsafe(no_mangle)]
+pub extern "C" fn set_log_callback(callback:Option<LogCallback>)->c_int{
+    if !init(){
+        return -2
+    }
+    *CALLBACK.write().unwrap_or_else(|e|e.into_inner()) = callback;
+    if callback.is_some() && !LEVEL_SET.load(Ordering::Relaxed){
+        log::set_max_level(LevelFilter::Info);
+    }
+    0
+}
+
+//运行中调整日志级别：0关闭，1~5为error,warn,info,debug,trace，其他值返回-1，日志没能装上时返回-2
+#[unsafe(no_mangle)]
+pub extern "C" fn set_log_level(level:c_int)->c_int{
+    let filter = match level{
+        0=>LevelFilter::Off,
+        1=>Level::Error.to_level_filter(),
+        2=>Level::Warn.to_level_filter(),
+        3=>Level::Info.to_level_filter(),
+        4=>Level::Debug.to_level_filter(),
+        5=>Level::Trace.to_level_filter(),
+        _=>return -1
+    };
+    if !init(){
+        return -2
+    }
+    LEVEL_SET.store(true, Ordering::Relaxed);
+    log::set_max_level(filter);
+    0
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::{ffi::CStr, sync::Mutex};
+
+    static LINES:Mutex<Vec<(c_int,String)>> = Mutex::new(vec![]);
+
+    extern "C" fn collect(level:c_int,target:*const c_char,msg:*const c_char,kvs:*const c_char){
+        let (target,msg,kvs) = unsafe{(CStr::from_ptr(target).to_string_lossy(),CStr::from_ptr(msg).to_string_lossy(),CStr::from_ptr(kvs).to_string_lossy())};
+        LINES.lock().unwrap().push((level,format!("{} {} {}",target,msg,kvs)));
+    }
+
+    #[test]
+    fn log_callback(){
+        assert_eq!(set_log_callback(Some(collect)),0);
+        log::info!(server = "127.0.0.1:5677", attempt = 2; "connected");
+        log::debug!("hidden");
+        assert_eq!(set_log_level(9),-1);
+        assert_eq!(set_log_level(1),0);
+        log::warn!("hidden");
+        log::error!(code = -26, error:% = "a\"b"; "shown");
+        assert_eq!(set_log_callback(None),0);
+        log::error!("hidden");
+        let lines = LINES.lock().unwrap();
+        assert_eq!(*lines,vec![(3,r#"cso::ffi_log::tests connected {"server":"127.0.0.1:5677","attempt":"2"}"#.to_string()),(1,r#"cso::ffi_log::tests shown {"code":"-26","error":"a\"b"}"#.to_string())]);
+    }
+}
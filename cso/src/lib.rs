@@ -1,9 +1,35 @@
 use std::ffi::CStr;
 use std::os::raw::{c_int,c_char};
 use rweb_common::proxy_list::ProxyList;
-use rwebc::quic_client::node_run;
+use std::sync::{Arc, Mutex, Weak};
+use rwebc::{proxy_table::ProxyTable, quic_client::{NodeOptions, ProxyStringList}, stats::Stats, supervisor::Supervisor};
+#[cfg(feature="p2p")]
+use rwebc::p2p_client::DiyTcpListener;
 #[cfg(feature="p2p")]
 use rweb_common::p2p_list::P2pCell;
+mod ffi_log;
+
+//正在运行的supervisor和各自的统计，quic_node_stop让它们下线，stats_json随时读取
+static RUNNING:Mutex<Vec<(Weak<Supervisor>,Stats)>> = Mutex::new(vec![]);
+
+//每次运行用自己的Stats，登记后运行，run在quic_node_stop之后返回，返回后不再列在stats_json里
+fn running(supervisor:Supervisor,stats:&Stats)->Arc<Supervisor>{
+    let supervisor = Arc::new(supervisor.with_stats(stats.clone()));
+    if let Ok(mut r) = RUNNING.lock() {
+        r.retain(|(s,_)|s.strong_count() > 0);
+        r.push((Arc::downgrade(&supervisor),stats.clone()));
+    }
+    supervisor
+}
+
+//让所有正在运行的quic_node_run*和p2pclient*下线，等已有的流结束(最多30秒)后它们返回0
+#[unsafe(no_mangle)]
+pub extern "C" fn quic_node_stop() {
+    let running:Vec<Arc<Supervisor>> = RUNNING.lock().map(|r|r.iter().filter_map(|(s,_)|s.upgrade()).collect()).unwrap_or_default();
+    for supervisor in running {
+        supervisor.shutdown();
+    }
+}
 
 #[unsafe(no_mangle)]
 pub extern "C" fn quic_node_run(
@@ -11,6 +37,7 @@ pub extern "C" fn quic_node_run(
     server_port: c_int,
     proxy_list: *const c_char,
 ) -> c_int {
+    ffi_log::init();
     // 转换C字符串到Rust字符串
     if let Ok(server_host) = unsafe { CStr::from_ptr(server_host).to_str() } {
         if server_host.is_empty() {
@@ -28,20 +55,15 @@ pub extern "C" fn quic_node_run(
                     Ok(rt) => rt,
                     Err(_) => return -37,
                 };
-                loop{
-                    #[cfg(feature="p2p")]
-                    let r = node_run(server_host,server_port as u16,proxy_list.clone(),None);
-                    #[cfg(not(feature="p2p"))]
-                    let r = node_run(server_host,server_port as u16,proxy_list.clone());
-                    match rt.block_on(r) {
-                        Ok(_) => {},
-                        Err(_e) => {
-                            #[cfg(feature="log")]
-                            eprintln!("{:?}",_e)
-                        },
-                    }
-                    std::thread::sleep(std::time::Duration::from_secs(30));//max_idle_timeout为21秒,这里如果是因为mac地址重复而无法连接的话，立即重连会被踢掉。
-                }
+                let proxy_list = Arc::new(proxy_list);
+                let stats = Stats::default();
+                let supervisor = running(Supervisor::new(server_host,server_port as u16),&stats);//按断开原因退避重连，quic_node_stop后返回0
+                let diy_stream = |server_addr|ProxyStringList::new(proxy_list.clone(),server_addr).with_stats(stats.clone());
+                #[cfg(feature="p2p")]
+                rt.block_on(supervisor.run(diy_stream,None::<DiyTcpListener>));
+                #[cfg(not(feature="p2p"))]
+                rt.block_on(supervisor.run(diy_stream));
+                0
             } else {
                 return -32
             }
@@ -53,6 +75,110 @@ pub extern "C" fn quic_node_run(
     }
 }
 
+//proxy_list_file为proxy_list的json文件，运行中修改文件后新的连接使用新的代理表，已有的连接不断开
+/// # Safety
+/// server_host、proxy_list_file必须是以\0结尾的有效字符串，为NULL时返回-38
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quic_node_run_file(
+    server_host: *const c_char,
+    server_port: c_int,
+    proxy_list_file: *const c_char,
+) -> c_int {
+    ffi_log::init();
+    if server_host.is_null() || proxy_list_file.is_null() {
+        return -38;
+    }
+    let Ok(server_host) = (unsafe { CStr::from_ptr(server_host).to_str() }) else {
+        return -36
+    };
+    if server_host.is_empty() {
+        return -30;
+    }
+    let Ok(path) = (unsafe { CStr::from_ptr(proxy_list_file).to_str() }) else {
+        return -35
+    };
+    let path = std::path::PathBuf::from(path);
+    let Ok(text) = std::fs::read_to_string(&path) else {
+        return -31
+    };
+    let Ok(proxy_list) = serde_json::from_str::<Vec<ProxyList>>(&text) else {
+        return -32
+    };
+    let rt = match tokio::runtime::Builder::new_current_thread()
+    .enable_io()
+    .enable_time()
+    .build() {
+        Ok(rt) => rt,
+        Err(_) => return -37,
+    };
+    let table = ProxyTable::from(proxy_list);
+    rt.spawn(table.clone().watch_file(path,std::time::Duration::from_secs(2)));
+    let stats = Stats::default();
+    let supervisor = running(Supervisor::new(server_host,server_port as u16),&stats);
+    let diy_stream = |server_addr|ProxyStringList::from_table(table.clone(),server_addr).with_stats(stats.clone());
+    #[cfg(feature="p2p")]
+    rt.block_on(supervisor.run(diy_stream,None::<DiyTcpListener>));
+    #[cfg(not(feature="p2p"))]
+    rt.block_on(supervisor.run(diy_stream));
+    0
+}
+
+//与quic_node_run相同，options为NodeOptions的json，例如{"reverse_list":[{"mac":"aabbcc00020c","name":"syslog","listen":"0.0.0.0:514"}]}，为NULL时都用默认值
+/// # Safety
+/// server_host、proxy_list必须是以\0结尾的有效字符串，options可以为NULL
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quic_node_run_options(
+    server_host: *const c_char,
+    server_port: c_int,
+    proxy_list: *const c_char,
+    options: *const c_char,
+) -> c_int {
+    ffi_log::init();
+    if server_host.is_null() || proxy_list.is_null() {
+        return -38;
+    }
+    let Ok(server_host) = (unsafe { CStr::from_ptr(server_host).to_str() }) else {
+        return -36
+    };
+    if server_host.is_empty() {
+        return -30;
+    }
+    let Ok(proxy_list) = (unsafe { CStr::from_ptr(proxy_list).to_str() }) else {
+        return -35
+    };
+    let Ok(proxy_list) = serde_json::from_str::<Vec<ProxyList>>(proxy_list) else {
+        return -32
+    };
+    let options = if options.is_null() {
+        NodeOptions::default()
+    } else {
+        match unsafe { CStr::from_ptr(options).to_str() }.map(serde_json::from_str::<NodeOptions>) {
+            Ok(Ok(options)) => options,
+            Ok(Err(e)) => {
+                log::error!(error:% = e; "options parse failed");
+                return -39
+            },
+            Err(_) => return -39,
+        }
+    };
+    let rt = match tokio::runtime::Builder::new_current_thread()
+    .enable_io()
+    .enable_time()
+    .build() {
+        Ok(rt) => rt,
+        Err(_) => return -37,
+    };
+    let proxy_list = Arc::new(proxy_list);
+    let stats = Stats::default();
+    let supervisor = running(Supervisor::new(server_host,server_port as u16),&stats);
+    let diy_stream = |server_addr|ProxyStringList::new(proxy_list.clone(),server_addr).with_reverse(options.reverse_list.clone()).with_stats(stats.clone());
+    #[cfg(feature="p2p")]
+    rt.block_on(supervisor.run(diy_stream,None::<DiyTcpListener>));
+    #[cfg(not(feature="p2p"))]
+    rt.block_on(supervisor.run(diy_stream));
+    0
+}
+
 #[cfg(feature="p2p")]
 #[unsafe(no_mangle)]
 pub extern "C" fn p2pclient(
@@ -61,6 +187,7 @@ pub extern "C" fn p2pclient(
     proxy_list: *const c_char,
     p2p_list_c_str: *const c_char,
 ) -> c_int {
+    ffi_log::init();
     // 转换C字符串到Rust字符串
     if let Ok(server_host) = unsafe { CStr::from_ptr(server_host).to_str() } {
         if server_host.is_empty() {
@@ -72,9 +199,9 @@ pub extern "C" fn p2pclient(
             }
             if let Ok(proxy_list) = serde_json::from_str::<Vec<ProxyList>>(proxy_addr) {
                 if let Ok(p2p_list_str) = unsafe{CStr::from_ptr(p2p_list_c_str).to_str() }{
-                    println!("p2p_list: {}",p2p_list_str);
+                    log::info!(p2p_list:% = p2p_list_str; "p2p_list");
                     if let Err(e) = serde_json::from_str::<Vec<P2pCell>>(p2p_list_str){
-                        eprintln!("p2p_list parse error: {}",e);
+                        log::error!(error:% = e; "p2p_list parse failed");
                     }
                     if let Ok(p2p_list) = serde_json::from_str::<Vec<P2pCell>>(p2p_list_str) {
                         let rt = match tokio::runtime::Builder::new_current_thread()
@@ -84,16 +211,13 @@ pub extern "C" fn p2pclient(
                             Ok(rt) => rt,
                             Err(_) => return -37,
                         };
-                        loop{
-                            match rt.block_on(node_run(server_host,server_port as u16,proxy_list.clone(),Some(p2p_list.clone()))) {
-                                Ok(_) => {},
-                                Err(_e) => {
-                                    #[cfg(feature="log")]
-                                    eprintln!("{:?}",_e)
-                                },
-                            }
-                            std::thread::sleep(std::time::Duration::from_secs(30));//max_idle_timeout为21秒,这里如果是因为mac地址重复而无法连接的话，立即重连会被踢掉。
-                        }
+                        let proxy_list = Arc::new(proxy_list);
+                        let stats = Stats::default();
+                        let supervisor = running(Supervisor::new(server_host,server_port as u16),&stats);
+                        let p_client = DiyTcpListener{list:p2p_list,..Default::default()};
+                        let stats = stats.with_p2p(p_client.sessions.clone());
+                        rt.block_on(supervisor.run(|server_addr|ProxyStringList::new(proxy_list.clone(),server_addr).with_stats(stats.clone()),Some(p_client)));
+                        0
                     }else{                        
                         return -34
                     }
@@ -109,4 +233,114 @@ pub extern "C" fn p2pclient(
     } else {
         -36
     }
-}
\ No newline at end of file
+}
+
+//与p2pclient相同，options为NodeOptions的json，例如{"stun_servers":["1.2.3.4:3478"]}，为NULL时都用默认值
+/// # Safety
+/// server_host、proxy_list、p2p_list必须是以\0结尾的有效字符串，options可以为NULL
+#[cfg(feature="p2p")]
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn p2pclient_options(
+    server_host: *const c_char,
+    server_port: c_int,
+    proxy_list: *const c_char,
+    p2p_list: *const c_char,
+    options: *const c_char,
+) -> c_int {
+    ffi_log::init();
+    if server_host.is_null() || proxy_list.is_null() || p2p_list.is_null() {
+        return -38;
+    }
+    let Ok(server_host) = (unsafe { CStr::from_ptr(server_host).to_str() }) else {
+        return -36
+    };
+    if server_host.is_empty() {
+        return -30;
+    }
+    let Ok(proxy_list) = (unsafe { CStr::from_ptr(proxy_list).to_str() }) else {
+        return -35
+    };
+    let Ok(proxy_list) = serde_json::from_str::<Vec<ProxyList>>(proxy_list) else {
+        return -32
+    };
+    let Ok(p2p_list) = (unsafe { CStr::from_ptr(p2p_list).to_str() }) else {
+        return -33
+    };
+    let p2p_list = match serde_json::from_str::<Vec<P2pCell>>(p2p_list) {
+        Ok(p2p_list) => p2p_list,
+        Err(e) => {
+            log::error!(error:% = e; "p2p_list parse failed");
+            return -34
+        },
+    };
+    let options = if options.is_null() {
+        NodeOptions::default()
+    } else {
+        match unsafe { CStr::from_ptr(options).to_str() }.map(serde_json::from_str::<NodeOptions>) {
+            Ok(Ok(options)) => options,
+            Ok(Err(e)) => {
+                log::error!(error:% = e; "options parse failed");
+                return -39
+            },
+            Err(_) => return -39,
+        }
+    };
+    let rt = match tokio::runtime::Builder::new_current_thread()
+    .enable_io()
+    .enable_time()
+    .build() {
+        Ok(rt) => rt,
+        Err(_) => return -37,
+    };
+    let proxy_list = Arc::new(proxy_list);
+    let stats = Stats::default();
+    let supervisor = running(Supervisor::new(server_host,server_port as u16),&stats);
+    let p_client = DiyTcpListener{list:p2p_list,stun_servers:options.stun_servers,sessions:options.sessions,..Default::default()};
+    let stats = stats.with_p2p(p_client.sessions.clone());
+    let reverse_list = options.reverse_list;
+    rt.block_on(supervisor.run(|server_addr|ProxyStringList::new(proxy_list.clone(),server_addr).with_reverse(reverse_list.clone()).with_stats(stats.clone()),Some(p_client)));
+    0
+}
+
+//把统计的json快照写入buf(以\0结尾)，返回json的字节数(不含\0)；返回值不小于len时buf不够，内容被截断，按返回值+1重新分配
+//json为数组，每个正在运行的quic_node_run*/p2pclient*一项，按开始运行的顺序，没有在运行的为[]
+//buf为NULL或len为0时只返回需要的长度，可以在其他线程上随时调用
+/// # Safety
+/// buf不为NULL时必须指向至少len字节可写的内存
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn stats_json(buf: *mut c_char, len: c_int) -> c_int {
+    let runs:Vec<String> = RUNNING.lock().map(|r|r.iter().filter(|(s,_)|s.strong_count() > 0).map(|(_,stats)|stats.json()).collect()).unwrap_or_default();
+    let json = format!("[{}]",runs.join(","));
+    if !buf.is_null() && len > 0 {
+        let n = json.len().min(len as usize - 1);
+        unsafe {
+            std::ptr::copy_nonoverlapping(json.as_ptr() as *const c_char, buf, n);
+            *buf.add(n) = 0;
+        }
+    }
+    json.len() as c_int
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    //每次运行一项，返回后不再列出
+    #[test]
+    fn stats_per_run(){
+        let json = ||{
+            let len = unsafe{stats_json(std::ptr::null_mut(),0)};
+            let mut buf = vec![0 as c_char;len as usize + 1];
+            assert_eq!(unsafe{stats_json(buf.as_mut_ptr(),buf.len() as c_int)},len);
+            serde_json::from_str::<serde_json::Value>(&unsafe{CStr::from_ptr(buf.as_ptr())}.to_string_lossy()).unwrap()
+        };
+        let (a,b) = (Stats::default(),Stats::default());
+        let run_a = running(Supervisor::new("127.0.0.1",1),&a);
+        let run_b = running(Supervisor::new("127.0.0.1",2),&b);
+        assert_eq!(json().as_array().map(|r|r.len()),Some(2));
+        drop(run_a);
+        assert_eq!(json().as_array().map(|r|r.len()),Some(1));
+        drop(run_b);
+        assert_eq!(json(),serde_json::json!([]));
+    }
+}
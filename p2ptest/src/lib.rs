@@ -1 +1,3 @@
-pub mod quic_server;
\ No newline at end of file
+pub mod quic_server;
+pub mod stun_server;
+pub mod nat_sim;
\ No newline at end of file
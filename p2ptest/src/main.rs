@@ -1,5 +1,46 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use clap::Parser;
+
+#[derive(Parser)]
+#[clap(
+    author = "reform <reformgg@gmail.com>",
+    version = "0.1.0",
+    about = "NAT类型测试服务",
+    long_about = "RFC 5780风格的NAT类型测试服务，rwebc用它测出映射和过滤行为以及端口分配增量"
+)]
+struct Opts {
+    ///主端口
+    #[clap(short, long, default_value = "5678")]
+    port: u16,
+    ///备用端口，用于测试端口相关的映射和过滤
+    #[clap(long, default_value = "5679")]
+    alt_port: u16,
+    ///主ip，设置备用ip时必须为具体地址
+    #[clap(long, default_value = "0.0.0.0")]
+    ip: IpAddr,
+    ///备用ip，服务器有第二个公网ip时设置，可以区分地址相关和端口相关
+    #[clap(long)]
+    alt_ip: Option<IpAddr>,
+    ///STUN端口，回应标准STUN Binding请求，0为不开启
+    #[clap(long, default_value = "3478")]
+    stun_port: u16,
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    p2ptest::quic_server::run(5678).await;
-    println!("Hello, world!");
+    let opts = Opts::parse();
+    if opts.alt_ip.is_some() && opts.ip == IpAddr::V4(Ipv4Addr::UNSPECIFIED){
+        eprintln!("--alt-ip需要同时设置--ip");
+        return;
+    }
+    if opts.stun_port != 0{
+        match p2ptest::stun_server::StunServer::bind(SocketAddr::new(opts.ip,opts.stun_port)).await{
+            Ok(stun)=>{
+                println!("stun server listen on {}",opts.stun_port);
+                tokio::spawn(stun.serve());
+            },
+            Err(e)=>eprintln!("stun server bind error:{}",e)
+        }
+    }
+    p2ptest::quic_server::run(opts.port,opts.alt_port,opts.ip,opts.alt_ip).await;
 }
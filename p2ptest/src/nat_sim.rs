@@ -0,0 +1,249 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    io::{self, IoSliceMut},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex},
+    task::{Context, Poll}
+};
+use quinn::{udp::{RecvMeta, Transmit}, AsyncUdpSocket, UdpPoller};
+use rweb_common::nat::{NatBehavior, NatProfile};
+use tokio::{net::UdpSocket, sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}};
+
+//回环上的用户态NAT，直接给quinn当socket用
+//内部endpoint发出的包按映射规则换成外部socket(external_ip上的真实udp端口)发出，外部socket收到的包按过滤规则决定是否交给内部
+//external_ip用127.0.0.x区分不同的NAT，这样地址相关和端口相关的过滤才有区别
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum NatKind{
+    FullCone,//EI映射，EI过滤
+    Restricted,//EI映射，AD过滤
+    PortRestricted,//EI映射，APD过滤
+    Symmetric,//APD映射，APD过滤
+}
+
+//外部端口分配方式
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum PortAlloc{
+    Sequential(i32),//按步长递增
+    Random,
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct NatConfig{
+    pub mapping:NatBehavior,
+    pub filtering:NatBehavior,
+    pub alloc:PortAlloc,
+    pub external_ip:IpAddr,
+}
+
+impl NatConfig{
+    pub fn new(kind:NatKind,external_ip:IpAddr)->Self{
+        let (mapping,filtering) = match kind{
+            NatKind::FullCone => (NatBehavior::EndpointIndependent,NatBehavior::EndpointIndependent),
+            NatKind::Restricted => (NatBehavior::EndpointIndependent,NatBehavior::AddressDependent),
+            NatKind::PortRestricted => (NatBehavior::EndpointIndependent,NatBehavior::AddressAndPortDependent),
+            NatKind::Symmetric => (NatBehavior::AddressAndPortDependent,NatBehavior::AddressAndPortDependent),
+        };
+        Self{mapping,filtering,alloc:PortAlloc::Sequential(1),external_ip}
+    }
+
+    pub fn with_alloc(mut self,alloc:PortAlloc)->Self{
+        self.alloc = alloc;
+        self
+    }
+
+    //这个NAT的真实画像，测试里可以直接交给节点，省去测NAT
+    pub fn profile(&self)->NatProfile{
+        let port_delta = match (self.mapping,self.alloc){
+            (NatBehavior::EndpointIndependent,_)|(_,PortAlloc::Random) => 0,
+            (_,PortAlloc::Sequential(delta)) => delta,
+        };
+        NatProfile{mapping:self.mapping,filtering:self.filtering,port_delta}
+    }
+}
+
+#[derive(Default)]
+struct NatState{
+    mappings:HashMap<SocketAddr,Arc<UdpSocket>>,//映射键->外部socket
+    contacted:HashMap<u16,HashSet<SocketAddr>>,//外部端口->从这个端口发往过的地址
+    next_port:Option<u16>,
+    blocked:HashSet<IpAddr>,//模拟线路中断，收发都丢
+}
+
+pub struct NatSocket{
+    config:NatConfig,
+    inner:SocketAddr,
+    state:Arc<Mutex<NatState>>,
+    tx:UnboundedSender<(Vec<u8>,SocketAddr)>,
+    rx:Mutex<UnboundedReceiver<(Vec<u8>,SocketAddr)>>,
+    dropped:Arc<AtomicU64>,
+}
+
+impl Debug for NatSocket{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NatSocket").field("config",&self.config).field("inner",&self.inner).finish()
+    }
+}
+
+impl NatSocket{
+    pub fn new(config:NatConfig)->Arc<Self>{
+        let (tx,rx) = unbounded_channel();
+        Arc::new(Self{
+            config,
+            inner:SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192,168,1,2)),fastrand::u16(1024..)),
+            state:Arc::new(Mutex::new(NatState::default())),
+            tx,
+            rx:Mutex::new(rx),
+            dropped:Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    //已经分配的外部地址
+    pub fn external_addrs(&self)->Vec<SocketAddr>{
+        self.state.lock().map(|s|s.mappings.values().filter_map(|m|m.local_addr().ok()).collect()).unwrap_or_default()
+    }
+
+    //被过滤规则丢弃的包数
+    pub fn dropped(&self)->u64{
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    //切断或恢复到某个地址的线路，映射保留
+    pub fn block(&self,ip:IpAddr,blocked:bool){
+        if let Ok(mut state) = self.state.lock(){
+            if blocked{
+                state.blocked.insert(ip);
+            }else{
+                state.blocked.remove(&ip);
+            }
+        }
+    }
+
+    fn mapping_key(&self,destination:SocketAddr)->SocketAddr{
+        match self.config.mapping{
+            NatBehavior::EndpointIndependent => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED),0),
+            NatBehavior::AddressDependent | NatBehavior::Ambiguous => SocketAddr::new(destination.ip(),0),//Ambiguous只是测不出，模拟时按AD
+            NatBehavior::AddressAndPortDependent => destination,
+        }
+    }
+
+    //按分配方式找一个能绑定的外部端口
+    fn bind_external(&self,state:&mut NatState)->io::Result<std::net::UdpSocket>{
+        for _ in 0..100{
+            let port = match self.config.alloc{
+                PortAlloc::Random => fastrand::u16(1024..),
+                PortAlloc::Sequential(delta) => {
+                    let port = match state.next_port{
+                        Some(port) => (port as i32 + delta).clamp(1024,65535) as u16,
+                        None => fastrand::u16(20000..40000),
+                    };
+                    state.next_port = Some(port);
+                    port
+                }
+            };
+            if let Ok(socket) = std::net::UdpSocket::bind(SocketAddr::new(self.config.external_ip,port)){
+                return Ok(socket);
+            }
+        }
+        std::net::UdpSocket::bind(SocketAddr::new(self.config.external_ip,0))
+    }
+
+    fn external(&self,destination:SocketAddr)->io::Result<Arc<UdpSocket>>{
+        let mut state = self.state.lock().map_err(|_|io::Error::other("nat state poisoned"))?;
+        let key = self.mapping_key(destination);
+        let socket = match state.mappings.get(&key){
+            Some(socket)=>socket.clone(),
+            None=>{
+                let socket = self.bind_external(&mut state)?;
+                socket.set_nonblocking(true)?;
+                let socket = Arc::new(UdpSocket::from_std(socket)?);
+                state.mappings.insert(key,socket.clone());
+                tokio::spawn(forward_in(socket.clone(),self.config.filtering,self.state.clone(),self.tx.clone(),self.dropped.clone()));
+                socket
+            }
+        };
+        let port = socket.local_addr()?.port();
+        state.contacted.entry(port).or_default().insert(destination);
+        Ok(socket)
+    }
+}
+
+//外部socket收到的包按过滤规则转给内部，内部socket关掉后退出
+async fn forward_in(socket:Arc<UdpSocket>,filtering:NatBehavior,state:Arc<Mutex<NatState>>,tx:UnboundedSender<(Vec<u8>,SocketAddr)>,dropped:Arc<AtomicU64>){
+    let mut buf = vec![0u8;65535];
+    let port = match socket.local_addr(){
+        Ok(addr)=>addr.port(),
+        Err(_)=>return
+    };
+    while let Ok((len,src)) = socket.recv_from(&mut buf).await{
+        let allowed = state.lock().map(|s|{
+            if s.blocked.contains(&src.ip()){
+                return false;
+            }
+            let contacted = s.contacted.get(&port);
+            match filtering{
+                NatBehavior::EndpointIndependent => true,
+                NatBehavior::AddressDependent | NatBehavior::Ambiguous => contacted.is_some_and(|c|c.iter().any(|a|a.ip() == src.ip())),
+                NatBehavior::AddressAndPortDependent => contacted.is_some_and(|c|c.contains(&src)),
+            }
+        }).unwrap_or_default();
+        if !allowed{
+            dropped.fetch_add(1,Ordering::Relaxed);
+            continue;
+        }
+        if tx.send((buf[..len].to_vec(),src)).is_err(){
+            break;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Writable;
+
+impl UdpPoller for Writable{
+    fn poll_writable(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncUdpSocket for NatSocket{
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(Writable)
+    }
+
+    //发不出去就当丢包，和真实NAT一样
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        if self.state.lock().is_ok_and(|s|s.blocked.contains(&transmit.destination.ip())){
+            return Ok(());
+        }
+        let socket = self.external(transmit.destination)?;
+        let segment = transmit.segment_size.unwrap_or(transmit.contents.len()).max(1);
+        for datagram in transmit.contents.chunks(segment){
+            socket.try_send_to(datagram,transmit.destination).unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+        let mut rx = match self.rx.lock(){
+            Ok(rx)=>rx,
+            Err(_)=>return Poll::Ready(Err(io::Error::other("nat rx poisoned")))
+        };
+        match rx.poll_recv(cx){
+            Poll::Ready(Some((data,src)))=>{
+                let len = data.len().min(bufs[0].len());
+                bufs[0][..len].copy_from_slice(&data[..len]);
+                meta[0] = RecvMeta{addr:src,len,stride:len,ecn:None,dst_ip:None};
+                Poll::Ready(Ok(1))
+            },
+            Poll::Ready(None)=>Poll::Ready(Err(io::Error::other("nat closed"))),
+            Poll::Pending=>Poll::Pending
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.inner)
+    }
+}
@@ -1,11 +1,13 @@
 use std::{
     error::Error,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc
+    sync::Arc,
+    time::Duration
 };
 use rustls::pki_types::pem::PemObject;
 use quinn::{ClientConfig, Endpoint, Incoming, ServerConfig, TransportConfig, VarInt};
-use rweb_common::io::header::write_addr;
+use rweb_common::{io::header::write_addr, nat::{NAT_CMD_CHANGE_IP, NAT_CMD_CHANGE_PORT, NAT_CMD_INFO, NAT_RESULT_FAIL, NAT_RESULT_OK, NAT_RESULT_UNSUPPORTED}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, time::timeout};
 use rweb_common::key::CER_BIN;
 use rweb_common::key::KEY_BIN;
 
@@ -13,6 +15,7 @@ use rweb_common::key::KEY_BIN;
 //const KEY_BIN:&[u8] = include_bytes!("../../reform.key");
 const KEEPALIVE_INTERVAL_MILLIS:u64=10_000;
 const IDLE_TIMEOUT_MILLIS:u32=21_000;
+const CALLBACK_TIMEOUT_MILLIS:u64=2_000;
 
 fn make_server_udp_endpoint(addr:SocketAddr, cert_der:&[u8], priv_key:&[u8]) -> Result<Endpoint, Box<dyn Error>> {
     Ok(Endpoint::server( configure_host_server(cert_der,priv_key)?, addr)?)
@@ -37,59 +40,139 @@ fn configure_host_client(cert_der:&[u8]) -> ClientConfig {
     let mut transport_config = TransportConfig::default();
     transport_config
         //.keep_alive_interval(Some(std::time::Duration::from_millis(KEEPALIVE_INTERVAL_MILLIS)))
-        .max_idle_timeout(Some(quinn::IdleTimeout::from(quinn::VarInt::from_u32(10))))
+        .max_idle_timeout(Some(quinn::IdleTimeout::from(quinn::VarInt::from_u32(IDLE_TIMEOUT_MILLIS))))
         .max_concurrent_bidi_streams(10000_u16.into())
         .max_concurrent_uni_streams(1000_u16.into());
     client_config.transport_config(std::sync::Arc::new(transport_config));
     client_config
 }
 
-pub async fn run(port:u16){
-    let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
-    let mut endpoint = make_server_udp_endpoint(bind_addr,CER_BIN.as_bytes(),KEY_BIN.as_bytes()).unwrap();
-    endpoint.set_default_client_config(configure_host_client(CER_BIN.as_bytes()));
-    li(endpoint).await;
+//RFC 5780风格的NAT测试服务
+//主地址ip:port，备用端口alt_port；有备用ip时ip不能是0.0.0.0，共监听四个地址
+//每个连接先在uni流上返回测得的地址，之后在bi流上接受NAT_CMD_*命令
+pub struct NatServer{
+    endpoints:Vec<Endpoint>,
+    ctx:NatCtx,
+}
+
+#[derive(Clone)]
+struct NatCtx{
+    alt_port:Endpoint,//ip:alt_port，回连测试过滤行为
+    alt_ip:Option<Endpoint>,//alt_ip:alt_port
+    info:SocketAddr,//告诉客户端的备用地址
+}
+
+impl NatServer{
+    pub fn bind(ip:IpAddr,port:u16,alt_port:u16,alt_ip:Option<IpAddr>)->Result<Self,Box<dyn Error>>{
+        let bind = |addr:SocketAddr|->Result<Endpoint,Box<dyn Error>>{
+            let mut endpoint = make_server_udp_endpoint(addr,CER_BIN.as_bytes(),KEY_BIN.as_bytes())?;
+            endpoint.set_default_client_config(configure_host_client(CER_BIN.as_bytes()));
+            Ok(endpoint)
+        };
+        let primary = bind(SocketAddr::new(ip,port))?;
+        let port = primary.local_addr()?.port();
+        let alt = bind(SocketAddr::new(ip,alt_port))?;
+        let alt_port = alt.local_addr()?.port();
+        let mut endpoints = vec![primary,alt.clone()];
+        let alt_ip_endpoint = match alt_ip{
+            Some(alt_ip)=>{
+                endpoints.push(bind(SocketAddr::new(alt_ip,port))?);
+                let endpoint = bind(SocketAddr::new(alt_ip,alt_port))?;
+                endpoints.push(endpoint.clone());
+                Some(endpoint)
+            },
+            None=>None
+        };
+        let info = SocketAddr::new(alt_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),alt_port);
+        Ok(Self{endpoints,ctx:NatCtx{alt_port:alt,alt_ip:alt_ip_endpoint,info}})
+    }
+
+    pub fn local_addr(&self)->SocketAddr{
+        self.endpoints[0].local_addr().unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED),0))
+    }
+
+    pub async fn serve(self){
+        let lis = self.endpoints.into_iter().map(|endpoint|Box::pin(li(endpoint,self.ctx.clone())));
+        futures::future::join_all(lis).await;
+    }
+}
+
+pub async fn run(port:u16,alt_port:u16,ip:IpAddr,alt_ip:Option<IpAddr>){
+    let server = NatServer::bind(ip,port,alt_port,alt_ip).unwrap();
+    println!("nat test server listen on {}",server.local_addr());
+    server.serve().await;
 }
     
-async fn li(endpoint:Endpoint){
+async fn li(endpoint:Endpoint,ctx:NatCtx){
     loop{
         let inc = endpoint.accept().await;
         match inc{
             Some(conn)=>{
-                tokio::spawn(handle_incomming(conn));
+                tokio::spawn(handle_incomming(conn,ctx.clone()));
             },
             None=>{
+                break
             }
         }
     }
 }
 
-async fn handle_incomming(inc:Incoming)->Result<(),Box<dyn Error+Send+Sync>>{
+async fn handle_incomming(inc:Incoming,ctx:NatCtx)->Result<(),Box<dyn Error+Send+Sync>>{
     println!("accept from {}", inc.remote_address());
     let conn = inc.await?;
-    let mut uni = conn.open_uni().await?;
     let addr = conn.remote_address();
+    let mut uni = conn.open_uni().await?;
     write_addr(&mut uni,addr).await?;
-    uni.stopped().await.unwrap_or_default();//等待对方接收完数据关闭流，如果不等待的话，这里会直接关闭流，导致对方无法接收数据。
+    uni.finish()?;//对方读完后关闭连接即可，旧客户端只读这个地址
+    while let Ok((mut send,mut recv)) = conn.accept_bi().await{
+        match recv.read_u8().await?{
+            NAT_CMD_INFO=>write_addr(&mut send,ctx.info).await?,
+            NAT_CMD_CHANGE_PORT=>send.write_u8(callback(&ctx.alt_port,addr).await).await?,
+            NAT_CMD_CHANGE_IP=>{
+                let result = match &ctx.alt_ip{
+                    Some(endpoint)=>callback(endpoint,addr).await,
+                    None=>NAT_RESULT_UNSUPPORTED
+                };
+                send.write_u8(result).await?
+            },
+            _=>break
+        }
+        send.finish()?;
+    }
     Ok(())
 }
 
+//从另一个地址主动连接客户端测得的地址，能连上说明NAT放行了这个来源
+async fn callback(endpoint:&Endpoint,addr:SocketAddr)->u8{
+    let Ok(connecting) = endpoint.connect(addr,"reform") else{
+        return NAT_RESULT_FAIL
+    };
+    match timeout(Duration::from_millis(CALLBACK_TIMEOUT_MILLIS),connecting).await{
+        Ok(Ok(conn))=>{
+            conn.close(VarInt::from_u32(0),b"Ok");
+            NAT_RESULT_OK
+        },
+        _=>NAT_RESULT_FAIL
+    }
+}
 
 #[cfg(test)]
 mod tests{
-    use std::{error::Error, net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs}, sync::Arc};
+    use std::{error::Error, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::Arc};
     use rweb_common::io::header::read_addr;
     use quinn::{ClientConfig, Endpoint, ServerConfig, TransportConfig, VarInt};
     use rustls::pki_types::pem::PemObject;
 
-    use crate::quic_server::{CER_BIN, KEY_BIN, KEEPALIVE_INTERVAL_MILLIS, IDLE_TIMEOUT_MILLIS};
+    use crate::quic_server::{NatServer, CER_BIN, KEY_BIN, KEEPALIVE_INTERVAL_MILLIS, IDLE_TIMEOUT_MILLIS};
 
     #[tokio::test]
     async fn tt(){
         let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
         let mut endpoint = make_server_udp_endpoint(bind_addr,CER_BIN.as_bytes(),KEY_BIN.as_bytes()).unwrap();
         endpoint.set_default_client_config(configure_host_client(CER_BIN.as_bytes()));
-        let addr = "127.0.0.1:5678".to_socket_addrs().unwrap().next().unwrap();
+        let server = NatServer::bind(IpAddr::V4(Ipv4Addr::LOCALHOST),0,0,None).unwrap();
+        let addr = server.local_addr();
+        tokio::spawn(server.serve());
         //let li = li(endpoint.clone());
         //tokio::spawn(li);
         println!("addr:{}",addr.to_string());
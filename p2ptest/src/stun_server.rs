@@ -0,0 +1,27 @@
+use std::{io, net::SocketAddr};
+use rweb_common::stun::{binding_response, parse_header, STUN_BINDING_REQUEST};
+use tokio::net::UdpSocket;
+
+//标准STUN服务，只回应Binding请求，rwebc或其他STUN客户端用它取反射地址
+pub struct StunServer{
+    socket:UdpSocket,
+}
+
+impl StunServer{
+    pub async fn bind(addr:SocketAddr)->io::Result<Self>{
+        Ok(Self{socket:UdpSocket::bind(addr).await?})
+    }
+
+    pub fn local_addr(&self)->io::Result<SocketAddr>{
+        self.socket.local_addr()
+    }
+
+    pub async fn serve(self){
+        let mut buf = vec![0u8;2048];
+        while let Ok((len,src)) = self.socket.recv_from(&mut buf).await{
+            if let Some((STUN_BINDING_REQUEST,txid)) = parse_header(&buf[..len]){
+                self.socket.send_to(&binding_response(&txid,src,"p2ptest"),src).await.unwrap_or_default();
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::{error::Error, str::FromStr, time::Duration};
+use rweb_common::{io::socks5::SocksAddr, mac::Mac, RwebError};
+use tokio::net::{TcpListener, TcpStream};
+use crate::{http_proxy::parse_target, tunnel::Tunnel};
+
+//打开隧道失败时的重试次数，设备断线重连或rwebs重启期间不直接断开本地连接
+const OPEN_RETRY:u64 = 5;
+
+//类似ssh -L，格式为 [本地地址:]本地端口:设备标签:目标主机:目标端口
+#[derive(Debug,Clone)]
+pub struct Forward{
+    pub bind:String,
+    pub mac:Mac,
+    pub target:SocksAddr,
+}
+
+impl FromStr for Forward{
+    type Err = RwebError;
+    fn from_str(s:&str)->Result<Self,Self::Err>{
+        let err = ||RwebError::new(8101,format!("forward format error:{}",s));
+        //目标主机可能是[ipv6]，先取出最后的 目标主机:目标端口
+        let (rest,target) = match s.rsplit_once("]:"){
+            Some((rest,port)) if rest.contains('[') && port.parse::<u16>().is_ok() => {
+                let (rest,host) = rest.rsplit_once('[').ok_or_else(err)?;
+                (rest.strip_suffix(':').ok_or_else(err)?,format!("[{}]:{}",host,port))
+            },
+            _ => {
+                let (rest,port) = s.rsplit_once(':').ok_or_else(err)?;
+                let (rest,host) = rest.rsplit_once(':').ok_or_else(err)?;
+                (rest,format!("{}:{}",host,port))
+            }
+        };
+        let (local,device) = rest.rsplit_once(':').ok_or_else(err)?;
+        let bind = match local.rsplit_once(':'){
+            Some((addr,port))=>format!("{}:{}",addr,port),
+            None=>format!("127.0.0.1:{}",local)
+        };
+        let target = parse_target(&target,0).map_err(|e|RwebError::new(8101,e))?;
+        if target.port() == 0{
+            return Err(err());
+        }
+        Ok(Self{bind,mac:device.parse()?,target})
+    }
+}
+
+//监听本地端口，每个连接都经设备转到target
+pub async fn run_forward(forward:Forward,tunnel:Tunnel)->Result<(),RwebError>{
+    let listener = TcpListener::bind(&forward.bind).await.map_err(|e|RwebError::new(8102,e))?;
+    log::info!("forward {} -> {} via {}",listener.local_addr().unwrap_or(([0,0,0,0],0).into()),forward.target,forward.mac);
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                log::debug!("forward accept from {}", addr);
+                let tunnel = tunnel.clone();
+                let forward = forward.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_forward(stream,tunnel,&forward).await{
+                        log::warn!("forward {} -> {} error:{}",addr,forward.target,e);
+                    }
+                });
+            }
+            Err(e) => {
+                log::warn!("accept error:{}", e);
+            }
+        }
+    }
+}
+
+async fn handle_forward(mut client_stream:TcpStream,tunnel:Tunnel,forward:&Forward)->Result<(),Box<dyn Error+Send+Sync>>{
+    let mut retry = 0;
+    let mut server_stream = loop{
+        match tunnel.open(forward.mac,&forward.target).await{
+            Ok(server_stream)=>break server_stream,
+            //7101为设备未连接，8003/8005为连不上rwebs，其他错误(如目标不可达)不重试
+            Err(e) if retry < OPEN_RETRY && matches!(e.code,7101|8003|8005) => {
+                retry += 1;
+                log::info!("forward open {} retry {}:{}",forward.target,retry,e);
+                tokio::time::sleep(Duration::from_secs(retry)).await;
+            },
+            Err(e)=>return Err(e.into())
+        }
+    };
+    tokio::io::copy_bidirectional(&mut client_stream,&mut server_stream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    #[test]
+    fn parse(){
+        let f:Forward = "2222:aabbccddeeff:192.168.2.10:22".parse().unwrap();
+        assert_eq!(f.bind,"127.0.0.1:2222");
+        assert_eq!(f.target,SocksAddr::Ip("192.168.2.10:22".parse().unwrap()));
+        let f:Forward = "0.0.0.0:502:aa-bb-cc-dd-ee-ff:plc.lan:502".parse().unwrap();
+        assert_eq!(f.bind,"0.0.0.0:502");
+        assert_eq!(f.target,SocksAddr::Domain("plc.lan".to_string(),502));
+        let f:Forward = "2222:aabbccddeeff:[fe80::1]:22".parse().unwrap();
+        assert_eq!(f.target,SocksAddr::Ip("[fe80::1]:22".parse().unwrap()));
+        let f:Forward = "[::1]:2222:aabbccddeeff:192.168.2.10:22".parse().unwrap();
+        assert_eq!(f.bind,"[::1]:2222");
+        assert!("2222:aabbccddeeff:192.168.2.10".parse::<Forward>().is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::error::Error;
+use rweb_common::{get_header, io::socks5::SocksAddr};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+use crate::{rules::Rules, tunnel::Tunnel};
+
+//本地http_proxy，CONNECT直接走隧道，普通http请求改写为相对路径后走隧道
+pub async fn handle_http(mut client_stream:TcpStream,tunnel:Tunnel,rules:&Rules)->Result<(),Box<dyn Error+Send+Sync>>{
+    let mut header = get_header(&mut client_stream).await?;
+    if header.method == "CONNECT"{
+        let target = parse_target(&header.uri,443)?;
+        let mac = match select(rules,&target){
+            Some(mac)=>mac,
+            None=>{
+                client_stream.write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n").await?;
+                return Err(format!("no device for {}",target).into());
+            }
+        };
+        match tunnel.open(mac,&target).await{
+            Ok(mut server_stream)=>{
+                client_stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+                tokio::io::copy_bidirectional(&mut client_stream,&mut server_stream).await?;
+            },
+            Err(e)=>{
+                client_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n").await?;
+                return Err(e.into());
+            }
+        }
+        return Ok(());
+    }
+    let uri = url::Url::parse(&header.uri)?;
+    let host = uri.host_str().ok_or("no host")?;
+    let target = parse_target(&format!("{}:{}",host,uri.port_or_known_default().unwrap_or(80)),80)?;
+    let mac = match select(rules,&target){
+        Some(mac)=>mac,
+        None=>{
+            client_stream.write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n").await?;
+            return Err(format!("no device for {}",target).into());
+        }
+    };
+    let method = header.method.clone();
+    header.remove(&method);//去掉方法头
+    header.remove("Proxy-Connection");
+    header.remove("Proxy-Authorization");
+    header.insert("Connection".to_string(),"close".to_string());//一个连接只对应一个目标
+    header.uri = match uri.query(){
+        Some(query)=>format!("{}?{}",uri.path(),query),
+        None=>uri.path().to_string()
+    };
+    match tunnel.open(mac,&target).await{
+        Ok(mut server_stream)=>{
+            server_stream.write_all(&Into::<Vec<u8>>::into(header)).await?;
+            tokio::io::copy_bidirectional(&mut client_stream,&mut server_stream).await?;
+        },
+        Err(e)=>{
+            client_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n").await?;
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+pub fn select(rules:&Rules,target:&SocksAddr)->Option<rweb_common::mac::Mac>{
+    match target{
+        SocksAddr::Ip(addr)=>rules.select(&addr.ip().to_string()),
+        SocksAddr::Domain(host,_)=>rules.select(host),
+    }
+}
+
+pub fn parse_target(authority:&str,default_port:u16)->Result<SocksAddr,Box<dyn Error+Send+Sync>>{
+    if let Ok(addr) = authority.parse(){
+        return Ok(SocksAddr::Ip(addr));
+    }
+    if authority.matches(':').count() > 1 && !authority.starts_with('['){//不带方括号的ipv6，没有端口
+        return Ok(SocksAddr::Ip(std::net::SocketAddr::new(authority.parse()?,default_port)));
+    }
+    let (host,port) = match authority.rsplit_once(':'){
+        Some((host,port)) if !host.ends_with(']') || host.starts_with('[') => (host,port.parse()?),
+        _ => (authority,default_port)
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok(match host.parse(){
+        Ok(ip)=>SocksAddr::Ip(std::net::SocketAddr::new(ip,port)),
+        Err(_)=>SocksAddr::Domain(host.to_string(),port)
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn target(){
+        let ip = |s:&str|SocksAddr::Ip(s.parse().unwrap());
+        assert_eq!(parse_target("192.168.2.10:22",443).unwrap(),ip("192.168.2.10:22"));
+        assert_eq!(parse_target("192.168.2.10",443).unwrap(),ip("192.168.2.10:443"));
+        assert_eq!(parse_target("[fe80::1]:22",443).unwrap(),ip("[fe80::1]:22"));
+        assert_eq!(parse_target("fe80::1",443).unwrap(),ip("[fe80::1]:443"));
+        assert_eq!(parse_target("::1",80).unwrap(),ip("[::1]:80"));
+        assert!(parse_target("fe80::zz",443).is_err());
+        assert_eq!(parse_target("plc.lan:502",443).unwrap(),SocksAddr::Domain("plc.lan".to_string(),502));
+        assert_eq!(parse_target("plc.lan",443).unwrap(),SocksAddr::Domain("plc.lan".to_string(),443));
+    }
+}
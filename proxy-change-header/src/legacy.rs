@@ -0,0 +1,36 @@
+use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}};
+use rweb_common::get_header;
+use std::error::Error;
+
+//旧版用法：把请求头里的Host改成device_addr后明文转给rwebs，只支持http
+pub async fn run(bind:&str,port:u16,device_addr:String)->Result<(),Box<dyn Error+Send+Sync>>{
+    let listener = TcpListener::bind(format!("{}:{}",bind,port)).await?;
+    log::info!("listen on {}",listener.local_addr()?);
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                log::debug!("accept from {}", addr);
+                let device_addr = device_addr.clone();
+                tokio::spawn(async move {
+                    translate(stream,device_addr).await.unwrap_or_default();
+                });
+            }
+            Err(e) => {
+                log::warn!("accept error:{}", e);
+            }
+        }
+    }
+}
+
+async fn translate(mut client_stream:TcpStream,device_addr:String)->Result<(),Box<dyn Error+Send+Sync>>{
+    let mut header = get_header(&mut client_stream).await?;
+    header.set("Host".to_string(), device_addr.clone());
+    let server_stream = TcpStream::connect(device_addr).await?;
+    let (mut server_tcp_read, mut server_tcp_write) = tokio::io::split(server_stream);
+    let (mut client_tcp_read, mut client_tcp_write) = tokio::io::split(client_stream);
+    server_tcp_write.write_all(&Into::<Vec<u8>>::into(header)).await?;
+    tokio::select! {
+        _ = tokio::io::copy(&mut server_tcp_read, &mut client_tcp_write) => Ok(()),
+        _ = tokio::io::copy(&mut client_tcp_read, &mut server_tcp_write) => Ok(()),
+    }
+}
@@ -1,37 +1,165 @@
-use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}};
-use rweb_common::get_header;
-use std::error::Error;
+mod tunnel;
+mod rules;
+mod http_proxy;
+mod socks_proxy;
+mod forward;
+mod legacy;
+
+use std::{error::Error, sync::Arc};
+use tokio::net::TcpListener;
 use clap::Parser;
+use rules::{Rule, Rules};
+use tunnel::Tunnel;
+use forward::Forward;
+use rweb_common::{mac::Mac, RwebError};
 
 #[derive(Parser)]
 #[clap(
     author = "reform <reformgg@gmail.com>",
-    version = "0.1.0",
-    about = "http穿透代理代理服务http_proxy本地代理",
-    long_about = "如果需要使用设备的http_proxy功能,必须运行本程序,"
+    version = "0.2.0",
+    about = "rwebs本地隧道客户端，提供本地http_proxy和socks5代理",
+    long_about = "通过tls连接rwebs的web端口，用socks5经由设备访问目标，浏览器等工具可以把本程序当作普通的本地代理使用"
 )]
 struct Opts {
-    ///本地http_proxy端口，启动后会监听此端口，浏览器可以设置http代理地址为此端口。
+    ///本地http_proxy端口，浏览器可以设置http代理地址为此端口。
     #[clap(short, long, default_value = "5678")]
     port: u16,
-    ///rwebs的设备地址
-    #[clap(short, long, default_value = "aabbccddeeff.aaa.cn:5677")]
-    device_addr: String,
+    ///本地socks5端口，不设置则不启动socks5代理
+    #[clap(long)]
+    socks_port: Option<u16>,
+    ///本地监听地址
+    #[clap(long, default_value = "127.0.0.1")]
+    bind: String,
+    ///rwebs地址(host:port)
+    #[clap(short, long)]
+    server: Option<String>,
+    ///rwebs证书对应的域名，tls的sni为 设备标签.域名
+    #[clap(long)]
+    domain: Option<String>,
+    ///rwebs的--socks-password，命令行上的密码在ps里可见，最好用--password-file或环境变量RWEB_SOCKS_PASSWORD
+    #[clap(long)]
+    password: Option<String>,
+    ///从文件读取socks密码，去掉首尾空白
+    #[clap(long)]
+    password_file: Option<String>,
+    ///旧版参数，设备地址(设备标签.域名:端口)，相当于--server=该地址 --domain=域名 --device=设备标签；没有密码时按旧版只改Host转发http
+    #[clap(long = "device-addr", alias = "device_addr")]
+    device_addr: Option<String>,
+    ///默认设备标签，规则都不匹配时使用
+    #[clap(short, long)]
+    device: Option<String>,
+    ///规则文件，json数组：[{"pattern":"192.168.2.*","device":"aabbccddeeff"}]
+    #[clap(short, long)]
+    rules: Option<String>,
+    ///校验rwebs证书的ca文件(pem)，不设置使用内置根证书
+    #[clap(long)]
+    ca_file: Option<String>,
+    ///端口转发，类似ssh -L，格式为 [本地地址:]本地端口:设备标签:目标主机:目标端口，可以多次指定
+    #[clap(short = 'L', long)]
+    forward: Vec<Forward>,
+}
+
+const PASSWORD_ENV:&str = "RWEB_SOCKS_PASSWORD";
+
+//旧版--device-addr拆成(server,domain,device)
+fn split_device_addr(device_addr:&str)->Result<(String,String,String),RwebError>{
+    let err = ||RwebError::new(8201,format!("device addr format error:{}",device_addr));
+    let (host,_port) = device_addr.rsplit_once(':').ok_or_else(err)?;
+    let (device,domain) = host.split_once('.').ok_or_else(err)?;
+    device.parse::<Mac>()?;
+    Ok((device_addr.to_string(),domain.to_string(),device.to_string()))
+}
+
+//依次为--password-file、环境变量、--password
+fn load_password(opts:&Opts,env:Option<String>)->Result<Option<String>,RwebError>{
+    if let Some(file) = &opts.password_file{
+        let password = std::fs::read_to_string(file).map_err(|e|RwebError::new(8202,format!("read {} error:{}",file,e)))?;
+        return Ok(Some(password.trim().to_string()));
+    }
+    if env.is_some(){
+        return Ok(env);
+    }
+    if opts.password.is_some(){
+        log::warn!("--password is visible in the process list, use --password-file or {}",PASSWORD_ENV);
+    }
+    Ok(opts.password.clone())
 }
 
 #[tokio::main]
 async fn main() {
-    simple_logger::init_with_level(log::Level::Info).unwrap();
-    let opts = Opts::parse();
-    let listener = TcpListener::bind(format!("0.0.0.0:{}",opts.port)).await.unwrap();
-    log::info!("listen on {}",listener.local_addr().unwrap());
+    simple_logger::init_with_level(log::Level::Info).unwrap_or_default();
+    if let Err(e) = run(Opts::parse()).await{
+        log::error!("{}",e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(opts:Opts)->Result<(),Box<dyn Error+Send+Sync>>{
+    rustls::crypto::aws_lc_rs::default_provider().install_default().map_err(|_|"failed to install rustls crypto provider")?;
+    let password = load_password(&opts,std::env::var(PASSWORD_ENV).ok())?;
+    let legacy = opts.device_addr.as_deref().map(split_device_addr).transpose()?;
+    if let (Some(device_addr),None) = (&opts.device_addr,&password){
+        log::warn!("--device-addr without a socks password, forwarding http by rewriting Host as before");
+        return legacy::run(&opts.bind,opts.port,device_addr.clone()).await;
+    }
+    let (server,domain,device) = match legacy{
+        Some((server,domain,device))=>(opts.server.clone().unwrap_or(server),opts.domain.clone().unwrap_or(domain),opts.device.clone().or(Some(device))),
+        None=>(opts.server.clone().ok_or("--server is required")?,opts.domain.clone().ok_or("--domain is required")?,opts.device.clone()),
+    };
+    let password = password.ok_or(format!("socks password is required, use --password-file or {}",PASSWORD_ENV))?;
+    let rule_list:Vec<Rule> = match &opts.rules{
+        Some(file)=>serde_json::from_str(&std::fs::read_to_string(file).map_err(|e|format!("read {} error:{}",file,e))?)?,
+        None=>vec![]
+    };
+    let default = device.as_deref().map(|d|d.parse()).transpose()?;
+    let rules = Arc::new(Rules::new(rule_list,default)?);
+    let tunnel = Tunnel::new(server,domain,password,opts.ca_file.as_deref())?;
+    for forward in opts.forward.clone(){
+        let tunnel = tunnel.clone();
+        tokio::spawn(async move {
+            let bind = forward.bind.clone();
+            if let Err(e) = forward::run_forward(forward,tunnel).await{
+                log::error!("forward {} error:{}",bind,e);
+            }
+        });
+    }
+    if let Some(socks_port) = opts.socks_port{
+        let listener = TcpListener::bind(format!("{}:{}",opts.bind,socks_port)).await?;
+        log::info!("socks5 listen on {}",listener.local_addr()?);
+        let tunnel = tunnel.clone();
+        let rules = rules.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        log::debug!("socks5 accept from {}", addr);
+                        let tunnel = tunnel.clone();
+                        let rules = rules.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = socks_proxy::handle_socks5(stream,tunnel,&rules).await{
+                                log::warn!("socks5 {} error:{}",addr,e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        log::warn!("accept error:{}", e);
+                    }
+                }
+            }
+        });
+    }
+    let listener = TcpListener::bind(format!("{}:{}",opts.bind,opts.port)).await?;
+    log::info!("http_proxy listen on {}",listener.local_addr()?);
     loop {
         match listener.accept().await {
             Ok((stream, addr)) => {
                 log::debug!("accept from {}", addr);
-                let device_addr = opts.device_addr.clone();
+                let tunnel = tunnel.clone();
+                let rules = rules.clone();
                 tokio::spawn(async move {
-                    translate(stream,device_addr).await.unwrap_or_default();
+                    if let Err(e) = http_proxy::handle_http(stream,tunnel,&rules).await{
+                        log::warn!("http_proxy {} error:{}",addr,e);
+                    }
                 });
             }
             Err(e) => {
@@ -41,15 +169,23 @@ async fn main() {
     }
 }
 
-async fn translate(mut client_stream:TcpStream,device_addr:String)->Result<(),Box<dyn Error+Send+Sync>>{
-    let mut header = get_header(&mut client_stream).await?;
-    header.set("Host".to_string(), device_addr.clone());
-    let server_stream = TcpStream::connect(device_addr).await?;
-    let (mut server_tcp_read, mut server_tcp_write) = tokio::io::split(server_stream);
-    let (mut client_tcp_read, mut client_tcp_write) = tokio::io::split(client_stream);
-    server_tcp_write.write_all(&Into::<Vec<u8>>::into(header)).await?;
-    tokio::select! {
-        _ = tokio::io::copy(&mut server_tcp_read, &mut client_tcp_write) => Ok(()),
-        _ = tokio::io::copy(&mut client_tcp_read, &mut server_tcp_write) => Ok(()),
-    }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn legacy_args(){
+        assert_eq!(split_device_addr("aabbccddeeff.aaa.cn:5677").unwrap(),("aabbccddeeff.aaa.cn:5677".to_string(),"aaa.cn".to_string(),"aabbccddeeff".to_string()));
+        assert!(split_device_addr("aabbccddeeff.aaa.cn").is_err());
+        assert!(split_device_addr("nomac.aaa.cn:5677").is_err());
+        let opts = Opts::parse_from(["proxy-change-header","--device_addr","aabbccddeeff.aaa.cn:5677","--password","cli"]);
+        assert_eq!(opts.device_addr.as_deref(),Some("aabbccddeeff.aaa.cn:5677"));
+        assert_eq!(load_password(&opts,Some("env".to_string())).unwrap().as_deref(),Some("env"));
+        assert_eq!(load_password(&opts,None).unwrap().as_deref(),Some("cli"));
+        let file = std::env::temp_dir().join(format!("pch-password-{}",std::process::id()));
+        std::fs::write(&file,"from-file\n").unwrap();
+        let opts = Opts::parse_from(["proxy-change-header","--device-addr","aabbccddeeff.aaa.cn:5677","--password-file",file.to_str().unwrap()]);
+        assert_eq!(load_password(&opts,Some("env".to_string())).unwrap().as_deref(),Some("from-file"));
+        std::fs::remove_file(file).unwrap_or_default();
+    }
+}
@@ -0,0 +1,59 @@
+use rweb_common::{mac::Mac, RwebError};
+use serde::Deserialize;
+
+//按目标地址选择设备，pattern支持*通配，例如"192.168.2.*"、"*.lan"
+#[derive(Debug,Clone,Deserialize)]
+pub struct Rule{
+    pub pattern:String,
+    pub device:String,
+}
+
+#[derive(Debug,Clone)]
+pub struct Rules{
+    rules:Vec<(String,Mac)>,
+    default:Option<Mac>,
+}
+
+impl Rules{
+    pub fn new(rules:Vec<Rule>,default:Option<Mac>)->Result<Self,RwebError>{
+        let rules = rules.into_iter().map(|r|Ok((r.pattern.to_lowercase(),r.device.parse()?))).collect::<Result<Vec<_>,RwebError>>()?;
+        Ok(Self{rules,default})
+    }
+
+    //host不带端口，按顺序匹配，都不匹配时使用默认设备
+    pub fn select(&self,host:&str)->Option<Mac>{
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_lowercase();
+        self.rules.iter().find(|(pattern,_)|wildcard_match(pattern,&host)).map(|(_,mac)|*mac).or(self.default)
+    }
+}
+
+fn wildcard_match(pattern:&str,s:&str)->bool{
+    match pattern.split_once('*'){
+        None=>pattern == s,
+        Some((prefix,rest))=>{
+            if !s.starts_with(prefix){
+                return false;
+            }
+            let s = &s[prefix.len()..];
+            if rest.is_empty(){
+                return true;
+            }
+            (0..=s.len()).filter(|i|s.is_char_boundary(*i)).any(|i|wildcard_match(rest,&s[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    #[test]
+    fn select(){
+        let rules = Rules::new(vec![
+            Rule{pattern:"192.168.2.*".to_string(),device:"aabbccddeeff".to_string()},
+            Rule{pattern:"*.LAN".to_string(),device:"112233445566".to_string()},
+        ],None).unwrap();
+        assert_eq!(rules.select("192.168.2.10"),Some("aabbccddeeff".parse().unwrap()));
+        assert_eq!(rules.select("nas.lan"),Some("112233445566".parse().unwrap()));
+        assert_eq!(rules.select("192.168.3.10"),None);
+    }
+}
@@ -0,0 +1,40 @@
+use std::{error::Error, net::SocketAddr};
+use rweb_common::io::socks5;
+use tokio::net::TcpStream;
+use crate::{http_proxy::select, rules::Rules, tunnel::Tunnel};
+
+//本地socks5代理，只监听本机时不做认证，认证由到rwebs的隧道完成
+pub async fn handle_socks5(mut client_stream:TcpStream,tunnel:Tunnel,rules:&Rules)->Result<(),Box<dyn Error+Send+Sync>>{
+    let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+    let methods = socks5::read_greeting(&mut client_stream).await?;
+    if !methods.contains(&socks5::METHOD_NO_AUTH){
+        socks5::write_method(&mut client_stream,socks5::METHOD_NO_ACCEPTABLE).await?;
+        return Err("socks5 need no auth method".into());
+    }
+    socks5::write_method(&mut client_stream,socks5::METHOD_NO_AUTH).await?;
+    let (cmd,target) = socks5::read_request(&mut client_stream).await?;
+    if cmd != socks5::CMD_CONNECT{
+        socks5::write_reply(&mut client_stream,socks5::REP_CMD_NOT_SUPPORTED,unspecified).await?;
+        return Err(format!("socks5 cmd {} not support",cmd).into());
+    }
+    let mac = match select(rules,&target){
+        Some(mac)=>mac,
+        None=>{
+            socks5::write_reply(&mut client_stream,socks5::REP_NOT_ALLOWED,unspecified).await?;
+            return Err(format!("no device for {}",target).into());
+        }
+    };
+    match tunnel.open(mac,&target).await{
+        Ok(mut server_stream)=>{
+            socks5::write_reply(&mut client_stream,socks5::REP_SUCCEEDED,unspecified).await?;
+            tokio::io::copy_bidirectional(&mut client_stream,&mut server_stream).await?;
+        },
+        Err(e)=>{
+            //rwebs返回的错误码透传给本地客户端
+            let rep = if e.code > 7100 && e.code < 7109 {(e.code - 7100) as u8}else{socks5::REP_GENERAL_FAILURE};
+            socks5::write_reply(&mut client_stream,rep,unspecified).await?;
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
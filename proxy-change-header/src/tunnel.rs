@@ -0,0 +1,44 @@
+use std::sync::Arc;
+use rustls::{pki_types::{pem::PemObject, CertificateDer, ServerName}, ClientConfig, RootCertStore};
+use rweb_common::{io::socks5::{self, SocksAddr}, mac::Mac, RwebError};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+//到rwebs的隧道：tls(sni为设备标签.域名) + socks5(用户名为设备标签)
+#[derive(Clone)]
+pub struct Tunnel{
+    connector:TlsConnector,
+    server:String,
+    domain:String,
+    password:String,
+}
+
+impl Tunnel{
+    //ca_file为空时使用webpki内置根证书校验rwebs
+    pub fn new(server:String,domain:String,password:String,ca_file:Option<&str>)->Result<Self,RwebError>{
+        let mut roots = RootCertStore::empty();
+        match ca_file{
+            Some(ca_file)=>{
+                for cert in CertificateDer::pem_file_iter(ca_file).map_err(|e|RwebError::new(8001,e))?{
+                    roots.add(cert.map_err(|e|RwebError::new(8001,e))?).map_err(|e|RwebError::new(8002,e))?;
+                }
+            },
+            None=>roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned())
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self{connector:TlsConnector::from(Arc::new(config)),server,domain,password})
+    }
+
+    //打开一条经过设备mac到target的流
+    pub async fn open(&self,mac:Mac,target:&SocksAddr)->Result<TlsStream<TcpStream>,RwebError>{
+        let tcp_stream = TcpStream::connect(&self.server).await.map_err(|e|RwebError::new(8003,e))?;
+        tcp_stream.set_nodelay(true).unwrap_or_default();
+        let server_name = ServerName::try_from(format!("{}.{}",mac,self.domain)).map_err(|e|RwebError::new(8004,e))?;
+        let mut tls_stream = self.connector.connect(server_name,tcp_stream).await.map_err(|e|RwebError::new(8005,e))?;
+        socks5::client_connect(&mut tls_stream,&mac.to_string(),&self.password,target).await?;
+        log::debug!("tunnel open {} via {}",target,mac);
+        Ok(tls_stream)
+    }
+}
@@ -0,0 +1,38 @@
+//设备后面目标(摄像头等)的状态，设备定时探测后报告给rwebs
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub enum TargetHealth{
+    #[default]
+    Unknown,//还没探测过，或设备不支持探测
+    Up,
+    Down,
+}
+
+impl From<TargetHealth> for u8{
+    fn from(h:TargetHealth)->u8{
+        match h{
+            TargetHealth::Unknown=>0x00,
+            TargetHealth::Up=>0x01,
+            TargetHealth::Down=>0x02,
+        }
+    }
+}
+
+impl From<u8> for TargetHealth{
+    fn from(v:u8)->Self{
+        match v{
+            0x01=>TargetHealth::Up,
+            0x02=>TargetHealth::Down,
+            _=>TargetHealth::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for TargetHealth{
+    fn fmt(&self,f:&mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        f.write_str(match self{
+            TargetHealth::Unknown=>"unknown",
+            TargetHealth::Up=>"up",
+            TargetHealth::Down=>"down",
+        })
+    }
+}
@@ -1,12 +1,75 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 #[cfg(feature="p2p")]
 use std::str::FromStr;
 
-use crate::{mac::Mac, RwebError};
+use crate::{health::TargetHealth, mac::Mac, socket::canonical, RwebError};
 
 pub const METHOD_P2P:&str = "P2P";
 pub const METHOD_P2PTEST:&str = "P2PTEST";
+pub const METHOD_REVERSE:&str = "REVERSE";
+pub const METHOD_RELAY:&str = "RELAY";
+//p2p直连建立后请求方先在uni流上出示rwebs发的票据，被请求方验证通过才处理这个连接上的流
+pub const METHOD_P2P_AUTH:&str = "P2PAUTH";
+pub const P2P_TICKET:&str = "P2p-Ticket";
+//rwebs随票据告诉请求方它被记成哪个设备，请求方出示票据时一起带上，被请求方核对
+pub const P2P_REQUESTER:&str = "P2p-Requester";
+//票据有效期，被请求方收到后这么久内没用掉就作废
+pub const P2P_TICKET_SECS:u64 = 30;
+
+//rwebs发给设备的命令，访问者的请求不能用这些方法，否则设备会当作rwebs发来的
+pub fn internal_method(method:&str)->bool{
+    [METHOD_P2P,METHOD_P2PTEST,METHOD_P2P_AUTH,METHOD_RELAY,METHOD_REVERSE].iter().any(|m|m.eq_ignore_ascii_case(method))
+}
+
+//rwebs在转给设备的请求头里写上访问者，设备读出后去掉，不转给目标；设备只相信声明了FEATURE_VISITOR的rwebs直接转来的
+pub const RWEB_CLIENT:&str = "Rweb-Client";
+pub const RWEB_SESSION:&str = "Rweb-Session";
+
+//访问者的ip和rwebs上这个前端连接的编号，同一个连接上的请求编号相同
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Visitor{
+    pub ip:IpAddr,
+    pub session:u64,
+}
+
+impl Visitor{
+    //取出并去掉header里的访问者，头名不区分大小写(经h2转来的为小写)
+    pub fn take(header:&mut Header)->Option<Self>{
+        let mut take = |name:&str|{
+            let key = header.header.keys().find(|k|k.eq_ignore_ascii_case(name))?.clone();
+            header.header.remove(&key)
+        };
+        let ip = take(RWEB_CLIENT);
+        let session = take(RWEB_SESSION);
+        Some(Self{ip:ip?.parse().ok()?,session:session?.parse().ok()?})
+    }
+
+    //去掉访问者自己带的同名头，在请求行后面写上
+    pub fn set(&self,head:&[u8])->Vec<u8>{
+        let mut buf = strip_lines(head,&[RWEB_CLIENT,RWEB_SESSION]);
+        let at = buf.iter().position(|&b|b == b'\n').map(|i|i + 1).unwrap_or(buf.len());
+        buf.splice(at..at,self.lines().into_bytes());
+        buf
+    }
+
+    pub fn lines(&self)->String{
+        format!("{}: {}\r\n{}: {}\r\n",RWEB_CLIENT,self.ip,RWEB_SESSION,self.session)
+    }
+}
+
+//在原始请求头里去掉这些头(不区分大小写)，其余的字节原样保留；Header转回字节会丢掉重复的头并打乱顺序
+pub fn strip_lines(head:&[u8],names:&[&str])->Vec<u8>{
+    let mut buf = Vec::with_capacity(head.len());
+    for (i,line) in head.split_inclusive(|&b|b == b'\n').enumerate(){
+        let name = line.split(|&b|b == b':').next().unwrap_or_default().trim_ascii();
+        if i > 0 && line.contains(&b':') && names.iter().any(|n|n.as_bytes().eq_ignore_ascii_case(name)){
+            continue;
+        }
+        buf.extend_from_slice(line);
+    }
+    buf
+}
 
 pub enum UniCommand{
     MacList = 0x00,
@@ -59,13 +122,51 @@ impl Header{
         self.header.insert(key,value);
     }
 
+    //设备主动打开的反向转发流，uri为rwebs上配置的服务名
+    pub fn new_reverse(mac:Mac,name:&str)->Self{
+        let mut header = HashMap::new();
+        header.insert("mac".to_string(), mac.to_string());
+        Self{
+            method:METHOD_REVERSE.to_string(),
+            uri:name.to_string(),
+            version:"HTTP/1.1".to_string(),
+            header,
+        }
+    }
+
+    pub fn parse_reverse(&self)->Result<(Mac,String),RwebError>{
+        let mac = self.get("mac").ok_or(RwebError::new(2401,"header error"))?.parse::<Mac>().map_err(|e|RwebError::new(2402,e))?;
+        Ok((mac,self.uri.clone()))
+    }
+
+    //设备经rwebs中转到另一台设备，uri为目标设备标签
+    pub fn new_relay(mac:Mac)->Self{
+        Self{
+            method:METHOD_RELAY.to_string(),
+            uri:mac.to_string(),
+            version:"HTTP/1.1".to_string(),
+            header:HashMap::new(),
+        }
+    }
+
+    //rwebs打开到目标设备的中转流时先写这个头，告诉目标请求方是哪台设备
+    pub fn new_relay_from(to:Mac,from:Mac)->Self{
+        let mut header = Self::new_relay(to);
+        header.insert("mac".to_string(), from.to_string());
+        header
+    }
+
+    pub fn parse_relay_from(&self)->Result<Mac,RwebError>{
+        self.get("mac").ok_or(RwebError::new(2406,"header error"))?.parse::<Mac>().map_err(|e|RwebError::new(2407,e))
+    }
+
     #[cfg(feature="p2p")]
     pub fn new_p2p(mac:Mac,addr:SocketAddr,self_addr:Option<SocketAddr>)->Self{
         let mut header = HashMap::new();
         header.insert("mac".to_string(), mac.to_string());
-        header.insert("addr".into(), addr.to_string());
+        header.insert("addr".into(), canonical(addr).to_string());
         if let Some(self_addr) = self_addr{
-            header.insert("self_addr".into(), self_addr.to_string());
+            header.insert("self_addr".into(), canonical(self_addr).to_string());
         }
         Self{
             method:METHOD_P2P.to_string(),
@@ -79,9 +180,9 @@ impl Header{
     pub fn new_p2ptest(mac:Mac,addr:SocketAddr,self_addr:Option<SocketAddr>)->Self{
         let mut header = HashMap::new();
         header.insert("mac".to_string(), mac.to_string());
-        header.insert("addr".into(), addr.to_string());
+        header.insert("addr".into(), canonical(addr).to_string());
         if let Some(self_addr) = self_addr{
-            header.insert("self_addr".into(), self_addr.to_string());
+            header.insert("self_addr".into(), canonical(self_addr).to_string());
         }
         Self{
             method:METHOD_P2P.to_string(),
@@ -91,6 +192,32 @@ impl Header{
         }
     }
 
+    //mac为要访问的设备，即rwebs发票据时的目标，requester为rwebs告诉的请求方
+    #[cfg(feature="p2p")]
+    pub fn new_p2p_auth(mac:Mac,requester:Mac,ticket:&str)->Self{
+        let mut header = HashMap::new();
+        header.insert("mac".to_string(), mac.to_string());
+        header.insert(P2P_REQUESTER.to_string(), requester.to_string());
+        header.insert(P2P_TICKET.to_string(), ticket.to_string());
+        Self{
+            method:METHOD_P2P_AUTH.to_string(),
+            uri:"/p2p".to_string(),
+            version:"HTTP/1.1".to_string(),
+            header,
+        }
+    }
+
+    #[cfg(feature="p2p")]
+    pub fn parse_p2p_auth(&self)->Result<(Mac,Mac,String),RwebError>{
+        if self.method != METHOD_P2P_AUTH{
+            return Err(RwebError::new(2404,"not p2p auth"));
+        }
+        let mac = Mac::from_str(self.get("mac").ok_or(RwebError::new(2401,"header error"))?).map_err(|e|RwebError::new(2402,e))?;
+        let requester = Mac::from_str(self.get(P2P_REQUESTER).ok_or(RwebError::new(2408,"no requester"))?).map_err(|e|RwebError::new(2408,e))?;
+        let ticket = self.get(P2P_TICKET).ok_or(RwebError::new(2405,"no ticket"))?;
+        Ok((mac,requester,ticket.to_string()))
+    }
+
     #[cfg(feature="p2p")]
     pub fn parse_p2p(&self)->Result<(Mac,SocketAddr,Option<SocketAddr>),RwebError>{
         let mac = Mac::from_str(self.get("mac").ok_or(RwebError::new(2401,"header error"))?).map_err(|e|RwebError::new(2402,e))?;
@@ -170,14 +297,13 @@ pub async fn get_header<R: AsyncRead + Unpin>(stream:&mut R)->Result<Header,Rweb
     let mut buf = Vec::new();
     let mut header = [0u8; 1];
     loop{ 
-        if let Ok(_) = stream.read_exact(&mut header).await{
-            buf.push(header[0]);
-            if buf.ends_with(b"\r\n\r\n"){
-                break
-            }else{
-                if buf.len() > 256*256{
-                    return Err(RwebError::new(500,"header too long"))
-                }
+        stream.read_exact(&mut header).await.map_err(|e|RwebError::new(501,e))?;//对端关闭时返回错误，不能一直循环
+        buf.push(header[0]);
+        if buf.ends_with(b"\r\n\r\n"){
+            break
+        }else{
+            if buf.len() > 256*256{
+                return Err(RwebError::new(500,"header too long"))
             }
         }
     }
@@ -190,18 +316,19 @@ pub async fn write_header<R: AsyncWrite + Unpin>(header:Header,stream:&mut R)->R
     Ok(())
 }
 
+//双栈socket上的::ffff:a.b.c.d按ipv4写
 pub async fn write_addr<S:AsyncWrite+Unpin>(s:&mut S,addr:SocketAddr)->Result<(),RwebError>{
-    match addr{
+    match canonical(addr){
         SocketAddr::V4(addr) => {
             let ip = addr.ip().octets();
             s.write_u8(0x04).await.map_err(|e|RwebError::new(500,e))?;
-            s.write(&ip).await.map_err(|e|RwebError::new(500,e))?;
+            s.write_all(&ip).await.map_err(|e|RwebError::new(500,e))?;
             s.write_all(&addr.port().to_be_bytes()).await.map_err(|e|RwebError::new(500,e))?;
         },
         SocketAddr::V6(addr) => {
             let ip = addr.ip().octets();            
             s.write_u8(0x06).await.map_err(|e|RwebError::new(500,e))?;
-            s.write(&ip).await.map_err(|e|RwebError::new(500,e))?;
+            s.write_all(&ip).await.map_err(|e|RwebError::new(500,e))?;
             s.write_all(&addr.port().to_be_bytes()).await.map_err(|e|RwebError::new(500,e))?;
         }
     }
@@ -240,6 +367,10 @@ pub async fn write_mac_list<S:AsyncWrite+Unpin>(s:&mut S,macs:&[Mac])->Result<()
 
 pub async fn read_mac_list<S:AsyncRead+Unpin>(s:&mut S)->Result<Vec<Mac>,RwebError>{
     let len = s.read_u16().await.map_err(|e|RwebError::new(500,e))?;
+    read_macs(s,len).await
+}
+
+async fn read_macs<S:AsyncRead+Unpin>(s:&mut S,len:u16)->Result<Vec<Mac>,RwebError>{
     let mut macs = vec![];
     for _ in 0..len{
         let mut buf = [0x00;6];
@@ -247,4 +378,74 @@ pub async fn read_mac_list<S:AsyncRead+Unpin>(s:&mut S)->Result<Vec<Mac>,RwebErr
         macs.push(buf.into());
     }
     Ok(macs)
+}
+
+//设备报告目标状态的uni流以HEALTH_REPORT开头(mac列表的个数不会是这个值)，之后为u16个数和(mac,状态)
+//只认mac列表的rwebs会读不完整而忽略这个流
+pub const HEALTH_REPORT:u16 = 0xffff;
+
+//rwebs在自己打开的uni流上告诉设备新mac列表里哪些已在别的连接上在线而没有上线，以MAC_REJECTED开头，之后为mac列表
+pub const MAC_REJECTED:u16 = 0xfffe;
+
+pub async fn write_rejected<S:AsyncWrite+Unpin>(s:&mut S,macs:&[Mac])->Result<(),RwebError>{
+    s.write_u16(MAC_REJECTED).await.map_err(|e|RwebError::new(500,e))?;
+    write_mac_list(s,macs).await
+}
+
+//rwebs注册设备后在自己打开的uni流上告诉设备支持的功能，以SERVER_FEATURES开头，之后为u32标志；旧版rwebs不发
+pub const SERVER_FEATURES:u16 = 0xfffd;
+//rwebs会去掉访问者自己带的Rweb-Client/Rweb-Session再写上真实的，设备只在收到这个标志后才相信这两个头
+pub const FEATURE_VISITOR:u32 = 1;
+
+pub async fn write_features<S:AsyncWrite+Unpin>(s:&mut S,flags:u32)->Result<(),RwebError>{
+    s.write_u16(SERVER_FEATURES).await.map_err(|e|RwebError::new(500,e))?;
+    s.write_u32(flags).await.map_err(|e|RwebError::new(500,e))
+}
+
+//rwebs在自己打开的uni流上发给设备的内容
+#[derive(Debug)]
+pub enum ServerUni{
+    Rejected(Vec<Mac>),
+    Features(u32),
+}
+
+pub async fn read_server_uni<S:AsyncRead+Unpin>(s:&mut S)->Result<ServerUni,RwebError>{
+    match s.read_u16().await.map_err(|e|RwebError::new(500,e))?{
+        MAC_REJECTED=>Ok(ServerUni::Rejected(read_mac_list(s).await?)),
+        SERVER_FEATURES=>Ok(ServerUni::Features(s.read_u32().await.map_err(|e|RwebError::new(500,e))?)),
+        _=>Err(RwebError::new(501,"unknown uni message"))
+    }
+}
+
+//设备在注册之后的uni流上发来的内容
+#[derive(Debug)]
+pub enum UniMessage{
+    MacList(Vec<Mac>),
+    Health(Vec<(Mac,TargetHealth)>),
+}
+
+pub async fn write_health<S:AsyncWrite+Unpin>(s:&mut S,health:&[(Mac,TargetHealth)])->Result<(),RwebError>{
+    s.write_u16(HEALTH_REPORT).await.map_err(|e|RwebError::new(500,e))?;
+    s.write_u16(health.len() as u16).await.map_err(|e|RwebError::new(500,e))?;
+    for (mac,h) in health{
+        s.write_all(mac.as_ref()).await.map_err(|e|RwebError::new(500,e))?;
+        s.write_u8((*h).into()).await.map_err(|e|RwebError::new(500,e))?;
+    }
+    Ok(())
+}
+
+pub async fn read_uni<S:AsyncRead+Unpin>(s:&mut S)->Result<UniMessage,RwebError>{
+    let len = s.read_u16().await.map_err(|e|RwebError::new(500,e))?;
+    if len != HEALTH_REPORT{
+        return Ok(UniMessage::MacList(read_macs(s,len).await?));
+    }
+    let len = s.read_u16().await.map_err(|e|RwebError::new(500,e))?;
+    let mut health = vec![];
+    for _ in 0..len{
+        let mut buf = [0x00;6];
+        s.read_exact(&mut buf).await.map_err(|e|RwebError::new(500,e))?;
+        let h = s.read_u8().await.map_err(|e|RwebError::new(500,e))?;
+        health.push((buf.into(),h.into()));
+    }
+    Ok(UniMessage::Health(health))
 }
\ No newline at end of file
@@ -1,6 +1,7 @@
 pub mod header;
 pub mod peek_stream;
 pub mod stream_copy;
+pub mod socks5;
 
 pub trait ResetHeader {
     fn reset_header(&mut self, header: super::Header);
@@ -1,7 +1,7 @@
 
 
 use std::{pin::Pin, task::{Context, Poll}};
-use crate::{Header, RwebError};
+use crate::{io::header::Visitor, Header, RwebError};
 use tokio::io::{AsyncReadExt, ReadBuf, AsyncRead, AsyncWrite};
 
 pub struct PeekableStream<T: AsyncRead + AsyncWrite + Unpin> {
@@ -95,6 +95,11 @@ impl<T: AsyncRead + AsyncWrite + Unpin> PeekableStream <T> {
         buf.try_into()
     }
 
+    //peek_header之后调用，请求头里写上访问者
+    pub fn peek_set_visitor(&mut self,visitor:&Visitor){
+        self.peek_buf = visitor.set(&self.peek_buf);
+    }
+
     pub async fn peek_header_set(&mut self,k:String,v:String)->Result<(),RwebError>{
         let mut header:Header = self.peek_buf.clone().try_into()?;
         header.set(k, v);
@@ -0,0 +1,253 @@
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::RwebError;
+
+pub const VERSION:u8 = 0x05;
+pub const METHOD_NO_AUTH:u8 = 0x00;
+pub const METHOD_USERPASS:u8 = 0x02;
+pub const METHOD_NO_ACCEPTABLE:u8 = 0xff;
+pub const CMD_CONNECT:u8 = 0x01;
+pub const CMD_BIND:u8 = 0x02;
+pub const CMD_UDP_ASSOCIATE:u8 = 0x03;
+pub const REP_SUCCEEDED:u8 = 0x00;
+pub const REP_GENERAL_FAILURE:u8 = 0x01;
+pub const REP_NOT_ALLOWED:u8 = 0x02;
+pub const REP_HOST_UNREACHABLE:u8 = 0x04;
+pub const REP_CMD_NOT_SUPPORTED:u8 = 0x07;
+
+//socks5请求中的目标地址，域名不在本地解析
+#[derive(Debug,Clone,PartialEq)]
+pub enum SocksAddr{
+    Ip(SocketAddr),
+    Domain(String,u16),
+}
+
+impl SocksAddr{
+    pub fn port(&self)->u16{
+        match self{
+            SocksAddr::Ip(addr) => addr.port(),
+            SocksAddr::Domain(_,port) => *port,
+        }
+    }
+}
+
+impl std::fmt::Display for SocksAddr{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            SocksAddr::Ip(addr) => write!(f,"{}",addr),
+            SocksAddr::Domain(host,port) => write!(f,"{}:{}",host,port),
+        }
+    }
+}
+
+impl From<SocketAddr> for SocksAddr{
+    fn from(addr:SocketAddr)->Self{
+        SocksAddr::Ip(addr)
+    }
+}
+
+//读取客户端问候，返回客户端支持的认证方式
+pub async fn read_greeting<S:AsyncRead+Unpin>(s:&mut S)->Result<Vec<u8>,RwebError>{
+    let version = s.read_u8().await.map_err(|e|RwebError::new(7001,e))?;
+    if version != VERSION{
+        return Err(RwebError::new(7002,"socks version error"));
+    }
+    let n = s.read_u8().await.map_err(|e|RwebError::new(7001,e))?;
+    let mut methods = vec![0x00;n as usize];
+    s.read_exact(&mut methods).await.map_err(|e|RwebError::new(7001,e))?;
+    Ok(methods)
+}
+
+pub async fn write_method<S:AsyncWrite+Unpin>(s:&mut S,method:u8)->Result<(),RwebError>{
+    s.write_all(&[VERSION,method]).await.map_err(|e|RwebError::new(7003,e))
+}
+
+//RFC 1929用户名密码认证
+pub async fn read_userpass<S:AsyncRead+Unpin>(s:&mut S)->Result<(String,String),RwebError>{
+    let version = s.read_u8().await.map_err(|e|RwebError::new(7001,e))?;
+    if version != 0x01{
+        return Err(RwebError::new(7004,"socks auth version error"));
+    }
+    let mut user = vec![0x00;s.read_u8().await.map_err(|e|RwebError::new(7001,e))? as usize];
+    s.read_exact(&mut user).await.map_err(|e|RwebError::new(7001,e))?;
+    let mut pass = vec![0x00;s.read_u8().await.map_err(|e|RwebError::new(7001,e))? as usize];
+    s.read_exact(&mut pass).await.map_err(|e|RwebError::new(7001,e))?;
+    Ok((String::from_utf8(user).map_err(|e|RwebError::new(7005,e))?,String::from_utf8(pass).map_err(|e|RwebError::new(7005,e))?))
+}
+
+//比较密码，耗时与哪一位不同无关
+pub fn password_eq(a:&[u8],b:&[u8])->bool{
+    a.len() == b.len() && a.iter().zip(b).fold(0u8,|acc,(x,y)|acc | (x ^ y)) == 0
+}
+
+pub async fn write_auth_status<S:AsyncWrite+Unpin>(s:&mut S,ok:bool)->Result<(),RwebError>{
+    s.write_all(&[0x01,if ok{0x00}else{0x01}]).await.map_err(|e|RwebError::new(7003,e))
+}
+
+//返回(命令,目标地址)
+pub async fn read_request<S:AsyncRead+Unpin>(s:&mut S)->Result<(u8,SocksAddr),RwebError>{
+    let mut head = [0x00;3];
+    s.read_exact(&mut head).await.map_err(|e|RwebError::new(7001,e))?;
+    if head[0] != VERSION{
+        return Err(RwebError::new(7002,"socks version error"));
+    }
+    Ok((head[1],read_socks_addr(s).await?))
+}
+
+pub async fn write_reply<S:AsyncWrite+Unpin>(s:&mut S,rep:u8,bind:SocketAddr)->Result<(),RwebError>{
+    let mut buf = vec![VERSION,rep,0x00];
+    write_socks_addr(&mut buf,&bind.into());
+    s.write_all(&buf).await.map_err(|e|RwebError::new(7003,e))
+}
+
+//客户端握手，user为空时不认证，成功后s即为到target的流
+pub async fn client_connect<S:AsyncRead+AsyncWrite+Unpin>(s:&mut S,user:&str,pass:&str,target:&SocksAddr)->Result<(),RwebError>{
+    let method = if user.is_empty(){METHOD_NO_AUTH}else{METHOD_USERPASS};
+    s.write_all(&[VERSION,0x01,method]).await.map_err(|e|RwebError::new(7003,e))?;
+    let mut resp = [0x00;2];
+    s.read_exact(&mut resp).await.map_err(|e|RwebError::new(7001,e))?;
+    if resp[1] != method{
+        return Err(RwebError::new(7006,"socks method not accepted"));
+    }
+    if method == METHOD_USERPASS{
+        let mut buf = vec![0x01,user.len() as u8];
+        buf.extend_from_slice(user.as_bytes());
+        buf.push(pass.len() as u8);
+        buf.extend_from_slice(pass.as_bytes());
+        s.write_all(&buf).await.map_err(|e|RwebError::new(7003,e))?;
+        s.read_exact(&mut resp).await.map_err(|e|RwebError::new(7001,e))?;
+        if resp[1] != 0x00{
+            return Err(RwebError::new(7007,"socks auth failed"));
+        }
+    }
+    let mut buf = vec![VERSION,CMD_CONNECT,0x00];
+    write_socks_addr(&mut buf,target);
+    s.write_all(&buf).await.map_err(|e|RwebError::new(7003,e))?;
+    let mut head = [0x00;3];
+    s.read_exact(&mut head).await.map_err(|e|RwebError::new(7001,e))?;
+    read_socks_addr(s).await?;
+    if head[1] != REP_SUCCEEDED{
+        return Err(RwebError::new(7100+head[1] as i32,format!("socks connect {} failed",target)));
+    }
+    Ok(())
+}
+
+async fn read_socks_addr<S:AsyncRead+Unpin>(s:&mut S)->Result<SocksAddr,RwebError>{
+    let atyp = s.read_u8().await.map_err(|e|RwebError::new(7001,e))?;
+    let addr = match atyp{
+        0x01 => {
+            let mut ip = [0u8;4];
+            s.read_exact(&mut ip).await.map_err(|e|RwebError::new(7001,e))?;
+            SocksAddr::Ip(SocketAddr::from((ip,s.read_u16().await.map_err(|e|RwebError::new(7001,e))?)))
+        },
+        0x03 => {
+            let mut host = vec![0x00;s.read_u8().await.map_err(|e|RwebError::new(7001,e))? as usize];
+            s.read_exact(&mut host).await.map_err(|e|RwebError::new(7001,e))?;
+            SocksAddr::Domain(String::from_utf8(host).map_err(|e|RwebError::new(7005,e))?,s.read_u16().await.map_err(|e|RwebError::new(7001,e))?)
+        },
+        0x04 => {
+            let mut ip = [0u8;16];
+            s.read_exact(&mut ip).await.map_err(|e|RwebError::new(7001,e))?;
+            SocksAddr::Ip(SocketAddr::from((ip,s.read_u16().await.map_err(|e|RwebError::new(7001,e))?)))
+        },
+        _ => return Err(RwebError::new(7008,"socks addr type error"))
+    };
+    Ok(addr)
+}
+
+fn write_socks_addr(buf:&mut Vec<u8>,addr:&SocksAddr){
+    match addr{
+        SocksAddr::Ip(SocketAddr::V4(addr)) => {
+            buf.push(0x01);
+            buf.extend_from_slice(&addr.ip().octets());
+        },
+        SocksAddr::Ip(SocketAddr::V6(addr)) => {
+            match addr.ip().to_ipv4_mapped(){
+                Some(ip) => {
+                    buf.push(0x01);
+                    buf.extend_from_slice(&ip.octets());
+                },
+                None => {
+                    buf.push(0x04);
+                    buf.extend_from_slice(&addr.ip().octets());
+                }
+            }
+        },
+        SocksAddr::Domain(host,_) => {
+            buf.push(0x03);
+            buf.push(host.len() as u8);
+            buf.extend_from_slice(host.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::net::{Ipv6Addr, SocketAddrV6};
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn greeting_and_auth(){
+        assert_eq!(read_greeting(&mut &[5u8,2,0,2][..]).await.unwrap(),vec![METHOD_NO_AUTH,METHOD_USERPASS]);
+        assert_eq!(read_greeting(&mut &[4u8,1,0][..]).await.unwrap_err().code,7002);
+        assert_eq!(read_greeting(&mut &[5u8,2,0][..]).await.unwrap_err().code,7001);
+        assert_eq!(read_userpass(&mut &b"\x01\x03abc\x02pw"[..]).await.unwrap(),("abc".to_string(),"pw".to_string()));
+        assert_eq!(read_userpass(&mut &b"\x02\x03abc\x02pw"[..]).await.unwrap_err().code,7004);
+        assert_eq!(read_userpass(&mut &b"\x01\x02\xff\xfe\x00"[..]).await.unwrap_err().code,7005);
+        assert!(password_eq(b"secret",b"secret"));
+        assert!(!password_eq(b"secret",b"secreT"));
+        assert!(!password_eq(b"secret",b"secret1"));
+    }
+
+    #[tokio::test]
+    async fn request(){
+        let ipv4 = SocketAddr::from(([10,0,0,1],80));
+        assert_eq!(read_request(&mut &[5u8,1,0,1,10,0,0,1,0,80][..]).await.unwrap(),(CMD_CONNECT,SocksAddr::Ip(ipv4)));
+        assert_eq!(read_request(&mut &b"\x05\x01\x00\x03\x07abc.com\x01\xbb"[..]).await.unwrap(),(CMD_CONNECT,SocksAddr::Domain("abc.com".to_string(),443)));
+        let mut ipv6 = vec![5u8,3,0,4];
+        ipv6.extend(Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend(22u16.to_be_bytes());
+        assert_eq!(read_request(&mut &ipv6[..]).await.unwrap(),(CMD_UDP_ASSOCIATE,SocksAddr::Ip(SocketAddr::from((Ipv6Addr::LOCALHOST,22)))));
+        assert_eq!(read_request(&mut &[5u8,1,0,9][..]).await.unwrap_err().code,7008);
+        assert_eq!(read_request(&mut &[4u8,1,0,1][..]).await.unwrap_err().code,7002);
+        assert_eq!(read_request(&mut &[5u8,1,0,1,10,0][..]).await.unwrap_err().code,7001);
+
+        //ipv4映射的ipv6地址按ipv4写回
+        let mut reply = Vec::new();
+        write_reply(&mut reply,REP_SUCCEEDED,SocketAddr::V6(SocketAddrV6::new(ipv4_mapped(),8080,0,0))).await.unwrap();
+        assert_eq!(reply,[5,0,0,1,10,0,0,1,0x1f,0x90]);
+    }
+
+    fn ipv4_mapped()->Ipv6Addr{
+        std::net::Ipv4Addr::new(10,0,0,1).to_ipv6_mapped()
+    }
+
+    //客户端握手与服务端解析配合，服务端拒绝时返回7100+REP
+    #[tokio::test]
+    async fn client_handshake(){
+        for rep in [REP_SUCCEEDED,REP_HOST_UNREACHABLE]{
+            let (mut client,mut server) = duplex(1024);
+            let target = SocksAddr::Domain("abc.com".to_string(),443);
+            let t = target.clone();
+            let server = tokio::spawn(async move{
+                assert_eq!(read_greeting(&mut server).await.unwrap(),vec![METHOD_USERPASS]);
+                write_method(&mut server,METHOD_USERPASS).await.unwrap();
+                assert_eq!(read_userpass(&mut server).await.unwrap(),("aabbccddeeff".to_string(),"pw".to_string()));
+                write_auth_status(&mut server,true).await.unwrap();
+                assert_eq!(read_request(&mut server).await.unwrap(),(CMD_CONNECT,t));
+                write_reply(&mut server,rep,SocketAddr::from(([0,0,0,0],0))).await.unwrap();
+            });
+            let res = client_connect(&mut client,"aabbccddeeff","pw",&target).await;
+            server.await.unwrap();
+            assert_eq!(res.err().map(|e|e.code),(rep != REP_SUCCEEDED).then_some(7100 + rep as i32));
+        }
+        let (mut client,mut server) = duplex(1024);
+        tokio::spawn(async move{
+            read_greeting(&mut server).await.unwrap();
+            write_method(&mut server,METHOD_NO_ACCEPTABLE).await.unwrap();
+        });
+        assert_eq!(client_connect(&mut client,"","",&SocksAddr::Ip(SocketAddr::from(([1,2,3,4],80)))).await.unwrap_err().code,7006);
+    }
+}
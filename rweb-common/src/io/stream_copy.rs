@@ -17,6 +17,19 @@ impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Stream<R,W>{
     pub fn remote_address(&self)->SocketAddr{
         self.remote_addr
     }
+
+    //peek_header之后调用，去掉请求头里的这些头，其余原样转发
+    pub fn peek_strip(&mut self,names:&[&str]){
+        self.peek_buf = super::header::strip_lines(&self.peek_buf,names);
+    }
+
+    //peek_header之后调用，改成Connection: close，目标回应完这个请求就关闭，同一个流上的后续请求不会被处理
+    pub fn peek_close(&mut self){
+        let mut buf = super::header::strip_lines(&self.peek_buf,&["Connection","Keep-Alive"]);
+        let at = buf.iter().position(|&b|b == b'\n').map(|i|i + 1).unwrap_or(buf.len());
+        buf.splice(at..at,b"Connection: close\r\n".iter().copied());
+        self.peek_buf = buf;
+    }
 }
 
 impl <R:AsyncRead + Unpin + Send, W:AsyncWrite + Unpin + Send> super::ResetHeader for Stream<R,W> {
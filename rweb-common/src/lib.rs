@@ -1,12 +1,22 @@
 pub mod mac;
 pub mod io;
 pub mod proxy_list;
+pub mod reverse_list;
+pub mod relay_list;
+pub mod nat;
+pub mod stun;
 #[cfg(feature="p2p")]
 pub mod p2p_list;
+pub mod server_list;
+pub mod socket;
+pub mod health;
 use std::error::Error;
 pub use io::header::{get_header,Header};
 pub mod key;
 
+//设备连接rwebs时可带的ALPN，rwebs开启h3后和h3一起提供；不带ALPN的设备照样能连上，rwebc不带
+pub const ALPN_RWEB:&[u8] = b"rweb";
+
 #[derive(Debug,Clone)]
 pub struct RwebError{
     pub code:i32,
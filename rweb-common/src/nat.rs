@@ -0,0 +1,109 @@
+use std::{fmt::Display, net::SocketAddr, str::FromStr};
+use crate::{Header, RwebError};
+
+//p2ptest服务的bi流命令，客户端写1字节命令
+pub const NAT_CMD_INFO:u8 = 0x00;//返回备用地址(write_addr)，ip为0.0.0.0表示没有备用ip
+pub const NAT_CMD_CHANGE_PORT:u8 = 0x01;//从备用端口回连客户端，返回1字节结果
+pub const NAT_CMD_CHANGE_IP:u8 = 0x02;//从备用ip和备用端口回连客户端，返回1字节结果
+pub const NAT_RESULT_FAIL:u8 = 0x00;
+pub const NAT_RESULT_OK:u8 = 0x01;
+pub const NAT_RESULT_UNSUPPORTED:u8 = 0xff;
+
+//RFC 5780中映射和过滤行为
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum NatBehavior{
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+    Ambiguous,//测试服务没有备用ip时只换端口，映射不变时分不出EI和AD
+}
+
+impl Display for NatBehavior{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self{
+            NatBehavior::EndpointIndependent => "EI",
+            NatBehavior::AddressDependent => "AD",
+            NatBehavior::AddressAndPortDependent => "APD",
+            NatBehavior::Ambiguous => "EI/AD",
+        })
+    }
+}
+
+impl FromStr for NatBehavior{
+    type Err = RwebError;
+    fn from_str(s:&str)->Result<Self,Self::Err>{
+        match s{
+            "EI" => Ok(NatBehavior::EndpointIndependent),
+            "AD" => Ok(NatBehavior::AddressDependent),
+            "APD" => Ok(NatBehavior::AddressAndPortDependent),
+            "EI/AD" => Ok(NatBehavior::Ambiguous),
+            _ => Err(RwebError::new(2410,format!("nat behavior error:{}",s)))
+        }
+    }
+}
+
+//节点的NAT画像，p2p请求时放在头里交给对端，决定打洞策略
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct NatProfile{
+    pub mapping:NatBehavior,
+    pub filtering:NatBehavior,
+    pub port_delta:i32,//连续两次映射的端口差，映射与目标无关时为0
+}
+
+impl NatProfile{
+    //映射与目标无关时对端测得的地址就是打洞地址，否则按端口差预测下一次映射
+    pub fn predict(&self,addr:SocketAddr)->SocketAddr{
+        if self.mapping == NatBehavior::EndpointIndependent || self.port_delta == 0{
+            return addr;
+        }
+        let port = (addr.port() as i32 + self.port_delta).clamp(1,65535) as u16;
+        SocketAddr::new(addr.ip(),port)
+    }
+
+    //映射与目标有关且测不出分配步长，只能随机猜端口
+    pub fn is_random(&self)->bool{
+        self.mapping != NatBehavior::EndpointIndependent && self.port_delta == 0
+    }
+
+    //打洞时连续尝试的端口数
+    pub fn port_len(&self,symmetric_len:u16)->u16{
+        match self.mapping{
+            NatBehavior::EndpointIndependent => 1,
+            _ if self.port_delta.unsigned_abs() == 1 => (symmetric_len / 10).max(1),//递增分配，预测较准
+            _ => symmetric_len,
+        }
+    }
+
+    pub fn write_header(&self,header:&mut Header){
+        header.insert("Nat-Mapping".to_string(),self.mapping.to_string());
+        header.insert("Nat-Filtering".to_string(),self.filtering.to_string());
+        header.insert("Nat-Delta".to_string(),self.port_delta.to_string());
+    }
+
+    pub fn from_header(header:&Header)->Option<Self>{
+        Some(Self{
+            mapping:header.get("Nat-Mapping")?.parse().ok()?,
+            filtering:header.get("Nat-Filtering")?.parse().ok()?,
+            port_delta:header.get("Nat-Delta")?.parse().ok()?,
+        })
+    }
+}
+
+//按连续几次映射的端口估计分配步长
+//过半的差值一致时取这个差值；都同向且不超过MAX_STRIDE时认为中间被别的连接占用，取最小步长；否则按随机分配处理
+pub fn allocation_delta(ports:&[u16])->Option<i32>{
+    const MAX_STRIDE:i32 = 32;
+    let deltas:Vec<i32> = ports.windows(2).map(|w|w[1] as i32 - w[0] as i32).filter(|d|*d != 0).collect();
+    if deltas.is_empty(){
+        return None;
+    }
+    let (delta,count) = deltas.iter().map(|d|(*d,deltas.iter().filter(|x|*x == d).count())).max_by_key(|(_,c)|*c)?;
+    if count * 2 > deltas.len(){
+        return Some(delta);
+    }
+    let same_sign = deltas.iter().all(|d|d.signum() == deltas[0].signum());
+    if same_sign && deltas.iter().all(|d|d.abs() <= MAX_STRIDE){
+        return deltas.iter().min_by_key(|d|d.abs()).copied();
+    }
+    None
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use serde::{ser::SerializeStruct, Deserialize, Serialize};
+use crate::RwebError;
 
 #[derive(Debug,Clone)]
 pub struct ProxyList{
@@ -8,8 +9,50 @@ pub struct ProxyList{
 }
 
 impl ProxyList {
-    pub fn new(mac: super::mac::Mac, url: url::Url) -> Self {
-        Self { mac, url }
+    //和从json读入一样检查目标
+    pub fn new(mac: super::mac::Mac, url: url::Url) -> Result<Self, RwebError> {
+        let list = Self { mac, url };
+        list.target()?;
+        Ok(list)
+    }
+
+    pub fn target(&self) -> Result<Target, RwebError> {
+        Target::from_url(&self.url)
+    }
+}
+
+//scheme的默认端口，不在表里的scheme(如tcp、tls、ssh)必须写明端口，如tcp://192.168.2.12:22
+pub const DEFAULT_PORTS: &[(&str, u16)] = &[("http", 80), ("https", 443), ("rtsp", 554), ("rtsps", 322), ("ws", 80), ("wss", 443)];
+//连上后再套一层tls的scheme
+pub const TLS_SCHEMES: &[&str] = &["https", "rtsps", "wss", "tls"];
+
+pub fn default_port(scheme: &str) -> Option<u16> {
+    DEFAULT_PORTS.iter().find(|(s, _)| *s == scheme).map(|(_, p)| *p)
+}
+
+//设备转发的目标，读入代理表时就检查，不等到第一次连接
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Unix(String),//unix:///run/app.sock
+    Tcp { host: String, port: u16, tls: bool },
+}
+
+impl Target {
+    pub fn from_url(url: &url::Url) -> Result<Self, RwebError> {
+        if url.scheme() == "unix" {
+            if url.path().is_empty() || url.path() == "/" {
+                return Err(RwebError::new(5035, format!("{} have no path", url)));
+            }
+            return Ok(Target::Unix(url.path().to_string()));
+        }
+        let host = match url.host() {
+            Some(url::Host::Domain(d)) if !d.is_empty() => d.to_string(),
+            Some(url::Host::Ipv4(ip)) => ip.to_string(),
+            Some(url::Host::Ipv6(ip)) => ip.to_string(),//不带[]，可以直接和端口一起解析
+            _ => return Err(RwebError::new(5026, format!("{} have no host", url))),
+        };
+        let port = url.port().or(default_port(url.scheme())).ok_or(RwebError::new(5036, format!("{} have no port", url)))?;
+        Ok(Target::Tcp { host, port, tls: TLS_SCHEMES.contains(&url.scheme()) })
     }
 }
 
@@ -21,10 +64,10 @@ impl<'de> Deserialize<'de> for ProxyList{
         let s:HashMap<&'de str,&'de str> = HashMap::deserialize(deserializer)?;
         let mac = s.get("mac").ok_or(serde::de::Error::custom("missing mac"))?.to_string();
         let addr = s.get("url").ok_or(serde::de::Error::custom("missing addr"))?.to_string();
-        Ok(Self{
-            mac:mac.parse().map_err(|_|serde::de::Error::custom("invalid mac"))?,
-            url:url::Url::parse(&addr).map_err(|_|serde::de::Error::custom("invalid url"))?,
-        })
+        Self::new(
+            mac.parse().map_err(|_|serde::de::Error::custom("invalid mac"))?,
+            url::Url::parse(&addr).map_err(|_|serde::de::Error::custom("invalid url"))?,
+        ).map_err(|e|serde::de::Error::custom(e.msg))
     }
 }
 
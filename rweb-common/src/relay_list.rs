@@ -0,0 +1,145 @@
+use std::{collections::HashMap, fmt::Display, net::{IpAddr, Ipv4Addr}, path::PathBuf, str::FromStr};
+use super::mac::Mac;
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
+
+//本机的监听地址，json里写"bind":"127.0.0.1"或"bind":"unix:///run/app.sock"，不写时为0.0.0.0
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Bind{
+    Ip(IpAddr),//监听ip:port
+    Unix(PathBuf),//监听unix socket，不用port
+}
+
+impl Default for Bind{
+    fn default()->Self{
+        Bind::Ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+}
+
+impl FromStr for Bind{
+    type Err = std::net::AddrParseError;
+    fn from_str(s:&str)->Result<Self,Self::Err>{
+        match s.strip_prefix("unix://"){
+            Some(path)=>Ok(Bind::Unix(PathBuf::from(path))),
+            None=>s.parse().map(Bind::Ip)
+        }
+    }
+}
+
+impl Display for Bind{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            Bind::Ip(ip)=>write!(f,"{}",ip),
+            Bind::Unix(path)=>write!(f,"unix://{}",path.display()),
+        }
+    }
+}
+
+//设备端的中转列表，本机按bind和port监听，接入的连接转到mac；p2p_list也是这个格式
+//bind为unix socket时json里不用写port，port为0
+#[derive(Debug,Clone)]
+pub struct RelayCell{
+    pub mac:Mac,
+    pub port:u16,
+    pub bind:Bind,
+}
+
+impl RelayCell{
+    pub fn new(mac:Mac,port:u16)->Self{
+        Self{mac,port,bind:Bind::default()}
+    }
+
+    pub fn unix(mac:Mac,path:impl Into<PathBuf>)->Self{
+        Self{mac,port:0,bind:Bind::Unix(path.into())}
+    }
+
+    pub fn with_bind(mut self,bind:Bind)->Self{
+        self.bind = bind;
+        self
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrInt<'a> {
+    Str(&'a str),
+    Int(u16),
+}
+
+impl<'de> Deserialize<'de> for RelayCell{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s:HashMap<&'de str,StringOrInt> = HashMap::deserialize(deserializer)?;
+        let mac = match s.get("mac").ok_or(serde::de::Error::custom("missing mac"))?{
+            StringOrInt::Str(mac) => mac.to_string(),
+            StringOrInt::Int(_) => return Err(serde::de::Error::custom("mac should be string")),
+        };
+        let bind = match s.get("bind"){
+            Some(StringOrInt::Str(bind)) => bind.parse().map_err(|_|serde::de::Error::custom("invalid bind"))?,
+            Some(StringOrInt::Int(_)) => return Err(serde::de::Error::custom("bind should be string")),
+            None => Bind::default(),
+        };
+        let port = match (s.get("port"),&bind){
+            (Some(StringOrInt::Str(_)),_) => return Err(serde::de::Error::custom("port should be u16")),
+            (Some(StringOrInt::Int(port)),_) => *port,
+            (None,Bind::Unix(_)) => 0,
+            (None,Bind::Ip(_)) => return Err(serde::de::Error::custom("missing port")),
+        };
+        Ok(Self{
+            mac:mac.parse().map_err(|_|serde::de::Error::custom("invalid mac"))?,
+            port,
+            bind,
+        })
+    }
+}
+
+impl Serialize for RelayCell{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {        
+        let unix = matches!(self.bind,Bind::Unix(_));
+        let mut state = serializer.serialize_struct("RelayCell", if unix {2} else {3})?;
+        state.serialize_field("mac", &self.mac.to_string())?;
+        if !unix{
+            state.serialize_field("port", &self.port)?;
+        }
+        state.serialize_field("bind", &self.bind.to_string())?;
+        state.end()
+    }
+}
+
+//rwebs端的中转许可，from上的设备可以经rwebs中转到to
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct RelayRule{
+    pub from:Mac,
+    pub to:Mac,
+}
+
+impl<'de> Deserialize<'de> for RelayRule{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s:HashMap<&'de str,&'de str> = HashMap::deserialize(deserializer)?;
+        let from = s.get("from").ok_or(serde::de::Error::custom("missing from"))?;
+        let to = s.get("to").ok_or(serde::de::Error::custom("missing to"))?;
+        Ok(Self{
+            from:from.parse().map_err(|_|serde::de::Error::custom("invalid from"))?,
+            to:to.parse().map_err(|_|serde::de::Error::custom("invalid to"))?,
+        })
+    }
+}
+
+impl Serialize for RelayRule{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut state = serializer.serialize_struct("RelayRule", 2)?;
+        state.serialize_field("from", &self.from.to_string())?;
+        state.serialize_field("to", &self.to.to_string())?;
+        state.end()
+    }
+}
@@ -0,0 +1,78 @@
+use std::{collections::HashMap, net::SocketAddr};
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
+
+//设备端配置：在listen上监听，接入的连接经quic连接转到rwebs上名为name的服务
+#[derive(Debug,Clone)]
+pub struct ReverseCell{
+    pub mac:super::mac::Mac,
+    pub name:String,
+    pub listen:SocketAddr,
+}
+
+//rwebs端配置：允许设备mac使用名为name的服务，rwebs连接target
+#[derive(Debug,Clone)]
+pub struct ReverseRule{
+    pub mac:super::mac::Mac,
+    pub name:String,
+    pub target:String,
+}
+
+impl<'de> Deserialize<'de> for ReverseCell{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s:HashMap<&'de str,&'de str> = HashMap::deserialize(deserializer)?;
+        let mac = s.get("mac").ok_or(serde::de::Error::custom("missing mac"))?;
+        let name = s.get("name").ok_or(serde::de::Error::custom("missing name"))?;
+        let listen = s.get("listen").ok_or(serde::de::Error::custom("missing listen"))?;
+        Ok(Self{
+            mac:mac.parse().map_err(|_|serde::de::Error::custom("invalid mac"))?,
+            name:name.to_string(),
+            listen:listen.parse().map_err(|_|serde::de::Error::custom("invalid listen"))?,
+        })
+    }
+}
+
+impl Serialize for ReverseCell{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut state = serializer.serialize_struct("ReverseCell", 3)?;
+        state.serialize_field("mac", &self.mac.to_string())?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("listen", &self.listen.to_string())?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ReverseRule{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s:HashMap<&'de str,&'de str> = HashMap::deserialize(deserializer)?;
+        let mac = s.get("mac").ok_or(serde::de::Error::custom("missing mac"))?;
+        let name = s.get("name").ok_or(serde::de::Error::custom("missing name"))?;
+        let target = s.get("target").ok_or(serde::de::Error::custom("missing target"))?;
+        Ok(Self{
+            mac:mac.parse().map_err(|_|serde::de::Error::custom("invalid mac"))?,
+            name:name.to_string(),
+            target:target.to_string(),
+        })
+    }
+}
+
+impl Serialize for ReverseRule{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut state = serializer.serialize_struct("ReverseRule", 3)?;
+        state.serialize_field("mac", &self.mac.to_string())?;
+        state.serialize_field("name", &self.name)?;
+        state.serialize_field("target", &self.target)?;
+        state.end()
+    }
+}
@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+//rwebs服务器，json格式为{"host":"a.abc.com","port":5677,"priority":0,"weight":1}
+//priority小的优先，都连不上时才用下一级；同一级按weight随机分配，同DNS SRV
+#[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
+pub struct ServerCell{
+    pub host:String,
+    pub port:u16,
+    #[serde(default)]
+    pub priority:u16,
+    #[serde(default="default_weight")]
+    pub weight:u16,
+}
+
+fn default_weight()->u16{
+    1
+}
+
+impl ServerCell{
+    pub fn new(host:&str,port:u16)->Self{
+        Self{host:host.to_string(),port,priority:0,weight:default_weight()}
+    }
+
+    pub fn with_priority(mut self,priority:u16)->Self{
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_weight(mut self,weight:u16)->Self{
+        self.weight = weight;
+        self
+    }
+}
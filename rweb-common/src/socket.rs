@@ -0,0 +1,42 @@
+use std::{io, net::SocketAddr};
+use socket2::{Domain, Protocol, Socket, Type};
+
+//[::]时关掉IPV6_V6ONLY，一个socket同时收发ipv4(对端地址为::ffff:a.b.c.d)，不依赖系统的bindv6only设置
+fn socket(addr:&SocketAddr,ty:Type,protocol:Protocol)->io::Result<Socket>{
+    let socket = Socket::new(Domain::for_address(*addr),ty,Some(protocol))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified(){
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+pub fn udp_socket(addr:SocketAddr)->io::Result<std::net::UdpSocket>{
+    let socket = socket(&addr,Type::DGRAM,Protocol::UDP)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+//需要在tokio运行时里调用
+pub fn tcp_listener(addr:SocketAddr)->io::Result<tokio::net::TcpListener>{
+    let socket = socket(&addr,Type::STREAM,Protocol::TCP)?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;//同tokio的TcpListener::bind
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+//双栈socket上ipv4对端的地址是::ffff:a.b.c.d，发给别人或比较前换回ipv4
+pub fn canonical(addr:SocketAddr)->SocketAddr{
+    SocketAddr::new(addr.ip().to_canonical(),addr.port())
+}
+
+//发给双栈socket时ipv4目标要写成::ffff:a.b.c.d，ipv4的socket发不了ipv6目标
+pub fn for_socket(local:SocketAddr,addr:SocketAddr)->Option<SocketAddr>{
+    match (local,addr){
+        (SocketAddr::V6(_),SocketAddr::V4(v4))=>Some(SocketAddr::new(v4.ip().to_ipv6_mapped().into(),v4.port())),
+        (SocketAddr::V4(_),SocketAddr::V6(v6))=>v6.ip().to_ipv4_mapped().map(|ip|SocketAddr::new(ip.into(),v6.port())),
+        _=>Some(addr)
+    }
+}
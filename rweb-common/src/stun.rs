@@ -0,0 +1,106 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+//RFC 8489 STUN Binding，只实现取反射地址需要的部分
+//消息头20字节：类型(2) 长度(2) magic cookie(4) 事务id(12)，之后是4字节对齐的属性
+pub const STUN_MAGIC:u32 = 0x2112A442;
+pub const STUN_BINDING_REQUEST:u16 = 0x0001;
+pub const STUN_BINDING_RESPONSE:u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS:u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS:u16 = 0x0020;
+const ATTR_SOFTWARE:u16 = 0x8022;
+const HEADER_LEN:usize = 20;
+
+pub type TransactionId = [u8;12];
+
+//头两位为0且带magic cookie，quic包的首字节第二位总是1，可以和quic共用一个端口
+pub fn is_stun(buf:&[u8])->bool{
+    buf.len() >= HEADER_LEN && buf[0] & 0xc0 == 0 && buf[4..8] == STUN_MAGIC.to_be_bytes()
+}
+
+fn message(kind:u16,txid:&TransactionId,attrs:&[u8])->Vec<u8>{
+    let mut buf = Vec::with_capacity(HEADER_LEN + attrs.len());
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&STUN_MAGIC.to_be_bytes());
+    buf.extend_from_slice(txid);
+    buf.extend_from_slice(attrs);
+    buf
+}
+
+fn attr(buf:&mut Vec<u8>,kind:u16,value:&[u8]){
+    buf.extend_from_slice(&kind.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(buf.len().next_multiple_of(4),0);
+}
+
+//cookie和事务id拼成的异或掩码，ipv4只用前4字节
+fn xor_mask(txid:&TransactionId)->[u8;16]{
+    let mut mask = [0u8;16];
+    mask[..4].copy_from_slice(&STUN_MAGIC.to_be_bytes());
+    mask[4..].copy_from_slice(txid);
+    mask
+}
+
+pub fn binding_request(txid:&TransactionId)->Vec<u8>{
+    message(STUN_BINDING_REQUEST,txid,&[])
+}
+
+//成功响应，带XOR-MAPPED-ADDRESS和SOFTWARE
+pub fn binding_response(txid:&TransactionId,addr:SocketAddr,software:&str)->Vec<u8>{
+    let mask = xor_mask(txid);
+    let mut value = vec![0,if addr.is_ipv4() {1} else {2}];
+    value.extend_from_slice(&(addr.port() ^ (STUN_MAGIC >> 16) as u16).to_be_bytes());
+    let ip = match addr.ip(){
+        IpAddr::V4(ip)=>ip.octets().to_vec(),
+        IpAddr::V6(ip)=>ip.octets().to_vec(),
+    };
+    value.extend(ip.iter().zip(mask.iter()).map(|(a,b)|a ^ b));
+    let mut attrs = vec![];
+    attr(&mut attrs,ATTR_XOR_MAPPED_ADDRESS,&value);
+    attr(&mut attrs,ATTR_SOFTWARE,software.as_bytes());
+    message(STUN_BINDING_RESPONSE,txid,&attrs)
+}
+
+//返回消息类型和事务id
+pub fn parse_header(buf:&[u8])->Option<(u16,TransactionId)>{
+    if !is_stun(buf) || HEADER_LEN + u16::from_be_bytes([buf[2],buf[3]]) as usize > buf.len(){
+        return None;
+    }
+    Some((u16::from_be_bytes([buf[0],buf[1]]),buf[8..HEADER_LEN].try_into().ok()?))
+}
+
+//从成功响应里取反射地址，优先XOR-MAPPED-ADDRESS，老服务器只有MAPPED-ADDRESS
+pub fn parse_response(buf:&[u8])->Option<(TransactionId,SocketAddr)>{
+    let (kind,txid) = parse_header(buf)?;
+    if kind != STUN_BINDING_RESPONSE{
+        return None;
+    }
+    let end = HEADER_LEN + u16::from_be_bytes([buf[2],buf[3]]) as usize;
+    let mut pos = HEADER_LEN;
+    let mut mapped = None;
+    while pos + 4 <= end{
+        let kind = u16::from_be_bytes([buf[pos],buf[pos+1]]);
+        let len = u16::from_be_bytes([buf[pos+2],buf[pos+3]]) as usize;
+        let value = buf.get(pos+4..pos+4+len)?;
+        match kind{
+            ATTR_XOR_MAPPED_ADDRESS=>return Some((txid,parse_addr(value,Some(&xor_mask(&txid)))?)),
+            ATTR_MAPPED_ADDRESS=>mapped = parse_addr(value,None),
+            _=>{}
+        }
+        pos += (4 + len).next_multiple_of(4);
+    }
+    Some((txid,mapped?))
+}
+
+fn parse_addr(value:&[u8],mask:Option<&[u8;16]>)->Option<SocketAddr>{
+    let mask = mask.copied().unwrap_or([0;16]);
+    let port = u16::from_be_bytes([value.get(2)? ^ mask[0],value.get(3)? ^ mask[1]]);
+    let ip = |len:usize|->Option<Vec<u8>>{Some(value.get(4..4+len)?.iter().zip(mask.iter()).map(|(a,b)|a ^ b).collect())};
+    let ip = match value.get(1)?{
+        1=>IpAddr::V4(Ipv4Addr::from(<[u8;4]>::try_from(ip(4)?).ok()?)),
+        2=>IpAddr::V6(Ipv6Addr::from(<[u8;16]>::try_from(ip(16)?).ok()?)),
+        _=>return None
+    };
+    Some(SocketAddr::new(ip,port))
+}
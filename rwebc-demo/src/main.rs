@@ -1,4 +1,4 @@
-use std::os::raw::{c_int,c_char};
+use std::{ffi::CStr, os::raw::{c_int,c_char}};
 use clap::Parser;
 
 #[derive(Parser)]
@@ -29,7 +29,16 @@ struct Opts {
     p2p_list: Option<String>,
     ///p2p_list可以写入json文件，文件路径，demo中优先使用p2p_list，如果为空则读取p2p_list_file
     #[clap(short, long)]
-    p2p_list_file: Option<String>
+    p2p_list_file: Option<String>,
+    ///日志级别，0关闭，1~5为error,warn,info,debug,trace，so里的日志通过set_log_callback交给demo打印
+    #[clap(long, default_value = "3")]
+    log_level: c_int,
+}
+
+//so在自己的线程上回调，字符串只在回调期间有效
+extern "C" fn print_log(level:c_int,target:*const c_char,msg:*const c_char,kvs:*const c_char){
+    let (target,msg,kvs) = unsafe{(CStr::from_ptr(target).to_string_lossy(),CStr::from_ptr(msg).to_string_lossy(),CStr::from_ptr(kvs).to_string_lossy())};
+    println!("[{}] {}: {} {}",level,target,msg,kvs);
 }
 
 fn main(){
@@ -44,11 +53,27 @@ fn main(){
     
     //c语言的字符串要加\0结尾
     let server_host = opts.server_host+"\0";
+    let lib = unsafe{libloading::Library::new(&lib_path).unwrap()};
+    unsafe{
+        let set_log_callback: libloading::Symbol<unsafe extern "C" fn(Option<extern "C" fn(c_int,*const c_char,*const c_char,*const c_char)>) -> c_int> = lib.get(b"set_log_callback").unwrap();
+        set_log_callback(Some(print_log));
+        let set_log_level: libloading::Symbol<unsafe extern "C" fn(c_int) -> c_int> = lib.get(b"set_log_level").unwrap();
+        set_log_level(opts.log_level);
+    }
+    //只给了proxy_list_file且不用p2p时，修改文件后不用重启即生效
+    if let (None,Some(file),None,None) = (&opts.proxy_list,&opts.proxy_list_file,&opts.p2p_list,&opts.p2p_list_file){
+        let file = file.clone()+"\0";
+        let _ret = unsafe{
+            let func: libloading::Symbol<unsafe extern "C" fn(*const c_char, c_int, *const c_char) -> i32> = lib.get(b"quic_node_run_file").unwrap();
+            func(server_host.as_ptr() as *const c_char, opts.server_port as c_int, file.as_ptr() as *const c_char)
+        };
+        println!("ret: {}", _ret);
+        return;
+    }
     let proxy_list = opts.proxy_list.unwrap_or(std::fs::read_to_string(opts.proxy_list_file.unwrap()).unwrap())+"\0";
     println!("server_host: {}, server_port: {}, proxy_list_file: {}", server_host, opts.server_port, proxy_list);
-    let lib = unsafe{libloading::Library::new(&lib_path).unwrap()};
     //只有编译rwebc时使用p2p这个featrure才能加载p2pclient
-    let _ret = if let Some(Ok(p2p_list)) = opts.p2p_list.map(|l|Some(Ok(l+"\0"))).unwrap_or(opts.p2p_list_file.map(|p|std::fs::read_to_string(p))){
+    let _ret = if let Some(Ok(p2p_list)) = opts.p2p_list.map(|l|Some(Ok(l+"\0"))).unwrap_or(opts.p2p_list_file.map(std::fs::read_to_string)){
         unsafe{
             let func: libloading::Symbol<unsafe extern "C" fn(*const c_char, c_int, *const c_char, *const c_char) -> i32> = lib.get(b"p2pclient").unwrap();
             func(
@@ -0,0 +1,113 @@
+use std::{net::SocketAddr, sync::Arc};
+use rweb_common::{mac::Mac, RwebError};
+use tokio::{io::{duplex, DuplexStream}, sync::mpsc};
+use crate::{context::{StreamContext, StreamError}, quic_client::DiyStream};
+
+const CHANNEL_BUF:usize = 64 * 1024;
+
+//进程内的一次访问，stream另一端接着访问方；addr为CONNECT的目标，直接访问设备时为None
+pub struct ChannelRequest{
+    pub mac:Mac,
+    pub addr:Option<SocketAddr>,
+    pub context:StreamContext,//请求头、访问者等
+    pub stream:DuplexStream,
+}
+
+//嵌入rwebc的应用自己提供目标：对mac_list里设备的访问都交给ChannelAccept，由应用的代码处理
+#[derive(Clone)]
+pub struct ChannelStream{
+    mac_list:Arc<Vec<Mac>>,
+    tx:mpsc::Sender<ChannelRequest>,
+}
+
+pub struct ChannelAccept(mpsc::Receiver<ChannelRequest>);
+
+impl ChannelAccept{
+    //ChannelStream全部drop后返回None
+    pub async fn accept(&mut self)->Option<ChannelRequest>{
+        self.0.recv().await
+    }
+}
+
+//ChannelStream交给run_diy_stream，ChannelAccept留给应用
+pub fn channel(mac_list:Vec<Mac>)->(ChannelStream,ChannelAccept){
+    let (tx,rx) = mpsc::channel(64);
+    (ChannelStream{mac_list:Arc::new(mac_list),tx},ChannelAccept(rx))
+}
+
+impl DiyStream for ChannelStream{
+    #[allow(refining_impl_trait)]
+    async fn new_diy_stream(&self,ctx:&StreamContext)->Result<DuplexStream,StreamError>{
+        let (local,remote) = duplex(CHANNEL_BUF);
+        self.tx.send(ChannelRequest{mac:ctx.mac,addr:ctx.proxy_addr,context:ctx.clone(),stream:remote}).await.map_err(|_|RwebError::new(5040,"channel closed"))?;
+        Ok(local)
+    }
+
+    fn mac_list(&self)->Vec<Mac>{
+        self.mac_list.to_vec()
+    }
+}
+
+#[cfg(all(test,unix))]
+mod tests{
+    use super::*;
+    use std::{net::{IpAddr, Ipv4Addr}, path::PathBuf};
+    use rweb_common::{get_header, proxy_list::ProxyList, relay_list::{Bind, RelayCell, RelayRule}};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpStream, UnixListener, UnixStream}, time::{sleep, Duration}};
+    use crate::{quic_client::ProxyStringList, testutil::{self, run}, AsyncReadWrite};
+
+    async fn get(mut stream:impl AsyncReadWrite)->String{
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n").await.unwrap();
+        let mut body = String::new();
+        stream.read_to_string(&mut body).await.unwrap();
+        body
+    }
+
+    //a经rwebs中转到b和c：a在unix socket和127.0.0.1上监听，b的目标是进程内的channel，c的目标是unix socket
+    #[tokio::test]
+    async fn channel_relay_unix(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let path = |name:&str|->PathBuf{std::env::temp_dir().join(format!("rwebc-{}-{}.sock",std::process::id(),name))};
+        let mac_a:Mac = "aabbccdd0501".parse().unwrap();
+        let mac_b:Mac = "aabbccdd0502".parse().unwrap();
+        let mac_c:Mac = "aabbccdd0503".parse().unwrap();
+        let server = rwebs::quic_server::QuicServer::default().with_relay(vec![RelayRule{from:mac_a,to:mac_b},RelayRule{from:mac_a,to:mac_c}]);
+        let server_addr = SocketAddr::from(([127,0,0,1],testutil::start(&server)));
+        let (node_b,mut accept) = channel(vec![mac_b]);
+        tokio::spawn(async move{
+            while let Some(mut req) = accept.accept().await{
+                let _ = get_header(&mut req.stream).await;
+                req.stream.write_all(format!("HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nchannel {}",req.mac).as_bytes()).await.unwrap_or_default();
+            }
+        });
+        let target = UnixListener::bind(path("target")).unwrap();
+        tokio::spawn(async move{
+            while let Ok((mut s,_)) = target.accept().await{
+                let _ = get_header(&mut s).await;
+                s.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nunix").await.unwrap_or_default();
+            }
+        });
+        let target_url = url::Url::parse(&format!("unix://{}",path("target").display())).unwrap();
+        let node_c = ProxyStringList::new(Arc::new(vec![ProxyList::new(mac_c,target_url).unwrap()]),server_addr);
+        run(server_addr,node_b);
+        run(server_addr,node_c);
+        testutil::registered(&server,&[mac_b,mac_c]).await;
+        let tcp_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        //unix socket的项不用写port
+        let mut relay_list:Vec<RelayCell> = serde_json::from_str(&format!(r#"[{{"mac":"{}","bind":"unix://{}"}},{{"mac":"{}","bind":"unix://{}"}}]"#,mac_b,path("b").display(),mac_c,path("c").display())).unwrap();
+        assert_eq!(relay_list[1].bind,Bind::Unix(path("c")));
+        relay_list.push(RelayCell::new(mac_b,tcp_port).with_bind(Bind::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST))));
+        let node_a = ProxyStringList::new(Arc::new(vec![ProxyList::new(mac_a,url::Url::parse("http://127.0.0.1:1").unwrap()).unwrap()]),server_addr).with_relay(relay_list);
+        run(server_addr,node_a);
+        sleep(Duration::from_millis(500)).await;//等a在中转列表上监听
+        assert!(get(UnixStream::connect(path("b")).await.unwrap()).await.ends_with(&format!("channel {}",mac_b)));
+        assert!(get(UnixStream::connect(path("c")).await.unwrap()).await.ends_with("unix"));
+        assert!(get(TcpStream::connect(("127.0.0.1",tcp_port)).await.unwrap()).await.ends_with(&format!("channel {}",mac_b)));
+        //还在监听的socket文件不删
+        assert_eq!(crate::listen::CellListener::bind(&RelayCell::unix(mac_b,path("b"))).await.err().map(|e|e.code),Some(5031));
+        assert!(get(UnixStream::connect(path("b")).await.unwrap()).await.ends_with(&format!("channel {}",mac_b)));
+        for name in ["target","b","c"]{
+            std::fs::remove_file(path(name)).unwrap_or_default();
+        }
+    }
+}
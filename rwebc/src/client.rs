@@ -0,0 +1,306 @@
+use std::{net::SocketAddr, sync::Arc};
+use rweb_common::{nat::NatProfile, proxy_list::ProxyList, relay_list::RelayCell, reverse_list::ReverseCell, server_list::ServerCell, RwebError};
+use tokio::{select, sync::{broadcast, watch}, task::JoinHandle, time::Duration};
+use crate::{health::HealthCheck, proxy_table::ProxyTable, quic_client::{ProxyStringList, Trust, Tuning}, stats::Stats, supervisor::{Backoff, ConnEvent, Disconnect, Supervisor}};
+
+//p2p设置，与p2p_client::DiyTcpListener的字段相同；没有p2p feature时start返回错误
+#[derive(Debug,Clone,Default)]
+pub struct P2pConfig{
+    pub list:Vec<RelayCell>,//p2p_list，与中转列表格式相同
+    pub nat_server:Option<SocketAddr>,
+    pub nat_profile:Option<NatProfile>,
+    pub stun_servers:Vec<SocketAddr>,
+}
+
+//RwebClient::status的值
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum ClientStatus{
+    Connecting,
+    Connected{server:SocketAddr},
+    Disconnected{cause:Disconnect},//等着重连
+    Draining{streams:u64},//shutdown后等已有的流结束
+    Stopped,
+}
+
+#[derive(Default)]
+pub struct RwebClientBuilder{
+    servers:Vec<ServerCell>,
+    trust:Trust,
+    tuning:Tuning,
+    backoff:Backoff,
+    drain:Option<Duration>,
+    table:Option<ProxyTable>,
+    health:Option<HealthCheck>,
+    reverse:Vec<ReverseCell>,
+    relay:Vec<RelayCell>,
+    p2p:Option<P2pConfig>,
+    stats:Option<Stats>,
+}
+
+impl RwebClientBuilder{
+    //可以多次调用，多个rwebs时按ServerCell的priority和weight选择
+    pub fn server(mut self,host:&str,port:u16)->Self{
+        self.servers.push(ServerCell::new(host,port));
+        self
+    }
+
+    pub fn servers(mut self,servers:Vec<ServerCell>)->Self{
+        self.servers.extend(servers);
+        self
+    }
+
+    pub fn trust(mut self,trust:Trust)->Self{
+        self.trust = trust;
+        self
+    }
+
+    pub fn tuning(mut self,tuning:Tuning)->Self{
+        self.tuning = tuning;
+        self
+    }
+
+    pub fn backoff(mut self,backoff:Backoff)->Self{
+        self.backoff = backoff;
+        self
+    }
+
+    //shutdown时最多等已有的流这么久，默认30秒
+    pub fn drain(mut self,drain:Duration)->Self{
+        self.drain = Some(drain);
+        self
+    }
+
+    //固定的代理表
+    pub fn targets(self,proxy_list:Vec<ProxyList>)->Self{
+        self.proxy_table(ProxyTable::from(proxy_list))
+    }
+
+    //运行中会变的代理表，RwebClient::proxy_table也返回它
+    pub fn proxy_table(mut self,table:ProxyTable)->Self{
+        self.table = Some(table);
+        self
+    }
+
+    pub fn health(mut self,check:HealthCheck)->Self{
+        self.health = Some(check);
+        self
+    }
+
+    pub fn reverse(mut self,reverse_list:Vec<ReverseCell>)->Self{
+        self.reverse = reverse_list;
+        self
+    }
+
+    pub fn relay(mut self,relay_list:Vec<RelayCell>)->Self{
+        self.relay = relay_list;
+        self
+    }
+
+    pub fn p2p(mut self,p2p:P2pConfig)->Self{
+        self.p2p = Some(p2p);
+        self
+    }
+
+    //不给时新建一个，都可以从RwebClient::stats读取
+    pub fn stats(mut self,stats:Stats)->Self{
+        self.stats = Some(stats);
+        self
+    }
+
+    //在当前的tokio运行时里开始连接rwebs
+    pub fn start(self)->Result<RwebClient,RwebError>{
+        if self.servers.is_empty(){
+            return Err(RwebError::new(-45,"no rwebs server"));
+        }
+        #[cfg(not(feature="p2p"))]
+        if self.p2p.is_some(){
+            return Err(RwebError::new(-46,"p2p feature not enabled"));
+        }
+        self.tuning.check()?;
+        let table = self.table.unwrap_or_else(||ProxyTable::from(vec![]));
+        let stats = self.stats.unwrap_or_default();
+        #[cfg(feature="p2p")]
+        let p_client = self.p2p.map(|p|crate::p2p_client::DiyTcpListener{list:p.list,nat_server:p.nat_server,nat_profile:p.nat_profile,stun_servers:p.stun_servers,..Default::default()});
+        #[cfg(feature="p2p")]
+        let stats = match &p_client{
+            Some(p)=>stats.with_p2p(p.sessions.clone()),
+            None=>stats,
+        };
+        let mut supervisor = Supervisor::with_servers(self.servers).with_backoff(self.backoff).with_trust(self.trust).with_tuning(self.tuning).with_stats(stats.clone());
+        if let Some(drain) = self.drain{
+            supervisor = supervisor.with_drain(drain);
+        }
+        let supervisor = Arc::new(supervisor);
+        let (health,reverse,relay) = (self.health,self.reverse,self.relay);
+        let (t,s) = (table.clone(),stats.clone());
+        let diy_stream = move |server_addr|{
+            let mut diy_stream = ProxyStringList::from_table(t.clone(),server_addr).with_reverse(reverse.clone()).with_relay(relay.clone()).with_stats(s.clone());
+            if let Some(check) = &health{
+                diy_stream = diy_stream.with_health(check.clone());
+            }
+            diy_stream
+        };
+        let (status_tx,status) = watch::channel(ClientStatus::Connecting);
+        let mut events = supervisor.subscribe();
+        let s = supervisor.clone();
+        let task = tokio::spawn(async move{
+            #[cfg(feature="p2p")]
+            let run = s.run(diy_stream,p_client);
+            #[cfg(not(feature="p2p"))]
+            let run = s.run(diy_stream);
+            tokio::pin!(run);
+            loop{
+                select! {
+                    _ = &mut run=>break,
+                    event = events.recv()=>match event{
+                        Ok(event)=>update_status(&status_tx,event),
+                        Err(broadcast::error::RecvError::Lagged(_))=>{},
+                        Err(_)=>break,
+                    }
+                }
+            }
+            status_tx.send_replace(ClientStatus::Stopped);
+        });
+        Ok(RwebClient{supervisor,status,stats,table,task})
+    }
+}
+
+fn update_status(status:&watch::Sender<ClientStatus>,event:ConnEvent){
+    status.send_if_modified(|s|{
+        let new = match event{
+            ConnEvent::Connecting{..} if matches!(s,ClientStatus::Connected{..})=>return false,
+            ConnEvent::Connecting{..}=>ClientStatus::Connecting,
+            ConnEvent::Connected{server}=>ClientStatus::Connected{server},
+            ConnEvent::Disconnected{cause:Disconnect::Shutdown,..}=>ClientStatus::Stopped,
+            ConnEvent::Disconnected{cause,..}=>ClientStatus::Disconnected{cause},
+            ConnEvent::Retry{..}=>return false,
+            ConnEvent::Draining{streams}=>ClientStatus::Draining{streams},
+        };
+        if *s == new{
+            return false;
+        }
+        *s = new;
+        true
+    });
+}
+
+//RwebClient::builder().server(host,port).targets(list).start()，p2p开不开用法相同
+pub struct RwebClient{
+    supervisor:Arc<Supervisor>,
+    status:watch::Receiver<ClientStatus>,
+    stats:Stats,
+    table:ProxyTable,
+    task:JoinHandle<()>,
+}
+
+impl RwebClient{
+    pub fn builder()->RwebClientBuilder{
+        RwebClientBuilder::default()
+    }
+
+    pub fn status(&self)->watch::Receiver<ClientStatus>{
+        self.status.clone()
+    }
+
+    pub fn events(&self)->broadcast::Receiver<ConnEvent>{
+        self.supervisor.subscribe()
+    }
+
+    pub fn stats(&self)->&Stats{
+        &self.stats
+    }
+
+    pub fn proxy_table(&self)->&ProxyTable{
+        &self.table
+    }
+
+    //先从rwebs下线不再接新的流，等已有的流结束或drain超时后断开，返回时已停止
+    pub async fn shutdown(mut self){
+        self.supervisor.shutdown();
+        (&mut self.task).await.unwrap_or_default();
+    }
+}
+
+//没有调shutdown就丢弃时同样下线，在后台等已有的流结束
+impl Drop for RwebClient{
+    fn drop(&mut self){
+        self.supervisor.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use rweb_common::mac::Mac;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::UnixListener, time::sleep};
+
+    //读到请求后过一会儿再回应
+    fn slow_web(name:&str,delay:Duration)->url::Url{
+        let path = std::env::temp_dir().join(format!("rwebc-{}-{}.sock",std::process::id(),name));
+        std::fs::remove_file(&path).unwrap_or_default();
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move{
+            while let Ok((mut stream,_)) = listener.accept().await{
+                tokio::spawn(async move{
+                    stream.read(&mut [0;1024]).await.unwrap_or_default();
+                    sleep(delay).await;
+                    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap_or_default();
+                });
+            }
+        });
+        url::Url::parse(&format!("unix://{}",path.display())).unwrap()
+    }
+
+    //shutdown先从rwebs下线，已有的流走完后才停止
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn client_shutdown(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        assert_eq!(RwebClient::builder().start().err().map(|e|e.code),Some(-45));
+        assert!(Trust::from_pem(b"","reform").is_err());
+        let tuning = |idle_timeout,keep_alive|RwebClient::builder().server("127.0.0.1",1).tuning(Tuning{idle_timeout,keep_alive,..Default::default()}).start().err().map(|e|e.code);
+        assert_eq!(tuning(Duration::MAX,None),Some(-47));
+        assert_eq!(tuning(Duration::from_secs(5),Some(Duration::from_secs(5))),Some(-47));
+        let server = rwebs::quic_server::QuicServer::default();
+        let port = crate::testutil::start(&server);
+        let mac:Mac = "aabbccdd0c01".parse().unwrap();
+        let client = RwebClient::builder().server("127.0.0.1",port).targets(vec![ProxyList::new(mac,slow_web("slow",Duration::from_secs(1))).unwrap()]).start().unwrap();
+        let mut status = client.status();
+        let mut events = client.events();
+        tokio::time::timeout(Duration::from_secs(10),status.wait_for(|s|matches!(s,ClientStatus::Connected{..}))).await.unwrap().unwrap();
+        crate::testutil::registered(&server,&[mac]).await;//rwebs收到mac列表
+
+        let mut stream = server.open_stream(mac).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n").await.unwrap();
+        while client.stats().snapshot().streams.values().all(|c|c.active == 0){
+            sleep(Duration::from_millis(20)).await;
+        }
+        let stopping = tokio::spawn(client.shutdown());
+        tokio::time::timeout(Duration::from_secs(5),status.wait_for(|s|matches!(s,ClientStatus::Draining{streams:1}))).await.unwrap().unwrap();
+        for _ in 0..50{//不再分配新的流
+            if server.open_stream(mac).await.is_err_and(|e|e.code == 402){
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(server.open_stream(mac).await.err().map(|e|e.code),Some(402));
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(5),stopping).await.unwrap().unwrap();
+        assert_eq!(*status.borrow(),ClientStatus::Stopped);
+        let mut last = None;
+        while let Ok(event) = events.try_recv(){
+            last = Some(event);
+        }
+        assert!(matches!(last,Some(ConnEvent::Disconnected{cause:Disconnect::Shutdown,uptime:Some(_),..})));
+
+        //没有shutdown就丢弃时也下线
+        let client = RwebClient::builder().server("127.0.0.1",port).targets(vec![ProxyList::new(mac,slow_web("slow",Duration::from_secs(1))).unwrap()]).start().unwrap();
+        let mut status = client.status();
+        tokio::time::timeout(Duration::from_secs(10),status.wait_for(|s|matches!(s,ClientStatus::Connected{..}))).await.unwrap().unwrap();
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5),status.wait_for(|s|*s == ClientStatus::Stopped)).await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::{fmt::Display, net::{IpAddr, SocketAddr}};
+use rweb_common::{mac::Mac, Header, RwebError};
+
+//流是怎么来的
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Access{
+    Target,//访问代理表里的目标，网页、rtsp等
+    Connect,//CONNECT(http_proxy、socks5)，proxy_addr为要连的地址
+    HttpProxy,//旧版http_proxy，请求行里为完整url，proxy_addr为其中的地址
+    Probe,//目标探测，没有请求头和访问者
+}
+
+//DiyStream::new_diy_stream按它选择目标或拒绝
+#[derive(Debug,Clone)]
+pub struct StreamContext{
+    pub mac:Mac,
+    pub access:Access,
+    pub header:Option<Header>,//流开头的请求头，已去掉rwebs加的访问者；旧版http_proxy为改写后的
+    pub proxy_addr:Option<SocketAddr>,
+    pub client_ip:Option<IpAddr>,//声明了FEATURE_VISITOR的rwebs直接转来的访问者ip，旧版rwebs、p2p直连和中转时为None
+    pub session:Option<u64>,//rwebs上访问者这个连接的编号，与client_ip同时有
+    pub p2p:bool,//经p2p直连进来，不经过rwebs
+    pub relay:Option<Mac>,//经rwebs中转来的请求方设备，这时没有访问者
+}
+
+impl StreamContext{
+    pub fn new(mac:Mac,access:Access)->Self{
+        Self{mac,access,header:None,proxy_addr:None,client_ip:None,session:None,p2p:false,relay:None}
+    }
+
+    //请求行里的路径，没有请求头时为None
+    pub fn path(&self)->Option<&str>{
+        self.header.as_ref().map(|h|h.uri.as_str())
+    }
+
+    //请求头里的Host，不区分大小写
+    pub fn host(&self)->Option<&str>{
+        self.header.as_ref()?.header.iter().find(|(k,_)|k.eq_ignore_ascii_case("host")).map(|(_,v)|v.as_str())
+    }
+}
+
+//new_diy_stream的错误：Reject按给的状态码回应访问者，Error只断开这个流并记下错误码
+#[derive(Debug,Clone)]
+pub enum StreamError{
+    Reject{status:u16,reason:String},
+    Error(RwebError),
+}
+
+impl StreamError{
+    pub fn reject(status:u16,reason:impl Display)->Self{
+        Self::Reject{status,reason:reason.to_string()}
+    }
+
+    //version为请求头里的协议，rtsp请求按RTSP/1.0回应
+    pub(crate) fn response(status:u16,reason:&str,version:&str)->String{
+        let reason = reason.replace(['\r','\n'], " ");
+        format!("{} {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",version,status,reason,reason.len(),reason)
+    }
+}
+
+impl From<RwebError> for StreamError{
+    fn from(e:RwebError)->Self{
+        Self::Error(e)
+    }
+}
+
+impl From<StreamError> for RwebError{
+    fn from(e:StreamError)->Self{
+        match e{
+            StreamError::Reject{status,reason}=>RwebError::new(status as i32,reason),
+            StreamError::Error(e)=>e,
+        }
+    }
+}
+
+impl Display for StreamError{
+    fn fmt(&self,f:&mut std::fmt::Formatter<'_>)->std::fmt::Result{
+        match self{
+            Self::Reject{status,reason}=>write!(f,"rejected {} {}",status,reason),
+            Self::Error(e)=>write!(f,"{}",e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use tokio::{io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream}, net::TcpStream, time::{timeout, Duration}};
+    use crate::{quic_client::DiyStream, testutil};
+
+    //拒绝/admin，其他请求回应看到的访问者；目标支持keep-alive，收到Connection: close才关闭，所以要per_request
+    #[derive(Clone)]
+    struct Gate(Mac);
+
+    impl DiyStream for Gate{
+        #[allow(refining_impl_trait)]
+        async fn new_diy_stream(&self,ctx:&StreamContext)->Result<DuplexStream,StreamError>{
+            if ctx.path() == Some("/admin"){
+                return Err(StreamError::reject(403,"forbidden"));
+            }
+            let (local,mut remote) = duplex(4096);
+            let seen = format!("relay={:?} ip={:?} session={} access={:?} host={:?}",ctx.relay,ctx.client_ip,ctx.session.unwrap_or_default(),ctx.access,ctx.host());
+            tokio::spawn(async move{
+                let mut buf = Vec::new();
+                let mut temp = vec![0;4096];
+                loop{
+                    while let Some(end) = buf.windows(4).position(|w|w == b"\r\n\r\n"){
+                        let request = String::from_utf8_lossy(&buf.drain(..end + 4).collect::<Vec<_>>()).to_ascii_lowercase();
+                        let body = format!("{} leaked={}",seen,request.contains("rweb-"));
+                        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",body.len(),body);
+                        remote.write_all(response.as_bytes()).await.unwrap_or_default();
+                        if request.contains("connection: close"){
+                            return;
+                        }
+                    }
+                    match remote.read(&mut temp).await{
+                        Ok(n) if n > 0=>buf.extend_from_slice(&temp[..n]),
+                        _=>return
+                    }
+                }
+            });
+            Ok(local)
+        }
+
+        fn mac_list(&self)->Vec<Mac>{
+            vec![self.0]
+        }
+
+        fn per_request(&self)->bool{
+            true
+        }
+    }
+
+    async fn get(addr:SocketAddr,path:&str)->String{
+        testutil::send(addr,&format!("GET {} HTTP/1.1\r\nHost: aabbccdd0d01.abc.com\r\nRweb-Client: 1.2.3.4\r\nrweb-session: 7\r\n\r\n",path)).await
+    }
+
+    fn session(response:&str)->&str{
+        response.split("session=").nth(1).and_then(|s|s.split(' ').next()).unwrap()
+    }
+
+    //访问者伪造的Rweb-*头被rwebs去掉，设备拿到真实ip和连接编号，目标看不到这些头；也不能用RELAY冒充中转
+    #[tokio::test]
+    async fn stream_context(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = rwebs::quic_server::QuicServer::default();
+        let port = testutil::start(&server);
+        let web_addr = testutil::web(&server).await;
+        let mac:Mac = "aabbccdd0d01".parse().unwrap();
+        let server_addr = SocketAddr::from(([127,0,0,1],port));
+        testutil::run(server_addr,Gate(mac));
+        testutil::registered(&server,&[mac]).await;
+
+        let first = get(web_addr,"/").await;
+        assert!(first.starts_with("HTTP/1.1 200"),"{}",first);
+        assert!(first.contains("ip=Some(127.0.0.1)"),"{}",first);
+        assert!(first.contains("access=Target host=Some(\"aabbccdd0d01.abc.com\") leaked=false"),"{}",first);
+        assert_ne!(session(&first),"7");
+        assert_ne!(session(&first),"0");
+        let second = get(web_addr,"/").await;
+        assert_ne!(session(&first),session(&second));
+        let denied = get(web_addr,"/admin").await;
+        assert!(denied.starts_with("HTTP/1.1 403 forbidden\r\n"),"{}",denied);
+        assert!(denied.ends_with("\r\n\r\nforbidden"));
+        assert!(first.contains("relay=None"),"{}",first);
+        //访问者冒充rwebs的中转头，rwebs直接拒绝，设备拿不到伪造的请求方
+        let relay = testutil::send(web_addr,"RELAY /relay HTTP/1.1\r\nHost: aabbccdd0d01.abc.com\r\nmac: aabbccdd0dff\r\n\r\n").await;
+        assert!(relay.starts_with("HTTP/1.1 400"),"{}",relay);
+
+        //keep-alive连接上的第二个请求也要经过new_diy_stream，不能跟着第一个请求直接到目标
+        let mut stream = TcpStream::connect(web_addr).await.unwrap();
+        let host = "Host: aabbccdd0d01.abc.com\r\nConnection: keep-alive\r\n\r\n";
+        stream.write_all(format!("GET / HTTP/1.1\r\n{}GET /admin HTTP/1.1\r\n{}",host,host).as_bytes()).await.unwrap();
+        let mut response = String::new();
+        timeout(Duration::from_secs(5),stream.read_to_string(&mut response)).await.unwrap().unwrap();
+        assert_eq!(response.matches("HTTP/1.1 200").count(),1,"{}",response);
+    }
+}
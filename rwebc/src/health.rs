@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use quinn::Connection;
+use rweb_common::{health::TargetHealth, io::header::write_health, mac::Mac, proxy_list::ProxyList, RwebError};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, select, time::{sleep, timeout, Duration}};
+use crate::{context::{Access, StreamContext}, quic_client::DiyStream};
+
+//目标探测设置，默认每30秒连一次目标，能连上就算正常
+//request为true时连上后http/https发GET、rtsp发OPTIONS，收到响应行才算正常
+#[derive(Debug,Clone)]
+pub struct HealthCheck{
+    pub interval:Duration,
+    pub timeout:Duration,
+    pub request:bool,
+}
+
+impl Default for HealthCheck{
+    fn default()->Self{
+        Self{interval:Duration::from_secs(30),timeout:Duration::from_secs(5),request:false}
+    }
+}
+
+impl HealthCheck{
+    pub fn with_interval(mut self,interval:Duration)->Self{
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_request(mut self,request:bool)->Self{
+        self.request = request;
+        self
+    }
+}
+
+//和转发时一样用new_diy_stream连接目标
+pub async fn probe(diy_stream:&impl DiyStream,target:&ProxyList,check:&HealthCheck)->TargetHealth{
+    let res = timeout(check.timeout, async{
+        let ctx = StreamContext::new(target.mac,Access::Probe);
+        let mut stream = diy_stream.new_diy_stream(&ctx).await.map_err(RwebError::from)?;
+        if !check.request{
+            return Ok(());
+        }
+        let url = &target.url;
+        let host = match url.port(){
+            Some(port)=>format!("{}:{}",url.host_str().unwrap_or_default(),port),
+            None=>url.host_str().unwrap_or_default().to_string()
+        };
+        let (request,expect) = match url.scheme(){
+            "http"|"https"|"ws"|"wss"=>(format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",url.path(),host),b"HTTP/"),
+            "rtsp"|"rtsps"=>(format!("OPTIONS {} RTSP/1.0\r\nCSeq: 1\r\n\r\n",url),b"RTSP/"),
+            _=>return Ok(())//没有对应的请求时只看能不能连上
+        };
+        stream.write_all(request.as_bytes()).await.map_err(|e|RwebError::new(5060,e))?;
+        let mut status = [0u8;5];
+        stream.read_exact(&mut status).await.map_err(|e|RwebError::new(5061,e))?;
+        if &status != expect{
+            return Err(RwebError::new(5062,"unexpected response"));
+        }
+        Ok(())
+    }).await;
+    match res{
+        Ok(Ok(()))=>TargetHealth::Up,
+        _=>TargetHealth::Down
+    }
+}
+
+//定时探测代理表里的全部目标，有变化的在新的uni流上报告给rwebs；代理表变了马上探测一次
+//没有设置HealthCheck时一直等待
+pub(crate) async fn report_health(connection:Connection,diy_stream:impl DiyStream)->Result<(),RwebError>{
+    let (Some(check),Some(table)) = (diy_stream.health_check().cloned(),diy_stream.proxy_table().cloned()) else{
+        return std::future::pending().await
+    };
+    let mut changes = table.subscribe();
+    let mut reported:HashMap<Mac,TargetHealth> = HashMap::new();
+    loop{
+        let targets = table.get();
+        let health = futures::future::join_all(targets.iter().map(|t|probe(&diy_stream, t, &check))).await;
+        reported.retain(|mac,_|targets.iter().any(|t|t.mac == *mac));
+        let changed:Vec<(Mac,TargetHealth)> = targets.iter().map(|t|t.mac).zip(health)
+            .filter(|(mac,h)|reported.get(mac) != Some(h)).collect();
+        if !changed.is_empty(){
+            let mut uni_stream = connection.open_uni().await.map_err(|e|RwebError{code:-15,msg:e.to_string()})?;
+            write_health(&mut uni_stream, &changed).await?;
+            uni_stream.finish().unwrap_or_default();
+            log::info!(changed:? = changed; "target health changed");
+            reported.extend(changed);
+        }
+        select!{
+            _ = sleep(check.interval)=>{},
+            _ = changes.changed()=>{}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use tokio::net::UnixListener;
+    use crate::{proxy_table::ProxyTable, quic_client::ProxyStringList};
+
+    //连上就回http响应行
+    fn web(name:&str,listen:bool)->url::Url{
+        let path = std::env::temp_dir().join(format!("rwebc-{}-{}.sock",std::process::id(),name));
+        std::fs::remove_file(&path).unwrap_or_default();
+        if listen{
+            let listener = UnixListener::bind(&path).unwrap();
+            tokio::spawn(async move{
+                while let Ok((mut stream,_)) = listener.accept().await{
+                    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap_or_default();
+                }
+            });
+        }
+        url::Url::parse(&format!("unix://{}",path.display())).unwrap()
+    }
+
+    async fn wait_for(server:&rwebs::quic_server::QuicServer,expect:&[(Mac,TargetHealth)]){
+        for _ in 0..50{
+            let mut devices = server.devices().await;
+            devices.sort_by_key(|d|d.0.to_string());
+            if devices == expect{
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        panic!("{:?} != {:?}",server.devices().await,expect);
+    }
+
+    //目标状态报告给rwebs，不可达的目标直接返回503，目标恢复或代理表变化后更新
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn health_report(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = rwebs::quic_server::QuicServer::default();
+        let port = crate::testutil::start(&server);
+        let mac_a:Mac = "aabbccdd0901".parse().unwrap();
+        let mac_b:Mac = "aabbccdd0902".parse().unwrap();
+        let table = ProxyTable::from(vec![ProxyList::new(mac_a,web("up",true)).unwrap(),ProxyList::new(mac_b,web("down",false)).unwrap()]);
+        let check = HealthCheck::default().with_interval(Duration::from_millis(200)).with_request(true);
+        let diy_stream = ProxyStringList::from_table(table.clone(),"127.0.0.1:0".parse().unwrap()).with_health(check);
+        crate::testutil::run(([127,0,0,1],port).into(),diy_stream);
+        wait_for(&server,&[(mac_a,TargetHealth::Up),(mac_b,TargetHealth::Down)]).await;
+        assert_eq!(server.open_target(mac_b).await.err().map(|e|e.code),Some(503));
+        assert!(server.open_target(mac_a).await.is_ok());
+        assert!(server.open_connect(mac_b,"127.0.0.1:1",None).await.is_err_and(|e|e.code != 503));//CONNECT不看目标状态，由设备去连
+
+        web("down",true);
+        wait_for(&server,&[(mac_a,TargetHealth::Up),(mac_b,TargetHealth::Up)]).await;
+        table.set(vec![ProxyList::new(mac_b,web("none",false)).unwrap()]).unwrap();
+        wait_for(&server,&[(mac_b,TargetHealth::Down)]).await;
+    }
+}
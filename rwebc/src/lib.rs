@@ -1,9 +1,29 @@
 pub mod quic_client;
+mod relay;
+pub mod listen;
+pub mod channel;
+pub mod supervisor;
+pub mod servers;
+pub mod proxy_table;
+pub mod health;
+pub mod stats;
+pub mod context;
+pub mod client;
 //pub mod c_so;
 #[cfg(feature="p2p")]
-mod p2p_client;
+pub mod p2p_client;
 #[cfg(feature="p2p")]
-mod symmetric;
+pub mod p2p_session;
+#[cfg(feature="p2p")]
+pub mod symmetric;
+#[cfg(feature="p2p")]
+pub mod nat;
+#[cfg(feature="p2p")]
+pub mod stun;
+#[cfg(feature="p2p")]
+mod ticket;
+#[cfg(test)]
+mod testutil;
 //pub use c_so::quic_node_run;
 //#[cfg(feature="p2p")]
 //pub use c_so::p2pclient;
@@ -0,0 +1,42 @@
+use rweb_common::{relay_list::{Bind, RelayCell}, RwebError};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use crate::AsyncReadWrite;
+
+//中转列表和p2p_list里一项的本机监听，按cell.bind监听tcp或unix socket
+pub enum CellListener{
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl CellListener{
+    pub async fn bind(cell:&RelayCell)->Result<Self,RwebError>{
+        match &cell.bind{
+            Bind::Ip(ip)=>Ok(Self::Tcp(TcpListener::bind((*ip,cell.port)).await.map_err(|e|RwebError::new(5031,e))?)),
+            #[cfg(unix)]
+            Bind::Unix(path)=>{
+                use std::os::unix::fs::FileTypeExt;
+                //上次退出时留下的socket文件会导致绑定失败，只删连不上的socket文件，连得上说明还有进程在监听
+                if std::fs::symlink_metadata(path).is_ok_and(|m|m.file_type().is_socket()){
+                    if tokio::net::UnixStream::connect(path).await.is_ok(){
+                        return Err(RwebError::new(5031,format!("{} in use",path.display())));
+                    }
+                    std::fs::remove_file(path).map_err(|e|RwebError::new(5031,e))?;
+                }
+                Ok(Self::Unix(UnixListener::bind(path).map_err(|e|RwebError::new(5031,e))?))
+            },
+            #[cfg(not(unix))]
+            Bind::Unix(_)=>Err(RwebError::new(5031,"unix socket not supported")),
+        }
+    }
+
+    pub async fn accept(&self)->Result<Box<dyn AsyncReadWrite+Send>,RwebError>{
+        match self{
+            Self::Tcp(l)=>Ok(Box::new(l.accept().await.map_err(|e|RwebError::new(5030,e))?.0)),
+            #[cfg(unix)]
+            Self::Unix(l)=>Ok(Box::new(l.accept().await.map_err(|e|RwebError::new(5030,e))?.0)),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::net::SocketAddr;
+use quinn::{Connection, Endpoint, VarInt};
+use rweb_common::{io::header::read_addr, nat::{allocation_delta, NatBehavior, NatProfile, NAT_CMD_CHANGE_IP, NAT_CMD_CHANGE_PORT, NAT_CMD_INFO, NAT_RESULT_OK}, socket::canonical, RwebError};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, time::{timeout, timeout_at, Duration, Instant}};
+
+const CALLBACK_WAIT_MILLIS:u64 = 3_000;
+//映射与目标有关时额外探测的次数，用来估计端口分配步长
+const DELTA_PROBES:usize = 4;
+
+//用p2ptest服务按RFC 5780测出NAT画像，endpoint必须是之后用来打洞的那个
+//没有备用ip时映射只看换端口后是否变化，不变时报告为Ambiguous；过滤无法区分与目标无关和地址相关，按地址相关处理
+pub async fn classify(endpoint:&Endpoint,server:SocketAddr)->Result<NatProfile,RwebError>{
+    let (conn,mapped) = mapped_addr(endpoint, server).await?;
+    let alt = command_addr(&conn).await?;
+    let alt_ip = (!alt.ip().is_unspecified()).then_some(alt.ip());
+    //过滤测试要在联系其他地址之前做，否则映射测试发出的包会在NAT上打开过滤
+    let filtering = if let Some(alt_ip) = alt_ip && callback(endpoint, &conn, NAT_CMD_CHANGE_IP, SocketAddr::new(alt_ip,alt.port())).await?{
+        NatBehavior::EndpointIndependent
+    }else if callback(endpoint, &conn, NAT_CMD_CHANGE_PORT, SocketAddr::new(server.ip(),alt.port())).await?{
+        NatBehavior::AddressDependent
+    }else{
+        NatBehavior::AddressAndPortDependent
+    };
+    conn.close(VarInt::from_u32(0), b"Ok");
+    let (alt_port_conn,mapped_alt_port) = mapped_addr(endpoint, SocketAddr::new(server.ip(),alt.port())).await?;
+    alt_port_conn.close(VarInt::from_u32(0), b"Ok");
+    let mut ports = vec![mapped.port(),mapped_alt_port.port()];//按分配顺序记录映射端口，用来估计步长
+    let mapping = match alt_ip{
+        Some(alt_ip)=>{
+            let (c2,mapped2) = mapped_addr(endpoint, SocketAddr::new(alt_ip,server.port())).await?;
+            c2.close(VarInt::from_u32(0), b"Ok");
+            let (c3,mapped3) = mapped_addr(endpoint, SocketAddr::new(alt_ip,alt.port())).await?;
+            c3.close(VarInt::from_u32(0), b"Ok");
+            ports.extend([mapped2.port(),mapped3.port()]);
+            if mapped2 == mapped{
+                NatBehavior::EndpointIndependent
+            }else if mapped2 == mapped3{
+                NatBehavior::AddressDependent
+            }else{
+                NatBehavior::AddressAndPortDependent
+            }
+        },
+        None if mapped_alt_port == mapped => NatBehavior::Ambiguous,
+        None => NatBehavior::AddressAndPortDependent
+    };
+    let port_delta = match mapping{
+        NatBehavior::EndpointIndependent => 0,
+        _ => {
+            //同一socket的目标地址用完了，再用新socket各测一次，NAT一般全局按顺序分配
+            for i in 0..DELTA_PROBES{
+                let target = if i % 2 == 0 {server} else {SocketAddr::new(server.ip(),alt.port())};
+                if let Ok(probe) = crate::quic_client::make_endpoint() && let Ok((c,addr)) = mapped_addr(&probe, target).await{
+                    c.close(VarInt::from_u32(0), b"Ok");
+                    ports.push(addr.port());
+                }
+            }
+            allocation_delta(&ports).unwrap_or(0)//0表示随机分配
+        }
+    };
+    let profile = NatProfile{
+        mapping,
+        filtering,
+        port_delta,
+    };
+    log::info!(profile:? = profile, mapped:% = mapped, ports:? = ports; "nat profile");
+    Ok(profile)
+}
+
+async fn mapped_addr(endpoint:&Endpoint,server:SocketAddr)->Result<(Connection,SocketAddr),RwebError>{
+    let conn = endpoint.connect(server, "reform").map_err(|e|RwebError::new(6001,e))?.await.map_err(|e|RwebError::new(6002,e))?;
+    let mut uni = conn.accept_uni().await.map_err(|e|RwebError::new(6003,e))?;
+    let addr = read_addr(&mut uni).await?;
+    Ok((conn,addr))
+}
+
+async fn command_addr(conn:&Connection)->Result<SocketAddr,RwebError>{
+    let (mut send,mut recv) = conn.open_bi().await.map_err(|e|RwebError::new(6004,e))?;
+    send.write_u8(NAT_CMD_INFO).await.map_err(|e|RwebError::new(6005,e))?;
+    read_addr(&mut recv).await
+}
+
+//让服务从另一个地址(from)回连，同时在endpoint上接受这个连接
+//其他来源的连接(比如p2p对端)不应答，对端重发后由之后的endpoint.accept处理
+async fn callback(endpoint:&Endpoint,conn:&Connection,cmd:u8,from:SocketAddr)->Result<bool,RwebError>{
+    let (mut send,mut recv) = conn.open_bi().await.map_err(|e|RwebError::new(6004,e))?;
+    send.write_u8(cmd).await.map_err(|e|RwebError::new(6005,e))?;
+    let accept = async{
+        let deadline = Instant::now() + Duration::from_millis(CALLBACK_WAIT_MILLIS);
+        while let Ok(Some(incoming)) = timeout_at(deadline, endpoint.accept()).await{
+            if canonical(incoming.remote_address()) != canonical(from){
+                log::debug!(remote:% = incoming.remote_address(); "nat test ignore incoming");
+                incoming.ignore();
+                continue;
+            }
+            if let Ok(Ok(c)) = timeout(Duration::from_millis(CALLBACK_WAIT_MILLIS), incoming).await{
+                c.closed().await;
+            }
+            break;
+        }
+    };
+    tokio::pin!(accept);
+    let result = tokio::select!{
+        r = recv.read_u8() => r,//服务回连失败时不用再等
+        _ = &mut accept => recv.read_u8().await,
+    };
+    Ok(result.map_err(|e|RwebError::new(6006,e))? == NAT_RESULT_OK)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    #[tokio::test]
+    async fn classify_loopback(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        //127.0.0.2在linux上默认可用，作为备用ip
+        let server = p2ptest::quic_server::NatServer::bind(IpAddr::V4(Ipv4Addr::LOCALHOST),0,0,Some(IpAddr::V4(Ipv4Addr::new(127,0,0,2)))).unwrap();
+        let addr = server.local_addr();
+        tokio::spawn(server.serve());
+        let endpoint = crate::quic_client::make_endpoint().unwrap();
+        let profile = classify(&endpoint, addr).await.unwrap();
+        assert_eq!(profile,NatProfile{mapping:NatBehavior::EndpointIndependent,filtering:NatBehavior::EndpointIndependent,port_delta:0});
+    }
+
+    //在模拟NAT后面测，映射和过滤应与模拟的一致；探测步长的新socket不经过模拟NAT，这里不比较步长
+    #[tokio::test]
+    async fn classify_nat_sim(){
+        use p2ptest::nat_sim::{NatConfig, NatKind, NatSocket};
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = p2ptest::quic_server::NatServer::bind(IpAddr::V4(Ipv4Addr::LOCALHOST),0,0,Some(IpAddr::V4(Ipv4Addr::new(127,0,0,2)))).unwrap();
+        let addr = server.local_addr();
+        tokio::spawn(server.serve());
+        let cases = [NatKind::FullCone,NatKind::Restricted,NatKind::PortRestricted,NatKind::Symmetric].map(|kind|async move{
+            let config = NatConfig::new(kind,IpAddr::V4(Ipv4Addr::new(127,0,0,21)));
+            let endpoint = crate::quic_client::make_endpoint_with_socket(NatSocket::new(config)).unwrap();
+            let profile = classify(&endpoint, addr).await.unwrap();
+            assert_eq!((profile.mapping,profile.filtering),(config.mapping,config.filtering),"{:?}",kind);
+        });
+        futures::future::join_all(cases).await;
+    }
+
+    //没有备用ip时换端口映射不变，分不出EI和AD；换端口就变的仍是APD
+    #[tokio::test]
+    async fn classify_without_alt_ip(){
+        use p2ptest::nat_sim::{NatConfig, NatKind, NatSocket};
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = p2ptest::quic_server::NatServer::bind(IpAddr::V4(Ipv4Addr::LOCALHOST),0,0,None).unwrap();
+        let addr = server.local_addr();
+        tokio::spawn(server.serve());
+        for (kind,mapping) in [(NatKind::FullCone,NatBehavior::Ambiguous),(NatKind::Symmetric,NatBehavior::AddressAndPortDependent)]{
+            let config = NatConfig::new(kind,IpAddr::V4(Ipv4Addr::new(127,0,0,22)));
+            let endpoint = crate::quic_client::make_endpoint_with_socket(NatSocket::new(config)).unwrap();
+            assert_eq!(classify(&endpoint, addr).await.unwrap().mapping,mapping,"{:?}",kind);
+        }
+    }
+
+    //测试期间连进来的其他连接不会被当作回连用掉，测完后仍能接受
+    #[tokio::test]
+    async fn classify_keeps_incoming(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = p2ptest::quic_server::NatServer::bind(IpAddr::V4(Ipv4Addr::LOCALHOST),0,0,Some(IpAddr::V4(Ipv4Addr::new(127,0,0,2)))).unwrap();
+        let addr = server.local_addr();
+        tokio::spawn(server.serve());
+        let endpoint = crate::quic_client::make_endpoint().unwrap();
+        let local = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST),endpoint.local_addr().unwrap().port());
+        let peer = crate::quic_client::make_endpoint().unwrap();
+        let connecting = peer.connect(local,"reform").unwrap();
+        let profile = classify(&endpoint, addr).await.unwrap();
+        assert_eq!(profile.filtering,NatBehavior::EndpointIndependent);
+        let incoming = timeout(Duration::from_secs(5),endpoint.accept()).await.unwrap().unwrap();
+        assert_eq!(incoming.remote_address().port(),peer.local_addr().unwrap().port());
+        drop(connecting);
+    }
+}
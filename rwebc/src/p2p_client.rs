@@ -1,8 +1,14 @@
-use crate::{symmetric, AsyncReadWrite};
-use rweb_common::{io::{header::{get_header, write_header, Header},stream_copy}, mac::Mac, p2p_list::P2pCell, RwebError};
-use quinn::{Endpoint, Connection};
-use std::net::SocketAddr;
-use tokio::{net::TcpListener, io::AsyncWriteExt, time::Duration};
+use crate::{listen::CellListener, quic_client::NodeEndpoint, p2p_session::{P2pSessions, P2pState, Session}, relay::relay_stream, symmetric::{self, PunchBudget, PunchPlan, PunchReport}, AsyncReadWrite};
+use rweb_common::{io::{header::{get_header, write_header, Header, P2P_REQUESTER, P2P_TICKET},stream_copy}, mac::Mac, nat::NatProfile, p2p_list::P2pCell, RwebError};
+use quinn::{Connection, VarInt};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{net::TcpListener, io::AsyncWriteExt, select, time::{sleep, Duration}};
+
+//直连断开或打洞失败后重新打洞的间隔，从P2P_RETRY_MIN_SECS翻倍到P2P_RETRY_SECS，期间走中转
+const P2P_RETRY_MIN_SECS:u64 = 2;
+const P2P_RETRY_SECS:u64 = 60;
+//直连健康检查间隔
+const P2P_HEALTH_SECS:u64 = 5;
 
 pub trait Accept{
     fn accept(&self)->impl Future<Output = Result<Box<dyn AsyncReadWrite+Send>, RwebError>> + Send;
@@ -12,6 +18,8 @@ pub trait P2pListen: Send{
     type A: Accept + Send;
     fn listen(&self)->impl Future<Output = Result<Self::A, RwebError>> + Send;
     fn mac(&self)->Mac;
+    //每次打洞结束后回调，可用于统计或上报
+    fn punch_report(&self,_report:&PunchReport){}
 }
 
 impl Accept for TcpListener{
@@ -21,11 +29,16 @@ impl Accept for TcpListener{
     }
 }
 
+impl Accept for CellListener{
+    async fn accept(&self)->Result<Box<dyn AsyncReadWrite+Send>, RwebError> {
+        self.accept().await
+    }
+}
+
 impl P2pListen for P2pCell{
-    type A = TcpListener;
-    async fn listen(&self)->Result<TcpListener, RwebError> {
-        let listener = TcpListener::bind(("0.0.0.0",self.port)).await.map_err(|e|RwebError::new(5031,e))?;
-        Ok(listener)
+    type A = CellListener;
+    async fn listen(&self)->Result<CellListener, RwebError> {
+        CellListener::bind(self).await
     }
     fn mac(&self)->Mac {
         self.mac.clone()
@@ -35,136 +48,460 @@ impl P2pListen for P2pCell{
 pub trait P2PListener<K: PartialEq + Clone,L: P2pListen + Send>: Send + Sync + Unpin + Clone + 'static{
     fn new_listener(&self,key:K)->Result<L, RwebError>;
     fn list(&self)->Vec<K>;
+    //p2ptest服务地址，设置后连上rwebs时先测出NAT画像
+    fn nat_server(&self)->Option<SocketAddr>{
+        None
+    }
+    //已知的NAT画像，设置后不再测
+    fn nat_profile(&self)->Option<NatProfile>{
+        None
+    }
+    //STUN服务器，打洞时用来测本机反射地址，按顺序尝试
+    fn stun_servers(&self)->Vec<SocketAddr>{
+        vec![]
+    }
+    //各p2p会话的状态写到这里
+    fn sessions(&self)->P2pSessions{
+        P2pSessions::default()
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct DiyTcpListener{
     pub list:Vec<P2pCell>,
+    pub nat_server:Option<SocketAddr>,
+    pub nat_profile:Option<NatProfile>,
+    pub stun_servers:Vec<SocketAddr>,
+    pub sessions:P2pSessions,
 }
 
-impl P2PListener<u16,P2pCell> for DiyTcpListener{
-    fn new_listener(&self,key:u16)->Result<P2pCell, RwebError> {
-        if let Some(cell) = self.list.iter().find(|cell| cell.port == key){
-            Ok(cell.clone())
-        }else{
-            Err(RwebError::new(54,"port not found".to_string()))
-        }
+//key为list里的序号，unix socket的项没有端口
+impl P2PListener<usize,P2pCell> for DiyTcpListener{
+    fn new_listener(&self,key:usize)->Result<P2pCell, RwebError> {
+        self.list.get(key).cloned().ok_or(RwebError::new(54,"cell not found".to_string()))
+    }
+
+    fn list(&self)->Vec<usize> {
+        (0..self.list.len()).collect()
+    }
+
+    fn nat_server(&self)->Option<SocketAddr> {
+        self.nat_server
+    }
+
+    fn nat_profile(&self)->Option<NatProfile> {
+        self.nat_profile
+    }
+
+    fn stun_servers(&self)->Vec<SocketAddr> {
+        self.stun_servers.clone()
     }
 
-    fn list(&self)->Vec<u16> {
-        self.list.iter().map(|cell| cell.port).collect()
+    fn sessions(&self)->P2pSessions {
+        self.sessions.clone()
     }
 }
 
-pub async fn p2p_connect<K: PartialEq + Clone,L: P2pListen + 'static, D: P2PListener<K,L>>(endpoint:Endpoint,connection:Connection,listeners:D)->Result<(),RwebError>{    
-    #[cfg(feature="log")]
-    println!("p2p client connect");
+pub async fn p2p_connect<K: PartialEq + Clone,L: P2pListen + 'static, D: P2PListener<K,L>>(endpoint:NodeEndpoint,connection:Connection,listeners:D,nat:Option<NatProfile>)->Result<(),RwebError>{    
+    log::debug!("p2p client connect");
     let mut p2p_threads = vec![];
     for p in listeners.list(){
         if let Ok(l) = listeners.new_listener(p){
             let endpoint = endpoint.clone();
             let connection = connection.clone();
-            p2p_threads.push(Box::pin(p2p_cell(l, endpoint, connection)));
+            p2p_threads.push(Box::pin(p2p_cell(l, endpoint, connection, nat, listeners.sessions())));
         }
     }
+    if p2p_threads.is_empty(){//只被动接受打洞
+        return futures::future::pending().await;
+    }
     let (result, _index, _remaining) = futures::future::select_all(p2p_threads).await;
     match result{
         Ok(_) => {
-            #[cfg(feature="log")]
-            println!("p2p client connect success");
+            log::info!("p2p client connected");
         },
         Err(e) => {
-            #[cfg(feature="log")]
-            println!("p2p client connect timeout");
+            log::warn!("p2p client connect timeout");
             return Err(RwebError::new(55,e))
         }
     }
     result
 }
 
-async fn p2p_cell<L:P2pListen>(l:L,endpoint:Endpoint,connection:Connection)->Result<(),RwebError>{
+//本机一直监听，打洞成功时走p2p直连，直连不可用时经rwebs中转
+async fn p2p_cell<L:P2pListen>(l:L,endpoint:NodeEndpoint,connection:Connection,nat:Option<NatProfile>,sessions:P2pSessions)->Result<(),RwebError>{
     let mac = l.mac();
-    #[cfg(feature="log")]
-    println!("p2p client listen mac:{}",mac);
+    log::info!(label:% = mac; "p2p client listen");
+    let listener = l.listen().await.map_err(|e|RwebError{code:-19,msg:e.to_string()})?;
+    let session = Arc::new(Session::new(mac,sessions));
+    let streams = endpoint.streams.clone();
+    select! {
+        r = p2p_keep(&l, endpoint, connection.clone(), &session, nat)=>r,
+        r = async {
+            loop{
+                let accept_stream = listener.accept().await?;
+                let session = session.clone();
+                let connection = connection.clone();
+                let active = streams.open();
+                tokio::spawn(async move{
+                    let _active = active;
+                    p2p_stream_cell(accept_stream, mac, &session, connection).await.unwrap_or_default();
+                });
+            }
+        }=>r
+    }
+}
+
+//维持到对端的直连，断开或打洞失败后退避重试，重试期间走中转
+async fn p2p_keep<L:P2pListen>(l:&L,endpoint:NodeEndpoint,connection:Connection,session:&Session,nat:Option<NatProfile>)->Result<(),RwebError>{
+    let mac = l.mac();
+    let mut backoff = P2P_RETRY_MIN_SECS;
+    loop{
+        session.update(|s|{
+            s.state = P2pState::Punching;
+            s.punches += 1;
+        });
+        let punched = p2p_punch(mac, endpoint.clone(), &connection, nat).await.and_then(|(r,report)|{
+            log::info!(report:% = report; "punch finished");
+            l.punch_report(&report);
+            session.update(|s|s.last_report = Some(report));
+            r
+        });
+        match punched{
+            Ok(p2p_conn)=>{
+                backoff = P2P_RETRY_MIN_SECS;
+                session.set_direct(Some(p2p_conn.clone()));
+                let reason = p2p_monitor(&p2p_conn, session).await;
+                log::warn!(label:% = mac, reason:% = reason; "p2p closed, use relay");
+                p2p_conn.close(VarInt::from_u32(0), b"repunch");
+                session.set_direct(None);
+            },
+            Err(e)=>{
+                log::warn!(label:% = mac, code:% = e.code, error:% = e.msg; "p2p punch failed, use relay");
+                //与rwebs的连接断开时由run_diy_stream退出，这里不用处理
+                session.set_direct(None);
+                session.update(|s|s.failures += 1);
+            }
+        }
+        sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(P2P_RETRY_SECS);
+    }
+}
+
+//直连的健康检查：定时记录rtt，连接关闭(quic保活超时)或转发时打不开流就结束
+async fn p2p_monitor(conn:&Connection,session:&Session)->String{
+    loop{
+        select!{
+            reason = conn.closed()=>return reason.to_string(),
+            _ = session.broken()=>return "open stream failed".to_string(),
+            _ = sleep(Duration::from_secs(P2P_HEALTH_SECS))=>session.update(|s|s.rtt = Some(conn.rtt())),
+        }
+    }
+}
+
+//经rwebs交换地址后按双方NAT画像打洞，外层错误表示没能交换地址，内层为打洞结果和报告
+async fn p2p_punch(mac:Mac,endpoint:NodeEndpoint,connection:&Connection,nat:Option<NatProfile>)->Result<(Result<Connection,RwebError>,PunchReport),RwebError>{
     let (mut send_stream,mut recv_stream) = connection.open_bi().await.map_err(|e|RwebError{code:-19,msg:e.to_string()})?;
-    #[cfg(feature="p2ptest")]
-    let test_addr = p2ptest(endpoint.clone()).await.ok();
-    #[cfg(not(feature="p2ptest"))]
-    let test_addr = None;
-    write_header(Header::new_p2p(mac,SocketAddr::from(([0,0,0,0],0)),test_addr), &mut send_stream).await.map_err(|e|RwebError{code:-20,msg:e.to_string()})?;
-    #[cfg(feature="log")]
-    println!("p2p client send header mac:{}",mac);
+    let test_addr = crate::stun::last_reflexive(&endpoint);//rwebs优先把这个地址告诉对端，用后台刷新的缓存不在这里等STUN
+    let mut header = Header::new_p2p(mac,SocketAddr::from(([0,0,0,0],0)),test_addr);
+    if let Some(nat) = nat{
+        nat.write_header(&mut header);
+    }
+    write_header(header, &mut send_stream).await.map_err(|e|RwebError{code:-20,msg:e.to_string()})?;
+    log::debug!(label:% = mac; "p2p request sent");
     let header =  get_header(& mut recv_stream).await.map_err(|e|RwebError{code:-21,msg:e.to_string()})?;
     let (_mac,addr,_self_addr) = header.parse_p2p().map_err(|e|RwebError{code:-22,msg:e.to_string()})?;
-    let mut port_len = 1;//如果一个端口都不给，会报错
-    #[cfg(all(feature="p2ptest",feature="log"))]
-    if let Some(test_addr) = test_addr{
-        if let Some(self_addr) = _self_addr{
-            if self_addr != test_addr{
-                println!("当前节点处于受限锥形NAT网络(对称NAT),无法直接打洞,{} != {}",self_addr,test_addr);
-            }else{
-                println!("当前节点处于全锥形NAT网络,可以直接打洞,{} == {}",self_addr,test_addr);
-            }
-        }
+    if let (Some(test_addr),Some(self_addr)) = (test_addr,_self_addr) && self_addr != test_addr{
+        log::info!(stun:% = test_addr, rwebs:% = self_addr; "STUN测得地址与rwebs测得地址不同,NAT映射与目标有关");
     }
-    match header.get("Nat-Type").map(|s|s.as_str()){
-        Some("FullCone")=>{
-            #[cfg(feature="log")]
-            println!("对端处于全锥形NAT网络,可以直接打洞,{}",addr);
+    let symmetric = header.get("Nat-Type").is_some_and(|t|t == "Symmetric");//旧版对端没有NAT画像
+    let plan = PunchPlan::new(addr,NatProfile::from_header(&header),nat,symmetric);
+    log::info!(label:% = _mac, plan:? = plan; "p2p connect");
+    let extra = symmetric::extra_endpoints(&endpoint,plan.sockets);
+    let (conn,report) = symmetric::punch(&endpoint,&extra,plan,PunchBudget::default()).await;
+    let requester = header.get(P2P_REQUESTER).and_then(|r|r.parse::<Mac>().ok());
+    let conn = match (conn,header.get(P2P_TICKET),requester){
+        (Ok(conn),Some(ticket),Some(requester))=>present_ticket(conn, mac, requester, ticket).await,
+        (Ok(conn),_,_)=>{//旧版rwebs不发票据，对端不会处理这个直连，改走中转
+            conn.close(VarInt::from_u32(403), b"ticket");
+            Err(RwebError::new(60,"no p2p ticket from rwebs"))
         },
-        Some("Symmetric")=>{
-            port_len = crate::PORT_LEN;
-            #[cfg(feature="log")]
-            println!("对端处于对称NAT网络,无法直接打洞，尝试后延{}个端口,{}",port_len,addr);
-        },
-        _=>{}
-    }
-    #[cfg(feature="log")]
-    println!("p2p connect mac:{},addr:{}",_mac,addr);
-    for _ in 0..5{
-        match symmetric::symmetric_connect(endpoint.clone(),addr,port_len,Duration::from_secs(3)).await{
-            Ok(conn_result) => {
-                #[cfg(feature="log")]
-                println!("p2p connect success,addr:{}",addr);
-                let listener = l.listen().await.map_err(|e|RwebError{code:-19,msg:e.to_string()})?;
-                loop{
-                    if let Ok(accept_stream) = listener.accept().await{
-                        let p2p_conn = conn_result.clone();
-                        tokio::spawn(async move{
-                            p2p_stream_cell(accept_stream, mac, p2p_conn).await.unwrap_or_default();
-                        });
-                    }else{
-                        break
-                    }
-                }
+        (Err(e),_,_)=>Err(e)
+    };
+    Ok((conn,report))
+}
+
+//直连建立后先出示rwebs发的票据，对端验证通过才处理这个连接上的流
+async fn present_ticket(conn:Connection,mac:Mac,requester:Mac,ticket:&str)->Result<Connection,RwebError>{
+    let mut uni = conn.open_uni().await.map_err(|e|RwebError::new(57,e))?;
+    let header:Vec<u8> = Header::new_p2p_auth(mac,requester,ticket).into();
+    uni.write_all(&header).await.map_err(|e|RwebError::new(57,e))?;
+    uni.finish().map_err(|e|RwebError::new(57,e))?;
+    Ok(conn)
+}
+
+//优先走直连，直连打不开流时通知重新打洞并改走中转，对接入的连接透明
+async fn p2p_stream_cell(mut accept_stream:impl AsyncReadWrite,mac:Mac,session:&Session,connection:Connection)->Result<(),RwebError>{
+    if let Some(direct) = session.direct(){
+        match open_direct(&direct, mac).await{
+            Ok(mut p2p_stream)=>{
+                tokio::io::copy_bidirectional(&mut accept_stream, &mut p2p_stream).await.unwrap_or_default();
                 return Ok(())
             },
-            Err(_e) => {
-                #[cfg(feature="log")]
-                println!("p2p connect {} error:{:?}",addr,_e);
-            }
+            Err(_)=>session.mark_broken()//打不开流或写不进去说明直连已坏
         }
     }
-    Err(RwebError::new(56,"p2p connect timeout".to_string()))
+    let mut relay_stream = relay_stream(&connection, mac).await?;
+    tokio::io::copy_bidirectional(&mut accept_stream, &mut relay_stream).await.unwrap_or_default();
+    Ok(())
 }
 
-async fn p2p_stream_cell(mut accept_stream:impl AsyncReadWrite,mac:Mac,connection:Connection)->Result<(),RwebError>{
-    let p2p_stream = connection.open_bi().await.map_err(|e|RwebError::new(52,e))?;//这里出错的话直接退出。
-    let mut p2p_stream = stream_copy::Stream::new(p2p_stream,connection.remote_address());
-    p2p_stream.write_all(mac.as_ref()).await.unwrap_or_default();//在头部插入mac地址，所有主动向节点发送的bi流的第一个数据包都需要首先发送mac地址以便node得知使用哪条流来对接。
-    tokio::io::copy_bidirectional(&mut accept_stream, &mut p2p_stream).await.unwrap_or_default();
-    Ok(())
+async fn open_direct(direct:&Connection,mac:Mac)->Result<impl AsyncReadWrite,RwebError>{
+    let p2p_stream = direct.open_bi().await.map_err(|e|RwebError::new(51,e))?;
+    let mut p2p_stream = stream_copy::Stream::new(p2p_stream,direct.remote_address());
+    p2p_stream.write_all(mac.as_ref()).await.map_err(|e|RwebError::new(52,e))?;//在头部插入mac地址，所有主动向节点发送的bi流的第一个数据包都需要首先发送mac地址以便node得知使用哪条流来对接。
+    Ok(p2p_stream)
 }
 
-#[cfg(feature="p2ptest")]
-pub async fn p2ptest(endpoint:Endpoint)->Result<SocketAddr,RwebError>{
-    use std::net::ToSocketAddrs;
-    use common::io::header::read_addr;
-    use quinn::VarInt;
-    let test_server_addr = include_str!("../../p2ptest_addr.txt").to_socket_addrs().map_err(|e|RwebError::new(6031,e))?.next().ok_or(RwebError::new(5032,"addr error".to_string()))?;
-    let conn = endpoint.connect(test_server_addr, "reform").map_err(|e|RwebError::new(5032,e))?.await.map_err(|e|RwebError::new(5033,e))?;
-    let mut uni = conn.accept_uni().await.unwrap();
-    let addr = read_addr(&mut uni).await.unwrap();
-    conn.close(VarInt::from_u32(200), "Ok".as_bytes());
-    Ok(addr)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::sync::Arc;
+    use rweb_common::proxy_list::ProxyList;
+    use tokio::io::AsyncReadExt;
+    use tokio::time::timeout;
+    use crate::{quic_client::{make_endpoint, make_endpoint_with_socket, register, run_diy_stream_on, ProxyStringList}, testutil};
+
+    //两个节点经rwebs交换地址，在回环上完成quic直连，再经直连CONNECT到节点b网络内的服务
+    #[tokio::test]
+    async fn p2p_rendezvous(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = rwebs::quic_server::QuicServer::default();
+        let server_addr = SocketAddr::from(([127,0,0,1],testutil::start(&server)));
+        let web = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let web_addr = web.local_addr().unwrap();
+        tokio::spawn(async move{
+            while let Ok((mut s,_)) = web.accept().await{
+                let _ = get_header(&mut s).await;
+                s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await.unwrap_or_default();
+            }
+        });
+        let mac_a:Mac = "aabbccddee01".parse().unwrap();
+        let mac_b:Mac = "aabbccddee02".parse().unwrap();
+        let node_b = ProxyStringList::new(Arc::new(vec![ProxyList::new(mac_b,url::Url::parse("http://127.0.0.1:1").unwrap()).unwrap()]),server_addr);
+        testutil::run(server_addr,node_b);
+        testutil::registered(&server,&[mac_b]).await;
+        let endpoint = make_endpoint().unwrap();
+        let connection = register(&endpoint,server_addr,&[mac_a]).await.unwrap();
+        let nat = NatProfile{mapping:rweb_common::nat::NatBehavior::EndpointIndependent,filtering:rweb_common::nat::NatBehavior::EndpointIndependent,port_delta:0};
+        let mut direct = None;
+        for _ in 0..20{
+            match p2p_punch(mac_b,endpoint.clone(),&connection,Some(nat)).await{
+                Ok((conn,report))=>{
+                    assert_eq!((report.plan.kind,report.targets),(symmetric::PunchKind::Direct,1));
+                    assert_eq!(report.remote.is_some(),conn.is_ok());
+                    direct = conn.ok();
+                    break
+                },
+                Err(_)=>sleep(Duration::from_millis(200)).await
+            }
+        }
+        let direct = direct.expect("p2p punch failed");
+        assert_ne!(direct.remote_address(),server_addr);
+        let mut p2p_stream = stream_copy::Stream::new(direct.open_bi().await.unwrap(),direct.remote_address());
+        p2p_stream.write_all(mac_b.as_ref()).await.unwrap();
+        p2p_stream.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n",web_addr).as_bytes()).await.unwrap();
+        let resp = get_header(&mut p2p_stream).await.unwrap();
+        assert_eq!(resp.uri,"200");
+        p2p_stream.write_all(b"GET / HTTP/1.1\r\nHost: web\r\n\r\n").await.unwrap();
+        let mut body = String::new();
+        p2p_stream.read_to_string(&mut body).await.unwrap();
+        assert!(body.ends_with("ok"));
+    }
+
+    //两个节点分别在模拟NAT后面，检查哪些组合能打通
+    #[tokio::test(flavor = "multi_thread")]
+    async fn p2p_behind_nat(){
+        use p2ptest::nat_sim::{NatConfig, NatKind, NatSocket, PortAlloc};
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server_addr = SocketAddr::from(([127,0,0,1],testutil::start(&rwebs::quic_server::QuicServer::default())));
+        use NatKind::*;
+        const SEQ:PortAlloc = PortAlloc::Sequential(1);
+        let cases = [
+            ((FullCone,SEQ),(FullCone,SEQ),true),
+            ((Restricted,SEQ),(PortRestricted,SEQ),true),
+            ((PortRestricted,SEQ),(PortRestricted,SEQ),true),
+            ((Symmetric,SEQ),(PortRestricted,SEQ),true),//对端按步长预测
+            ((PortRestricted,SEQ),(Symmetric,SEQ),true),
+            ((Symmetric,PortAlloc::Random),(Symmetric,PortAlloc::Random),false),
+        ];
+        let run = |i:usize,((kind_a,alloc_a),(kind_b,alloc_b),expect):((NatKind,PortAlloc),(NatKind,PortAlloc),bool)|async move{
+            let config_a = NatConfig::new(kind_a,[127,0,1,i as u8 + 1].into()).with_alloc(alloc_a);
+            let config_b = NatConfig::new(kind_b,[127,0,2,i as u8 + 1].into()).with_alloc(alloc_b);
+            let mac_a:Mac = format!("aabbccdd01{:02x}",i).parse().unwrap();
+            let mac_b:Mac = format!("aabbccdd02{:02x}",i).parse().unwrap();
+            let node_b = ProxyStringList::new(Arc::new(vec![ProxyList::new(mac_b,url::Url::parse("http://127.0.0.1:1").unwrap()).unwrap()]),server_addr);
+            let nat_b = NatSocket::new(config_b);
+            let endpoint_b = make_endpoint_with_socket(nat_b.clone()).unwrap();
+            let p2p_b = DiyTcpListener{nat_profile:Some(config_b.profile()),..Default::default()};
+            tokio::spawn(run_diy_stream_on(endpoint_b,server_addr,node_b,Some(p2p_b)));
+            let endpoint_a = make_endpoint_with_socket(NatSocket::new(config_a)).unwrap();
+            let connection = register(&endpoint_a,server_addr,&[mac_a]).await.unwrap();
+            for _ in 0..20{//等待节点b上线
+                if let Ok((conn,report)) = p2p_punch(mac_b,endpoint_a.clone(),&connection,Some(config_a.profile())).await{
+                    //随机猜端口可能连到别的测试绑在0.0.0.0上的endpoint，只算连到b的NAT上的
+                    let connected = conn.is_ok_and(|c|nat_b.external_addrs().contains(&c.remote_address()));
+                    assert_eq!(connected,expect,"{:?} -> {:?}: {}",config_a,config_b,report);
+                    return;
+                }
+                sleep(Duration::from_millis(200)).await;
+            }
+            panic!("node b offline");
+        };
+        //打不通的组合会发起大量连接，放在最后跑，免得拖慢其他组合
+        let (connect,fail):(Vec<_>,Vec<_>) = cases.into_iter().enumerate().partition(|(_,case)|case.2);
+        futures::future::join_all(connect.into_iter().map(|(i,case)|run(i,case))).await;
+        futures::future::join_all(fail.into_iter().map(|(i,case)|run(i,case))).await;
+    }
+
+    //直连断开后回落到中转，线路恢复后重新打洞回到直连，会话状态可随时查询
+    #[tokio::test(flavor = "multi_thread")]
+    async fn p2p_session_repunch(){
+        use p2ptest::nat_sim::{NatConfig, NatKind, NatSocket};
+        use crate::p2p_session::{P2pSessions, P2pState};
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let mac_a:Mac = "aabbccdd0301".parse().unwrap();
+        let mac_b:Mac = "aabbccdd0302".parse().unwrap();
+        let server = rwebs::quic_server::QuicServer::default().with_relay(vec![rweb_common::relay_list::RelayRule{from:mac_a,to:mac_b}]);
+        let server_addr = SocketAddr::from(([127,0,0,1],testutil::start(&server)));
+        let web = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let web_addr = web.local_addr().unwrap();
+        tokio::spawn(async move{
+            while let Ok((mut s,_)) = web.accept().await{
+                let _ = get_header(&mut s).await;
+                s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await.unwrap_or_default();
+            }
+        });
+        let config_a = NatConfig::new(NatKind::PortRestricted,[127,0,3,1].into());
+        let config_b = NatConfig::new(NatKind::PortRestricted,[127,0,3,2].into());
+        let node_b = ProxyStringList::new(Arc::new(vec![ProxyList::new(mac_b,url::Url::parse("http://127.0.0.1:1").unwrap()).unwrap()]),server_addr);
+        let endpoint_b = make_endpoint_with_socket(NatSocket::new(config_b)).unwrap();
+        let p2p_b = DiyTcpListener{nat_profile:Some(config_b.profile()),..Default::default()};
+        tokio::spawn(run_diy_stream_on(endpoint_b,server_addr,node_b,Some(p2p_b)));
+        testutil::registered(&server,&[mac_b]).await;
+        let lport = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let sessions = P2pSessions::default();
+        let node_a = ProxyStringList::new(Arc::new(vec![ProxyList::new(mac_a,url::Url::parse("http://127.0.0.1:1").unwrap()).unwrap()]),server_addr);
+        let nat_a = NatSocket::new(config_a);
+        let endpoint_a = make_endpoint_with_socket(nat_a.clone()).unwrap();
+        let p2p_a = DiyTcpListener{list:vec![P2pCell::new(mac_b,lport)],nat_profile:Some(config_a.profile()),sessions:sessions.clone(),..Default::default()};
+        tokio::spawn(run_diy_stream_on(endpoint_a,server_addr,node_a,Some(p2p_a)));
+        let wait = |state:P2pState,secs:u64|{
+            let sessions = sessions.clone();
+            async move{
+                for _ in 0..secs * 10{
+                    if let Some(s) = sessions.get(&mac_b).filter(|s|s.state == state){
+                        return s;
+                    }
+                    sleep(Duration::from_millis(100)).await;
+                }
+                panic!("{:?} not reached:{:?}",state,sessions.get(&mac_b));
+            }
+        };
+        let fetch = ||async move{
+            let mut s = tokio::net::TcpStream::connect(("127.0.0.1",lport)).await.unwrap();
+            s.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n",web_addr).as_bytes()).await.unwrap();
+            assert_eq!(get_header(&mut s).await.unwrap().uri,"200");
+            s.write_all(b"GET / HTTP/1.1\r\nHost: web\r\n\r\n").await.unwrap();
+            let mut body = String::new();
+            s.read_to_string(&mut body).await.unwrap();
+            assert!(body.ends_with("ok"));
+        };
+        let direct = wait(P2pState::Direct,20).await;
+        assert!(direct.rtt.is_some() && direct.remote.is_some());
+        assert!(direct.last_report.as_ref().is_some_and(|r|r.remote == direct.remote));
+        fetch().await;
+        //切断a到b的线路，直连保活超时后改走中转
+        nat_a.block(config_b.external_ip,true);
+        let relay = wait(P2pState::Relay,40).await;
+        assert_eq!(relay.remote,None);
+        fetch().await;
+        nat_a.block(config_b.external_ip,false);
+        let again = wait(P2pState::Direct,40).await;
+        assert!(again.punches > direct.punches);
+        fetch().await;
+        assert_eq!(sessions.list().len(),1);
+    }
+
+    //直连必须出示rwebs发的票据：没有票据时连接被丢弃，票据或请求方错误时被关闭，票据只能用一次且只能访问目标设备，访问者不能经rwebs塞票据
+    #[tokio::test]
+    async fn p2p_ticket(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = rwebs::quic_server::QuicServer::default();
+        let server_addr = SocketAddr::from(([127,0,0,1],testutil::start(&server)));
+        let web = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let web_addr = web.local_addr().unwrap();
+        tokio::spawn(async move{
+            while let Ok((mut s,_)) = web.accept().await{
+                let _ = get_header(&mut s).await;
+                s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await.unwrap_or_default();
+            }
+        });
+        let mac_a:Mac = "aabbccdd0401".parse().unwrap();
+        let mac_b:Mac = "aabbccdd0402".parse().unwrap();
+        let mac_b2:Mac = "aabbccdd0403".parse().unwrap();
+        let list = [mac_b,mac_b2].map(|mac|ProxyList::new(mac,url::Url::parse("http://127.0.0.1:1").unwrap()).unwrap());
+        let node_b = ProxyStringList::new(Arc::new(list.to_vec()),server_addr);
+        let endpoint_b = make_endpoint().unwrap();
+        let addr_b = SocketAddr::from(([127,0,0,1],endpoint_b.local_addr().unwrap().port()));
+        tokio::spawn(run_diy_stream_on(endpoint_b,server_addr,node_b,Some(DiyTcpListener::default())));
+        let outsider = make_endpoint().unwrap();
+        let connect = ||async{
+            timeout(Duration::from_secs(2),outsider.connect(addr_b,"reform").unwrap()).await.ok().and_then(|c|c.ok())
+        };
+        testutil::registered(&server,&[mac_b,mac_b2]).await;
+        assert!(connect().await.is_none(),"unsolicited connection accepted");
+        //只做地址交换不打洞，拿到票据
+        let endpoint_a = make_endpoint().unwrap();
+        let connection = register(&endpoint_a,server_addr,&[mac_a]).await.unwrap();
+        let request = ||async{
+            let (mut send,mut recv) = connection.open_bi().await.unwrap();
+            write_header(Header::new_p2p(mac_b,SocketAddr::from(([0,0,0,0],0)),None),&mut send).await.unwrap();
+            let header = get_header(&mut recv).await.unwrap();
+            assert_eq!(header.get(P2P_REQUESTER),Some(&mac_a.to_string()));
+            header.get(P2P_TICKET).cloned().expect("no ticket")
+        };
+        let ticket = request().await;
+        let other = request().await;
+        assert_ne!(ticket,other);
+        let present_as = |ticket:String,requester:Mac|async move{
+            let conn = connect().await.expect("connection with pending ticket dropped");
+            present_ticket(conn,mac_b,requester,&ticket).await.unwrap()
+        };
+        let present = |ticket:String|present_as(ticket,mac_a);
+        let forged = present("00".repeat(16)).await;
+        assert!(matches!(forged.closed().await,quinn::ConnectionError::ApplicationClosed(c) if c.error_code == VarInt::from_u32(403)));
+        let stolen = present_as(ticket.clone(),mac_b2).await;//拿到票据但不是请求方
+        assert!(matches!(stolen.closed().await,quinn::ConnectionError::ApplicationClosed(c) if c.error_code == VarInt::from_u32(403)));
+        let direct = present(ticket.clone()).await;
+        let open = |direct:Connection,mac:Mac|{
+            async move{
+                let mut s = open_direct(&direct,mac).await.unwrap();
+                s.write_all(format!("CONNECT {} HTTP/1.1\r\n\r\n",web_addr).as_bytes()).await.unwrap();
+                get_header(&mut s).await.unwrap().uri
+            }
+        };
+        assert_eq!(open(direct.clone(),mac_b2).await,"400");//票据只给了mac_b
+        assert_eq!(open(direct,mac_b).await,"200");
+        let reused = present(ticket).await;//还有别的票据没用，能连上，但这张已经用掉
+        assert!(matches!(reused.closed().await,quinn::ConnectionError::ApplicationClosed(c) if c.error_code == VarInt::from_u32(403)));
+        assert_eq!(open(present(other).await,mac_b).await,"200");
+        assert!(connect().await.is_none(),"connection accepted after all tickets used");
+        //访问者经rwebs发P2P请求冒充rwebs的命令，rwebs直接拒绝，设备不会记下访问者给的票据
+        let front = testutil::web(&server).await;
+        let request = format!("P2P /p2p HTTP/1.1\r\nHost: {}.abc.com\r\nmac: {}\r\naddr: 127.0.0.1:9\r\n{}: {}\r\n\r\n",mac_b,mac_a,P2P_TICKET,"11".repeat(16));
+        let response = testutil::send(front,&request).await;
+        assert!(response.starts_with("HTTP/1.1 400"),"{}",response);
+        assert!(connect().await.is_none(),"connection accepted with a visitor's ticket");
+    }
+}
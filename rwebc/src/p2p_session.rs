@@ -0,0 +1,125 @@
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}};
+use futures::FutureExt;
+use quinn::Connection;
+use rweb_common::mac::Mac;
+use tokio::{sync::Notify, time::{Duration, Instant}};
+use crate::symmetric::PunchReport;
+
+//到某个设备的p2p会话状态
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum P2pState{
+    Punching,//正在打洞，期间走中转
+    Direct,//直连
+    Relay,//打洞失败或直连断开，等待重新打洞，期间走中转
+}
+
+#[derive(Debug,Clone)]
+pub struct P2pStatus{
+    pub mac:Mac,
+    pub state:P2pState,
+    pub remote:Option<SocketAddr>,//直连时对端地址
+    pub rtt:Option<Duration>,//直连的往返时间，定时更新
+    pub punches:u32,//打洞次数
+    pub failures:u32,//连续打洞失败次数
+    pub since:Instant,//进入当前状态的时间
+    pub last_report:Option<PunchReport>,
+}
+
+impl P2pStatus{
+    fn new(mac:Mac)->Self{
+        Self{mac,state:P2pState::Punching,remote:None,rtt:None,punches:0,failures:0,since:Instant::now(),last_report:None}
+    }
+}
+
+//所有p2p会话的状态，clone后共享同一份，交给P2PListener后即可随时查询
+#[derive(Debug,Clone,Default)]
+pub struct P2pSessions(Arc<Mutex<HashMap<Mac,P2pStatus>>>);
+
+impl P2pSessions{
+    pub fn get(&self,mac:&Mac)->Option<P2pStatus>{
+        self.0.lock().ok()?.get(mac).cloned()
+    }
+
+    pub fn list(&self)->Vec<P2pStatus>{
+        self.0.lock().map(|m|m.values().cloned().collect()).unwrap_or_default()
+    }
+
+    fn update(&self,mac:Mac,f:impl FnOnce(&mut P2pStatus)){
+        if let Ok(mut m) = self.0.lock(){
+            f(m.entry(mac).or_insert_with(||P2pStatus::new(mac)));
+        }
+    }
+}
+
+//单个会话，p2p_keep维护直连，转发时从这里取直连
+pub(crate) struct Session{
+    mac:Mac,
+    direct:Mutex<Option<Connection>>,
+    broken:Notify,//转发时直连打不开流，通知p2p_keep重新打洞
+    sessions:P2pSessions,
+}
+
+impl Session{
+    pub(crate) fn new(mac:Mac,sessions:P2pSessions)->Self{
+        sessions.update(mac,|_|{});
+        Self{mac,direct:Mutex::new(None),broken:Notify::new(),sessions}
+    }
+
+    pub(crate) fn direct(&self)->Option<Connection>{
+        self.direct.lock().ok()?.clone().filter(|c|c.close_reason().is_none())
+    }
+
+    pub(crate) fn set_direct(&self,conn:Option<Connection>){
+        let state = if conn.is_some(){P2pState::Direct}else{P2pState::Relay};
+        let remote = conn.as_ref().map(|c|rweb_common::socket::canonical(c.remote_address()));
+        let rtt = conn.as_ref().map(|c|c.rtt());
+        if let Ok(mut d) = self.direct.lock(){
+            *d = conn;
+        }
+        self.broken.notified().now_or_never();//换了直连，丢掉旧直连留下的通知
+        self.update(|s|{
+            s.state = state;
+            s.remote = remote;
+            s.rtt = rtt;
+            if state == P2pState::Direct{
+                s.failures = 0;
+            }
+        });
+    }
+
+    pub(crate) fn update(&self,f:impl FnOnce(&mut P2pStatus)){
+        self.sessions.update(self.mac,|s|{
+            let state = s.state;
+            f(s);
+            if s.state != state{
+                s.since = Instant::now();
+            }
+        });
+    }
+
+    //p2p_monitor不在等待时也会留下通知，不会丢
+    pub(crate) fn mark_broken(&self){
+        self.broken.notify_one();
+    }
+
+    pub(crate) async fn broken(&self){
+        self.broken.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use tokio::time::timeout;
+
+    //p2p_monitor没在等时标记的损坏也能收到，换了直连后旧的通知作废
+    #[tokio::test]
+    async fn broken_not_lost(){
+        let session = Session::new("aabbccdd3701".parse().unwrap(),P2pSessions::default());
+        session.mark_broken();
+        assert!(timeout(Duration::from_millis(100),session.broken()).await.is_ok());
+        session.mark_broken();
+        session.set_direct(None);
+        assert!(timeout(Duration::from_millis(100),session.broken()).await.is_err());
+    }
+}
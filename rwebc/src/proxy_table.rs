@@ -0,0 +1,179 @@
+use std::{path::PathBuf, sync::Arc};
+use rweb_common::{proxy_list::ProxyList, RwebError};
+use tokio::{sync::watch, time::{sleep, Duration}};
+
+//运行中可以整体替换的代理表，新的流按替换后的表找目标，已经建立的流不受影响
+//mac有变化时连着的rwebs会收到新的mac列表
+#[derive(Debug,Clone)]
+pub struct ProxyTable(Arc<watch::Sender<Arc<Vec<ProxyList>>>>);
+
+impl From<Arc<Vec<ProxyList>>> for ProxyTable{
+    fn from(list:Arc<Vec<ProxyList>>)->Self{
+        Self(Arc::new(watch::Sender::new(list)))
+    }
+}
+
+impl From<Vec<ProxyList>> for ProxyTable{
+    fn from(list:Vec<ProxyList>)->Self{
+        Arc::new(list).into()
+    }
+}
+
+impl ProxyTable{
+    //当前的表，拿到后不受之后替换的影响
+    pub fn get(&self)->Arc<Vec<ProxyList>>{
+        self.0.borrow().clone()
+    }
+
+    //字段是pub的，替换前再检查一遍目标，有一条不对就保留原来的表
+    pub fn set(&self,list:Vec<ProxyList>)->Result<(),RwebError>{
+        for item in list.iter(){
+            item.target()?;
+        }
+        self.0.send_replace(Arc::new(list));
+        Ok(())
+    }
+
+    pub fn subscribe(&self)->watch::Receiver<Arc<Vec<ProxyList>>>{
+        self.0.subscribe()
+    }
+
+    //从json文件读入并替换，格式同proxy_list.json，解析失败时保留原来的表
+    pub fn load(&self,path:&PathBuf)->Result<(),RwebError>{
+        let text = std::fs::read_to_string(path).map_err(|e|RwebError::new(5040,e))?;
+        self.set(serde_json::from_str(&text).map_err(|e|RwebError::new(5041,e))?)
+    }
+
+    //每隔interval检查一次文件，内容变了就重新读入；文件暂时读不到时不清空表
+    pub async fn watch_file(self,path:PathBuf,interval:Duration){
+        let mut last = std::fs::read_to_string(&path).ok();
+        loop{
+            sleep(interval).await;
+            let Ok(text) = std::fs::read_to_string(&path) else{
+                continue
+            };
+            if last.as_ref() == Some(&text){
+                continue;
+            }
+            match serde_json::from_str(&text).map_err(|e|RwebError::new(5041,e)).and_then(|list|self.set(list)){
+                Ok(())=>{
+                    log::info!(file:% = path.display(); "proxy list reloaded");
+                },
+                Err(e)=>{
+                    log::warn!(file:% = path.display(), error:% = e; "proxy list invalid");
+                }
+            }
+            last = Some(text);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use rweb_common::{mac::Mac, RwebError};
+    use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::UnixListener};
+    use std::net::SocketAddr;
+    use crate::{quic_client::ProxyStringList, testutil};
+
+    //读完请求头后先回自己的名字，之后原样回显
+    async fn web(name:&'static str)->url::Url{
+        let path = std::env::temp_dir().join(format!("rwebc-{}-{}.sock",std::process::id(),name));
+        std::fs::remove_file(&path).unwrap_or_default();
+        let listener = UnixListener::bind(&path).unwrap();
+        let url = url::Url::parse(&format!("unix://{}",path.display())).unwrap();
+        tokio::spawn(async move{
+            while let Ok((stream,_)) = listener.accept().await{
+                tokio::spawn(async move{
+                    let mut stream = BufReader::new(stream);
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).await.is_ok_and(|n|n > 2){
+                        line.clear();
+                    }
+                    stream.write_all(name.as_bytes()).await.unwrap_or_default();
+                    let (mut r,mut w) = tokio::io::split(stream);
+                    tokio::io::copy(&mut r,&mut w).await.unwrap_or_default();
+                });
+            }
+        });
+        url
+    }
+
+    //等rwebs收到新的mac列表，返回mac对应的目标名字
+    async fn request(server:&rwebs::quic_server::QuicServer,mac:Mac)->Result<(String,impl AsyncReadExt+AsyncWriteExt+Unpin),RwebError>{
+        let mut stream = server.open_stream(mac).await?;
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n").await.map_err(|e|RwebError::new(0,e))?;
+        let mut name = [0u8;4];
+        stream.read_exact(&mut name).await.map_err(|e|RwebError::new(0,e))?;
+        Ok((String::from_utf8_lossy(&name).to_string(),stream))
+    }
+
+    async fn wait_for(server:&rwebs::quic_server::QuicServer,mac:Mac,name:Option<&str>){
+        for _ in 0..50{
+            match request(server,mac).await{
+                Ok((n,_)) if Some(n.as_str()) == name=>return,
+                Err(_) if name.is_none()=>return,
+                _=>sleep(Duration::from_millis(100)).await
+            }
+        }
+        panic!("{} not {:?}",mac,name);
+    }
+
+    //替换代理表后新的流用新表，已有的流继续；mac增减经rwebs生效；改文件后自动重新读入
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn proxy_table_reload(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = rwebs::quic_server::QuicServer::default();
+        let server_addr = SocketAddr::from(([127,0,0,1],testutil::start(&server)));
+        let (web1,web2) = (web("web1").await,web("web2").await);
+        let mac_a:Mac = "aabbccdd0801".parse().unwrap();
+        let mac_b:Mac = "aabbccdd0802".parse().unwrap();
+        let table = ProxyTable::from(vec![ProxyList::new(mac_a,web1.clone()).unwrap()]);
+        let stats = crate::stats::Stats::default();
+        let diy_stream = ProxyStringList::from_table(table.clone(),"127.0.0.1:0".parse().unwrap()).with_stats(stats.clone());
+        testutil::run(server_addr,diy_stream);
+        wait_for(&server,mac_a,Some("web1")).await;
+        let (_,mut old) = request(&server,mac_a).await.unwrap();
+
+        let bad = url::Url::parse("tcp://192.168.2.12").unwrap();//没有端口
+        assert_eq!(ProxyList::new(mac_b,bad.clone()).err().map(|e|e.code),Some(5036));
+        assert_eq!(table.set(vec![ProxyList{mac:mac_b,url:bad}]).err().map(|e|e.code),Some(5036));
+        assert_eq!(table.get()[0].mac,mac_a);
+        table.set(vec![ProxyList::new(mac_b,web2.clone()).unwrap()]).unwrap();
+        wait_for(&server,mac_b,Some("web2")).await;
+        wait_for(&server,mac_a,None).await;
+        old.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8;4];
+        old.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo,b"ping");
+
+        let path = std::env::temp_dir().join(format!("rwebc-{}-proxy.json",std::process::id()));
+        std::fs::write(&path,format!(r#"[{{"mac":"{}","url":"{}"}}]"#,mac_b,web2)).unwrap();
+        tokio::spawn(table.clone().watch_file(path.clone(),Duration::from_millis(100)));
+        sleep(Duration::from_millis(300)).await;
+        std::fs::write(&path,format!(r#"[{{"mac":"{}","url":"{}"}},{{"mac":"{}","url":"{}"}}]"#,mac_a,web2,mac_b,web1)).unwrap();
+        wait_for(&server,mac_a,Some("web2")).await;
+        wait_for(&server,mac_b,Some("web1")).await;
+        std::fs::write(&path,"not json").unwrap();//解析失败保留原表
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(table.get().len(),2);
+        std::fs::remove_file(&path).unwrap_or_default();
+
+        //已在别的连接上在线的mac，rwebs不接受并告诉设备
+        let mac_c:Mac = "aabbccdd0803".parse().unwrap();
+        let other = ProxyStringList::new(std::sync::Arc::new(vec![ProxyList::new(mac_c,web1.clone()).unwrap()]),"127.0.0.1:0".parse().unwrap());
+        testutil::run(server_addr,other);
+        wait_for(&server,mac_c,Some("web1")).await;
+        assert!(stats.snapshot().rejected.is_empty());
+        table.set(vec![ProxyList::new(mac_b,web1.clone()).unwrap(),ProxyList::new(mac_c,web2.clone()).unwrap()]).unwrap();
+        for _ in 0..50{
+            if !stats.snapshot().rejected.is_empty(){
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(stats.snapshot().rejected,vec![mac_c.to_string()]);
+        wait_for(&server,mac_c,Some("web1")).await;//仍在原来的连接上
+    }
+}
@@ -1,24 +1,30 @@
-use std::{error::Error, net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs}, sync::Arc};
-use quinn::{ClientConfig, Connection, Endpoint, EndpointConfig, Incoming, ServerConfig, TransportConfig, VarInt};
+use std::{error::Error, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs}, sync::Arc};
+use quinn::{AsyncUdpSocket, ClientConfig, Connection, ConnectionError, Endpoint, EndpointConfig, ServerConfig, TransportConfig, VarInt};
 use rustls::pki_types::{pem::PemObject, CertificateDer, UnixTime};
-use tokio::{io::{AsyncRead, AsyncWrite, AsyncWriteExt}, net::TcpStream, select};
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncWriteExt}, net::{TcpListener, TcpStream}, select, sync::watch};
+use tokio::time::{sleep, timeout, Duration};
+//直连建立后等对端出示票据的时间
 #[cfg(feature="p2p")]
-use tokio::time::{sleep, Duration};
+const P2P_AUTH_SECS:u64 = 5;
 use url::Url;
+use serde::Deserialize;
 use tokio_rustls::TlsConnector;
 use rustls::{client::danger::{ServerCertVerified,ServerCertVerifier},pki_types::ServerName};
-use rweb_common::{io::{stream_copy::Stream, ResetHeader}, proxy_list::ProxyList, RwebError, mac::Mac};
+use rweb_common::{io::{header::{read_server_uni, ServerUni, Visitor, FEATURE_VISITOR, METHOD_RELAY, RWEB_CLIENT, RWEB_SESSION}, stream_copy::Stream, ResetHeader}, nat::NatProfile, proxy_list::{ProxyList, Target}, relay_list::RelayCell, reverse_list::ReverseCell, socket::{canonical, udp_socket}, RwebError, mac::Mac, get_header, Header};
 #[cfg(feature="p2p")]
-use rweb_common::{io::header::METHOD_P2P,p2p_list::P2pCell,Header};
+use rweb_common::{io::header::{METHOD_P2P, P2P_TICKET}, p2p_list::P2pCell};
 #[cfg(feature="p2p")]
-use super::{p2p_client::{DiyTcpListener, P2PListener, P2pListen,p2p_connect}, symmetric};
-#[cfg(all(feature="p2ptest",feature="p2p"))]
-use super::p2p_client::p2ptest;
-use super::AsyncReadWrite;
+use crate::ticket::{Ticket, Tickets};
+#[cfg(feature="p2p")]
+use {std::pin::Pin, quinn::Incoming};
+#[cfg(feature="p2p")]
+use super::{p2p_client::{DiyTcpListener, P2PListener, P2pListen,p2p_connect}, p2p_session::P2pSessions, symmetric::{self, PunchBudget, PunchPlan}};
+use super::{context::{Access, StreamContext, StreamError}, health::{report_health, HealthCheck}, proxy_table::ProxyTable, stats::{forward, Stats}, relay::listen_relay, servers, AsyncReadWrite};
+use futures::{stream::FuturesUnordered, StreamExt};
 
 use rweb_common::key::{CER_BIN, KEY_BIN};
 
-const IDLE_TIMEOUT_MILLIS:u32=21_000;
+pub(crate) const IDLE_TIMEOUT_MILLIS:u32=21_000;
 const KEEPALIVE_INTERVAL_MILLIS:u64=10_000;
 //const CER_BIN:&[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/../reform.cer"));
 //const KEY_BIN:&[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/../reform.key"));
@@ -34,21 +40,134 @@ static CONNECTOR:once_cell::sync::Lazy<TlsConnector> = once_cell::sync::Lazy::ne
 });
 
 pub trait DiyStream: Send + Sync + Unpin+ Clone + 'static {
-    fn new_diy_stream(&self, mac: Mac, proxy_addr:Option<SocketAddr>)->impl Future<Output = Result<impl AsyncReadWrite + Send, RwebError>> + Send;
-    fn mac_list(&self)->Vec<&Mac>;
+    //按ctx里的设备、请求头和访问者连接目标，也可以返回StreamError::Reject拒绝
+    fn new_diy_stream(&self, ctx:&StreamContext)->impl Future<Output = Result<impl AsyncReadWrite + Send, StreamError>> + Send;
+    fn mac_list(&self)->Vec<Mac>;
+    //mac_list会在运行中变化时返回所在的代理表，变化后把新的mac列表告诉rwebs
+    fn proxy_table(&self)->Option<&ProxyTable>{
+        None
+    }
+    //返回Some时定时探测代理表里的目标，状态报告给rwebs
+    fn health_check(&self)->Option<&HealthCheck>{
+        None
+    }
+    //返回Some时记录连接状态、流和流量
+    fn stats(&self)->Option<&Stats>{
+        None
+    }
+    //反向转发列表，默认不开启
+    fn reverse_list(&self)->&[ReverseCell]{
+        &[]
+    }
+    //只走rwebs中转的设备到设备列表，默认不开启
+    fn relay_list(&self)->&[RelayCell]{
+        &[]
+    }
+    //new_diy_stream按每个请求选择目标或拒绝时返回true，转给目标的http/1.x请求改成Connection: close，一个流只有一个请求
+    //默认false，keep-alive连接上后面的请求不经过new_diy_stream
+    fn per_request(&self)->bool{
+        false
+    }
+}
+
+//校验rwebs证书用的CA和证书里的名字，默认为内置证书
+#[derive(Debug,Clone)]
+pub struct Trust{
+    ca:Vec<CertificateDer<'static>>,
+    server_name:String,
+}
+
+impl Default for Trust{
+    fn default() -> Self {
+        Self{ca:vec![CertificateDer::from_pem_slice(CER_BIN.as_bytes()).unwrap()],server_name:"reform".to_string()}
+    }
+}
+
+impl Trust{
+    //pem里可以有多个CA，rwebs的证书由其中任一个签发即可
+    pub fn from_pem(pem:&[u8],server_name:&str)->Result<Self,RwebError>{
+        let ca = CertificateDer::pem_slice_iter(pem).collect::<Result<Vec<_>,_>>().map_err(|e|RwebError::new(-43,e))?;
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in ca.iter(){
+            roots.add(cert.clone()).map_err(|e|RwebError::new(-43,e))?;
+        }
+        if roots.is_empty(){
+            return Err(RwebError::new(-43,"no certificate in pem"));
+        }
+        ServerName::try_from(server_name).map_err(|e|RwebError::new(-44,e))?;
+        Ok(Self{ca,server_name:server_name.to_string()})
+    }
+}
+
+//连rwebs的quic参数，默认由rwebs保活
+#[derive(Debug,Clone,Copy)]
+pub struct Tuning{
+    pub idle_timeout:Duration,//与rwebs的取小的
+    pub keep_alive:Option<Duration>,
+    pub max_bidi_streams:u32,
+    pub max_uni_streams:u32,
+}
+
+impl Default for Tuning{
+    fn default() -> Self {
+        Self{idle_timeout:Duration::from_millis(IDLE_TIMEOUT_MILLIS as u64),keep_alive:None,max_bidi_streams:10000,max_uni_streams:1000}
+    }
+}
+
+impl Tuning{
+    //quinn不接受的空闲超时不能悄悄变成不超时；保活间隔要小于空闲超时(为0时不超时)
+    pub fn check(&self)->Result<(),RwebError>{
+        quinn::IdleTimeout::try_from(self.idle_timeout).map_err(|e|RwebError::new(-47,format!("idle_timeout {:?}:{}",self.idle_timeout,e)))?;
+        if self.keep_alive.is_some_and(|k|k.is_zero() || (!self.idle_timeout.is_zero() && k >= self.idle_timeout)){
+            return Err(RwebError::new(-47,format!("keep_alive {:?} not less than idle_timeout {:?}",self.keep_alive,self.idle_timeout)));
+        }
+        Ok(())
+    }
+}
+
+//连rwebs用的客户端配置和证书里的服务器名
+#[derive(Clone)]
+pub(crate) struct Dialer{
+    config:ClientConfig,
+    server_name:String,
+}
+
+impl Dialer{
+    pub(crate) fn new(trust:&Trust,tuning:&Tuning)->Self{
+        Self{config:configure_host_client(&trust.ca,tuning),server_name:trust.server_name.clone()}
+    }
 }
 
-fn configure_host_client(cert_der:&[u8]) -> ClientConfig {
+static DIALER:once_cell::sync::Lazy<Dialer> = once_cell::sync::Lazy::new(||Dialer::new(&Trust::default(),&Tuning::default()));
+
+//不带ALPN，rwebs开启h3时也按不带ALPN的设备接受
+fn configure_host_client(ca:&[CertificateDer<'static>],tuning:&Tuning) -> ClientConfig {
     let mut certs = rustls::RootCertStore::empty();
-    certs.add(rustls::pki_types::CertificateDer::from_pem_slice(cert_der).unwrap()).unwrap();
+    for cert in ca{
+        certs.add(cert.clone()).unwrap();
+    }
     let mut client_config = ClientConfig::with_root_certificates(Arc::new(certs)).unwrap();
     let mut transport_config = TransportConfig::default();
     transport_config
-        //.keep_alive_interval(Some(std::time::Duration::from_millis(KEEPALIVE_INTERVAL_MILLIS)))
+        .keep_alive_interval(tuning.keep_alive)
+        .max_idle_timeout(quinn::IdleTimeout::try_from(tuning.idle_timeout).ok())
+        .max_concurrent_bidi_streams(tuning.max_bidi_streams.into())
+        .max_concurrent_uni_streams(tuning.max_uni_streams.into());
+    client_config.transport_config(std::sync::Arc::new(transport_config));  
+    client_config
+}
+
+//p2p直连用的客户端配置，两端之间没有服务器保活，需要自己保活以维持NAT映射
+#[cfg(feature="p2p")]
+pub(crate) fn configure_p2p_client() -> ClientConfig {
+    let mut client_config = configure_host_client(&Trust::default().ca,&Tuning::default());
+    let mut transport_config = TransportConfig::default();
+    transport_config
+        .keep_alive_interval(Some(std::time::Duration::from_millis(KEEPALIVE_INTERVAL_MILLIS)))
         .max_idle_timeout(Some(quinn::IdleTimeout::from(quinn::VarInt::from_u32(IDLE_TIMEOUT_MILLIS))))
         .max_concurrent_bidi_streams(10000_u16.into())
         .max_concurrent_uni_streams(1000_u16.into());
-    client_config.transport_config(std::sync::Arc::new(transport_config));  
+    client_config.transport_config(std::sync::Arc::new(transport_config));
     client_config
 }
 
@@ -66,225 +185,601 @@ fn configure_host_server(cert_der:&[u8],priv_key:&[u8]) -> Result<ServerConfig,
 
 #[cfg(feature="p2p")]
 pub async fn run_diy_stream<K: PartialEq + Clone + 'static,L: P2pListen + 'static>(server_host:&str,server_port:u16,diy_stream:impl DiyStream,p_client:Option<impl P2PListener<K,L>>)->Result<(),RwebError>{
-    let server_addr = (server_host, server_port).to_socket_addrs().map_err(|e|RwebError{code:-10,msg:e.to_string()})?.next().ok_or("can't resolve").map_err(|e|RwebError{code:-11,msg:e.to_string()})?;
-    let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-    let socket = std::net::UdpSocket::bind(bind_addr).map_err(|e|RwebError{code:-10,msg:e.to_string()})?;
-    let server_config = configure_host_server(CER_BIN, KEY_BIN).map_err(|e|RwebError{code:-11,msg:e.to_string()})?;
-    let mut endpoint = Endpoint::new(EndpointConfig::default(), Some(server_config), socket, Arc::new(quinn::TokioRuntime)).map_err(|e|RwebError{code:-12,msg:e.to_string()})?;
-    endpoint.set_default_client_config(configure_host_client(CER_BIN));//客户端服务端共用一个socket
-    let conn = endpoint.connect(server_addr, "reform").map_err(|e|RwebError{code:-13,msg:e.to_string()})?;
-    let connection = conn.await.map_err(|e|RwebError{code:-14,msg:e.to_string()})?;
-    let mut uni_stream = connection.open_uni().await.map_err(|e|RwebError{code:-15,msg:e.to_string()})?;
+    let endpoint = make_endpoint()?;
+    let connection = connect_host(&endpoint, server_host, server_port).await?;
     let mac_list = diy_stream.mac_list();
-    uni_stream.write_u16(mac_list.len() as u16).await.map_err(|e|RwebError{code:-17,msg:e.to_string()})?;
-    for v in mac_list.iter(){
-        uni_stream.write_all(v.as_ref()).await.map_err(|e|RwebError{code:-18,msg:e.to_string()})?;
-    }
-    #[cfg(all(feature="p2ptest",feature="log"))]
-    if let Ok(addr) = p2ptest(endpoint.clone()).await{
-        println!("测试服务器检测此节点地址为:{}",addr);
+    announce(&connection, &mac_list).await?;
+    serve(endpoint, connection, mac_list, diy_stream, p_client).await
+}
+
+//使用给定的endpoint，例如make_endpoint_with_socket建的模拟NAT后面的endpoint
+#[cfg(feature="p2p")]
+pub async fn run_diy_stream_on<K: PartialEq + Clone + 'static,L: P2pListen + 'static>(endpoint:NodeEndpoint,server_addr:SocketAddr,diy_stream:impl DiyStream,p_client:Option<impl P2PListener<K,L>>)->Result<(),RwebError>{
+    let mac_list = diy_stream.mac_list();
+    let connection = register(&endpoint, server_addr, &mac_list).await?;
+    serve(endpoint, connection, mac_list, diy_stream, p_client).await
+}
+
+//已经注册到rwebs的连接上运行各项服务，直到连接断开，announced为注册时发的mac列表
+#[cfg(feature="p2p")]
+pub(crate) async fn serve<K: PartialEq + Clone + 'static,L: P2pListen + 'static>(endpoint:NodeEndpoint,connection:Connection,announced:Vec<Mac>,diy_stream:impl DiyStream,p_client:Option<impl P2PListener<K,L>>)->Result<(),RwebError>{
+    if let Some(p) = p_client.as_ref(){
+        crate::stun::set_servers(&endpoint, p.stun_servers());
     }
-    uni_stream.finish().unwrap_or_default();
-    drop(uni_stream);
+    let nat = match p_client.as_ref().map(|p|(p.nat_profile(),p.nat_server())){
+        Some((Some(nat),_))=>Some(nat),
+        Some((None,Some(nat_server)))=>crate::nat::classify(&endpoint, nat_server).await.ok(),
+        _=>None
+    };
+    let tickets = Tickets::default();
+    let features = ServerFeatures::default();
+    let _connected = diy_stream.stats().map(|s|s.connected(&connection));
     let res = if let Some(p_li) = p_client{
+        let endpoint_stun = endpoint.clone();
         select! {
-            a = listen_incoming(endpoint.clone(), diy_stream.clone())=>a,//监听p2p对端数据
-            b = listen_bi(endpoint.clone(), connection.clone(), diy_stream)=>b,//监听服务器命令
-            c = p2p_connect(endpoint, connection, p_li) =>c
+            a = listen_incoming(endpoint.clone(), diy_stream.clone(), tickets.clone())=>a,//监听p2p对端数据
+            b = listen_bi(endpoint.clone(), connection.clone(), diy_stream.clone(), nat, features.clone(), tickets)=>b,//监听服务器命令
+            c = p2p_connect(endpoint.clone(), connection.clone(), p_li, nat) =>c,
+            d = listen_reverse(connection.clone(), diy_stream.reverse_list().to_vec(), endpoint.streams.clone())=>d,//反向转发
+            e = listen_relay(connection.clone(), diy_stream.relay_list().to_vec(), endpoint.streams.clone())=>e,//中转
+            f = watch_mac_list(connection.clone(), announced, diy_stream.clone())=>f,
+            g = listen_server_uni(connection.clone(), features, diy_stream.clone())=>g,
+            h = report_health(connection, diy_stream)=>h,//目标探测
+            i = crate::stun::refresh(endpoint_stun)=>i,//定时刷新缓存的reflexive地址
         }
     }else{
         select! {
-            a = listen_incoming(endpoint.clone(), diy_stream.clone())=>a,//监听p2p对端数据
-            b = listen_bi(endpoint.clone(), connection, diy_stream)=>b,//监听服务器命令
+            a = listen_incoming(endpoint.clone(), diy_stream.clone(), tickets.clone())=>a,//监听p2p对端数据
+            b = listen_bi(endpoint.clone(), connection.clone(), diy_stream.clone(), nat, features.clone(), tickets)=>b,//监听服务器命令
+            c = listen_reverse(connection.clone(), diy_stream.reverse_list().to_vec(), endpoint.streams.clone())=>c,//反向转发
+            d = listen_relay(connection.clone(), diy_stream.relay_list().to_vec(), endpoint.streams.clone())=>d,//中转
+            e = watch_mac_list(connection.clone(), announced, diy_stream.clone())=>e,
+            f = listen_server_uni(connection.clone(), features, diy_stream.clone())=>f,
+            g = report_health(connection, diy_stream)=>g,//目标探测
         }
     };
-    #[cfg(feature="log")]
-    println!("{:?}",res);
+    log::info!(result:? = res; "serve ended");
     res
 }
 
 #[cfg(not(feature="p2p"))]
 pub async fn run_diy_stream(server_host:&str,server_port:u16,diy_stream:impl DiyStream)->Result<(),RwebError>{
-    let server_addr = (server_host, server_port).to_socket_addrs().map_err(|e|RwebError{code:-10,msg:e.to_string()})?.next().ok_or("can't resolve").map_err(|e|RwebError{code:-11,msg:e.to_string()})?;
-    let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-    let socket = std::net::UdpSocket::bind(bind_addr).map_err(|e|RwebError{code:-10,msg:e.to_string()})?;
+    let endpoint = make_endpoint()?;
+    let connection = connect_host(&endpoint, server_host, server_port).await?;
+    let mac_list = diy_stream.mac_list();
+    announce(&connection, &mac_list).await?;
+    serve(endpoint, connection, mac_list, diy_stream).await
+}
+
+//使用给定的endpoint，例如make_endpoint_with_socket建的endpoint
+#[cfg(not(feature="p2p"))]
+pub async fn run_diy_stream_on(endpoint:NodeEndpoint,server_addr:SocketAddr,diy_stream:impl DiyStream)->Result<(),RwebError>{
+    let mac_list = diy_stream.mac_list();
+    let connection = register(&endpoint, server_addr, &mac_list).await?;
+    serve(endpoint, connection, mac_list, diy_stream).await
+}
+
+#[cfg(not(feature="p2p"))]
+pub(crate) async fn serve(endpoint:NodeEndpoint,connection:Connection,announced:Vec<Mac>,diy_stream:impl DiyStream)->Result<(),RwebError>{
+    let features = ServerFeatures::default();
+    let _connected = diy_stream.stats().map(|s|s.connected(&connection));
+    let res = 
+    select! {
+        a = listen_incoming(endpoint.clone())=>a,//没有p2p时不接受任何连接
+        b = listen_bi(endpoint.clone(), connection.clone(), diy_stream.clone(), None, features.clone())=>b,//监听服务器命令
+        c = listen_reverse(connection.clone(), diy_stream.reverse_list().to_vec(), endpoint.streams.clone())=>c,//反向转发
+        d = listen_relay(connection.clone(), diy_stream.relay_list().to_vec(), endpoint.streams.clone())=>d,//中转
+        e = watch_mac_list(connection.clone(), announced, diy_stream.clone())=>e,
+        f = listen_server_uni(connection.clone(), features, diy_stream.clone())=>f,
+        g = report_health(connection, diy_stream)=>g,//目标探测
+    };
+    log::info!(result:? = res; "serve ended");
+    res
+}
+
+//quinn的Endpoint和包在它socket外面的StunSocket，STUN请求从quic用的同一个socket发出
+//make_endpoint建的endpoint都是这个，按Endpoint使用
+#[derive(Debug,Clone)]
+pub struct NodeEndpoint{
+    endpoint:Endpoint,
+    #[cfg(feature="p2p")]
+    pub(crate) stun:Arc<crate::stun::StunSocket>,
+    pub(crate) streams:ActiveStreams,
+}
+
+//在这个endpoint上处理中的流，rwebs转来的、直连进来的、反向转发、中转和p2p的都算，下线时等它归零
+#[derive(Debug,Clone)]
+pub(crate) struct ActiveStreams(Arc<watch::Sender<u64>>);
+
+impl Default for ActiveStreams{
+    fn default()->Self{
+        Self(Arc::new(watch::Sender::new(0)))
+    }
+}
+
+impl ActiveStreams{
+    //返回的guard释放时流结束
+    pub(crate) fn open(&self)->ActiveGuard{
+        self.0.send_modify(|n|*n += 1);
+        ActiveGuard(self.clone())
+    }
+
+    pub(crate) fn subscribe(&self)->watch::Receiver<u64>{
+        self.0.subscribe()
+    }
+}
+
+pub(crate) struct ActiveGuard(ActiveStreams);
+
+impl Drop for ActiveGuard{
+    fn drop(&mut self){
+        self.0.0.send_modify(|n|*n = n.saturating_sub(1));
+    }
+}
+
+impl std::ops::Deref for NodeEndpoint{
+    type Target = Endpoint;
+    fn deref(&self)->&Endpoint{
+        &self.endpoint
+    }
+}
+
+//客户端服务端共用一个socket，p2p时对端直接连到这个endpoint
+//优先绑[::]双栈，ipv4和ipv6的rwebs、p2p对端都能连；系统没有ipv6时绑0.0.0.0，ipv6地址直接连接失败
+pub(crate) fn make_endpoint()->Result<NodeEndpoint,RwebError>{
+    let socket = udp_socket(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0))
+        .or_else(|_|udp_socket(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)))
+        .map_err(|e|RwebError{code:-10,msg:e.to_string()})?;
+    let socket = quinn::Runtime::wrap_udp_socket(&quinn::TokioRuntime, socket).map_err(|e|RwebError{code:-10,msg:e.to_string()})?;
+    make_endpoint_with_socket(socket)
+}
+
+//使用自定义的udp socket，例如p2ptest::nat_sim::NatSocket
+pub fn make_endpoint_with_socket(socket:Arc<dyn AsyncUdpSocket>)->Result<NodeEndpoint,RwebError>{
+    #[cfg(feature="p2p")]
+    let stun = crate::stun::StunSocket::wrap(socket);//STUN和quic共用socket
+    #[cfg(feature="p2p")]
+    let socket = stun.clone();
     let server_config = configure_host_server(CER_BIN.as_bytes(), KEY_BIN.as_bytes()).map_err(|e|RwebError{code:-11,msg:e.to_string()})?;
-    let mut endpoint = Endpoint::new(EndpointConfig::default(), Some(server_config), socket, Arc::new(quinn::TokioRuntime)).map_err(|e|RwebError{code:-12,msg:e.to_string()})?;
-    endpoint.set_default_client_config(configure_host_client(CER_BIN.as_bytes()));//客户端服务端共用一个socket
-    let conn = endpoint.connect(server_addr, "reform").map_err(|e|RwebError{code:-13,msg:e.to_string()})?;
-    let connection = conn.await.map_err(|e|RwebError{code:-14,msg:e.to_string()})?;
+    let mut endpoint = Endpoint::new_with_abstract_socket(EndpointConfig::default(), Some(server_config), socket, Arc::new(quinn::TokioRuntime)).map_err(|e|RwebError{code:-12,msg:e.to_string()})?;
+    endpoint.set_default_client_config(DIALER.config.clone());
+    Ok(NodeEndpoint{endpoint,#[cfg(feature="p2p")] stun,streams:ActiveStreams::default()})
+}
+
+//代理表变化后mac有增减时，在新的uni流上把mac列表再发给rwebs，announced为注册时发的列表
+//连上到开始监听之间表可能已经变了，与announced不同时先发一次
+async fn watch_mac_list(connection:Connection,mut announced:Vec<Mac>,diy_stream:impl DiyStream)->Result<(),RwebError>{
+    let Some(mut changes) = diy_stream.proxy_table().map(|t|t.subscribe()) else{
+        return std::future::pending().await
+    };
+    loop{
+        let mac_list = diy_stream.mac_list();
+        if mac_list != announced{
+            announce(&connection, &mac_list).await?;
+            log::info!(labels:? = mac_list; "mac list announced");
+            if let Some(stats) = diy_stream.stats(){
+                stats.rejected(vec![]);
+            }
+            announced = mac_list;
+        }
+        if changes.changed().await.is_err(){
+            return std::future::pending().await;
+        }
+    }
+}
+
+//rwebs打开的uni流：没有接受的mac(已在别的连接上在线)记警告日志和stats，功能标志记到features
+async fn listen_server_uni(connection:Connection,features:ServerFeatures,diy_stream:impl DiyStream)->Result<(),RwebError>{
+    loop{
+        let mut uni = connection.accept_uni().await.map_err(connection_error)?;//与listen_bi一样按关闭原因给错误码
+        match timeout(Duration::from_secs(5), read_server_uni(&mut uni)).await{
+            Ok(Ok(ServerUni::Rejected(rejected)))=>{
+                log::warn!(labels:? = rejected; "mac already online on another connection");
+                if let Some(stats) = diy_stream.stats(){
+                    stats.rejected(rejected);
+                }
+            },
+            Ok(Ok(ServerUni::Features(flags)))=>features.set(flags),
+            _=>log::debug!("unknown uni stream from rwebs"),
+        }
+    }
+}
+
+//rwebs注册后发来的功能标志，旧版rwebs不发，收到前为None
+#[derive(Debug,Clone)]
+pub(crate) struct ServerFeatures(Arc<watch::Sender<Option<u32>>>);
+
+impl Default for ServerFeatures{
+    fn default()->Self{
+        Self(Arc::new(watch::Sender::new(None)))
+    }
+}
+
+impl ServerFeatures{
+    fn set(&self,flags:u32){
+        self.0.send_replace(Some(flags));
+    }
+
+    //rwebs是否写上了真实的访问者；uni流可能比请求晚到，最多等1秒，等不到当作旧版rwebs
+    async fn visitor(&self)->bool{
+        let mut features = self.0.subscribe();
+        if timeout(Duration::from_secs(1), features.wait_for(|f|f.is_some())).await.is_err(){
+            self.0.send_if_modified(|f|if f.is_none(){ *f = Some(0); true }else{ false });
+        }
+        self.0.borrow().is_some_and(|f|f & FEATURE_VISITOR != 0)
+    }
+}
+
+//连接rwebs并在uni流上注册mac列表
+pub(crate) async fn register(endpoint:&Endpoint,server_addr:SocketAddr,mac_list:&[Mac])->Result<Connection,RwebError>{
+    let connection = connect_server(endpoint, &DIALER, server_addr).await?;
+    announce(&connection, mac_list).await?;
+    Ok(connection)
+}
+
+//在uni流上注册mac列表
+pub(crate) async fn announce(connection:&Connection,mac_list:&[Mac])->Result<(),RwebError>{
     let mut uni_stream = connection.open_uni().await.map_err(|e|RwebError{code:-15,msg:e.to_string()})?;
-    let mac_list = diy_stream.mac_list();
     uni_stream.write_u16(mac_list.len() as u16).await.map_err(|e|RwebError{code:-17,msg:e.to_string()})?;
     for v in mac_list.iter(){
         uni_stream.write_all(v.as_ref()).await.map_err(|e|RwebError{code:-18,msg:e.to_string()})?;
     }
     uni_stream.finish().unwrap_or_default();
-    drop(uni_stream);
-    let res = 
-    select! {
-        a = listen_incoming(endpoint.clone(), diy_stream.clone())=>a,//监听p2p对端数据
-        b = listen_bi(endpoint.clone(), connection, diy_stream)=>b,//监听服务器命令
-    };
-    #[cfg(feature="log")]
-    println!("{:?}",res);
-    res
+    Ok(())
+}
+
+//解析出server_host的全部地址后按happy eyeballs连接
+async fn connect_host(endpoint:&Endpoint,server_host:&str,server_port:u16)->Result<Connection,RwebError>{
+    let attempts:Vec<_> = servers::resolve(server_host, server_port).await?.into_iter().map(|a|(a,servers::ATTEMPT_DELAY)).collect();
+    Ok(connect_any(endpoint, &DIALER, &attempts, |_|{}).await?.0)
+}
+
+//按顺序开始连接，前面的还没有结果时等给定的时间再开始下一个，失败时马上开始下一个，返回先连上的和它的序号
+pub(crate) async fn connect_any(endpoint:&Endpoint,dialer:&Dialer,attempts:&[(SocketAddr,Duration)],on_attempt:impl Fn(SocketAddr))->Result<(Connection,usize),RwebError>{
+    let mut pending = FuturesUnordered::new();
+    let mut next = 0;
+    let mut last_err = RwebError{code:ERR_CONNECT,msg:"no server address".to_string()};
+    loop{
+        if let Some(&(addr,_)) = attempts.get(next){
+            on_attempt(addr);
+            let i = next;
+            pending.push(async move{(i,connect_server(endpoint, dialer, addr).await)});
+            next += 1;
+        }else if pending.is_empty(){
+            return Err(last_err);
+        }
+        let wait = attempts.get(next).map(|a|a.1);
+        select! {
+            Some((i,r)) = pending.next()=>match r{
+                Ok(connection)=>return Ok((connection,i)),
+                Err(e)=>last_err = e,
+            },
+            _ = sleep(wait.unwrap_or_default()), if wait.is_some()=>{}
+        }
+    }
+}
+
+async fn connect_server(endpoint:&Endpoint,dialer:&Dialer,server_addr:SocketAddr)->Result<Connection,RwebError>{
+    let conn = endpoint.connect_with(dialer.config.clone(),server_addr, &dialer.server_name).map_err(|e|RwebError{code:ERR_CONNECT,msg:e.to_string()})?;
+    conn.await.map_err(|e|RwebError{code:ERR_HANDSHAKE,msg:e.to_string()})
+}
+
+//node_run_with和cso的quic_node_run_options的附加设置，json里没有的项用默认值
+#[derive(Debug,Clone,Default,Deserialize)]
+#[serde(default)]
+pub struct NodeOptions{
+    pub reverse_list:Vec<ReverseCell>,//反向转发，rwebs上要有对应的--reverse-file配置
+    #[cfg(feature="p2p")]
+    #[serde(skip)]
+    pub sessions:P2pSessions,//p2p_list的会话状态，传入clone后可随时查询
+    #[cfg(feature="p2p")]
+    pub stun_servers:Vec<SocketAddr>,//查询本节点reflexive地址的STUN服务器，为空时不查
 }
 
 #[cfg(feature="p2p")]
 pub async fn node_run(server_host:&str,server_port:u16,proxy_list:Vec<ProxyList>,p2p_list:Option<Vec<P2pCell>>)->Result<(),RwebError>{
-    let server_addr = (server_host, server_port).to_socket_addrs().map_err(|e|RwebError{code:-10,msg:e.to_string()})?.next().ok_or("can't resolve").map_err(|e|RwebError{code:-11,msg:e.to_string()})?;
-    let diy_stream = ProxyStringList::new(Arc::new(proxy_list),server_addr);
-    run_diy_stream(server_host,server_port,diy_stream, p2p_list.map(|list|DiyTcpListener{list})).await
+    node_run_with(server_host,server_port,proxy_list,p2p_list,NodeOptions::default()).await
+}
+
+#[cfg(feature="p2p")]
+pub async fn node_run_with(server_host:&str,server_port:u16,proxy_list:Vec<ProxyList>,p2p_list:Option<Vec<P2pCell>>,options:NodeOptions)->Result<(),RwebError>{
+    let endpoint = make_endpoint()?;
+    let connection = connect_host(&endpoint, server_host, server_port).await?;
+    let diy_stream = ProxyStringList::new(Arc::new(proxy_list),connection.remote_address()).with_reverse(options.reverse_list);
+    let mac_list = diy_stream.mac_list();
+    announce(&connection, &mac_list).await?;
+    serve(endpoint, connection, mac_list, diy_stream, p2p_list.map(|list|DiyTcpListener{list,sessions:options.sessions,stun_servers:options.stun_servers,..Default::default()})).await
 }
 
 #[cfg(not(feature="p2p"))]
 pub async fn node_run(server_host:&str,server_port:u16,proxy_list:Vec<ProxyList>)->Result<(),RwebError>{
-    let server_addr = (server_host, server_port).to_socket_addrs().map_err(|e|RwebError{code:-10,msg:e.to_string()})?.next().ok_or("can't resolve").map_err(|e|RwebError{code:-11,msg:e.to_string()})?;
-    let diy_stream = ProxyStringList::new(Arc::new(proxy_list),server_addr);
-    run_diy_stream(server_host,server_port,diy_stream).await
+    node_run_with(server_host,server_port,proxy_list,NodeOptions::default()).await
 }
 
-async fn listen_incoming(endpoint:Endpoint, diy_stream:impl DiyStream)->Result<(),RwebError>{
-    #[cfg(feature="log")]
-    println!("listen incomng");
-    loop{
-        match endpoint.accept().await{
-            Some(incoming) => {
-                let diy_stream = diy_stream.clone();
-                let endpoint = endpoint.clone();
-                tokio::spawn(async move {
-                    #[cfg(feature="log")]
-                    println!("accept incoming stream from {}",incoming.remote_address());
-                    handle_incoming_bi(endpoint, incoming, diy_stream).await.unwrap_or_else(|_e| {
-                        #[cfg(feature="log")]
-                        println!("handle_incomming error:{}", _e);
-                    });
-                });
+#[cfg(not(feature="p2p"))]
+pub async fn node_run_with(server_host:&str,server_port:u16,proxy_list:Vec<ProxyList>,options:NodeOptions)->Result<(),RwebError>{
+    let endpoint = make_endpoint()?;
+    let connection = connect_host(&endpoint, server_host, server_port).await?;
+    let diy_stream = ProxyStringList::new(Arc::new(proxy_list),connection.remote_address()).with_reverse(options.reverse_list);
+    let mac_list = diy_stream.mac_list();
+    announce(&connection, &mac_list).await?;
+    serve(endpoint, connection, mac_list, diy_stream).await
+}
+
+//只接受rwebs发过票据之后连进来的直连，未经请求的连接直接丢弃
+#[cfg(feature="p2p")]
+async fn listen_incoming(endpoint:NodeEndpoint, diy_stream:impl DiyStream, tickets:Tickets)->Result<(),RwebError>{
+    log::debug!("listen incoming");
+    while let Some(incoming) = endpoint.accept().await{
+        if !tickets.pending(){
+            log::debug!(remote:% = incoming.remote_address(); "drop unsolicited incoming");
+            incoming.ignore();
+            continue;
+        }
+        let diy_stream = diy_stream.clone();
+        let endpoint = endpoint.clone();
+        let tickets = tickets.clone();
+        tokio::spawn(async move {
+            log::debug!(remote:% = incoming.remote_address(); "accept incoming");
+            handle_incoming_bi(endpoint, incoming, diy_stream, tickets).await.unwrap_or_else(|e| {
+                log::debug!(error:% = e; "handle incoming failed");
+            });
+        });
+    }
+    Err(RwebError{code:-22,msg:"endpoint closed".to_string()})
+}
+
+#[cfg(not(feature="p2p"))]
+async fn listen_incoming(endpoint:NodeEndpoint)->Result<(),RwebError>{
+    while let Some(incoming) = endpoint.accept().await{
+        incoming.ignore();
+    }
+    Err(RwebError{code:-22,msg:"endpoint closed".to_string()})
+}
+
+//p2p对端的直连会复用同一个连接打开多条流，连接建立后对端先出示票据，只能访问票据里的设备
+//打洞时额外socket上的连接也从handle_bi里交给这里处理，返回装箱的future以免async递归
+#[cfg(feature="p2p")]
+fn handle_incoming_bi(endpoint:NodeEndpoint, incoming:Incoming, diy_stream:impl DiyStream, tickets:Tickets)->Pin<Box<dyn Future<Output = Result<(),RwebError>> + Send>>{
+    Box::pin(async move{
+        let incoming = incoming.await.map_err(|e|RwebError{code:-23,msg:e.to_string()})?;
+        let ticket = match timeout(Duration::from_secs(P2P_AUTH_SECS), p2p_auth(&incoming, &tickets)).await{
+            Ok(Ok(ticket))=>ticket,
+            Ok(Err(e))=>{
+                incoming.close(VarInt::from_u32(403), b"ticket");
+                return Err(e)
             },
-            None => {
-                
+            Err(_)=>{
+                incoming.close(VarInt::from_u32(403), b"ticket");
+                return Err(RwebError{code:-25,msg:"p2p auth timeout".to_string()})
             }
+        };
+        log::info!(requester:% = ticket.requester, label:% = ticket.target, remote:% = incoming.remote_address(); "p2p accepted");
+        loop{
+            let bi_stream = incoming.accept_bi().await.map_err(|e|RwebError{code:-21,msg:e.to_string()})?;
+            let endpoint = endpoint.clone();
+            let diy_stream = diy_stream.clone();
+            let tickets = tickets.clone();
+            let remote_addr = incoming.remote_address();
+            tokio::spawn(async move {
+                if let Err(e) = handle_bi(endpoint, remote_addr, bi_stream, diy_stream, None, None, tickets, Some(ticket.target)).await{
+                    log::debug!(error:% = e; "handle incoming stream failed");
+                }
+            });
         }
-    }
+    })
 }
 
-async fn handle_incoming_bi(endpoint:Endpoint, incoming:Incoming, diy_stream:impl DiyStream)->Result<(),RwebError>{
-    let incoming = incoming.await.map_err(|e|RwebError{code:-23,msg:e.to_string()})?;
-    let bi_stream = incoming.accept_bi().await.map_err(|e|RwebError{code:-21,msg:e.to_string()})?;
-    handle_bi(endpoint, incoming.remote_address(), bi_stream, diy_stream).await.map_err(|e|RwebError{code:-22,msg:e.to_string()})?;
-    Ok(())
+//读对端在uni流上出示的票据
+#[cfg(feature="p2p")]
+async fn p2p_auth(conn:&Connection,tickets:&Tickets)->Result<Ticket,RwebError>{
+    let mut uni = conn.accept_uni().await.map_err(|e|RwebError{code:-24,msg:e.to_string()})?;
+    let header = get_header(&mut uni).await?;
+    let (mac,requester,ticket) = header.parse_p2p_auth()?;
+    tickets.take(&ticket, mac, requester).ok_or(RwebError{code:-25,msg:"invalid ticket".to_string()})
 }
 
 //endpoint为quic对象
 //connection为与服务器的连接
 //diy_stream为将要代理的流集合。
-async fn listen_bi(endpoint:Endpoint, connection:Connection,diy_stream:impl DiyStream)->Result<(),RwebError>{
-    #[cfg(feature="log")]
-    println!("listen bi");
+//nat为本节点的NAT画像，p2p时告诉对端
+async fn listen_bi(endpoint:NodeEndpoint, connection:Connection,diy_stream:impl DiyStream,nat:Option<NatProfile>,features:ServerFeatures,#[cfg(feature="p2p")] tickets:Tickets)->Result<(),RwebError>{
+    log::debug!("listen bi");
     loop{
         match connection.accept_bi().await{
             Ok(bi_stream) => {
-                #[cfg(feature="log")]
-                println!("145 accept bi stream from {}",connection.remote_address());
+                log::trace!(remote:% = connection.remote_address(); "accept bi");
                 let diy_stream = diy_stream.clone();
                 let endpoint = endpoint.clone();
                 let remote_addr = connection.remote_address();
+                let features = features.clone();
+                #[cfg(feature="p2p")]
+                let tickets = tickets.clone();
                 tokio::spawn(async move {
-                    handle_bi(endpoint, remote_addr, bi_stream, diy_stream).await.unwrap_or_else(|_e| {
-                        #[cfg(feature="log")]
-                        println!("handle_bi error:{}", _e);
+                    handle_bi(endpoint, remote_addr, bi_stream, diy_stream, nat, Some(features), #[cfg(feature="p2p")] tickets, #[cfg(feature="p2p")] None).await.unwrap_or_else(|e| {
+                        log::debug!(error:% = e; "handle bi failed");
                     });
                 });
             },
             Err(e) => {
-                #[cfg(feature="log")]
-                println!("159 accept bi stream error:{}", e);
-                return Err(RwebError{code:-20,msg:e.to_string()});
+                log::warn!(remote:% = connection.remote_address(), error:% = e; "accept bi failed");
+                return Err(connection_error(e));
+            }
+        }
+    }
+}
+
+//与rwebs的连接断开的原因，重连时按原因区别对待
+pub const ERR_CONNECT:i32 = -13;//没有地址或发起连接失败
+pub const ERR_HANDSHAKE:i32 = -14;//quic握手失败
+pub const ERR_DUPLICATE_MAC:i32 = -26;//rwebs以401关闭，mac已经在线
+pub const ERR_IDLE_TIMEOUT:i32 = -27;
+pub const ERR_FAILBACK:i32 = -28;//优先级更高的服务器恢复了，主动断开换过去
+pub const ERR_SHUTDOWN:i32 = -29;//应用要求停止
+
+fn connection_error(e:ConnectionError)->RwebError{
+    match &e{
+        ConnectionError::ApplicationClosed(close) if close.error_code == VarInt::from_u32(401)=>RwebError{code:ERR_DUPLICATE_MAC,msg:e.to_string()},
+        ConnectionError::TimedOut=>RwebError{code:ERR_IDLE_TIMEOUT,msg:e.to_string()},
+        _=>RwebError{code:-20,msg:e.to_string()}
+    }
+}
+
+//反向转发：在设备网络监听，接入的连接经quic连接转到rwebs上配置的服务
+async fn listen_reverse(connection:Connection,reverse_list:Vec<ReverseCell>,streams:ActiveStreams)->Result<(),RwebError>{
+    if reverse_list.is_empty(){
+        return std::future::pending().await;
+    }
+    let mut accepts = Vec::with_capacity(reverse_list.len());
+    for cell in reverse_list{
+        let listener = TcpListener::bind(cell.listen).await.map_err(|e|RwebError{code:-40,msg:e.to_string()})?;
+        accepts.push(accept_reverse(listener, connection.clone(), cell, streams.clone()));
+    }
+    futures::future::try_join_all(accepts).await?;
+    Ok(())
+}
+
+async fn accept_reverse(listener:TcpListener,connection:Connection,cell:ReverseCell,streams:ActiveStreams)->Result<(),RwebError>{
+    loop{
+        let (tcp_stream,addr) = listener.accept().await.map_err(|e|RwebError{code:-41,msg:e.to_string()})?;
+        log::info!(name:% = cell.name, remote:% = addr; "reverse accepted");
+        let connection = connection.clone();
+        let cell = cell.clone();
+        let active = streams.open();
+        tokio::spawn(async move {
+            let _active = active;
+            if let Err(e) = handle_reverse(connection, tcp_stream, &cell).await{
+                log::warn!(error:% = e; "reverse failed");
+            }
+        });
+    }
+}
+
+async fn handle_reverse(connection:Connection,mut tcp_stream:TcpStream,cell:&ReverseCell)->Result<(),Box<dyn Error+Send+Sync>>{
+    let bi_stream = connection.open_bi().await?;
+    let mut quic_stream = Stream::new(bi_stream,connection.remote_address());
+    let header:Vec<u8> = Header::new_reverse(cell.mac,&cell.name).into();
+    quic_stream.write_all(&header).await?;
+    let resp = get_header(&mut quic_stream).await?;
+    if !resp.uri.starts_with('2'){//状态行解析后uri字段为状态码
+        return Err(RwebError::new(-42,format!("reverse {} refused:{}",cell.name,resp.uri)).into());
+    }
+    tokio::io::copy_bidirectional(&mut quic_stream, &mut tcp_stream).await?;
+    Ok(())
+}
+
+//DiyStream拒绝时按给的状态码回应访问者并正常结束这个流，其他错误记下错误码后断开
+async fn refuse<S:AsyncWrite+Unpin>(stream:&mut S,e:StreamError,header:&Header,stats:Option<&Stats>)->Result<(),Box<dyn Error+Send+Sync>>{
+    match e{
+        StreamError::Reject{status,reason}=>{
+            log::info!(status:% = status, reason:% = reason, uri:% = header.uri; "stream rejected");
+            stream.write_all(StreamError::response(status,&reason,&header.version).as_bytes()).await?;
+            Ok(())
+        },
+        StreamError::Error(e)=>{
+            if let Some(stats) = stats{
+                stats.failed(e.code);
             }
+            Err(e.into())
         }
     }
 }
 
-async fn handle_bi<S: AsyncWrite + Unpin + Send, R: AsyncRead + Unpin + Send>(_endpoint:Endpoint,bi_remote_addr:SocketAddr, bi_stream:(S,R),diy_stream:impl DiyStream)->Result<(),Box<dyn Error+Send+Sync>>{
+//features为rwebs连接上收到的功能标志，直连进来的流为None；tickets为rwebs发给本机的票据
+//direct为直连时票据里的设备，直连只能访问这个设备，直连和中转来的流都不能发p2p命令，也都没有访问者
+#[allow(clippy::too_many_arguments)]
+async fn handle_bi<S: AsyncWrite + Unpin + Send, R: AsyncRead + Unpin + Send>(_endpoint:NodeEndpoint,bi_remote_addr:SocketAddr, bi_stream:(S,R),diy_stream:impl DiyStream,_nat:Option<NatProfile>,features:Option<ServerFeatures>,#[cfg(feature="p2p")] tickets:Tickets,#[cfg(feature="p2p")] direct:Option<Mac>)->Result<(),Box<dyn Error+Send+Sync>>{
     let mut quic_stream = Stream::new(bi_stream,bi_remote_addr);
     if let Ok(mac) = quic_stream.read_mac().await{
-        #[cfg(feature="log")]
-        println!("handle_bi mac:{}",mac);
-        if !diy_stream.mac_list().contains(&&mac){
-            #[cfg(feature="log")]
-            println!("handle_bi mac not in list:{}",mac);
+        log::debug!(label:% = mac, remote:% = bi_remote_addr; "stream");
+        #[cfg(feature="p2p")]
+        let in_list = diy_stream.mac_list().contains(&mac) && direct.is_none_or(|d|d == mac);
+        #[cfg(not(feature="p2p"))]
+        let in_list = diy_stream.mac_list().contains(&mac);
+        if !in_list{
+            log::warn!(label:% = mac, remote:% = bi_remote_addr; "stream rejected, not in list");
             quic_stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\
                Content-Type: text/plain; charset=utf-8\r\n\
                Content-Length: 23\r\n\r\n\
                Error: Bad Request").await?;
             return Ok(());
         }
+        let _active = _endpoint.streams.open();
+        let stats = diy_stream.stats().cloned();
+        let _stream = stats.as_ref().map(|s|s.open_stream(mac));
+        #[cfg(feature="p2p")]
+        let p2p = direct.is_some();
+        #[cfg(not(feature="p2p"))]
+        let p2p = false;
+        //rwebs转来的中转流先有说明请求方设备的RELAY头，之后是请求方的原始内容，其中的访问者头不可信
+        let relay = match quic_stream.peek_header().await{
+            Ok(header) if !p2p && header.method == METHOD_RELAY=>{
+                quic_stream.peek_remove();
+                Some(header.parse_relay_from()?)
+            },
+            _=>None
+        };
         match quic_stream.peek_header().await{
             Ok(mut header) => {
-                #[cfg(feature="log")]
-                println!("header: {:?}", header);
+                log::trace!(label:% = mac, header:? = header; "stream");
+                //rwebs加的，不转给目标；只相信声明了FEATURE_VISITOR的rwebs直接转来的，旧版rwebs会原样转发访问者自己带的
+                let visitor = Visitor::take(&mut header).filter(|_|relay.is_none() && !p2p);
+                let visitor = match (visitor,&features){
+                    (Some(v),Some(f)) if f.visitor().await=>Some(v),
+                    _=>None
+                };
+                quic_stream.peek_strip(&[RWEB_CLIENT,RWEB_SESSION]);
+                let context = |access,header:&Header,proxy_addr|StreamContext{header:Some(header.clone()),proxy_addr,client_ip:visitor.map(|v|v.ip),session:visitor.map(|v|v.session),p2p,relay,..StreamContext::new(mac,access)};
                 match header.method.as_str(){
                     "CONNECT"=>{
                         quic_stream.peek_remove();
                         let url_addr = header.uri.to_socket_addrs()?.next().ok_or("can't resolve")?;
-                        let mut stream = diy_stream.new_diy_stream(mac,Some(url_addr)).await?;
+                        let ctx = context(Access::Connect,&header,Some(url_addr));
+                        let mut stream = match diy_stream.new_diy_stream(&ctx).await{
+                            Ok(stream)=>stream,
+                            Err(e)=>return refuse(&mut quic_stream,e,&header,stats.as_ref()).await
+                        };
                         quic_stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
                         quic_stream.flush().await?;
-                        tokio::io::copy_bidirectional(&mut quic_stream, &mut stream).await?;
+                        forward(stats.as_ref(), header.uri.clone(), &mut quic_stream, &mut stream).await?;
                     },
                     #[cfg(feature="p2p")]
-                    METHOD_P2P=>{
+                    METHOD_P2P if direct.is_none() && relay.is_none()=>{
                         quic_stream.peek_remove();
                         let (_mac,addr,_self_addr) = header.parse_p2p()?;
-                        #[cfg(feature="p2ptest")]
-                        let test_addr = p2ptest(_endpoint.clone()).await.ok();
-                        #[cfg(not(feature="p2ptest"))]
-                        let test_addr = None;
-                        #[cfg(all(feature="p2ptest",feature="log"))]
-                        if let Some(test_addr) = test_addr{
-                            if let Some(self_addr) = _self_addr{
-                                if self_addr != test_addr{
-                                    println!("当前节点正处于受限锥形NAT网络,无法直接打洞,{} != {}",self_addr,test_addr);
-                                }else{
-                                    println!("当前节点正处于全锥形NAT网络,可以直接打洞,{} != {}",self_addr,test_addr);
-                                }
-                            }
+                        if let Some(ticket) = header.get(P2P_TICKET){//先记下票据，对端打洞成功后可能马上连进来
+                            tickets.insert(ticket.clone(), mac, _mac);
                         }
-                        #[cfg(feature="log")]
-                        println!("p2p command {},{}",_mac,addr);
-                        let mut port_len = 1;//如果一个端口都不给，会报错
-                        match header.get("Nat-Type").map(|s|s.as_str()){
-                            Some("FullCone")=>{
-                                #[cfg(feature="log")]
-                                println!("对端处于全锥形NAT网络,可以直接打洞,{}",addr);
-                            },
-                            Some("Symmetric")=>{
-                                port_len = crate::PORT_LEN;
-                                #[cfg(feature="log")]
-                                println!("对端处于对称NAT网络,无法直接打洞，尝试后延{}个端口,{}",port_len,addr);
-                            },
-                            _=>{}
+                        let test_addr = crate::stun::last_reflexive(&_endpoint);//缓存的地址，不在这里等STUN
+                        if let (Some(test_addr),Some(self_addr)) = (test_addr,_self_addr) && self_addr != test_addr{
+                            log::info!(stun:% = test_addr, rwebs:% = self_addr; "STUN测得地址与rwebs测得地址不同,NAT映射与目标有关");
+                        }
+                        log::info!(label:% = _mac, peer:% = addr; "p2p command");
+                        let symmetric = header.get("Nat-Type").is_some_and(|t|t == "Symmetric");//旧版对端没有NAT画像
+                        let plan = PunchPlan::new(addr,NatProfile::from_header(&header),_nat,symmetric);
+                        let mut header = Header::new_p2p(_mac,addr,test_addr);
+                        if let Some(nat) = _nat{
+                            nat.write_header(&mut header);
                         }
-                        let header = Header::new_p2p(_mac,addr,test_addr);
                         let v:Vec<u8> = header.into();
                         quic_stream.write_all(&v).await?;
-                        for _i in 0..10{//这里连接只是向对方发送一下udp包使对方的NAT进行标记以便打洞，并不是真的要连接对方
-                            match symmetric::symmetric_connect(_endpoint.clone(),addr,port_len,Duration::from_secs(1)).await{
-                                Ok(conn)=>{
-                                    #[cfg(feature="log")]
-                                    println!("p2p connect success {}",addr);
-                                    conn.close(VarInt::from_u32(0), b"Ok");
-                                    break
-                                },
-                                Err(_e)=>{
-                                    #[cfg(feature="log")]
-                                    if _i==9{
-                                        println!("p2p connect {} error:{:?}",addr,_e);
-                                    }
+                        //这里连接只是向对方发送一下udp包使对方的NAT进行标记以便打洞，并不是真的要连接对方
+                        //额外的socket上对端可能连进来，连进来的连接和主socket上的一样处理
+                        let budget = PunchBudget{cell_timeout:Duration::from_secs(1),deadline:Duration::from_secs(10),..Default::default()};
+                        let extra = symmetric::extra_endpoints(&_endpoint,plan.sockets);
+                        for endpoint in extra.iter().cloned(){
+                            let diy_stream = diy_stream.clone();
+                            let tickets = tickets.clone();
+                            tokio::spawn(async move{
+                                if let Ok(Some(incoming)) = timeout(budget.deadline * 2, endpoint.accept()).await{
+                                    handle_incoming_bi(endpoint, incoming, diy_stream, tickets).await.unwrap_or_default();
                                 }
-                            }
-                            sleep(Duration::from_millis(100)).await;
+                            });
+                        }
+                        let (conn,report) = symmetric::punch(&_endpoint,&extra,plan,budget).await;
+                        log::info!(report:% = report; "punch finished");
+                        if let Ok(conn) = conn{
+                            conn.close(VarInt::from_u32(0), b"Ok");
                         }
                     },
                     _=>{
@@ -303,20 +798,32 @@ async fn handle_bi<S: AsyncWrite + Unpin + Send, R: AsyncRead + Unpin + Send>(_e
                             if header.uri.is_empty(){
                                 header.uri = "/".to_string();
                             }
-                            quic_stream.reset_header(header);
                             let proxy_addr = proxy_addr.to_socket_addrs()?.next().ok_or("can't resolve")?;
-                            let mut stream = diy_stream.new_diy_stream(mac,Some(proxy_addr)).await?;
-                            tokio::io::copy_bidirectional(&mut quic_stream, &mut stream).await?;
+                            let ctx = context(Access::HttpProxy,&header,Some(proxy_addr));
+                            let mut stream = match diy_stream.new_diy_stream(&ctx).await{
+                                Ok(stream)=>stream,
+                                Err(e)=>return refuse(&mut quic_stream,e,&header,stats.as_ref()).await
+                            };
+                            quic_stream.reset_header(header);
+                            forward(stats.as_ref(), proxy_addr.to_string(), &mut quic_stream, &mut stream).await?;
                         }else{
-                            let mut stream = diy_stream.new_diy_stream(mac,None).await?;
-                            tokio::io::copy_bidirectional(&mut quic_stream, &mut stream).await?;
+                            let ctx = context(Access::Target,&header,None);
+                            let mut stream = match diy_stream.new_diy_stream(&ctx).await{
+                                Ok(stream)=>stream,
+                                Err(e)=>return refuse(&mut quic_stream,e,&header,stats.as_ref()).await
+                            };
+                            //new_diy_stream只看到第一个请求，按请求选择目标时http/1.x让目标回应完就关闭，访问者后面的请求在新连接上再检查一次；websocket等升级后不再有请求
+                            if diy_stream.per_request() && header.version.starts_with("HTTP/") && !header.header.keys().any(|k|k.eq_ignore_ascii_case("upgrade")){
+                                quic_stream.peek_close();
+                            }
+                            let target = diy_stream.proxy_table().and_then(|t|t.get().iter().find(|p|p.mac == mac).map(|p|p.url.to_string())).unwrap_or(mac.to_string());
+                            forward(stats.as_ref(), target, &mut quic_stream, &mut stream).await?;
                         }
                     }
                 }
             },
             Err(e) => {
-                #[cfg(feature="log")]
-                println!("peek header error:{}", e);
+                log::debug!(label:% = mac, error:% = e; "stream peek header failed");
                 return Err(RwebError{code:-20,msg:e.to_string()}.into());
             }
         }
@@ -371,65 +878,246 @@ impl ServerCertVerifier for NoVerify {
 
 #[derive(Debug,Clone)]
 pub struct ProxyStringList{
-    pub proxy_list:Arc<Vec<ProxyList>>,
+    pub proxy_list:ProxyTable,
     server_addr:SocketAddr,
+    reverse_list:Arc<Vec<ReverseCell>>,
+    relay_list:Arc<Vec<RelayCell>>,
+    health:Option<HealthCheck>,
+    stats:Option<Stats>,
 }
 
 impl ProxyStringList{
     pub fn new(proxy_list:Arc<Vec<ProxyList>>,server_addr:SocketAddr)->Self{
-        Self{proxy_list,server_addr}
+        Self::from_table(proxy_list.into(), server_addr)
+    }
+
+    //代理表可以在运行中用ProxyTable::set或watch_file替换
+    pub fn from_table(proxy_list:ProxyTable,server_addr:SocketAddr)->Self{
+        let server_addr = canonical(server_addr);//双栈socket上连ipv4的rwebs时是::ffff:a.b.c.d
+        Self{proxy_list,server_addr,reverse_list:Arc::new(vec![]),relay_list:Arc::new(vec![]),health:None,stats:None}
+    }
+
+    pub fn with_reverse(mut self,reverse_list:Vec<ReverseCell>)->Self{
+        self.reverse_list = Arc::new(reverse_list);
+        self
+    }
+
+    pub fn with_relay(mut self,relay_list:Vec<RelayCell>)->Self{
+        self.relay_list = Arc::new(relay_list);
+        self
+    }
+
+    //定时探测代理表里的目标并报告给rwebs
+    pub fn with_health(mut self,check:HealthCheck)->Self{
+        self.health = Some(check);
+        self
+    }
+
+    pub fn with_stats(mut self,stats:Stats)->Self{
+        self.stats = Some(stats);
+        self
     }
 }
 
 impl DiyStream for ProxyStringList{
     #[allow(refining_impl_trait)]
-    async fn new_diy_stream(&self,mac: Mac,proxy_addr:Option<SocketAddr>)->Result<Box<dyn AsyncReadWrite + Send>, RwebError> {
-        #[cfg(feature="log")]
-        println!("proxy addr:tcp:{:?},server_addr:{}",proxy_addr,self.server_addr);
+    async fn new_diy_stream(&self,ctx:&StreamContext)->Result<Box<dyn AsyncReadWrite + Send>, StreamError> {
+        let (mac,proxy_addr) = (ctx.mac,ctx.proxy_addr);
+        log::debug!(label:% = mac, connect:? = proxy_addr, client:? = ctx.client_ip; "new stream");
         match proxy_addr{
             Some(proxy_addr) => {
-                if proxy_addr == self.server_addr{
-                    #[cfg(feature="log")]
-                    eprintln!("proxy addr:tcp:{},server_addr:{},loop detected",proxy_addr,self.server_addr);
-                    return Err(RwebError::new(5023,"loop detected"));
+                if canonical(proxy_addr) == self.server_addr{
+                    log::warn!(label:% = mac, connect:% = proxy_addr, server:% = self.server_addr; "loop detected");
+                    return Err(RwebError::new(5023,"loop detected").into());
                 }
                 let tcp_stream = TcpStream::connect(proxy_addr).await.map_err(|e|RwebError::new(5025,e.to_string()))?;
                 Ok(Box::new(tcp_stream))
             },
             None => {
-                let forward_url = &self.proxy_list.iter().find(|x|x.mac==mac).ok_or(RwebError::new(5024, "not found proxy addr"))?.url;
-                let host = forward_url.host_str().ok_or(RwebError::new(5026, "proxy_addr have no host"))?.to_string();
-                let forward_addr = if host.contains(":"){
-                    host.clone()
-                }else{
-                    host.clone() + match forward_url.scheme(){"http"=>":80","rtsp"=>":554","https"=>":443",_=>"error"}
+                let proxy_list = self.proxy_list.get();//按这个流开始时的表
+                let target = proxy_list.iter().find(|x|x.mac==mac).ok_or(RwebError::new(5024, "not found proxy addr"))?.target()?;
+                let (host,port,tls) = match target{
+                    #[cfg(unix)]
+                    Target::Unix(path)=>{
+                        let unix_stream = tokio::net::UnixStream::connect(path).await.map_err(|e|RwebError::new(5029,e.to_string()))?;
+                        return Ok(Box::new(unix_stream));
+                    },
+                    #[cfg(not(unix))]
+                    Target::Unix(_)=>return Err(RwebError::new(5029,"unix socket not supported").into()),
+                    Target::Tcp{host,port,tls}=>(host,port,tls)
                 };
-                let forward_addr = forward_addr.to_socket_addrs().map_err(|e|RwebError::new(5027, e))?.next().ok_or(RwebError::new(5028, "can't resolve"))?;
-                if forward_addr == self.server_addr{
+                let forward_addr = tokio::net::lookup_host((host.as_str(),port)).await.map_err(|e|RwebError::new(5027, e))?.next().ok_or(RwebError::new(5028, "can't resolve"))?;
+                if canonical(forward_addr) == self.server_addr{
                     return Err(RwebError{code:5026,msg:"loop detected".to_string()}.into());
                 }
                 let tcp_stream = TcpStream::connect(forward_addr).await.map_err(|e|RwebError::new(5029,e.to_string()))?;
-                #[cfg(feature="log")]
-                println!("proxy addr:tcp:{:?},server_addr:{}",forward_addr,self.server_addr);
-                match forward_url.scheme(){
-                    "http"|"rtsp" => {
-                        Ok(Box::new(tcp_stream))
-                    },
-                    "https" => {
-                        let tls_stream = CONNECTOR.connect(ServerName::try_from(host.split(":").next().ok_or(RwebError::new(5029,"proxy_addr have not host"))?.to_string())
-                        .map_err(|e|RwebError::new(5030,e))?, tcp_stream).await.map_err(|e|RwebError::new(5031,e.to_string()))?;
-                        //header.set("Host".to_string(), host.clone());//将host设置为代理地址的头，注释掉的话，会变成带mac的服务器地址
-                        //quic_stream.reset_header(header);
-                        Ok(Box::new(tls_stream))
-                    },
-                    _ => Err(RwebError{code:5026,msg:"loop detected".to_string()}.into())
+                log::debug!(label:% = mac, target:% = forward_addr; "target connected");
+                if !tls{
+                    return Ok(Box::new(tcp_stream));
                 }
-                
+                let tls_stream = CONNECTOR.connect(ServerName::try_from(host).map_err(|e|RwebError::new(5030,e))?, tcp_stream).await.map_err(|e|RwebError::new(5031,e.to_string()))?;
+                //header.set("Host".to_string(), host.clone());//将host设置为代理地址的头，注释掉的话，会变成带mac的服务器地址
+                //quic_stream.reset_header(header);
+                Ok(Box::new(tls_stream))
             }
         }
     }
 
-    fn mac_list(&self)->Vec<&Mac>{
-        self.proxy_list.iter().map(|x|&x.mac).collect()
+    fn mac_list(&self)->Vec<Mac>{
+        self.proxy_list.get().iter().map(|x|x.mac).collect()
     }
-}
\ No newline at end of file
+
+    fn proxy_table(&self)->Option<&ProxyTable>{
+        Some(&self.proxy_list)
+    }
+
+    fn health_check(&self)->Option<&HealthCheck>{
+        self.health.as_ref()
+    }
+
+    fn stats(&self)->Option<&Stats>{
+        self.stats.as_ref()
+    }
+
+    fn reverse_list(&self)->&[ReverseCell]{
+        &self.reverse_list
+    }
+
+    fn relay_list(&self)->&[RelayCell]{
+        &self.relay_list
+    }
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use crate::testutil;
+
+    //rwebs在[::]上双栈监听，设备分别从::1和127.0.0.1连上；设备的双栈socket连ipv4时按ipv4地址识别出回环
+    #[tokio::test]
+    async fn dual_stack(){
+        if std::net::UdpSocket::bind("[::1]:0").is_err(){//没有ipv6的机器上跳过
+            return eprintln!("dual_stack skipped: no ipv6");
+        }
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = rwebs::quic_server::QuicServer::default();
+        let port = testutil::start_on(&server,rweb_common::socket::udp_socket((Ipv6Addr::UNSPECIFIED,0).into()).unwrap());
+        let web = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let web_addr = web.local_addr().unwrap();
+        let same_port = TcpListener::bind(("127.0.0.1",port)).await.unwrap();//rwebs地址上也有服务，不检测回环时会连过来
+        tokio::spawn(async move{
+            loop{
+                tokio::select! {
+                    Ok((mut s,_)) = web.accept()=>s.write_all(b"web").await.unwrap_or_default(),
+                    Ok((mut s,_)) = same_port.accept()=>s.write_all(b"loop").await.unwrap_or_default(),
+                }
+            }
+        });
+        let mac_a:Mac = "aabbccdd0701".parse().unwrap();
+        let mac_b:Mac = "aabbccdd0702".parse().unwrap();
+        let url = url::Url::parse(&format!("http://{}",web_addr)).unwrap();
+        testutil::run((Ipv6Addr::LOCALHOST,port).into(),ProxyStringList::new(Arc::new(vec![ProxyList::new(mac_a,url.clone()).unwrap()]),"[::1]:0".parse().unwrap()));
+        #[cfg(feature="p2p")]
+        tokio::spawn(node_run("127.0.0.1",port,vec![ProxyList::new(mac_b,url).unwrap()],None));//连接的地址是::ffff:127.0.0.1
+        #[cfg(not(feature="p2p"))]
+        tokio::spawn(node_run("127.0.0.1",port,vec![ProxyList::new(mac_b,url).unwrap()]));
+        testutil::registered(&server,&[mac_a,mac_b]).await;
+        for mac in [mac_a,mac_b]{
+            let mut stream = server.open_connect(mac,&web_addr.to_string(),None).await.unwrap();
+            let mut body = String::new();
+            stream.read_to_string(&mut body).await.unwrap();
+            assert_eq!(body,"web");
+        }
+        assert!(server.open_connect(mac_b,&format!("127.0.0.1:{}",port),None).await.is_err());//设备拒绝，不会连到same_port
+    }
+
+    //url里写明的端口要用上，tcp/tls等没有默认端口的scheme在读入代理表时就报错
+    #[tokio::test]
+    async fn explicit_port_targets(){
+        let target = |u:&str|Target::from_url(&url::Url::parse(u).unwrap()).ok();
+        let tcp = |host:&str,port,tls|Some(Target::Tcp{host:host.to_string(),port,tls});
+        assert_eq!(target("http://192.168.2.12:8080"),tcp("192.168.2.12",8080,false));
+        assert_eq!(target("rtsp://cam.local"),tcp("cam.local",554,false));
+        assert_eq!(target("https://192.168.2.12"),tcp("192.168.2.12",443,true));
+        assert_eq!(target("tls://[::1]:993"),tcp("::1",993,true));
+        assert_eq!(target("ssh://192.168.2.12:22"),tcp("192.168.2.12",22,false));
+        assert_eq!(target("unix:///run/app.sock"),Some(Target::Unix("/run/app.sock".to_string())));
+        assert_eq!(Target::from_url(&url::Url::parse("tcp://192.168.2.12").unwrap()).err().map(|e|e.code),Some(5036));
+        assert!(serde_json::from_str::<Vec<ProxyList>>(r#"[{"mac":"aabbcc00020c","url":"tcp://192.168.2.12"}]"#).is_err());
+        assert!(serde_json::from_str::<Vec<ProxyList>>(r#"[{"mac":"aabbcc00020c","url":"tcp://192.168.2.12:22"}]"#).is_ok());
+
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = rwebs::quic_server::QuicServer::default();
+        let port = testutil::start(&server);
+        let web = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let web_addr = web.local_addr().unwrap();
+        tokio::spawn(async move{
+            while let Ok((mut s,_)) = web.accept().await{
+                s.write_all(b"web").await.unwrap_or_default();
+            }
+        });
+        let mac_a:Mac = "aabbccdd0a01".parse().unwrap();
+        let mac_b:Mac = "aabbccdd0a02".parse().unwrap();
+        let list = vec![ProxyList::new(mac_a,url::Url::parse(&format!("http://{}",web_addr)).unwrap()).unwrap(),ProxyList::new(mac_b,url::Url::parse(&format!("tcp://{}",web_addr)).unwrap()).unwrap()];
+        #[cfg(feature="p2p")]
+        tokio::spawn(node_run("127.0.0.1",port,list,None));
+        #[cfg(not(feature="p2p"))]
+        tokio::spawn(node_run("127.0.0.1",port,list));
+        testutil::registered(&server,&[mac_a,mac_b]).await;
+        for mac in [mac_a,mac_b]{
+            let mut stream = server.open_stream(mac).await.unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n").await.unwrap();
+            let mut body = String::new();
+            stream.read_to_string(&mut body).await.unwrap();
+            assert_eq!(body,"web");
+        }
+    }
+
+    //ProxyStringList不按请求选择目标，同一个流上的keep-alive请求都转给目标
+    #[tokio::test]
+    async fn keep_alive_target(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = rwebs::quic_server::QuicServer::default();
+        let port = testutil::start(&server);
+        let web = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let web_addr = web.local_addr().unwrap();
+        tokio::spawn(async move{
+            while let Ok((mut s,_)) = web.accept().await{
+                tokio::spawn(async move{
+                    loop{
+                        let Ok(header) = get_header(&mut s).await else{ return };
+                        let close = header.header.iter().any(|(k,v)|k.eq_ignore_ascii_case("connection") && v.eq_ignore_ascii_case("close"));
+                        let body = header.uri;
+                        s.write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",body.len(),body).as_bytes()).await.unwrap_or_default();
+                        if close{
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        let mac:Mac = "aabbccdd0e01".parse().unwrap();
+        let url = url::Url::parse(&format!("http://{}",web_addr)).unwrap();
+        testutil::run(([127,0,0,1],port).into(),ProxyStringList::new(Arc::new(vec![ProxyList::new(mac,url).unwrap()]),"127.0.0.1:0".parse().unwrap()));
+        testutil::registered(&server,&[mac]).await;
+        let mut stream = server.open_stream(mac).await.unwrap();
+        stream.write_all(b"GET /1 HTTP/1.1\r\nHost: test\r\n\r\nGET /2 HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut body = String::new();
+        tokio::time::timeout(Duration::from_secs(5),stream.read_to_string(&mut body)).await.unwrap().unwrap();
+        assert!(body.ends_with("/1HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n/2"),"{}",body);
+    }
+
+    #[test]
+    fn node_options(){
+        let options:NodeOptions = serde_json::from_str("{}").unwrap();
+        assert!(options.reverse_list.is_empty());
+        let options:NodeOptions = serde_json::from_str(r#"{"reverse_list":[{"mac":"aabbccdd3101","name":"syslog","listen":"127.0.0.1:514"}]}"#).unwrap();
+        assert_eq!(options.reverse_list[0].name,"syslog");
+        assert_eq!(options.reverse_list[0].listen,"127.0.0.1:514".parse().unwrap());
+        #[cfg(feature="p2p")]
+        {
+            let options:NodeOptions = serde_json::from_str(r#"{"stun_servers":["127.0.0.1:3478"]}"#).unwrap();
+            assert_eq!(options.stun_servers,vec!["127.0.0.1:3478".parse().unwrap()]);
+        }
+    }
+}
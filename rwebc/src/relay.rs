@@ -0,0 +1,50 @@
+use quinn::{Connection, RecvStream, SendStream};
+use rweb_common::{get_header, io::stream_copy::Stream, mac::Mac, relay_list::RelayCell, Header, RwebError};
+use tokio::io::AsyncWriteExt;
+use crate::{listen::CellListener, quic_client::ActiveStreams};
+
+//经rwebs中转打开到设备mac的流，rwebs会先写入mac，之后与p2p直连的流相同
+pub(crate) async fn relay_stream(connection:&Connection,mac:Mac)->Result<Stream<RecvStream,SendStream>,RwebError>{
+    let bi_stream = connection.open_bi().await.map_err(|e|RwebError::new(57,e))?;
+    let mut relay_stream = Stream::new(bi_stream,connection.remote_address());
+    let header:Vec<u8> = Header::new_relay(mac).into();
+    relay_stream.write_all(&header).await.map_err(|e|RwebError::new(58,e))?;
+    let resp = get_header(&mut relay_stream).await?;
+    if !resp.uri.starts_with('2'){//状态行解析后uri字段为状态码
+        return Err(RwebError::new(59,format!("relay {} refused:{}",mac,resp.uri)));
+    }
+    Ok(relay_stream)
+}
+
+//只走中转的列表，本机按cell.bind监听，接入的连接经rwebs转到cell.mac
+pub(crate) async fn listen_relay(connection:Connection,relay_list:Vec<RelayCell>,streams:ActiveStreams)->Result<(),RwebError>{
+    if relay_list.is_empty(){
+        return std::future::pending().await;
+    }
+    let mut accepts = Vec::with_capacity(relay_list.len());
+    for cell in relay_list{
+        let listener = CellListener::bind(&cell).await?;
+        accepts.push(accept_relay(listener, connection.clone(), cell.mac, streams.clone()));
+    }
+    futures::future::try_join_all(accepts).await?;
+    Ok(())
+}
+
+async fn accept_relay(listener:CellListener,connection:Connection,mac:Mac,streams:ActiveStreams)->Result<(),RwebError>{
+    loop{
+        let mut tcp_stream = listener.accept().await?;
+        let connection = connection.clone();
+        let active = streams.open();
+        tokio::spawn(async move {
+            let _active = active;
+            match relay_stream(&connection, mac).await{
+                Ok(mut relay_stream)=>{
+                    tokio::io::copy_bidirectional(&mut tcp_stream, &mut relay_stream).await.unwrap_or_default();
+                },
+                Err(e)=>{
+                    log::warn!(label:% = mac, code:% = e.code, error:% = e.msg; "relay failed");
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,104 @@
+use std::net::SocketAddr;
+use rweb_common::{server_list::ServerCell, RwebError};
+use tokio::{net::lookup_host, time::Duration};
+
+//同一级地址之间的间隔，RFC 8305建议250ms
+pub(crate) const ATTEMPT_DELAY:Duration = Duration::from_millis(250);
+//上一级的地址都还没连上时，等这么久再试下一级
+pub(crate) const GROUP_DELAY:Duration = Duration::from_secs(2);
+
+//ipv6和ipv4交替，同一地址族内保持解析出的顺序，从第一个地址的地址族开始
+pub fn happy_eyeballs(addrs:Vec<SocketAddr>)->Vec<SocketAddr>{
+    let first_v6 = addrs.first().is_some_and(|a|a.is_ipv6());
+    let (mut first,mut second):(Vec<_>,Vec<_>) = addrs.into_iter().partition(|a|a.is_ipv6() == first_v6);
+    first.dedup();
+    second.dedup();
+    let mut second = second.into_iter();
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    for a in first{
+        ordered.push(a);
+        ordered.extend(second.next());
+    }
+    ordered.extend(second);
+    ordered
+}
+
+//解析出全部地址，按happy eyeballs排好
+pub async fn resolve(host:&str,port:u16)->Result<Vec<SocketAddr>,RwebError>{
+    let addrs:Vec<_> = lookup_host((host,port)).await.map_err(|e|RwebError{code:-10,msg:e.to_string()})?.collect();
+    if addrs.is_empty(){
+        return Err(RwebError{code:-11,msg:"can't resolve".to_string()});
+    }
+    Ok(happy_eyeballs(addrs))
+}
+
+//按priority分级，小的在前；同一级按weight加权随机排序，weight为0的排在最后
+pub fn order(servers:&[ServerCell])->Vec<Vec<ServerCell>>{
+    let mut servers = servers.to_vec();
+    servers.sort_by_key(|s|s.priority);
+    let mut groups:Vec<Vec<ServerCell>> = vec![];
+    for s in servers{
+        match groups.last_mut(){
+            Some(g) if g[0].priority == s.priority=>g.push(s),
+            _=>groups.push(vec![s])
+        }
+    }
+    for g in groups.iter_mut(){
+        let mut rest = std::mem::take(g);
+        while !rest.is_empty(){
+            let total:u32 = rest.iter().map(|s|s.weight as u32).sum();
+            let mut pick = if total == 0 {0} else {fastrand::u32(0..total)};
+            let i = rest.iter().position(|s|{
+                if pick < s.weight as u32{
+                    return true;
+                }
+                pick -= s.weight as u32;
+                false
+            }).unwrap_or(0);
+            g.push(rest.remove(i));
+        }
+    }
+    groups
+}
+
+//所有服务器的连接顺序：(地址,前面的还没连上时等多久开始这个,第几级)
+//解析不出的服务器跳过，都解析不出时返回最后一个错误
+pub(crate) async fn attempts(groups:&[Vec<ServerCell>])->Result<Vec<(SocketAddr,Duration,usize)>,RwebError>{
+    let mut attempts = vec![];
+    let mut last_err = RwebError{code:-11,msg:"no server".to_string()};
+    for (level,g) in groups.iter().enumerate(){
+        let first = attempts.len();
+        for s in g{
+            match resolve(&s.host,s.port).await{
+                Ok(addrs)=>attempts.extend(addrs.into_iter().map(|a|(a,ATTEMPT_DELAY,level))),
+                Err(e)=>last_err = e
+            }
+        }
+        if let Some(a) = attempts.get_mut(first){
+            a.1 = GROUP_DELAY;
+        }
+    }
+    if attempts.is_empty(){
+        return Err(last_err);
+    }
+    Ok(attempts)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn eyeballs_and_order(){
+        let a = |s:&str|->SocketAddr{s.parse().unwrap()};
+        let addrs = vec![a("[::1]:1"),a("[::2]:1"),a("[::3]:1"),a("10.0.0.1:1"),a("10.0.0.1:1")];
+        assert_eq!(happy_eyeballs(addrs),vec![a("[::1]:1"),a("10.0.0.1:1"),a("[::2]:1"),a("[::3]:1")]);
+        let addrs = vec![a("10.0.0.1:1"),a("10.0.0.2:1"),a("[::1]:1"),a("[::2]:1"),a("[::3]:1")];
+        assert_eq!(happy_eyeballs(addrs),vec![a("10.0.0.1:1"),a("[::1]:1"),a("10.0.0.2:1"),a("[::2]:1"),a("[::3]:1")]);
+        let servers = vec![ServerCell::new("b",1).with_priority(1),ServerCell::new("a",1).with_weight(0),ServerCell::new("c",1).with_weight(5)];
+        let groups = order(&servers);
+        assert_eq!(groups.len(),2);
+        assert_eq!(groups[0],vec![servers[2].clone(),servers[1].clone()]);//weight为0的总在后面
+        assert_eq!(groups[1],vec![servers[0].clone()]);
+    }
+}
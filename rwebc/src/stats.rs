@@ -0,0 +1,333 @@
+use std::{collections::{BTreeMap, HashMap}, net::SocketAddr, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}};
+use quinn::Connection;
+use rweb_common::mac::Mac;
+use serde::Serialize;
+use tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, time::Instant};
+use crate::supervisor::ConnEvent;
+#[cfg(feature="p2p")]
+use crate::p2p_session::P2pSessions;
+
+//与rwebs的连接状态
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default,Serialize)]
+#[serde(rename_all="lowercase")]
+pub enum ConnState{
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+#[derive(Debug,Clone,Default,PartialEq,Eq,Serialize)]
+pub struct StreamCount{
+    pub active:u64,
+    pub total:u64,
+}
+
+//to_target为rwebs发给目标的字节数，from_target为目标发回的
+#[derive(Debug,Clone,Default,PartialEq,Eq,Serialize)]
+pub struct Traffic{
+    pub streams:u64,
+    pub to_target:u64,
+    pub from_target:u64,
+}
+
+//quinn统计的到rwebs的路径
+#[derive(Debug,Clone,Serialize)]
+pub struct ServerPath{
+    pub rtt_ms:u64,
+    pub sent_packets:u64,
+    pub lost_packets:u64,
+    pub lost_bytes:u64,
+    pub congestion_events:u64,
+}
+
+#[derive(Debug,Clone,Serialize)]
+pub struct P2pStat{
+    pub label:String,
+    pub state:String,
+    pub remote:Option<String>,
+    pub rtt_ms:Option<u64>,
+    pub punches:u32,
+    pub failures:u32,
+}
+
+//某一时刻的统计，streams按设备标签(mac)，targets按目标url或CONNECT的地址
+//server_failures为连rwebs失败的次数，target_failures为连目标失败的次数，都按错误码
+//rejected为代理表更新后rwebs没有接受的mac(已在别的连接上在线)
+#[derive(Debug,Clone,Serialize)]
+pub struct Snapshot{
+    pub state:ConnState,
+    pub server:Option<String>,
+    pub uptime_secs:Option<u64>,
+    pub connects:u64,
+    pub path:Option<ServerPath>,
+    pub streams:BTreeMap<String,StreamCount>,
+    pub targets:BTreeMap<String,Traffic>,
+    pub server_failures:BTreeMap<i32,u64>,
+    pub target_failures:BTreeMap<i32,u64>,
+    pub rejected:Vec<String>,
+    pub p2p:Vec<P2pStat>,
+}
+
+#[derive(Debug,Default)]
+struct Inner{
+    state:ConnState,
+    server:Option<SocketAddr>,
+    connection:Option<(Connection,Instant)>,
+    connects:u64,
+    streams:HashMap<Mac,StreamCount>,
+    targets:HashMap<String,Traffic>,
+    server_failures:BTreeMap<i32,u64>,
+    target_failures:BTreeMap<i32,u64>,
+    rejected:Vec<Mac>,
+    #[cfg(feature="p2p")]
+    p2p:Option<P2pSessions>,
+}
+
+//运行统计，clone后共享同一份；交给Supervisor::with_stats和ProxyStringList::with_stats后随时用snapshot读取
+#[derive(Debug,Clone,Default)]
+pub struct Stats(Arc<Mutex<Inner>>);
+
+impl Stats{
+    //一起报告P2PListener的p2p会话
+    #[cfg(feature="p2p")]
+    pub fn with_p2p(self,sessions:P2pSessions)->Self{
+        self.update(|s|s.p2p = Some(sessions));
+        self
+    }
+
+    pub fn snapshot(&self)->Snapshot{
+        let inner = self.0.lock().unwrap_or_else(|e|e.into_inner());
+        let path = inner.connection.as_ref().map(|(c,_)|{
+            let path = c.stats().path;
+            ServerPath{rtt_ms:path.rtt.as_millis() as u64,sent_packets:path.sent_packets,lost_packets:path.lost_packets,lost_bytes:path.lost_bytes,congestion_events:path.congestion_events}
+        });
+        #[cfg(feature="p2p")]
+        let p2p = inner.p2p.as_ref().map(|p|p.list()).unwrap_or_default().into_iter().map(|s|P2pStat{
+            label:s.mac.to_string(),
+            state:format!("{:?}",s.state).to_lowercase(),
+            remote:s.remote.map(|r|r.to_string()),
+            rtt_ms:s.rtt.map(|r|r.as_millis() as u64),
+            punches:s.punches,
+            failures:s.failures,
+        }).collect();
+        #[cfg(not(feature="p2p"))]
+        let p2p = vec![];
+        Snapshot{
+            state:inner.state,
+            server:inner.server.map(|s|s.to_string()),
+            uptime_secs:inner.connection.as_ref().map(|(_,since)|since.elapsed().as_secs()),
+            connects:inner.connects,
+            path,
+            streams:inner.streams.iter().map(|(m,c)|(m.to_string(),c.clone())).collect(),
+            targets:inner.targets.iter().map(|(t,c)|(t.clone(),c.clone())).collect(),
+            server_failures:inner.server_failures.clone(),
+            target_failures:inner.target_failures.clone(),
+            rejected:inner.rejected.iter().map(|m|m.to_string()).collect(),
+            p2p,
+        }
+    }
+
+    pub fn json(&self)->String{
+        serde_json::to_string(&self.snapshot()).unwrap_or_default()
+    }
+
+    fn update(&self,f:impl FnOnce(&mut Inner)){
+        f(&mut self.0.lock().unwrap_or_else(|e|e.into_inner()));
+    }
+
+    pub(crate) fn on_event(&self,event:&ConnEvent){
+        self.update(|s|match event{
+            ConnEvent::Connecting{..} if s.state != ConnState::Connected=>s.state = ConnState::Connecting,
+            ConnEvent::Connected{server}=>s.server = Some(*server),
+            ConnEvent::Disconnected{cause,error,uptime}=>{
+                s.state = ConnState::Disconnected;
+                if uptime.is_none() && *cause != crate::supervisor::Disconnect::Shutdown{//没连上
+                    *s.server_failures.entry(error.code).or_default() += 1;
+                }
+            },
+            _=>{}
+        });
+    }
+
+    //已向rwebs注册，返回的guard释放时为断开
+    pub(crate) fn connected(&self,connection:&Connection)->ConnectedGuard{
+        self.update(|s|{
+            s.state = ConnState::Connected;
+            s.server = Some(rweb_common::socket::canonical(connection.remote_address()));
+            s.connection = Some((connection.clone(),Instant::now()));
+            s.connects += 1;
+        });
+        ConnectedGuard(self.clone())
+    }
+
+    pub(crate) fn open_stream(&self,mac:Mac)->StreamGuard{
+        self.update(|s|{
+            let c = s.streams.entry(mac).or_default();
+            c.active += 1;
+            c.total += 1;
+        });
+        StreamGuard(self.clone(),mac)
+    }
+
+    pub(crate) fn rejected(&self,macs:Vec<Mac>){
+        self.update(|s|s.rejected = macs);
+    }
+
+    pub(crate) fn failed(&self,code:i32){
+        self.update(|s|*s.target_failures.entry(code).or_default() += 1);
+    }
+
+    fn traffic(&self,target:String,to_target:u64,from_target:u64){
+        self.update(|s|{
+            let t = s.targets.entry(target).or_default();
+            t.streams += 1;
+            t.to_target += to_target;
+            t.from_target += from_target;
+        });
+    }
+}
+
+pub(crate) struct ConnectedGuard(Stats);
+
+impl Drop for ConnectedGuard{
+    fn drop(&mut self){
+        self.0.update(|s|{
+            s.state = ConnState::Disconnected;
+            s.connection = None;
+        });
+    }
+}
+
+pub(crate) struct StreamGuard(Stats,Mac);
+
+impl Drop for StreamGuard{
+    fn drop(&mut self){
+        self.0.update(|s|if let Some(c) = s.streams.get_mut(&self.1){
+            c.active = c.active.saturating_sub(1);
+        });
+    }
+}
+
+//双向转发，结束后记下到这个目标的字节数，出错断开的也算
+pub(crate) async fn forward<A:AsyncRead+AsyncWrite+Unpin+?Sized,B:AsyncRead+AsyncWrite+Unpin+?Sized>(stats:Option<&Stats>,target:String,a:&mut A,b:&mut B)->std::io::Result<()>{
+    let mut b = Counted{inner:b,read:0,written:0};
+    let result = tokio::io::copy_bidirectional(a, &mut b).await;
+    if let Some(stats) = stats{
+        stats.traffic(target, b.written, b.read);
+    }
+    result.map(|_|())
+}
+
+//记下从目标读到和写给目标的字节数
+struct Counted<'a,T:?Sized>{
+    inner:&'a mut T,
+    read:u64,
+    written:u64,
+}
+
+impl<T:AsyncRead+Unpin+?Sized> AsyncRead for Counted<'_,T>{
+    fn poll_read(mut self:Pin<&mut Self>,cx:&mut Context<'_>,buf:&mut ReadBuf<'_>)->Poll<std::io::Result<()>>{
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        self.read += (buf.filled().len() - before) as u64;
+        poll
+    }
+}
+
+impl<T:AsyncWrite+Unpin+?Sized> AsyncWrite for Counted<'_,T>{
+    fn poll_write(mut self:Pin<&mut Self>,cx:&mut Context<'_>,buf:&[u8])->Poll<std::io::Result<usize>>{
+        let poll = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll{
+            self.written += n as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self:Pin<&mut Self>,cx:&mut Context<'_>)->Poll<std::io::Result<()>>{
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self:Pin<&mut Self>,cx:&mut Context<'_>)->Poll<std::io::Result<()>>{
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::time::Duration;
+    use rweb_common::proxy_list::ProxyList;
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::UnixListener, time::sleep};
+    use crate::{quic_client::ProxyStringList, supervisor::Supervisor};
+
+    fn web(name:&str,listen:bool)->url::Url{
+        let path = std::env::temp_dir().join(format!("rwebc-{}-{}.sock",std::process::id(),name));
+        std::fs::remove_file(&path).unwrap_or_default();
+        if listen{
+            let listener = UnixListener::bind(&path).unwrap();
+            tokio::spawn(async move{
+                while let Ok((mut stream,_)) = listener.accept().await{
+                    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap_or_default();
+                }
+            });
+        }
+        url::Url::parse(&format!("unix://{}",path.display())).unwrap()
+    }
+
+    async fn wait_for(stats:&Stats,f:impl Fn(&Snapshot)->bool){
+        for _ in 0..50{
+            if f(&stats.snapshot()){
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        panic!("{:?}",stats.snapshot());
+    }
+
+    //连接状态、流数、目标流量和连目标失败的次数
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stats_snapshot(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = rwebs::quic_server::QuicServer::default();
+        let port = crate::testutil::start(&server);
+        let mac_a:Mac = "aabbccdd0b01".parse().unwrap();
+        let mac_b:Mac = "aabbccdd0b02".parse().unwrap();
+        let up = web("stats-up",true);
+        let proxy_list = Arc::new(vec![ProxyList::new(mac_a,up.clone()).unwrap(),ProxyList::new(mac_b,web("stats-down",false)).unwrap()]);
+        let stats = Stats::default();
+        assert_eq!(stats.snapshot().state,ConnState::Disconnected);
+        let supervisor = Supervisor::new("127.0.0.1",port).with_stats(stats.clone());
+        let s = stats.clone();
+        let diy_stream = move |server_addr|ProxyStringList::new(proxy_list.clone(),server_addr).with_stats(s.clone());
+        crate::testutil::supervise(supervisor,diy_stream);
+        wait_for(&stats,|s|s.state == ConnState::Connected && s.path.is_some()).await;
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.server,Some(format!("127.0.0.1:{}",port)));
+        assert_eq!(snapshot.connects,1);
+
+        let request = b"GET / HTTP/1.1\r\nHost: test\r\n\r\n";
+        let mut stream = server.open_stream(mac_a).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut body = vec![];
+        stream.read_to_end(&mut body).await.unwrap();
+        let mut failed = server.open_stream(mac_b).await.unwrap();
+        failed.write_all(request).await.unwrap();
+        failed.read_to_end(&mut vec![]).await.unwrap_or_default();
+        wait_for(&stats,|s|s.target_failures.get(&5029) == Some(&1) && s.targets.contains_key(up.as_str())).await;
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.streams[&mac_a.to_string()].total,1);
+        assert_eq!(snapshot.streams[&mac_b.to_string()].total,1);
+        let traffic = &snapshot.targets[up.as_str()];
+        assert_eq!((traffic.streams,traffic.from_target),(1,body.len() as u64));
+        assert!(traffic.to_target > 0);
+        drop(stream);
+        wait_for(&stats,|s|s.streams.values().all(|c|c.active == 0)).await;
+
+        let json:serde_json::Value = serde_json::from_str(&stats.json()).unwrap();
+        assert_eq!(json["state"],"connected");
+        assert_eq!(json["target_failures"]["5029"],1);
+        assert_eq!(json["server_failures"],serde_json::json!({}));
+    }
+}
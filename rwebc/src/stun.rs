@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{self, IoSliceMut},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll}
+};
+use futures::{stream::FuturesUnordered, StreamExt};
+use quinn::{udp::{RecvMeta, Transmit}, AsyncUdpSocket, UdpPoller};
+use rweb_common::{socket::for_socket, stun::{binding_request, parse_response, TransactionId}, RwebError};
+use tokio::{sync::oneshot, time::{sleep, timeout, Duration, Instant}};
+use crate::quic_client::NodeEndpoint;
+
+//每个STUN服务器最多等待的时间，期间按RFC 8489从500ms起翻倍重发
+const STUN_TIMEOUT_MILLIS:u64 = 3_000;
+const STUN_RTO_MILLIS:u64 = 500;
+//serve期间定时刷新反射地址，p2p请求和回应直接用上次的结果
+const REFRESH_SECS:u64 = 60;
+
+//包在endpoint的socket外面，STUN响应在交给quinn之前截下来，这样测到的就是quic用的那个NAT映射
+//make_endpoint把它和Endpoint一起放在NodeEndpoint里
+pub struct StunSocket{
+    inner:Arc<dyn AsyncUdpSocket>,
+    pending:Mutex<HashMap<TransactionId,oneshot::Sender<SocketAddr>>>,
+    servers:Mutex<Vec<SocketAddr>>,
+    last:Mutex<Option<SocketAddr>>,//最近一次测得的反射地址
+}
+
+impl Debug for StunSocket{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StunSocket").field("inner",&self.inner).finish()
+    }
+}
+
+impl StunSocket{
+    pub(crate) fn wrap(inner:Arc<dyn AsyncUdpSocket>)->Arc<Self>{
+        Arc::new(Self{inner,pending:Mutex::new(HashMap::new()),servers:Mutex::new(vec![]),last:Mutex::new(None)})
+    }
+
+    async fn binding(&self,server:SocketAddr)->Result<SocketAddr,RwebError>{
+        //quinn发包前会按socket的地址族转换目标地址，这里直接发socket也要转
+        let local = self.inner.local_addr().map_err(|e|RwebError::new(6041,e))?;
+        let destination = for_socket(local,server).ok_or(RwebError::new(6041,format!("{} unreachable from {}",server,local)))?;
+        let txid:TransactionId = std::array::from_fn(|_|fastrand::u8(..));
+        let (tx,mut rx) = oneshot::channel();
+        self.pending.lock().map_err(|e|RwebError::new(6041,e.to_string()))?.insert(txid,tx);
+        let request = binding_request(&txid);
+        let transmit = Transmit{destination,ecn:None,contents:&request,segment_size:None,src_ip:None};
+        let deadline = Instant::now() + Duration::from_millis(STUN_TIMEOUT_MILLIS);
+        let mut rto = Duration::from_millis(STUN_RTO_MILLIS);
+        let result = loop{
+            self.inner.try_send(&transmit).unwrap_or_default();//发不出去等下次重发
+            let wait = rto.min(deadline.saturating_duration_since(Instant::now()));
+            match timeout(wait,&mut rx).await{
+                Ok(Ok(addr))=>break Ok(addr),
+                Ok(Err(e))=>break Err(RwebError::new(6041,e)),
+                Err(_) if Instant::now() >= deadline=>break Err(RwebError::new(6042,format!("stun {} timeout",server))),
+                Err(_)=>rto *= 2
+            }
+        };
+        if let Ok(mut pending) = self.pending.lock(){
+            pending.remove(&txid);
+        }
+        result
+    }
+
+    //是本机发出的请求的响应时交给等待的binding，返回true表示这个包不交给quinn
+    fn take_response(&self,buf:&[u8])->bool{
+        let Some((txid,addr)) = parse_response(buf) else{
+            return false
+        };
+        if let Some(tx) = self.pending.lock().ok().and_then(|mut p|p.remove(&txid)){
+            tx.send(addr).unwrap_or_default();
+        }
+        true
+    }
+}
+
+impl AsyncUdpSocket for StunSocket{
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        self.inner.clone().create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        self.inner.try_send(transmit)
+    }
+
+    //截掉STUN响应后把剩下的包往前挪，全部截掉时继续收，保证注册了唤醒
+    fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta]) -> Poll<io::Result<usize>> {
+        loop{
+            let n = match self.inner.poll_recv(cx, bufs, meta){
+                Poll::Ready(Ok(n))=>n,
+                other=>return other
+            };
+            let mut kept = 0;
+            for i in 0..n{
+                if meta[i].len == meta[i].stride && self.take_response(&bufs[i][..meta[i].len]){
+                    continue;
+                }
+                if kept != i{
+                    let (head,tail) = bufs.split_at_mut(i);
+                    head[kept][..meta[i].len].copy_from_slice(&tail[0][..meta[i].len]);
+                    meta[kept] = meta[i];
+                }
+                kept += 1;
+            }
+            if kept > 0{
+                return Poll::Ready(Ok(kept));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.inner.max_receive_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
+
+//设置endpoint取反射地址时用的STUN服务器
+pub fn set_servers(endpoint:&NodeEndpoint,servers:Vec<SocketAddr>){
+    if let Ok(mut s) = endpoint.stun.servers.lock(){
+        *s = servers;
+    }
+}
+
+//向一个STUN服务器问endpoint的反射地址，请求从endpoint的socket发出
+pub async fn binding(endpoint:&NodeEndpoint,server:SocketAddr)->Result<SocketAddr,RwebError>{
+    endpoint.stun.binding(server).await
+}
+
+//同时问已设置的STUN服务器，第一个回应的为准，不回应的服务器不会拖慢其他的
+pub async fn reflexive(endpoint:&NodeEndpoint)->Result<SocketAddr,RwebError>{
+    let socket = &endpoint.stun;
+    let servers = socket.servers.lock().map(|s|s.clone()).unwrap_or_default();
+    let mut pending:FuturesUnordered<_> = servers.into_iter().map(|server|socket.binding(server)).collect();
+    let mut last_err = RwebError::new(6043,"no stun server".to_string());
+    while let Some(r) = pending.next().await{
+        match r{
+            Ok(addr)=>{
+                if let Ok(mut last) = socket.last.lock(){
+                    *last = Some(addr);
+                }
+                return Ok(addr)
+            },
+            Err(e)=>last_err = e
+        }
+    }
+    Err(last_err)
+}
+
+//上次测得的反射地址，不等网络
+pub fn last_reflexive(endpoint:&NodeEndpoint)->Option<SocketAddr>{
+    endpoint.stun.last.lock().ok().and_then(|l|*l)
+}
+
+//定时刷新反射地址，地址变化时记日志；没有设置STUN服务器时一直等待
+pub(crate) async fn refresh(endpoint:NodeEndpoint)->Result<(),RwebError>{
+    if endpoint.stun.servers.lock().map(|s|s.is_empty()).unwrap_or(true){
+        return std::future::pending().await;
+    }
+    loop{
+        let last = last_reflexive(&endpoint);
+        match reflexive(&endpoint).await{
+            Ok(addr) if Some(addr) != last=>log::info!(reflexive:% = addr; "STUN服务器检测此节点地址"),
+            Ok(_)=>{},
+            Err(e)=>log::debug!(code:% = e.code, error:% = e.msg; "stun failed"),
+        }
+        sleep(Duration::from_secs(REFRESH_SECS)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use p2ptest::stun_server::StunServer;
+
+    //反射地址从endpoint自己的socket测出，测完quic照常可用，和NatServer在quic上测到的地址一致
+    #[tokio::test]
+    async fn stun_on_endpoint(){
+        use p2ptest::nat_sim::{NatConfig, NatKind, NatSocket};
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let stun = StunServer::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST),0)).await.unwrap();
+        let stun_addr = stun.local_addr().unwrap();
+        tokio::spawn(stun.serve());
+        let nat_server = p2ptest::quic_server::NatServer::bind(IpAddr::V4(Ipv4Addr::LOCALHOST),0,0,None).unwrap();
+        let nat_addr = nat_server.local_addr();
+        tokio::spawn(nat_server.serve());
+        let dead = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();//不回应的服务器
+        let nat = NatSocket::new(NatConfig::new(NatKind::PortRestricted,IpAddr::V4(Ipv4Addr::new(127,0,4,1))));
+        let endpoint = crate::quic_client::make_endpoint_with_socket(nat.clone()).unwrap();
+        assert_eq!(reflexive(&endpoint).await.unwrap_err().code,6043);
+        set_servers(&endpoint,vec![dead.local_addr().unwrap(),stun_addr]);
+        let start = Instant::now();
+        let addr = reflexive(&endpoint).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(STUN_TIMEOUT_MILLIS));//不等不回应的服务器
+        assert_eq!(last_reflexive(&endpoint),Some(addr));
+        assert_eq!(nat.external_addrs(),vec![addr]);
+        let conn = endpoint.connect(nat_addr,"reform").unwrap().await.unwrap();
+        let mut uni = conn.accept_uni().await.unwrap();
+        assert_eq!(rweb_common::io::header::read_addr(&mut uni).await.unwrap(),addr);
+        let endpoint = crate::quic_client::make_endpoint().unwrap();
+        let port = endpoint.local_addr().unwrap().port();
+        assert_eq!(binding(&endpoint,stun_addr).await.unwrap(),SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST),port));
+    }
+}
@@ -0,0 +1,363 @@
+use std::{future::Future, net::SocketAddr};
+use quinn::{Connection, VarInt};
+use rweb_common::{mac::Mac, server_list::ServerCell, RwebError};
+use tokio::{select, sync::{broadcast, watch}, time::{sleep, timeout, Duration, Instant}};
+#[cfg(feature="p2p")]
+use crate::p2p_client::{P2PListener, P2pListen};
+use crate::{quic_client::{announce, connect_any, make_endpoint, serve, Dialer, DiyStream, NodeEndpoint, Trust, Tuning, ERR_CONNECT, ERR_DUPLICATE_MAC, ERR_FAILBACK, ERR_HANDSHAKE, ERR_IDLE_TIMEOUT, ERR_SHUTDOWN, IDLE_TIMEOUT_MILLIS}, servers, stats::Stats};
+
+//mac重复时rwebs上旧连接要等空闲超时才释放，早于这个时间重连还会被踢
+const DUPLICATE_WAIT:Duration = Duration::from_millis(IDLE_TIMEOUT_MILLIS as u64 + 1_000);
+
+//连不上或断开的原因
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Disconnect{
+    Dns,//解析不出rwebs地址，多半是网络还没通
+    Handshake,//rwebs不可达或握手失败
+    Duplicate,//mac已在线，rwebs以401关闭
+    IdleTimeout,//连接空闲超时，网络抖动
+    Failback,//连着备用服务器时优先级更高的恢复了
+    Shutdown,//调用了shutdown，不再重连
+    Other,
+}
+
+impl Disconnect{
+    pub fn from_error(e:&RwebError)->Self{
+        match e.code{
+            ERR_CONNECT|ERR_HANDSHAKE=>Self::Handshake,
+            ERR_DUPLICATE_MAC=>Self::Duplicate,
+            ERR_IDLE_TIMEOUT=>Self::IdleTimeout,
+            ERR_FAILBACK=>Self::Failback,
+            ERR_SHUTDOWN=>Self::Shutdown,
+            _=>Self::Other
+        }
+    }
+}
+
+//连接状态事件，Supervisor::subscribe订阅
+#[derive(Debug,Clone)]
+pub enum ConnEvent{
+    Connecting{server:SocketAddr,attempt:u32},//每个地址开始连接时一个，多个地址可能同时在连
+    Connected{server:SocketAddr},//已向rwebs注册，mac重复时随后会收到Duplicate断开
+    Disconnected{cause:Disconnect,error:RwebError,uptime:Option<Duration>},//uptime为None表示没连上
+    Retry{cause:Disconnect,delay:Duration},
+    Draining{streams:u64},//已从rwebs下线，等这些流结束，随后为Disconnected{cause:Shutdown}
+}
+
+//重连间隔：按原因定起点，连续失败时翻倍直到max，再加最多20%的随机抖动
+#[derive(Debug,Clone,Copy)]
+pub struct Backoff{
+    pub min:Duration,
+    pub max:Duration,
+    pub stable:Duration,//连上保持这么久后再断开，从头计算失败次数
+    pub failback:Duration,//连着备用服务器时，隔这么久试一次优先级更高的
+}
+
+impl Default for Backoff{
+    fn default() -> Self {
+        Self{min:Duration::from_secs(1),max:Duration::from_secs(300),stable:Duration::from_secs(60),failback:Duration::from_secs(60)}
+    }
+}
+
+impl Backoff{
+    //failures为连续失败次数，从1开始
+    pub fn delay(&self,cause:Disconnect,failures:u32)->Duration{
+        let exp = |start:Duration|start.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(self.max);
+        let delay = match cause{
+            Disconnect::IdleTimeout if failures <= 1=>Duration::ZERO,//网络抖动，马上重连
+            Disconnect::Failback=>Duration::ZERO,
+            Disconnect::Dns=>exp(self.min * 2),
+            Disconnect::Duplicate=>exp(DUPLICATE_WAIT).max(DUPLICATE_WAIT),
+            _=>exp(self.min),
+        };
+        //节点多时rwebs重启后错开重连
+        delay + Duration::from_millis(fastrand::u64(0..=delay.as_millis() as u64 / 5 + 200))
+    }
+}
+
+//连接rwebs并在断开后按原因重连
+pub struct Supervisor{
+    servers:Vec<ServerCell>,
+    backoff:Backoff,
+    events:broadcast::Sender<ConnEvent>,
+    stats:Option<Stats>,
+    trust:Trust,
+    tuning:Tuning,
+    drain:Duration,
+    shutdown:watch::Sender<bool>,
+}
+
+impl Supervisor{
+    pub fn new(host:&str,port:u16)->Self{
+        Self::with_servers(vec![ServerCell::new(host,port)])
+    }
+
+    //多个rwebs，按ServerCell的priority和weight选择，连着备用的时候定时试优先级更高的
+    pub fn with_servers(servers:Vec<ServerCell>)->Self{
+        let (events,_) = broadcast::channel(64);
+        Self{servers,backoff:Backoff::default(),events,stats:None,trust:Trust::default(),tuning:Tuning::default(),drain:Duration::from_secs(30),shutdown:watch::Sender::new(false)}
+    }
+
+    pub fn with_backoff(mut self,backoff:Backoff)->Self{
+        self.backoff = backoff;
+        self
+    }
+
+    //连接状态和连不上的错误码记到stats，diy_stream也用同一个Stats时还有流和流量
+    pub fn with_stats(mut self,stats:Stats)->Self{
+        self.stats = Some(stats);
+        self
+    }
+
+    pub fn with_trust(mut self,trust:Trust)->Self{
+        self.trust = trust;
+        self
+    }
+
+    pub fn with_tuning(mut self,tuning:Tuning)->Self{
+        self.tuning = tuning;
+        self
+    }
+
+    //shutdown后最多等这么久让已有的流结束
+    pub fn with_drain(mut self,drain:Duration)->Self{
+        self.drain = drain;
+        self
+    }
+
+    //让run在下线并等已有的流结束后返回，可以在run之前调用
+    pub fn shutdown(&self){
+        self.shutdown.send_replace(true);
+    }
+
+    async fn stopping(&self){
+        self.shutdown.subscribe().wait_for(|s|*s).await.map(|_|()).unwrap_or_default();
+    }
+
+    pub fn subscribe(&self)->broadcast::Receiver<ConnEvent>{
+        self.events.subscribe()
+    }
+
+    fn publish(&self,event:ConnEvent){
+        match &event{
+            ConnEvent::Connecting{server,attempt}=>log::debug!(server:% = server, attempt:% = attempt; "connecting"),
+            ConnEvent::Connected{server}=>log::info!(server:% = server; "connected"),
+            ConnEvent::Disconnected{cause,error,uptime}=>log::warn!(cause:? = cause, code:% = error.code, error:% = error.msg, uptime:? = uptime; "disconnected"),
+            ConnEvent::Retry{cause,delay}=>log::info!(cause:? = cause, delay:? = delay; "retry"),
+            ConnEvent::Draining{streams}=>log::info!(streams:% = streams; "draining"),
+        }
+        if let Some(stats) = &self.stats{
+            stats.on_event(&event);
+        }
+        self.events.send(event).unwrap_or_default();//没有订阅者
+    }
+
+    //diy_stream按解析出的rwebs地址建，每次重连重新解析；shutdown后返回
+    #[cfg(feature="p2p")]
+    pub async fn run<D:DiyStream,K: PartialEq + Clone + 'static,L: P2pListen + 'static>(&self,diy_stream:impl Fn(SocketAddr)->D,p_client:Option<impl P2PListener<K,L>>){
+        self.supervise(diy_stream,|endpoint,connection,announced,diy|serve(endpoint,connection,announced,diy,p_client.clone())).await
+    }
+
+    #[cfg(not(feature="p2p"))]
+    pub async fn run<D:DiyStream>(&self,diy_stream:impl Fn(SocketAddr)->D){
+        self.supervise(diy_stream,serve).await
+    }
+
+    async fn supervise<D:DiyStream,F:Future<Output=Result<(),RwebError>>>(&self,diy_stream:impl Fn(SocketAddr)->D,serve:impl Fn(NodeEndpoint,Connection,Vec<Mac>,D)->F){
+        let dialer = Dialer::new(&self.trust,&self.tuning);
+        let mut attempt = 0;
+        let mut failures = 0;
+        loop{
+            attempt += 1;
+            let (cause,error,uptime) = self.once(attempt,&dialer,&diy_stream,&serve).await;
+            failures = if cause == Disconnect::Failback || uptime.is_some_and(|t|t >= self.backoff.stable) {1} else {failures + 1};
+            self.publish(ConnEvent::Disconnected{cause,error,uptime});
+            if cause == Disconnect::Shutdown{
+                return;
+            }
+            let delay = self.backoff.delay(cause,failures);
+            self.publish(ConnEvent::Retry{cause,delay});
+            select! {
+                _ = sleep(delay)=>{},
+                _ = self.stopping()=>{
+                    self.publish(ConnEvent::Disconnected{cause:Disconnect::Shutdown,error:RwebError::new(ERR_SHUTDOWN,"shutdown"),uptime:None});
+                    return;
+                }
+            }
+        }
+    }
+
+    //一次连接，返回断开原因
+    async fn once<D:DiyStream,F:Future<Output=Result<(),RwebError>>>(&self,attempt:u32,dialer:&Dialer,diy_stream:&impl Fn(SocketAddr)->D,serve:&impl Fn(NodeEndpoint,Connection,Vec<Mac>,D)->F)->(Disconnect,RwebError,Option<Duration>){
+        let groups = servers::order(&self.servers);
+        let connected = select! {
+            c = self.connect(attempt,dialer,&groups)=>c,
+            _ = self.stopping()=>Err((Disconnect::Shutdown,RwebError::new(ERR_SHUTDOWN,"shutdown"))),
+        };
+        let (connection,endpoint,level) = match connected{
+            Ok(c)=>c,
+            Err((cause,e))=>return (cause,e,None),
+        };
+        let server = rweb_common::socket::canonical(connection.remote_address());
+        let diy_stream = diy_stream(server);
+        let mac_list = diy_stream.mac_list();
+        if let Err(e) = announce(&connection,&mac_list).await{
+            return (Disconnect::from_error(&e),e,None);
+        }
+        self.publish(ConnEvent::Connected{server});
+        let start = Instant::now();
+        let serve = serve(endpoint.clone(),connection.clone(),mac_list,diy_stream);
+        tokio::pin!(serve);
+        let e = select! {
+            r = &mut serve=>r.err().unwrap_or(RwebError::new(-20,"closed")),
+            e = self.failback(dialer,&groups[..level])=>e,
+            _ = self.stopping()=>{
+                //先让rwebs不再分配新的流，已有的流在serve里继续跑
+                announce(&connection,&[]).await.unwrap_or_default();
+                select! {
+                    _ = &mut serve=>{},
+                    _ = timeout(self.drain,self.drained(&endpoint))=>{},
+                }
+                RwebError::new(ERR_SHUTDOWN,"shutdown")
+            }
+        };
+        endpoint.close(VarInt::from_u32(0),if e.code == ERR_SHUTDOWN {b"shutdown"} else {b"reconnect"});
+        (Disconnect::from_error(&e),e,Some(start.elapsed()))
+    }
+
+    //等这个endpoint上的流都结束，流数变化时发Draining
+    async fn drained(&self,endpoint:&NodeEndpoint){
+        let mut active = endpoint.streams.subscribe();
+        loop{
+            let streams = *active.borrow_and_update();
+            if streams == 0{
+                return;
+            }
+            self.publish(ConnEvent::Draining{streams});
+            if active.changed().await.is_err(){
+                return;
+            }
+        }
+    }
+
+    //按级别连接所有服务器的所有地址，返回连上的和它的级别
+    async fn connect(&self,attempt:u32,dialer:&Dialer,groups:&[Vec<ServerCell>])->Result<(Connection,NodeEndpoint,usize),(Disconnect,RwebError)>{
+        let attempts = servers::attempts(groups).await.map_err(|e|(Disconnect::Dns,e))?;
+        let endpoint = make_endpoint().map_err(|e|(Disconnect::Other,e))?;
+        let list:Vec<_> = attempts.iter().map(|a|(a.0,a.1)).collect();
+        let (connection,i) = connect_any(&endpoint,dialer,&list,|server|self.publish(ConnEvent::Connecting{server,attempt})).await
+            .map_err(|e|(Disconnect::from_error(&e),e))?;
+        Ok((connection,endpoint,attempts[i].2))
+    }
+
+    //定时试优先级更高的服务器，握手成功就返回，没有更高的时一直等
+    async fn failback(&self,dialer:&Dialer,higher:&[Vec<ServerCell>])->RwebError{
+        if higher.is_empty(){
+            return std::future::pending().await;
+        }
+        loop{
+            sleep(self.backoff.failback).await;
+            let Ok(attempts) = servers::attempts(higher).await else{
+                continue
+            };
+            let Ok(endpoint) = make_endpoint() else{
+                continue
+            };
+            let list:Vec<_> = attempts.iter().map(|a|(a.0,a.1)).collect();
+            if let Ok((connection,_)) = connect_any(&endpoint,dialer,&list,|_|{}).await{
+                connection.close(VarInt::from_u32(0),b"probe");
+                return RwebError::new(ERR_FAILBACK,format!("{} is back",rweb_common::socket::canonical(connection.remote_address())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use rwebs::quic_server::QuicServer;
+    use crate::testutil;
+
+    #[test]
+    fn backoff_delay(){
+        let b = Backoff::default();
+        let within = |d:Duration,base:Duration|d >= base && d <= base + base / 5 + Duration::from_millis(200);
+        assert!(within(b.delay(Disconnect::IdleTimeout,1),Duration::ZERO));
+        assert!(within(b.delay(Disconnect::IdleTimeout,2),Duration::from_secs(2)));
+        assert!(within(b.delay(Disconnect::Handshake,1),Duration::from_secs(1)));
+        assert!(within(b.delay(Disconnect::Handshake,4),Duration::from_secs(8)));
+        assert!(within(b.delay(Disconnect::Dns,1),Duration::from_secs(2)));
+        assert!(within(b.delay(Disconnect::Duplicate,1),DUPLICATE_WAIT));
+        assert!(within(b.delay(Disconnect::Handshake,40),b.max));
+    }
+
+    async fn next(events:&mut broadcast::Receiver<ConnEvent>)->ConnEvent{
+        tokio::time::timeout(Duration::from_secs(10),events.recv()).await.unwrap().unwrap()
+    }
+
+    //跳过Connecting，一个服务器有多个地址时会有多个
+    async fn connected(events:&mut broadcast::Receiver<ConnEvent>)->SocketAddr{
+        loop{
+            match next(events).await{
+                ConnEvent::Connecting{..}=>{},
+                ConnEvent::Connected{server}=>return server,
+                e=>panic!("{:?}",e)
+            }
+        }
+    }
+
+    fn spawn(supervisor:Supervisor,proxy_list:std::sync::Arc<Vec<rweb_common::proxy_list::ProxyList>>){
+        testutil::supervise(supervisor,move |server_addr|crate::quic_client::ProxyStringList::new(proxy_list.clone(),server_addr));
+    }
+
+    //解析失败按Dns退避；同一mac第二个节点被rwebs踢掉后至少等旧连接超时再连
+    #[tokio::test]
+    async fn supervisor_events(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dns = Supervisor::new("rwebs.invalid",3000);
+        let mut events = dns.subscribe();
+        spawn(dns,Default::default());
+        assert!(matches!(next(&mut events).await,ConnEvent::Disconnected{cause:Disconnect::Dns,uptime:None,..}));
+        assert!(matches!(next(&mut events).await,ConnEvent::Retry{cause:Disconnect::Dns,delay} if delay >= Duration::from_secs(2)));
+
+        let port = testutil::start(&QuicServer::default());
+        let mac = "aabbccdd0601".parse().unwrap();
+        let proxy_list = std::sync::Arc::new(vec![rweb_common::proxy_list::ProxyList::new(mac,url::Url::parse("http://127.0.0.1:1").unwrap()).unwrap()]);
+        let first = Supervisor::new("127.0.0.1",port);
+        let mut first_events = first.subscribe();
+        spawn(first,proxy_list.clone());
+        assert!(matches!(next(&mut first_events).await,ConnEvent::Connecting{attempt:1,..}));
+        assert!(matches!(next(&mut first_events).await,ConnEvent::Connected{server} if server.port() == port));
+        sleep(Duration::from_millis(200)).await;
+        let second = Supervisor::new("127.0.0.1",port);
+        let mut events = second.subscribe();
+        spawn(second,proxy_list);
+        connected(&mut events).await;
+        assert!(matches!(next(&mut events).await,ConnEvent::Disconnected{cause:Disconnect::Duplicate,..}));
+        assert!(matches!(next(&mut events).await,ConnEvent::Retry{cause:Disconnect::Duplicate,delay} if delay >= DUPLICATE_WAIT));
+        assert!(first_events.try_recv().is_err());//先连上的不受影响
+    }
+
+    //主服务器不回应时2秒后连备用，解析不出的跳过；主服务器恢复后换回去
+    #[tokio::test]
+    async fn supervisor_failback(){
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dead = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let primary = dead.local_addr().unwrap().port();
+        let backup = testutil::start(&QuicServer::default());
+        let servers = vec![
+            ServerCell::new("rwebs.invalid",primary),
+            ServerCell::new("127.0.0.1",primary),
+            ServerCell::new("127.0.0.1",backup).with_priority(1),
+        ];
+        let supervisor = Supervisor::with_servers(servers).with_backoff(Backoff{failback:Duration::from_secs(1),..Default::default()});
+        let mut events = supervisor.subscribe();
+        let proxy_list = std::sync::Arc::new(vec![rweb_common::proxy_list::ProxyList::new("aabbccdd0602".parse().unwrap(),url::Url::parse("http://127.0.0.1:1").unwrap()).unwrap()]);
+        spawn(supervisor,proxy_list);
+        assert_eq!(connected(&mut events).await.port(),backup);
+        testutil::start_on(&QuicServer::default(),dead);//主服务器恢复
+        assert!(matches!(next(&mut events).await,ConnEvent::Disconnected{cause:Disconnect::Failback,..}));
+        assert!(matches!(next(&mut events).await,ConnEvent::Retry{cause:Disconnect::Failback,delay} if delay < Duration::from_secs(1)));
+        assert_eq!(connected(&mut events).await.port(),primary);
+    }
+}
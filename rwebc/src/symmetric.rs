@@ -1,52 +1,215 @@
-use std::net::SocketAddr;
-use tokio::time::{timeout, Duration};
-use rweb_common::RwebError;
+use std::{fmt::Display, net::SocketAddr};
+use futures::{stream::FuturesUnordered, StreamExt};
+use tokio::time::{timeout, Duration, Instant};
+use rweb_common::{nat::{NatBehavior, NatProfile}, RwebError};
 use quinn::{Connection, Endpoint};
+use crate::quic_client::NodeEndpoint;
 
-pub async fn symmetric_connect(endpoint:Endpoint,addr:SocketAddr,port_len:u16,cell_timeout:Duration)->Result<Connection,RwebError>{
-    let mut joins = vec![];
-    for i in 0..port_len{
-        let port = 
-        match addr.port(){
-            0..1025=>{
-                if 65535 - addr.port() < i{addr.port() + i}else{addr.port() + i}//一般不允许小雨1024
-            },
-            1025..16384=>{
-                if 65535 - addr.port() < i{addr.port() - 1025 + i}else{addr.port() + i}//可能是自定义的
-            },
-            13684..32768=>{
-                if 32767 - addr.port() < i{addr.port() - 16384 + i}else{addr.port() + i}//家用设备
-            },
-            32768..49152=>{
-                if 60999 - addr.port() < i{addr.port() - 32768 + i}else{addr.port() + i}//可能是自定义的
-            },
-            49152..60100=>{
-                //if 60999 - addr.port() < i{addr.port() - 32767 + i}else{addr.port() + i}//linux默认                
-                if 65535 - addr.port() < i{addr.port() - 49152 + i}else{addr.port() + i}//RFC 6056，windows默认
+//对端随机分配端口时猜的端口数
+pub const BIRTHDAY_PORTS:u16 = 256;
+//本机随机分配端口时额外开的本地socket数，每个socket在NAT上各占一个映射
+pub const BIRTHDAY_SOCKETS:u16 = 32;
+const MIN_PORT:i32 = 1024;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum PunchKind{
+    Direct,//对端映射与目标无关，直接连对端地址
+    Sequential,//对端按步长分配，从对端地址按步长往后猜
+    Birthday,//对端随机分配，随机猜端口，本机也随机时多开socket凑生日悖论
+}
+
+impl Display for PunchKind{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self{
+            PunchKind::Direct => "direct",
+            PunchKind::Sequential => "sequential",
+            PunchKind::Birthday => "birthday",
+        })
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct PunchPlan{
+    pub kind:PunchKind,
+    pub addr:SocketAddr,//rwebs看到的对端地址
+    pub delta:i32,
+    pub ports:u16,//尝试的对端端口数
+    pub sockets:u16,//本地socket数
+}
+
+impl PunchPlan{
+    //peer为对端NAT画像，own为本机NAT画像；旧版对端没有画像时按Nat-Type判断，symmetric为true表示对端是对称NAT
+    pub fn new(addr:SocketAddr,peer:Option<NatProfile>,own:Option<NatProfile>,symmetric:bool)->Self{
+        let (kind,delta,ports) = match peer{
+            Some(peer) if peer.mapping == NatBehavior::EndpointIndependent => (PunchKind::Direct,0,1),
+            Some(peer) if peer.is_random() => (PunchKind::Birthday,0,BIRTHDAY_PORTS),
+            Some(peer) => (PunchKind::Sequential,peer.port_delta,peer.port_len(crate::PORT_LEN)),
+            None if symmetric => (PunchKind::Sequential,1,crate::PORT_LEN),
+            None => (PunchKind::Direct,0,1),
+        };
+        //对端过滤与端口有关时只认本机主socket的映射，多开socket没用
+        let peer_apd = peer.is_none_or(|p|p.filtering == NatBehavior::AddressAndPortDependent);
+        let sockets = match own{
+            Some(own) if own.is_random() && !peer_apd => BIRTHDAY_SOCKETS,
+            _ => 1
+        };
+        Self{kind,addr,delta,ports,sockets}
+    }
+
+    //依次要尝试的对端地址，端口越界时回绕到1024..=65535
+    pub fn targets(&self)->Vec<SocketAddr>{
+        let wrap = |port:i32|(MIN_PORT + (port - MIN_PORT).rem_euclid(65536 - MIN_PORT)) as u16;
+        match self.kind{
+            PunchKind::Direct => vec![self.addr],
+            PunchKind::Sequential => {
+                let delta = if self.delta == 0 {1} else {self.delta};
+                (0..self.ports as i32).map(|k|SocketAddr::new(self.addr.ip(),wrap(self.addr.port() as i32 + k * delta))).collect()
             },
-            60100..=65535=>{
-                if 65535 - addr.port() < i{addr.port() - 49152 + i}else{addr.port() + i}//RFC 6056，windows默认
+            PunchKind::Birthday => {
+                let mut targets = vec![self.addr];
+                while targets.len() < self.ports.max(1) as usize{
+                    let target = SocketAddr::new(self.addr.ip(),fastrand::u16(MIN_PORT as u16..=65535));
+                    if !targets.contains(&target){
+                        targets.push(target);
+                    }
+                }
+                targets
+            }
+        }
+    }
+}
+
+//一次打洞的上限，尝试次数和总时间先到哪个算哪个
+#[derive(Debug,Clone,Copy)]
+pub struct PunchBudget{
+    pub max_attempts:u32,
+    pub concurrency:usize,//同时在途的连接数
+    pub cell_timeout:Duration,//单次连接超时
+    pub deadline:Duration,
+}
+
+impl Default for PunchBudget{
+    fn default()->Self{
+        Self{max_attempts:2 * crate::PORT_LEN as u32,concurrency:256,cell_timeout:Duration::from_secs(3),deadline:Duration::from_secs(15)}
+    }
+}
+
+//每次打洞的结果，记录试过什么
+#[derive(Debug,Clone)]
+pub struct PunchReport{
+    pub plan:PunchPlan,
+    pub attempts:u32,
+    pub targets:usize,//不同的对端地址数
+    pub failed:u32,//连接出错的次数，其余为超时或被取消
+    pub elapsed:Duration,
+    pub remote:Option<SocketAddr>,//成功时对端地址
+    pub local:Option<SocketAddr>,//成功时使用的本地socket
+}
+
+impl Display for PunchReport{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f,"punch {} {} delta:{} sockets:{} attempts:{} targets:{} failed:{} elapsed:{:?} ",
+            self.plan.kind,self.plan.addr,self.plan.delta,self.plan.sockets,self.attempts,self.targets,self.failed,self.elapsed)?;
+        match (self.remote,self.local){
+            (Some(remote),Some(local))=>write!(f,"ok {}->{}",local,remote),
+            (Some(remote),None)=>write!(f,"ok {}",remote),
+            _=>f.write_str("fail")
+        }
+    }
+}
+
+//按计划打洞，endpoint为主socket，extra为额外的本地socket(plan.sockets-1个)
+//第i次尝试使用第i%sockets个socket，对端地址轮流使用，直到成功或用完预算
+pub async fn punch(endpoint:&Endpoint,extra:&[NodeEndpoint],plan:PunchPlan,budget:PunchBudget)->(Result<Connection,RwebError>,PunchReport){
+    let start = Instant::now();
+    let targets = plan.targets();
+    let mut sockets = vec![endpoint];
+    sockets.extend(extra.iter().take(plan.sockets.saturating_sub(1) as usize).map(|e|&**e));
+    let mut report = PunchReport{plan,attempts:0,targets:targets.len(),failed:0,elapsed:Duration::ZERO,remote:None,local:None};
+    let mut pending = FuturesUnordered::new();
+    let mut last_err = RwebError::new(5034,"timeout".to_string());
+    loop{
+        let left = budget.deadline.saturating_sub(start.elapsed());
+        while pending.len() < budget.concurrency.max(1) && report.attempts < budget.max_attempts && !left.is_zero(){
+            let i = report.attempts as usize;
+            let socket = sockets[i % sockets.len()];
+            let target = targets[(i / sockets.len()) % targets.len()];
+            pending.push(p2p_cell(socket.clone(),target,budget.cell_timeout.min(left)));
+            report.attempts += 1;
+            if pending.len() >= targets.len() * sockets.len(){
+                break;//单个地址时每轮只发一次，等这一轮结束再重试
             }
+        }
+        let next = match timeout(left,pending.next()).await{
+            Ok(Some(next))=>next,
+            _=>break
         };
-        joins.push(Box::pin(p2p_cell(endpoint.clone(), SocketAddr::new(addr.ip(), port),cell_timeout)));
+        match next{
+            Ok((conn,local))=>{
+                report.remote = Some(rweb_common::socket::canonical(conn.remote_address()));
+                report.local = local;
+                report.elapsed = start.elapsed();
+                return (Ok(conn),report);
+            },
+            Err(e)=>{
+                if e.code != 5034{
+                    report.failed += 1;
+                }
+                last_err = e;
+            }
+        }
     }
-    let a = futures::future::select_all(joins).await;
-    a.0
+    report.elapsed = start.elapsed();
+    (Err(RwebError::new(56,format!("p2p punch failed:{}",last_err.msg))),report)
 }
 
-async fn p2p_cell(endpoint:Endpoint,addr:SocketAddr,tmo:Duration)->Result<Connection,RwebError>{
-    let p2p_conn = endpoint.connect(addr, "reform").map_err(|e|RwebError::new(5032,e))?;
+//额外的本地socket，建不出来的跳过
+pub(crate) fn extra_endpoints(main:&NodeEndpoint,sockets:u16)->Vec<NodeEndpoint>{
+    (1..sockets).filter_map(|_|crate::quic_client::make_endpoint().ok()).map(|mut e|{
+        e.streams = main.streams.clone();//额外socket上接进来的流也算在主endpoint上
+        e
+    }).collect()
+}
+
+async fn p2p_cell(endpoint:Endpoint,addr:SocketAddr,tmo:Duration)->Result<(Connection,Option<SocketAddr>),RwebError>{
+    let p2p_conn = endpoint.connect_with(crate::quic_client::configure_p2p_client(), addr, "reform").map_err(|e|RwebError::new(5032,e))?;
     match timeout(tmo,p2p_conn).await{
-        Ok(Ok(p2p_conn)) => {
-            Ok(p2p_conn)
-        },
-        Ok(Err(_e)) => {
-            tokio::time::sleep(tmo).await;
-            Err(RwebError::new(5033,_e))
+        Ok(Ok(p2p_conn)) => Ok((p2p_conn,endpoint.local_addr().ok())),
+        Ok(Err(e)) => {
+            tokio::time::sleep(tmo).await;//对方拒绝时不要立刻重试同一地址
+            Err(RwebError::new(5033,e))
         },
-        Err(_e) => {
-            tokio::time::sleep(tmo).await;
-            Err(RwebError::new(5034,"timeout".to_string()))
-        }   
+        Err(_e) => Err(RwebError::new(5034,"timeout".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use rweb_common::nat::allocation_delta;
+
+    #[test]
+    fn delta(){
+        assert_eq!(allocation_delta(&[40000,40001,40002,40003]),Some(1));
+        assert_eq!(allocation_delta(&[40000,40002,40003,40007]),Some(1));
+        assert_eq!(allocation_delta(&[40000,39998,39996]),Some(-2));
+        assert_eq!(allocation_delta(&[40000,12345,55000,30001]),None);
+        assert_eq!(allocation_delta(&[40000,40000]),None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn plan(){
+        let addr:SocketAddr = "1.2.3.4:65534".parse().unwrap();
+        let ei = NatProfile{mapping:NatBehavior::EndpointIndependent,filtering:NatBehavior::AddressDependent,port_delta:0};
+        let seq = NatProfile{mapping:NatBehavior::AddressAndPortDependent,filtering:NatBehavior::AddressDependent,port_delta:2};
+        let random = NatProfile{mapping:NatBehavior::AddressAndPortDependent,filtering:NatBehavior::AddressDependent,port_delta:0};
+        assert_eq!(PunchPlan::new(addr,Some(ei),None,true).targets(),vec![addr]);
+        let p = PunchPlan::new(addr,Some(seq),None,false);
+        assert_eq!(p.kind,PunchKind::Sequential);
+        assert_eq!(&p.targets()[..3],&["1.2.3.4:65534".parse().unwrap(),"1.2.3.4:1024".parse().unwrap(),"1.2.3.4:1026".parse().unwrap()]);
+        let p = PunchPlan::new(addr,Some(random),Some(random),false);
+        assert_eq!((p.kind,p.sockets),(PunchKind::Birthday,BIRTHDAY_SOCKETS));
+        assert_eq!(p.targets().len(),BIRTHDAY_PORTS as usize);
+        assert_eq!(PunchPlan::new(addr,None,Some(random),true).sockets,1);
+    }
+}
@@ -0,0 +1,76 @@
+use std::{net::{SocketAddr, UdpSocket}, sync::Arc};
+use rustls::{pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}, ServerConfig};
+use rweb_common::{key::{CER_BIN, KEY_BIN}, mac::Mac};
+use rwebs::quic_server::QuicServer;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, time::{sleep, timeout, Duration}};
+use crate::{quic_client::{make_endpoint, run_diy_stream_on, DiyStream}, supervisor::Supervisor};
+
+//在127.0.0.1的随机端口上启动rwebs，返回端口
+pub(crate) fn start(server:&QuicServer)->u16{
+    start_on(server,UdpSocket::bind("127.0.0.1:0").unwrap())
+}
+
+//在绑定好的socket上启动rwebs，端口不会在绑定和启动之间被别人占去
+pub(crate) fn start_on(server:&QuicServer,socket:UdpSocket)->u16{
+    let port = socket.local_addr().unwrap().port();
+    let server = server.clone();
+    tokio::spawn(async move{
+        server.start_on(socket,None).await.unwrap_or_default();
+    });
+    port
+}
+
+//在127.0.0.1的随机端口上启动rwebs给访问者的前端，返回地址
+pub(crate) async fn web(server:&QuicServer)->SocketAddr{
+    let cert = CertificateDer::pem_slice_iter(CER_BIN.as_bytes()).collect::<Result<Vec<_>,_>>().unwrap();
+    let tls_config = Arc::new(ServerConfig::builder().with_no_client_auth().with_single_cert(cert,PrivateKeyDer::from_pem_slice(KEY_BIN.as_bytes()).unwrap()).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = server.clone();
+    tokio::spawn(async move{
+        while let Ok((stream,_)) = listener.accept().await{
+            tokio::spawn(rwebs::http_server::handle_stream(stream,server.clone(),tls_config.clone(),Default::default()));
+        }
+    });
+    addr
+}
+
+//以访问者身份在前端上发原始的请求，读到连接关闭
+pub(crate) async fn send(addr:SocketAddr,request:&str)->String{
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    timeout(Duration::from_secs(5),stream.read_to_string(&mut response)).await.unwrap().unwrap();
+    response
+}
+
+//不带p2p运行设备
+pub(crate) fn run(server_addr:SocketAddr,diy_stream:impl DiyStream){
+    #[cfg(feature="p2p")]
+    tokio::spawn(run_diy_stream_on(make_endpoint().unwrap(),server_addr,diy_stream,None::<crate::p2p_client::DiyTcpListener>));
+    #[cfg(not(feature="p2p"))]
+    tokio::spawn(run_diy_stream_on(make_endpoint().unwrap(),server_addr,diy_stream));
+}
+
+//不带p2p由supervisor运行设备
+pub(crate) fn supervise<D:DiyStream>(supervisor:Supervisor,diy_stream:impl Fn(SocketAddr)->D + Send + Sync + 'static){
+    tokio::spawn(async move{
+        #[cfg(feature="p2p")]
+        supervisor.run(diy_stream,None::<crate::p2p_client::DiyTcpListener>).await;
+        #[cfg(not(feature="p2p"))]
+        supervisor.run(diy_stream).await;
+    });
+}
+
+//等到rwebs上这些mac都在线
+pub(crate) async fn registered(server:&QuicServer,mac_list:&[Mac]){
+    timeout(Duration::from_secs(10),async{
+        loop{
+            let online = server.devices().await;
+            if mac_list.iter().all(|m|online.iter().any(|(o,_)|o == m)){
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    }).await.unwrap();
+}
@@ -0,0 +1,41 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+use rweb_common::mac::Mac;
+use tokio::time::Instant;
+
+//rwebs在p2p请求里发给本机的票据，直连进来的连接出示后才处理
+//票据一次性，只能由发票据时的请求方使用，只能访问发票据时的目标设备
+#[derive(Debug,Clone,Default)]
+pub(crate) struct Tickets(Arc<Mutex<HashMap<String,Ticket>>>);
+
+#[derive(Debug,Clone,Copy)]
+pub(crate) struct Ticket{
+    pub(crate) target:Mac,//对端要访问的本机设备
+    pub(crate) requester:Mac,//rwebs告诉的请求方，出示票据时必须一致
+    expires:Instant,
+}
+
+impl Tickets{
+    pub(crate) fn insert(&self,ticket:String,target:Mac,requester:Mac){
+        let expires = Instant::now() + tokio::time::Duration::from_secs(rweb_common::io::header::P2P_TICKET_SECS);
+        if let Ok(mut t) = self.0.lock(){
+            t.insert(ticket,Ticket{target,requester,expires});
+        }
+    }
+
+    //有未过期的票据时才可能有对端连进来，否则进来的连接都是未经请求的
+    pub(crate) fn pending(&self)->bool{
+        self.0.lock().is_ok_and(|mut t|{
+            let now = Instant::now();
+            t.retain(|_,ticket|ticket.expires > now);
+            !t.is_empty()
+        })
+    }
+
+    //验证并用掉票据，target和requester必须与发票据时一致，不一致时票据不作废
+    pub(crate) fn take(&self,ticket:&str,target:Mac,requester:Mac)->Option<Ticket>{
+        let mut t = self.0.lock().ok()?;
+        let found = t.get(ticket).filter(|found|found.target == target && found.requester == requester && found.expires > Instant::now()).copied()?;
+        t.remove(ticket);
+        Some(found)
+    }
+}
@@ -0,0 +1,186 @@
+use std::{any::Any, sync::Arc};
+use quinn::{crypto::{self, rustls::QuicServerConfig, ExportKeyingMaterialError, HeaderKey, KeyPair, Keys, PacketKey, Session, UnsupportedVersion}, ConnectionId, Side};
+use quinn_proto::{transport_parameters::TransportParameters, TransportError};
+
+//开启h3后rustls要求quic连接的ALPN必须匹配，不带ALPN的设备(rwebc都不带)会被拒绝
+//这里先看ClientHello：带ALPN的(浏览器h3、带ALPN_RWEB的设备)用alpn，不带的用plain(只有设备证书)
+pub(crate) struct OptionalAlpn{
+    pub alpn:Arc<QuicServerConfig>,
+    pub plain:Arc<QuicServerConfig>,
+}
+
+impl crypto::ServerConfig for OptionalAlpn{
+    fn initial_keys(&self,version:u32,dst_cid:&ConnectionId)->Result<Keys,UnsupportedVersion>{
+        self.alpn.initial_keys(version,dst_cid)
+    }
+
+    fn retry_tag(&self,version:u32,orig_dst_cid:&ConnectionId,packet:&[u8])->[u8;16]{
+        self.alpn.retry_tag(version,orig_dst_cid,packet)
+    }
+
+    fn start_session(self:Arc<Self>,version:u32,params:&TransportParameters)->Box<dyn Session>{
+        Box::new(PendingSession{
+            session:self.alpn.clone().start_session(version,params),
+            plain:Some(self.plain.clone().start_session(version,params)),
+            hello:Some(Vec::new()),
+        })
+    }
+}
+
+//ClientHello收全之前什么都不交给rustls，session还没有读过数据，其他方法可以直接用它
+struct PendingSession{
+    session:Box<dyn Session>,
+    plain:Option<Box<dyn Session>>,
+    hello:Option<Vec<u8>>,
+}
+
+impl Session for PendingSession{
+    fn initial_keys(&self,dst_cid:&ConnectionId,side:Side)->Keys{
+        self.session.initial_keys(dst_cid,side)
+    }
+
+    fn handshake_data(&self)->Option<Box<dyn Any>>{
+        self.session.handshake_data()
+    }
+
+    fn peer_identity(&self)->Option<Box<dyn Any>>{
+        self.session.peer_identity()
+    }
+
+    fn early_crypto(&self)->Option<(Box<dyn HeaderKey>,Box<dyn PacketKey>)>{
+        self.session.early_crypto()
+    }
+
+    fn early_data_accepted(&self)->Option<bool>{
+        self.session.early_data_accepted()
+    }
+
+    fn is_handshaking(&self)->bool{
+        self.session.is_handshaking()
+    }
+
+    fn read_handshake(&mut self,buf:&[u8])->Result<bool,TransportError>{
+        let Some(hello) = self.hello.as_mut() else{
+            return self.session.read_handshake(buf);
+        };
+        hello.extend_from_slice(buf);
+        let Some(alpn) = offers_alpn(hello) else{
+            return Ok(false);//ClientHello可能分在几个Initial包里
+        };
+        if !alpn && let Some(plain) = self.plain.take(){
+            self.session = plain;
+        }
+        self.plain = None;
+        let hello = self.hello.take().unwrap_or_default();
+        self.session.read_handshake(&hello)
+    }
+
+    fn transport_parameters(&self)->Result<Option<TransportParameters>,TransportError>{
+        self.session.transport_parameters()
+    }
+
+    fn write_handshake(&mut self,buf:&mut Vec<u8>)->Option<Keys>{
+        self.session.write_handshake(buf)
+    }
+
+    fn next_1rtt_keys(&mut self)->Option<KeyPair<Box<dyn PacketKey>>>{
+        self.session.next_1rtt_keys()
+    }
+
+    fn is_valid_retry(&self,orig_dst_cid:&ConnectionId,header:&[u8],payload:&[u8])->bool{
+        self.session.is_valid_retry(orig_dst_cid,header,payload)
+    }
+
+    fn export_keying_material(&self,output:&mut [u8],label:&[u8],context:&[u8])->Result<(),ExportKeyingMaterialError>{
+        self.session.export_keying_material(output,label,context)
+    }
+}
+
+const CLIENT_HELLO:u8 = 1;
+const EXT_ALPN:u16 = 16;
+
+//ClientHello里有没有ALPN扩展，没收全时为None；格式不对时当作没有，交给rustls报错
+fn offers_alpn(buf:&[u8])->Option<bool>{
+    if buf.len() < 4{
+        return None;
+    }
+    if buf[0] != CLIENT_HELLO{
+        return Some(false);
+    }
+    let len = u32::from_be_bytes([0,buf[1],buf[2],buf[3]]) as usize;
+    let body = buf.get(4..4 + len)?;
+    Some(hello_extensions(body).is_some_and(|ext|ext.contains(&EXT_ALPN)))
+}
+
+fn hello_extensions(body:&[u8])->Option<Vec<u16>>{
+    let mut r = Reader(body);
+    r.take(2 + 32)?;//legacy_version random
+    let n = r.u8()? as usize;
+    r.take(n)?;//legacy_session_id
+    let n = r.u16()? as usize;
+    r.take(n)?;//cipher_suites
+    let n = r.u8()? as usize;
+    r.take(n)?;//legacy_compression_methods
+    let n = r.u16()? as usize;
+    let mut ext = Reader(r.take(n)?);
+    let mut types = Vec::new();
+    while !ext.0.is_empty(){
+        types.push(ext.u16()?);
+        let n = ext.u16()? as usize;
+        ext.take(n)?;
+    }
+    Some(types)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a>{
+    fn take(&mut self,n:usize)->Option<&'a [u8]>{
+        if self.0.len() < n{
+            return None;
+        }
+        let (head,rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self)->Option<u8>{
+        self.take(1).map(|b|b[0])
+    }
+
+    fn u16(&mut self)->Option<u16>{
+        self.take(2).map(|b|u16::from_be_bytes([b[0],b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn hello(extensions:&[(u16,&[u8])])->Vec<u8>{
+        let mut body = vec![3,3];
+        body.extend([0;32]);
+        body.extend([0]);//session id
+        body.extend([0,2,0x13,0x01]);//cipher suites
+        body.extend([1,0]);//compression
+        let ext:Vec<u8> = extensions.iter().flat_map(|(t,d)|[t.to_be_bytes().to_vec(),(d.len() as u16).to_be_bytes().to_vec(),d.to_vec()].concat()).collect();
+        body.extend((ext.len() as u16).to_be_bytes());
+        body.extend(ext);
+        let len = (body.len() as u32).to_be_bytes();
+        [vec![CLIENT_HELLO,len[1],len[2],len[3]],body].concat()
+    }
+
+    #[test]
+    fn client_hello_alpn(){
+        let with = hello(&[(0,b"\x00"),(EXT_ALPN,b"\x00\x03\x02h3")]);
+        assert_eq!(offers_alpn(&with),Some(true));
+        assert_eq!(offers_alpn(&with[..with.len() - 1]),None);//分包
+        assert_eq!(offers_alpn(&hello(&[(0,b"\x00"),(57,b"")])),Some(false));
+        assert_eq!(offers_alpn(&[2,0,0,0]),Some(false));
+        let mut broken = hello(&[(EXT_ALPN,b"")]);
+        broken.truncate(broken.len() - 2);
+        let len = (broken.len() as u32 - 4).to_be_bytes();
+        broken[1..4].copy_from_slice(&len[1..]);
+        assert_eq!(offers_alpn(&broken),Some(false));
+    }
+}
@@ -0,0 +1,168 @@
+use std::{convert::Infallible, error::Error};
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{body::Incoming, header::{self, HeaderMap, HeaderName, HeaderValue}, service::service_fn, Method, Request, Response, StatusCode, Version};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rweb_common::{io::header::{internal_method, Visitor, RWEB_CLIENT, RWEB_SESSION}, mac::Mac};
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::quic_server::QuicServer;
+
+pub const ALPN_H2:&[u8] = b"h2";
+pub const ALPN_HTTP11:&[u8] = b"http/1.1";
+
+type Body = BoxBody<Bytes, hyper::Error>;
+
+//hop-by-hop头不能在http/2和http/1.1之间透传
+const HOP_HEADERS:[&str;8] = ["connection","keep-alive","proxy-connection","transfer-encoding","upgrade","te","trailer","http2-settings"];
+
+//浏览器通过ALPN协商到h2后走这里，每个h2流对应设备上的一条bi流，并在设备侧转换为http/1.1
+//没有开启enable_connect_protocol，即不支持RFC 8441的websocket over h2，浏览器会另开http/1.1连接走websocket
+//alt_svc为同端口上h3的Alt-Svc头，浏览器据此切换到http/3
+//visitor为浏览器的地址和这个连接的编号，每个请求都带给设备
+pub async fn serve_h2<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: T, quic_server: QuicServer, server_name: Option<String>, alt_svc: Option<HeaderValue>, visitor: Visitor) -> Result<(), Box<dyn Error+Send+Sync>> {
+    let service = service_fn(move |req: Request<Incoming>| {
+        let quic_server = quic_server.clone();
+        let server_name = server_name.clone();
+        let alt_svc = alt_svc.clone();
+        async move {
+            let mut resp = handle_request(req, quic_server, server_name, visitor).await.unwrap_or_else(|(status, msg)| {
+                log::debug!("h2 request error:{} {}", status, msg);
+                text_response(status, msg)
+            });
+            if let Some(alt_svc) = alt_svc {
+                resp.headers_mut().insert(header::ALT_SVC, alt_svc);
+            }
+            Ok::<_, Infallible>(resp)
+        }
+    });
+    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+        .serve_connection(TokioIo::new(stream), service)
+        .await?;
+    Ok(())
+}
+
+async fn handle_request(mut req: Request<Incoming>, quic_server: QuicServer, server_name: Option<String>, visitor: Visitor) -> Result<Response<Body>, (StatusCode, String)> {
+    let authority = req.uri().authority().map(|a| a.to_string())
+        .or(req.headers().get(header::HOST).and_then(|h| h.to_str().ok()).map(|h| h.to_string()))
+        .ok_or((StatusCode::BAD_REQUEST, "not found authority".to_string()))?;
+    let mac = host_mac(server_name.as_deref().unwrap_or(&authority))?;
+    log::info!("h2 method: {}, uri: {}, mac: {}", req.method(), req.uri(), mac);
+    if req.method() == Method::CONNECT {//http_proxy走h2时的CONNECT，转成设备侧的http/1.1 CONNECT
+        let mut device_stream = quic_server.open_connect(mac, &authority, Some(&visitor)).await.map_err(|e| (StatusCode::BAD_GATEWAY, e.msg))?;
+        tokio::spawn(async move {
+            match hyper::upgrade::on(&mut req).await {
+                Ok(upgraded) => {
+                    tokio::io::copy_bidirectional(&mut TokioIo::new(upgraded), &mut device_stream).await.unwrap_or_default();
+                },
+                Err(e) => log::debug!("h2 connect upgrade error:{}", e),
+            }
+        });
+        return Ok(Response::new(empty_body()));
+    }
+    let resp = forward_request(&quic_server, mac, &authority, req, visitor).await?;
+    Ok(resp.map(|body| body.boxed()))
+}
+
+pub(crate) fn host_mac(host: &str) -> Result<Mac, (StatusCode, String)> {
+    host.split('.').next().unwrap_or_default().try_into().map_err(|e| (StatusCode::BAD_REQUEST, format!("host error:{}", e)))
+}
+
+//h2和h3共用，把请求转成http/1.1发到设备的bi流上
+pub(crate) async fn forward_request<B>(quic_server: &QuicServer, mac: Mac, authority: &str, req: Request<B>, visitor: Visitor) -> Result<Response<Incoming>, (StatusCode, String)>
+where
+    B: hyper::body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn Error+Send+Sync>>,
+{
+    if internal_method(req.method().as_str()) {//不能冒充rwebs给设备发命令
+        return Err((StatusCode::BAD_REQUEST, format!("method {} not allowed", req.method())));
+    }
+    let device_stream = quic_server.open_target(mac).await.map_err(|e| match e.code {
+        503 => (StatusCode::SERVICE_UNAVAILABLE, e.msg),//设备报告目标不可达
+        _ => (StatusCode::BAD_GATEWAY, e.msg),
+    })?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake::<_, B>(TokioIo::new(device_stream)).await.map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            log::debug!("device http/1.1 connection error:{}", e);
+        }
+    });
+    let (mut parts, body) = req.into_parts();
+    parts.version = Version::HTTP_11;
+    parts.uri = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/").parse().map_err(|_| (StatusCode::BAD_REQUEST, "uri error".to_string()))?;
+    remove_hop_headers(&mut parts.headers);
+    parts.headers.insert(header::HOST, HeaderValue::from_str(authority).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?);
+    for (name, value) in [(RWEB_CLIENT, visitor.ip.to_string()), (RWEB_SESSION, visitor.session.to_string())] {//替换掉浏览器自己带的
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(&value)) {
+            parts.headers.insert(name, value);
+        }
+    }
+    let resp = sender.send_request(Request::from_parts(parts, body)).await.map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+    let (mut parts, body) = resp.into_parts();
+    remove_hop_headers(&mut parts.headers);
+    Ok(Response::from_parts(parts, body))
+}
+
+fn remove_hop_headers(headers: &mut HeaderMap) {
+    for name in HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+fn empty_body() -> Body {
+    Full::new(Bytes::new()).map_err(|never| match never {}).boxed()
+}
+
+pub(crate) fn text_response(status: StatusCode, msg: String) -> Response<Body> {
+    let mut resp = Response::new(Full::new(Bytes::from(msg)).map_err(|never| match never {}).boxed());
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use http_body_util::Empty;
+    use rweb_common::Header;
+    use crate::testutil;
+
+    //设备回应看到的请求行和头
+    fn echo(_mac: Mac, header: Header) -> String {
+        let get = |name: &str| header.header.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone()).unwrap_or_default();
+        let body = format!("{} {} {} host={} client={} session={}", header.method, header.uri, header.version, get("host"), get(RWEB_CLIENT), get(RWEB_SESSION));
+        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    async fn get(sender: &mut hyper::client::conn::http2::SendRequest<Empty<Bytes>>, uri: &str) -> (StatusCode, String) {
+        let req = Request::get(uri).header(RWEB_CLIENT, "1.2.3.4").body(Empty::new()).unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        let status = resp.status();
+        (status, String::from_utf8_lossy(&resp.into_body().collect().await.unwrap().to_bytes()).to_string())
+    }
+
+    //h2请求在rwebs上转成http/1.1发给设备，访问者地址替换掉浏览器自己带的，设备不在线时返回502，rwebs自己的命令方法返回400
+    #[tokio::test]
+    async fn h2_forward() {
+        let server = QuicServer::default();
+        let port = testutil::start(&server, false);
+        let mac: Mac = "aabbccdd2601".parse().unwrap();
+        let _device = testutil::device(&server, port, &[mac], echo).await;
+        let (client, server_io) = tokio::io::duplex(64 * 1024);
+        let visitor = Visitor { ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9)), session: 26 };
+        tokio::spawn(serve_h2(server_io, server.clone(), None, None, visitor));
+        let (mut sender, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(client)).await.unwrap();
+        tokio::spawn(conn);
+
+        let (status, body) = get(&mut sender, "https://aabbccdd2601.abc.com/a?b=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "GET /a?b=1 HTTP/1.1 host=aabbccdd2601.abc.com client=10.0.0.9 session=26");
+        let (status, _) = get(&mut sender, "https://aabbccdd2602.abc.com/").await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let (status, _) = get(&mut sender, "https://nomac.abc.com/").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let p2p = Request::builder().method("P2P").uri("https://aabbccdd2601.abc.com/p2p").body(Empty::new()).unwrap();
+        assert_eq!(sender.send_request(p2p).await.unwrap().status(), StatusCode::BAD_REQUEST);//不能冒充rwebs的命令
+    }
+}
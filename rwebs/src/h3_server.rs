@@ -0,0 +1,96 @@
+use std::error::Error;
+use bytes::{Buf, Bytes};
+use http_body_util::{BodyExt, Channel};
+use hyper::{body::Frame, header, Request, Response, StatusCode};
+use quinn::Connection;
+use rweb_common::io::header::Visitor;
+use crate::{h2_server::{forward_request, host_mac, text_response}, quic_server::QuicServer};
+
+pub const ALPN_H3:&[u8] = b"h3";
+
+type H3Stream<S> = h3::server::RequestStream<S, Bytes>;
+
+//与设备共用同一个quic Endpoint，握手时ALPN为h3的连接走这里
+pub async fn serve_h3(conn: Connection, quic_server: QuicServer, server_name: Option<String>) -> Result<(), Box<dyn Error+Send+Sync>> {
+    log::info!("h3 connection from {}", conn.remote_address());
+    let visitor = crate::visitor(conn.remote_address());
+    let mut h3_conn = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn)).await?;
+    loop {
+        match h3_conn.accept().await {
+            Ok(Some(resolver)) => {
+                let quic_server = quic_server.clone();
+                let server_name = server_name.clone();
+                tokio::spawn(async move {
+                    match resolver.resolve_request().await {
+                        Ok((req, stream)) => {
+                            if let Err(e) = handle_request(req, stream, quic_server, server_name, visitor).await {
+                                log::debug!("h3 request error:{}", e);
+                            }
+                        },
+                        Err(e) => log::debug!("h3 resolve request error:{}", e),
+                    }
+                });
+            },
+            Ok(None) => break,
+            Err(e) => {
+                log::debug!("h3 accept error:{}", e);
+                break
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn handle_request<S>(req: Request<()>, stream: H3Stream<S>, quic_server: QuicServer, server_name: Option<String>, visitor: Visitor) -> Result<(), Box<dyn Error+Send+Sync>>
+where
+    S: h3::quic::BidiStream<Bytes> + Send + 'static,
+    S::SendStream: Send,
+    S::RecvStream: Send,
+{
+    let (mut send, mut recv) = stream.split();
+    let authority = req.uri().authority().map(|a| a.to_string())
+        .or(req.headers().get(header::HOST).and_then(|h| h.to_str().ok()).map(|h| h.to_string()));
+    let resp = match authority {
+        Some(authority) => {
+            log::info!("h3 method: {}, uri: {}", req.method(), req.uri());
+            match host_mac(server_name.as_deref().unwrap_or(&authority)) {
+                Ok(mac) => {
+                    let (mut body_tx, body) = Channel::<Bytes, Box<dyn Error+Send+Sync>>::new(8);
+                    tokio::spawn(async move {//h3请求体转到http/1.1请求体
+                        loop {
+                            match recv.recv_data().await {
+                                Ok(Some(mut chunk)) => {
+                                    if body_tx.send(Frame::data(chunk.copy_to_bytes(chunk.remaining()))).await.is_err() {
+                                        break
+                                    }
+                                },
+                                Ok(None) => break,
+                                Err(e) => {
+                                    body_tx.abort(e.into());
+                                    break
+                                }
+                            }
+                        }
+                    });
+                    forward_request(&quic_server, mac, &authority, req.map(|_| body), visitor).await.map(|r| r.map(|b| b.boxed()))
+                },
+                Err(e) => Err(e),
+            }
+        },
+        None => Err((StatusCode::BAD_REQUEST, "not found authority".to_string())),
+    };
+    let resp = resp.unwrap_or_else(|(status, msg)| text_response(status, msg));
+    let (parts, mut body) = resp.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => send.send_data(data).await?,
+            Err(frame) => if let Ok(trailers) = frame.into_trailers() {
+                send.send_trailers(trailers).await?;
+                return Ok(())
+            },
+        }
+    }
+    send.finish().await?;
+    Ok(())
+}
@@ -1,8 +1,9 @@
-use rweb_common::{io::peek_stream::PeekableStream, mac::Mac};
+use rweb_common::{io::{header::{internal_method, Visitor}, peek_stream::PeekableStream, socks5}, mac::Mac};
 use tokio::{io::{AsyncRead, AsyncWrite, AsyncWriteExt}, net::{TcpListener, TcpStream}};
-use crate::quic_server::QuicServer;
+use crate::{h2_server::{serve_h2, ALPN_H2, ALPN_HTTP11}, quic_server::QuicServer, socks_server::{serve_socks5, SocksAuth}};
 use rustls::{pki_types::pem::PemObject, ServerConfig};
 use tokio_rustls::TlsAcceptor;
+use hyper::header::HeaderValue;
 use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -11,23 +12,46 @@ enum Scheme {
     TLS,
 }
 
-pub async fn run_https(port:u16,quic_server:QuicServer,priv_key:&str,cert_der:&str) -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
+//https端口上除http/1.1外其他前端协议的配置
+#[derive(Debug, Clone, Default)]
+pub struct FrontConfig {
+    ///quic端口上h3的端口，通过Alt-Svc告知浏览器
+    pub h3_port: Option<u16>,
+    ///socks5密码，没有设置时不接受socks5连接
+    pub socks: SocksAuth,
+    ///监听的ip，为空时为0.0.0.0，::为ipv4和ipv6双栈
+    pub listen: Vec<std::net::IpAddr>,
+}
+
+pub async fn run_https(port:u16,quic_server:QuicServer,priv_key:&str,cert_der:&str,front:FrontConfig) -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
     let priv_key = rustls::pki_types::PrivateKeyDer::from_pem_slice(priv_key.as_bytes())?;
     let cert_chain = extract_full_pem_certificates(cert_der).into_iter().filter_map(|s|rustls::pki_types::CertificateDer::from_pem_slice(s.as_bytes()).ok()).collect::<Vec<_>>();
-    let config = ServerConfig::builder()
+    let mut config = ServerConfig::builder()
     .with_no_client_auth()
     .with_single_cert(cert_chain, priv_key)?;
+    config.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP11.to_vec()];
     let tls_config = Arc::new(config);
-    let listener = TcpListener::bind(format!("0.0.0.0:{}",port)).await?;
-    log::info!("https_server listen on {}",listener.local_addr()?);
+    let front = Arc::new(front);
+    let mut accepts = tokio::task::JoinSet::new();
+    for addr in crate::listen_addrs(&front.listen,port){
+        let listener = rweb_common::socket::tcp_listener(addr)?;
+        log::info!("https_server listen on {}",listener.local_addr()?);
+        accepts.spawn(accept_https(listener,quic_server.clone(),tls_config.clone(),front.clone()));
+    }
+    accepts.join_next().await;
+    Ok(())
+}
+
+async fn accept_https(listener:TcpListener,quic_server:QuicServer,tls_config:Arc<ServerConfig>,front:Arc<FrontConfig>){
     loop{
         match listener.accept().await {
             Ok((stream, addr)) => {
                 log::debug!("accept from {}", addr);
                 let quic_server = quic_server.clone();
                 let server_config = tls_config.clone();
+                let front = front.clone();
                 tokio::spawn(async move {
-                    handle_stream(stream,quic_server,server_config).await.unwrap_or_else(|e| {
+                    handle_stream(stream,quic_server,server_config,front).await.unwrap_or_else(|e| {
                         log::warn!("handle client error:{}", e);
                     });
                 });
@@ -39,7 +63,8 @@ pub async fn run_https(port:u16,quic_server:QuicServer,priv_key:&str,cert_der:&s
     }
 }
 
-pub async fn handle_stream(stream: TcpStream, quic_server: QuicServer,tls_config:Arc<ServerConfig>) -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
+pub async fn handle_stream(stream: TcpStream, quic_server: QuicServer,tls_config:Arc<ServerConfig>,front:Arc<FrontConfig>) -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
+    let visitor = crate::visitor(stream.peer_addr()?);
     let mut first_byte = [0x00;1];
     stream.peek(&mut first_byte).await?;
     match first_byte[0] {//https连接
@@ -49,8 +74,22 @@ pub async fn handle_stream(stream: TcpStream, quic_server: QuicServer,tls_config
                 Ok(tls_stream) => {
                     let server_name = tls_stream.get_ref().1.server_name().map(|s|s.to_string());
                     log::info!("server name:{:?}",server_name);
-                    if let Err(e) = handle_client(PeekableStream::new(tls_stream), quic_server, server_name, Scheme::TLS).await {
-                        log::debug!("tls handle client error:{}", e);
+                    if tls_stream.get_ref().1.alpn_protocol() == Some(ALPN_H2) {
+                        let alt_svc = front.h3_port.and_then(|port|HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400",port)).ok());
+                        if let Err(e) = serve_h2(tls_stream, quic_server, server_name, alt_svc, visitor).await {
+                            log::debug!("h2 handle client error:{}", e);
+                        }
+                    }else{
+                        let mut tls_stream = PeekableStream::new(tls_stream);
+                        let mut first_byte = [0x00;1];
+                        tls_stream.peek(&mut first_byte).await?;
+                        if first_byte[0] == socks5::VERSION {//tls内的socks5
+                            if let Err(e) = serve_socks5(tls_stream, quic_server, &front.socks, visitor).await {
+                                log::debug!("tls socks5 handle client error:{}", e);
+                            }
+                        }else if let Err(e) = handle_client(tls_stream, quic_server, server_name, Scheme::TLS, visitor).await {
+                            log::debug!("tls handle client error:{}", e);
+                        }
                     }
                 }
                 Err(e) => {
@@ -58,8 +97,13 @@ pub async fn handle_stream(stream: TcpStream, quic_server: QuicServer,tls_config
                 }
             }
         }
+        socks5::VERSION => {
+            if let Err(e) = serve_socks5(stream, quic_server, &front.socks, visitor).await {
+                log::debug!("socks5 handle client error:{}", e);
+            }
+        }
         _ => {
-            if let Err(e) = handle_client(stream, quic_server, None, Scheme::TCP).await {
+            if let Err(e) = handle_client(stream, quic_server, None, Scheme::TCP, visitor).await {
                 log::debug!("tcp handle client error:{}", e);
             }
         }
@@ -67,15 +111,20 @@ pub async fn handle_stream(stream: TcpStream, quic_server: QuicServer,tls_config
     Ok(())
 }
 
-async fn handle_client<T: AsyncRead + AsyncWrite + Unpin>(stream: T, quic_server: QuicServer,http_proxy_host: Option<String>,schme:Scheme) -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
+async fn handle_client<T: AsyncRead + AsyncWrite + Unpin>(stream: T, quic_server: QuicServer,http_proxy_host: Option<String>,schme:Scheme,visitor:Visitor) -> Result<(), Box<dyn std::error::Error+Send+Sync>> {
     let mut stream = PeekableStream::new(stream);
     let header = stream.peek_header().await?;
     log::info!("header: {:?}", header);
+    if internal_method(&header.method) {//不能冒充rwebs给设备发命令
+        stream.write_all("HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n".as_bytes()).await?;
+        return Err(format!("method {} not allowed", header.method).into());
+    }
+    stream.peek_set_visitor(&visitor);//只有第一个请求带上，同一连接上后面的请求原样转发
     if header.method.as_str() == "OPTIONS" && header.version.as_str() == "RTSP/1.0" {//代理rtsp协议，仅支持tcp和端口复用的rtsp，也就是支持NAT的rtsp
         let url = url::Url::parse(&header.uri).map_err(|e| format!("url parse error:{}", e))?;
         let host = url.host_str().ok_or("host error")?.to_string();
         let mac:Mac = host.split('.').next().ok_or("host error")?.try_into()?;
-        if let Err(e) = quic_server.translate(mac,stream).await{
+        if let Err(e) = quic_server.translate_target(mac,stream).await{
             log::warn!("translate error:{}", e);
             return Err("rweb http_proxy not support rtsp, you can use tcp".into());
         }
@@ -88,7 +137,11 @@ async fn handle_client<T: AsyncRead + AsyncWrite + Unpin>(stream: T, quic_server
     let host_header = http_proxy_host.unwrap_or(header.get("Host").ok_or("not found Host header")?.to_string());
     let mac:Mac = host_header.split('.').next().ok_or("host error")?.try_into()?;
     log::info!("method: {}, version: {}, mac: {}", header.method, header.version, mac);
-    quic_server.translate(mac,stream).await?;
+    if header.method == "CONNECT"{//https代理由设备连接任意目标，与代理表里的目标状态无关
+        quic_server.translate(mac,stream).await?;
+    }else{
+        quic_server.translate_target(mac,stream).await?;
+    }
     Ok(())
 }
 
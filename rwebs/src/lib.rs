@@ -1,7 +1,17 @@
 pub mod http_server;
 pub mod quic_server;
+pub mod h2_server;
+pub mod h3_server;
+pub mod socks_server;
+pub mod status;
+mod alpn;
+#[cfg(test)]
+mod testutil;
 //pub mod quic_p2p_server;
+use std::{error::Error, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::atomic::{AtomicU64, Ordering}};
 use clap::Parser;
+use rweb_common::{io::header::Visitor, relay_list::RelayRule, reverse_list::ReverseRule};
+use serde::de::DeserializeOwned;
 
 #[derive(Parser)]
 #[clap(
@@ -20,21 +30,101 @@ struct Opts {
     ///证书文件路径
     #[clap(short, long, default_value = "reform.cer")]
     cert: String,
+    ///在quic端口上同时提供http/3，不带ALPN的旧版本设备仍可连接
+    #[clap(long)]
+    h3: bool,
+    ///socks5密码，设置后web端口同时接受socks5(可在tls内)，用户名为设备标签
+    #[clap(long)]
+    socks_password: Option<String>,
+    ///按设备的socks5密码文件，json对象，例如{"aabbccddeeff":"密码"}，文件里的设备只能用自己的密码
+    #[clap(long)]
+    socks_password_file: Option<String>,
+    ///反向转发配置文件，json数组，例如[{"mac":"aabbccddeeff","name":"syslog","target":"10.0.0.5:514"}]，设备只能使用配置给自己的服务
+    #[clap(long)]
+    reverse_file: Option<String>,
+    ///设备到设备中转的许可文件，json数组，例如[{"from":"aabbccddeeff","to":"aabbccdd0001"}]，没有配置的不能中转
+    #[clap(long)]
+    relay_file: Option<String>,
+    ///监听的ip，quic和https都在这些地址上监听，可多次指定；::为ipv4和ipv6双栈，不能再同时指定0.0.0.0
+    #[clap(long, default_value = "0.0.0.0")]
+    listen: Vec<IpAddr>,
+    ///状态页地址，例如127.0.0.1:5680，GET /devices列出在线设备和目标状态；没有鉴权，不要监听在公网地址上
+    #[clap(long)]
+    status_addr: Option<SocketAddr>,
 }
 
-pub async fn run(){
+//没有指定时监听0.0.0.0
+pub(crate) fn listen_addrs(listen:&[IpAddr],port:u16)->Vec<SocketAddr>{
+    if listen.is_empty(){
+        return vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED),port)];
+    }
+    listen.iter().map(|ip|SocketAddr::new(*ip,port)).collect()
+}
+
+static SESSION:AtomicU64 = AtomicU64::new(1);
+
+//每个前端连接(tcp、tls、h3)一个编号，随请求头告诉设备
+pub(crate) fn visitor(addr:SocketAddr)->Visitor{
+    Visitor{ip:rweb_common::socket::canonical(addr).ip(),session:SESSION.fetch_add(1,Ordering::Relaxed)}
+}
+
+//读取反向转发、中转等json数组配置文件
+pub fn load_rules<T:DeserializeOwned>(path:&str)->Result<Vec<T>,Box<dyn Error>>{
+    let text = std::fs::read_to_string(path).map_err(|e|format!("{}:{}",path,e))?;
+    Ok(serde_json::from_str(&text).map_err(|e|format!("{}:{}",path,e))?)
+}
+
+pub async fn run()->Result<(),Box<dyn Error>>{
     let opts = Opts::parse();
     //let quic_s = quic_server::QuicServer::default();
-    let quic_s = quic_server::QuicServer::default();
+    let devices = match opts.socks_password_file.as_deref().map(socks_server::SocksAuth::load_devices).transpose(){
+        Ok(devices)=>devices.unwrap_or_default(),
+        Err(e)=>return Err(format!("socks password file error:{}",e).into()),
+    };
+    let socks = socks_server::SocksAuth{password:opts.socks_password,devices};
+    let reverse:Vec<ReverseRule> = opts.reverse_file.as_deref().map(load_rules).transpose()?.unwrap_or_default();
+    let relay:Vec<RelayRule> = opts.relay_file.as_deref().map(load_rules).transpose()?.unwrap_or_default();
+    let quic_s = quic_server::QuicServer::default().with_reverse(reverse).with_relay(relay).with_listen(opts.listen.clone());
     let peers = quic_s.clone();
     rustls::crypto::aws_lc_rs::default_provider()
     .install_default()
-    .expect("failed to install default crypto provider");
-    let key = std::fs::read_to_string(opts.key).unwrap();
-    let cert = std::fs::read_to_string(opts.cert).unwrap();
+    .map_err(|_|"failed to install default crypto provider")?;
+    let key = std::fs::read_to_string(&opts.key).map_err(|e|format!("key file {}:{}",opts.key,e))?;
+    let cert = std::fs::read_to_string(&opts.cert).map_err(|e|format!("cert file {}:{}",opts.cert,e))?;
     tokio::select! {
-        _ = quic_s.start(opts.port) => {},
+        res = quic_s.start(opts.port,opts.h3.then_some((key.as_str(),cert.as_str()))) => res,
         //_ = http_server::run(opts.port,peers.clone()) => {},//如果用http代理，必须使用proxy_change_header，如果用https则不用。
-        _ = http_server::run_https(opts.port,peers.clone(),&key,&cert) => {},
+        res = http_server::run_https(opts.port,peers.clone(),&key,&cert,http_server::FrontConfig{h3_port:opts.h3.then_some(opts.port),socks,listen:opts.listen}) => res.map_err(|e|e.to_string().into()),
+        res = async{
+            match opts.status_addr{
+                Some(addr)=>status::run_status(addr,peers.clone()).await,
+                None=>std::future::pending().await,
+            }
+        } => res.map_err(|e|e.to_string().into()),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn rule_files(){
+        let dir = std::env::temp_dir().join(format!("rwebs_reverse_{}",std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ok = dir.join("ok.json");
+        std::fs::write(&ok,r#"[{"mac":"aabbccdd3101","name":"syslog","target":"127.0.0.1:514"}]"#).unwrap();
+        let rules:Vec<ReverseRule> = load_rules(ok.to_str().unwrap()).unwrap();
+        assert_eq!(rules.len(),1);
+        assert_eq!(rules[0].target,"127.0.0.1:514");
+        let bad = dir.join("bad.json");
+        std::fs::write(&bad,r#"[{"mac":"xx","name":"syslog","target":"127.0.0.1:514"}]"#).unwrap();
+        assert!(load_rules::<ReverseRule>(bad.to_str().unwrap()).unwrap_err().to_string().contains("bad.json"));
+        assert!(load_rules::<ReverseRule>(dir.join("none.json").to_str().unwrap()).is_err());
+        let relay = dir.join("relay.json");
+        std::fs::write(&relay,r#"[{"from":"aabbccdd3201","to":"aabbccdd3202"}]"#).unwrap();
+        let rules:Vec<RelayRule> = load_rules(relay.to_str().unwrap()).unwrap();
+        assert_eq!(rules[0].to,"aabbccdd3202".parse().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
-}
\ No newline at end of file
+}
@@ -2,5 +2,8 @@
 async fn main() {    
     //console_subscriber::init();
     simple_logger::init_with_level(log::Level::Info).unwrap();
-    rwebs::run().await;
-}
\ No newline at end of file
+    if let Err(e) = rwebs::run().await{
+        log::error!("{}",e);
+        std::process::exit(1);
+    }
+}
@@ -1,38 +1,79 @@
 use std::{
-    collections::HashMap, error::Error, net::{IpAddr, Ipv4Addr, SocketAddr}, sync::Arc, time::Duration
+    collections::{HashMap, HashSet}, error::Error, net::{IpAddr, SocketAddr}, sync::Arc, time::Duration
 };
-use rustls::pki_types::pem::PemObject;
-use rweb_common::{mac::Mac, RwebError};
-use quinn::{Connection, Endpoint, Incoming, ServerConfig, VarInt};
-use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, sync::RwLock, time::timeout};
+use rustls::{pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}, server::{ClientHello, ResolvesServerCert}, sign::CertifiedKey};
+use rweb_common::{health::TargetHealth, io::{header::{read_uni, write_features, write_rejected, UniMessage, Visitor, FEATURE_VISITOR, METHOD_RELAY, METHOD_REVERSE}, stream_copy::Stream}, mac::Mac, relay_list::RelayRule, reverse_list::ReverseRule, RwebError, ALPN_RWEB};
+use quinn::{crypto::rustls::{HandshakeData, QuicServerConfig}, Connection, Endpoint, EndpointConfig, Incoming, RecvStream, SendStream, ServerConfig, TokioRuntime, VarInt};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::TcpStream, select, sync::RwLock, time::timeout};
 use rweb_common::key::{CER_BIN, KEY_BIN};
+use crate::{alpn::OptionalAlpn, h3_server::{serve_h3, ALPN_H3}, http_server::extract_full_pem_certificates};
+use rweb_common::{get_header, Header};
 #[cfg(feature="p2p")]
-use tokio::select;
-#[cfg(feature="p2p")]
-use quinn::{RecvStream, SendStream};
-#[cfg(feature="p2p")]
-use rweb_common::{get_header,Header};
+use rweb_common::{io::header::{METHOD_P2P, P2P_REQUESTER, P2P_TICKET}, nat::NatProfile, socket::canonical};
 
 const KEEPALIVE_INTERVAL_MILLIS:u64=10_000;
 const IDLE_TIMEOUT_MILLIS:u32=21_000;
 
 #[derive(Debug,Clone,Default)]
 pub struct QuicServer{
-    peers:Arc<RwLock<HashMap<Mac,Connection>>>
+    peers:Arc<RwLock<HashMap<Mac,Connection>>>,
+    health:Arc<RwLock<HashMap<Mac,TargetHealth>>>,//设备报告的目标状态，设备下线时去掉
+    reverse:Arc<HashMap<(Mac,String),String>>,//(设备,服务名)->rwebs可达的目标地址
+    relay:Arc<HashSet<RelayRule>>,//允许的设备到设备中转，为空时不能中转
+    listen:Arc<Vec<IpAddr>>,//为空时监听0.0.0.0
 }
 
 impl QuicServer{
+
+    //设置允许设备使用的反向转发服务
+    pub fn with_reverse(mut self,rules:Vec<ReverseRule>)->Self{
+        self.reverse = Arc::new(rules.into_iter().map(|r|((r.mac,r.name),r.target)).collect());
+        self
+    }
+
+    //设置允许的设备到设备中转
+    pub fn with_relay(mut self,rules:Vec<RelayRule>)->Self{
+        self.relay = Arc::new(rules.into_iter().collect());
+        self
+    }
+
+    //quic监听的ip，::为ipv4和ipv6双栈
+    pub fn with_listen(mut self,listen:Vec<IpAddr>)->Self{
+        self.listen = Arc::new(listen);
+        self
+    }
     
-    pub async fn start(&self,port:u16)->Result<(),Box<dyn Error>>{
-        let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
-        let endpoint = make_server_udp_endpoint(bind_addr,CER_BIN.as_bytes(),KEY_BIN.as_bytes())?;
-        log::info!("quic server listen on {}",bind_addr);
+    //h3为Some时同一个Endpoint也接受浏览器的h3连接，内容为https使用的(priv_key,cert_der)，设备连接仍使用内置证书
+    pub async fn start(&self,port:u16,h3:Option<(&str,&str)>)->Result<(),Box<dyn Error>>{
+        let server_config = server_config(h3)?;
+        let sockets = crate::listen_addrs(&self.listen,port).into_iter().map(rweb_common::socket::udp_socket).collect::<Result<Vec<_>,_>>()?;
+        self.serve(sockets,server_config).await
+    }
+
+    //在已经绑定好的socket上运行，测试时用端口0绑定后交给这里
+    pub async fn start_on(&self,socket:std::net::UdpSocket,h3:Option<(&str,&str)>)->Result<(),Box<dyn Error>>{
+        let server_config = server_config(h3)?;
+        self.serve(vec![socket],server_config).await
+    }
+
+    async fn serve(&self,sockets:Vec<std::net::UdpSocket>,server_config:ServerConfig)->Result<(),Box<dyn Error>>{
+        let mut accepts = tokio::task::JoinSet::new();
+        for socket in sockets{
+            log::info!("quic server listen on {:?}",socket.local_addr());
+            let endpoint = Endpoint::new(EndpointConfig::default(), Some(server_config.clone()), socket, Arc::new(TokioRuntime))?;
+            accepts.spawn(self.clone().accept(endpoint));
+        }
+        accepts.join_next().await;
+        Ok(())
+    }
+
+    async fn accept(self,endpoint:Endpoint){
         loop{
             match endpoint.accept().await{
                 Some(conn)=>{
-                    let peers = self.peers.clone();
+                    let quic_server = self.clone();
                     tokio::spawn(async move {                        
-                        if let Err(_e) = handle_incomming(conn,peers).await{
+                        if let Err(_e) = handle_incomming(conn,quic_server).await{
                             //println!("handle incomming error:{}",_e);
                         }
                     });
@@ -44,94 +85,219 @@ impl QuicServer{
         }
     }
 
-    pub async fn translate<T:AsyncRead+AsyncWrite+Unpin>(&self,mac:Mac,mut tcp_stream:T)->Result<(),Box<dyn Error+Send+Sync>>{
+    //打开一条到设备的bi流，并先写入要连接的mac地址
+    pub async fn open_stream(&self,mac:Mac)->Result<Stream<RecvStream,SendStream>,RwebError>{
         let peers = self.peers.read().await;
-        if let Some(conn) = peers.get(&mac){
-            if let Ok(stream) = conn.open_bi().await{
-                let mut quic_stream = rweb_common::io::stream_copy::Stream::new(stream,conn.remote_address());
-                drop(peers);
-                quic_stream.write(mac.as_ref()).await?;//先告诉节点自己要连接的mac地址
-                tokio::io::copy_bidirectional(&mut tcp_stream, &mut quic_stream).await?;
-                Ok(())
-            }else{
-                drop(peers);
-                let body = "设备未连接";
-                let response = format!(
-                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
-                    body.len(),
-                    body
-                );
-                tcp_stream.write_all(response.as_bytes()).await?;
-                Err(RwebError::new(502,"设备连接无法使用".to_string()).into())
-            }
-        }else{
-            drop(peers);
-            let body = "设备未连接";
+        let conn = peers.get(&mac).ok_or(RwebError::new(402,"设备未连接"))?.clone();
+        drop(peers);
+        let stream = conn.open_bi().await.map_err(|_|RwebError::new(502,"设备连接无法使用"))?;
+        let mut quic_stream = Stream::new(stream,conn.remote_address());
+        quic_stream.write_all(mac.as_ref()).await.map_err(|e|RwebError::new(502,e))?;//先告诉节点自己要连接的mac地址
+        Ok(quic_stream)
+    }
+
+    //打开到设备代理表里目标的流，设备报告目标不可达时直接返回503
+    pub async fn open_target(&self,mac:Mac)->Result<Stream<RecvStream,SendStream>,RwebError>{
+        if self.target_health(mac).await == TargetHealth::Down{
+            return Err(RwebError::new(503,"目标不可达"));
+        }
+        self.open_stream(mac).await
+    }
+
+    pub async fn target_health(&self,mac:Mac)->TargetHealth{
+        self.health.read().await.get(&mac).copied().unwrap_or_default()
+    }
+
+    //在线的设备和设备报告的目标状态，没有报告的为Unknown
+    pub async fn devices(&self)->Vec<(Mac,TargetHealth)>{
+        let peers = self.peers.read().await;
+        let health = self.health.read().await;
+        peers.keys().map(|mac|(*mac,health.get(mac).copied().unwrap_or_default())).collect()
+    }
+
+    //打开到设备的流并让设备CONNECT到target(host:port)，成功后即为到target的流；visitor随请求头告诉设备
+    pub async fn open_connect(&self,mac:Mac,target:&str,visitor:Option<&Visitor>)->Result<Stream<RecvStream,SendStream>,RwebError>{
+        let mut quic_stream = self.open_stream(mac).await?;
+        let lines = visitor.map(|v|v.lines()).unwrap_or_default();
+        quic_stream.write_all(format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n{}\r\n", target, target, lines).as_bytes()).await.map_err(|e|RwebError::new(502,e))?;
+        let resp = get_header(&mut quic_stream).await.map_err(|e|RwebError::new(502,e))?;
+        if !resp.uri.starts_with('2'){//状态行解析后uri字段为状态码
+            return Err(RwebError::new(504,format!("device connect {} failed:{}",target,resp.uri)));
+        }
+        Ok(quic_stream)
+    }
+
+    pub async fn translate<T:AsyncRead+AsyncWrite+Unpin>(&self,mac:Mac,tcp_stream:T)->Result<(),Box<dyn Error+Send+Sync>>{
+        translate(self.open_stream(mac).await,tcp_stream).await
+    }
+
+    //访问设备代理表里的目标(不是CONNECT)，目标不可达时马上返回503页面
+    pub async fn translate_target<T:AsyncRead+AsyncWrite+Unpin>(&self,mac:Mac,tcp_stream:T)->Result<(),Box<dyn Error+Send+Sync>>{
+        translate(self.open_target(mac).await,tcp_stream).await
+    }
+}
+
+async fn translate<T:AsyncRead+AsyncWrite+Unpin>(quic_stream:Result<Stream<RecvStream,SendStream>,RwebError>,mut tcp_stream:T)->Result<(),Box<dyn Error+Send+Sync>>{
+    match quic_stream{
+        Ok(mut quic_stream)=>{
+            tokio::io::copy_bidirectional(&mut tcp_stream, &mut quic_stream).await?;
+            Ok(())
+        },
+        Err(e)=>{
+            let (status,body) = match e.code{
+                503=>("503 Service Unavailable","目标不可达"),
+                _=>("200 OK","设备未连接")
+            };
             let response = format!(
-                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+                status,
                 body.len(),
                 body
             );
             tcp_stream.write_all(response.as_bytes()).await?;
-            Err(RwebError::new(402,"设备未连接".to_string()).into())
+            Err(e.into())
         }
-    }    
+    }
 }
 
-//接收服务端发来的p2p连接请求
-#[cfg(feature="p2p")]
-async fn handle_bi(connection:Connection,peers:Arc<RwLock<HashMap<Mac,Connection>>>,self_mac:Mac)->Result<(),Box<dyn Error+Send+Sync>>{
-    log::info!("handle_bi mac:{}",self_mac);
+//设备主动打开的bi流，按请求方法分发：反向转发、中转、p2p
+async fn handle_bi(connection:Connection,quic_server:QuicServer,mac_list:Arc<RwLock<Vec<Mac>>>)->Result<(),Box<dyn Error+Send+Sync>>{
     loop{
-        let bi_stream = connection.accept_bi().await?;
+        let (bi_send,mut bi_recv) = connection.accept_bi().await?;
         let connection = connection.clone();
-        let peers = peers.clone();
+        let quic_server = quic_server.clone();
+        let mac_list = mac_list.read().await.clone();//按流开始时的列表
         tokio::spawn(async move{
-            let res = handle_bi_cell(connection, bi_stream, peers, self_mac).await;
-            log::info!("handle_bi_cell error:{:?}",res);
+            let header = match get_header(&mut bi_recv).await{
+                Ok(header)=>header,
+                Err(e)=>return log::warn!("device bi header error:{}",e)
+            };
+            let res = match header.method.as_str(){
+                METHOD_REVERSE=>handle_reverse(Stream::new((bi_send,bi_recv),connection.remote_address()),header,&quic_server,&mac_list).await,
+                METHOD_RELAY=>handle_relay(Stream::new((bi_send,bi_recv),connection.remote_address()),header,&quic_server,&mac_list).await,
+                #[cfg(feature="p2p")]
+                METHOD_P2P=>match mac_list.first(){
+                    Some(mac)=>handle_bi_cell(connection,(bi_send,bi_recv),header,quic_server.peers.clone(),*mac).await,
+                    None=>Err("p2p without mac".into())
+                },
+                method=>Err(format!("unknown method:{}",method).into())
+            };
+            if let Err(e) = res{
+                log::info!("handle_bi error:{}",e);
+            }
         });
     }
 }
 
+//反向转发：mac必须是本连接注册的设备，且rwebs配置了该设备可用的服务
+async fn handle_reverse<T:AsyncRead+AsyncWrite+Unpin>(mut quic_stream:T,header:Header,quic_server:&QuicServer,mac_list:&[Mac])->Result<(),Box<dyn Error+Send+Sync>>{
+    let (mac,name) = header.parse_reverse()?;
+    let target = match quic_server.reverse.get(&(mac,name.clone())){
+        Some(target) if mac_list.contains(&mac) => target,
+        _ => {
+            quic_stream.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+            return Err(format!("reverse {} not allowed for {}",name,mac).into());
+        }
+    };
+    match TcpStream::connect(target).await{
+        Ok(mut tcp_stream)=>{
+            log::info!("reverse {} -> {} from {}",name,target,mac);
+            quic_stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+            tokio::io::copy_bidirectional(&mut quic_stream,&mut tcp_stream).await?;
+            Ok(())
+        },
+        Err(e)=>{
+            quic_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+            Err(format!("reverse {} connect {} error:{}",name,target,e).into())
+        }
+    }
+}
+
+//设备到设备的中转：本连接上要有设备被允许中转到目标，打开到目标设备的流后先写入目标mac和说明请求方的RELAY头，之后原样转发
+//目标设备按RELAY头知道这是另一台设备的流，不当作rwebs转来的访问
+async fn handle_relay<T:AsyncRead+AsyncWrite+Unpin>(mut quic_stream:T,header:Header,quic_server:&QuicServer,mac_list:&[Mac])->Result<(),Box<dyn Error+Send+Sync>>{
+    let to:Mac = header.uri.parse()?;
+    if mac_list.contains(&to){
+        quic_stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+        return Err(format!("relay to self:{}",to).into());
+    }
+    let Some(from) = mac_list.iter().find(|from|quic_server.relay.contains(&RelayRule{from:**from,to})).copied() else{
+        quic_stream.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+        return Err(format!("relay {:?} -> {} not allowed",mac_list,to).into());
+    };
+    let device_stream = match quic_server.open_stream(to).await{
+        Ok(mut device_stream)=>{
+            let relay_header:Vec<u8> = Header::new_relay_from(to,from).into();
+            device_stream.write_all(&relay_header).await.map(|_|device_stream).map_err(|e|RwebError::new(502,e))
+        },
+        Err(e)=>Err(e)
+    };
+    match device_stream{
+        Ok(mut device_stream)=>{
+            log::info!("relay {} -> {}",from,to);
+            quic_stream.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+            tokio::io::copy_bidirectional(&mut quic_stream,&mut device_stream).await?;
+            Ok(())
+        },
+        Err(e)=>{
+            quic_stream.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+            Err(e.into())
+        }
+    }
+}
+
 //解析p2p请求,获取对端地址，尝试与对端打洞
 #[cfg(feature="p2p")]
-async fn handle_bi_cell(connection:Connection,(mut bi_send, mut bi_recv):(SendStream,RecvStream),peers:Arc<RwLock<HashMap<Mac,Connection>>>,self_mac:Mac)->Result<(),Box<dyn Error+Send+Sync>>{
-    let header = get_header(&mut bi_recv).await?;
+async fn handle_bi_cell(connection:Connection,(mut bi_send, _bi_recv):(SendStream,RecvStream),header:Header,peers:Arc<RwLock<HashMap<Mac,Connection>>>,self_mac:Mac)->Result<(),Box<dyn Error+Send+Sync>>{
     log::info!("header: {:?}", header);
     let (mac,_,req_self_addr) = header.parse_p2p()?;
     log::info!("handle_p2p_request client mac:{},node mac:{}",self_mac,mac);
-    let peers_ = peers.read().await;
-    if let Some(peer) = peers_.get(&mac){
+    let peer = peers.read().await.get(&mac).cloned();//不在持有读锁时等待对端
+    if let Some(peer) = peer{
         let (mut sendstream,mut recvstream) = peer.open_bi().await?;//打开对端bi流
-        let mut bi2_header = Header::new_p2p(self_mac, req_self_addr.unwrap_or(connection.remote_address()),Some(peer.remote_address())); //使用节点自测地址利于预测端口
+        //双栈监听时ipv4设备的地址是::ffff:a.b.c.d，换回ipv4再交给对端和比较
+        let (remote,peer_remote) = (canonical(connection.remote_address()),canonical(peer.remote_address()));
+        let mut bi2_header = Header::new_p2p(self_mac, req_self_addr.unwrap_or(remote),Some(peer_remote)); //使用节点自测地址利于预测端口
         if let Some(req_self_addr) = req_self_addr{
-            if req_self_addr != connection.remote_address(){
-                log::warn!("请求方处于受限锥形NAT网络,第三方测地址{} != 服务器测得地址{}",req_self_addr,connection.remote_address());
+            if req_self_addr != remote{
+                log::warn!("请求方处于受限锥形NAT网络,第三方测地址{} != 服务器测得地址{}",req_self_addr,remote);
                 bi2_header.header.insert("Nat-Type".to_string(),"Symmetric".to_string());
             }else{
-                log::warn!("请求方处于全锥形NAT网络,第三方测地址{} == 服务器测得地址{}",req_self_addr,connection.remote_address());
+                log::warn!("请求方处于全锥形NAT网络,第三方测地址{} == 服务器测得地址{}",req_self_addr,remote);
                 bi2_header.header.insert("Nat-Type".to_string(),"FullCone".to_string());
             }
         }
+        if let Some(nat) = NatProfile::from_header(&header){//转发请求方的NAT画像
+            nat.write_header(&mut bi2_header);
+        }
+        //一次性票据，请求方连上对端后出示，对端只接受拿着票据的直连
+        let ticket = p2p_ticket()?;
+        bi2_header.insert(P2P_TICKET.to_string(),ticket.clone());
         let mut bi2_header_vec:Vec<u8> = bi2_header.into();//构造向对端bi流发送p2p请求包
         bi2_header_vec.splice(0..0,mac.as_ref().iter().cloned());//在头部插入mac地址，所有主动向节点发送的bi流的第一个数据包都需要首先发送mac地址以便node得知使用哪条流来对接。
-        if let Ok(_) = sendstream.write_all(&bi2_header_vec).await{//向对端发送p2p请求包
+        if sendstream.write_all(&bi2_header_vec).await.is_ok(){//向对端发送p2p请求包
             let header = get_header(&mut recvstream).await?;//等待对端回复p2p请求
             let (_,_,resp_self_addr) = header.parse_p2p()?;
+            let resp_nat = NatProfile::from_header(&header);
             //log::info!("tell {} connect {} success",mac,connection.remote_address());
-            let mut header = Header::new_p2p(mac, resp_self_addr.unwrap_or(peer.remote_address()),Some(connection.remote_address()));//使用节点自测地址利于预测端口
+            let mut header = Header::new_p2p(mac, resp_self_addr.unwrap_or(peer_remote),Some(remote));//使用节点自测地址利于预测端口
             if let Some(resp_self_addr) = resp_self_addr{
-                if resp_self_addr != peer.remote_address(){
-                    log::warn!("被请求方处于受限锥形NAT网络,第三方测地址{} != 服务器测得地址{}",resp_self_addr,peer.remote_address());
+                if resp_self_addr != peer_remote{
+                    log::warn!("被请求方处于受限锥形NAT网络,第三方测地址{} != 服务器测得地址{}",resp_self_addr,peer_remote);
                     header.header.insert("Nat-Type".to_string(),"Symmetric".to_string());
                 }else{
                     header.header.insert("Nat-Type".to_string(),"FullCone".to_string());
                 }
             }
+            if let Some(nat) = resp_nat{//转发被请求方的NAT画像
+                nat.write_header(&mut header);
+            }
+            header.insert(P2P_TICKET.to_string(),ticket);
+            header.insert(P2P_REQUESTER.to_string(),self_mac.to_string());//被请求方按这个核对出示票据的一方
             let bi_header_vec:Vec<u8> = header.into();
             //bi_header_vec.splice(0..0,mac.as_ref().iter().cloned());//在头部插入mac地址，所有主动向节点发送的bi流的第一个数据包都需要首先发送mac地址以便node得知使用哪条流来对接。
             bi_send.write_all(&bi_header_vec).await?;
-            log::info!("tell {} connect {} success ",self_mac,peer.remote_address());
+            log::info!("tell {} connect {} success ",self_mac,peer_remote);
         }
 
     }else{
@@ -140,8 +306,20 @@ async fn handle_bi_cell(connection:Connection,(mut bi_send, mut bi_recv):(SendSt
     Ok(())
 }
 
-async fn handle_incomming(incoming:Incoming,peers:Arc<RwLock<HashMap<Mac,Connection>>>)->Result<(),Box<dyn Error+Send+Sync>>{
+#[cfg(feature="p2p")]
+fn p2p_ticket()->Result<String,RwebError>{
+    let mut buf = [0u8;16];
+    rustls::crypto::ring::default_provider().secure_random.fill(&mut buf).map_err(|e|RwebError::new(10403,format!("{:?}",e)))?;
+    Ok(buf.iter().map(|b|format!("{:02x}",b)).collect())
+}
+
+async fn handle_incomming(incoming:Incoming,quic_server:QuicServer)->Result<(),Box<dyn Error+Send+Sync>>{
     let conn = incoming.await?;
+    let handshake = conn.handshake_data().and_then(|d|d.downcast::<HandshakeData>().ok());
+    if let Some(handshake) = handshake.filter(|h|h.protocol.as_deref() == Some(ALPN_H3)){//浏览器的h3连接，其余为设备连接
+        return serve_h3(conn, quic_server, handshake.server_name).await;
+    }
+    let peers = quic_server.peers.clone();
     let mut uni = conn.accept_uni().await?;
     let mac_list_len = timeout(Duration::from_secs(5), uni.read_u16()).await??;
     let mut mac_list:Vec<Mac> =  Vec::with_capacity(mac_list_len as usize);
@@ -150,6 +328,10 @@ async fn handle_incomming(incoming:Incoming,peers:Arc<RwLock<HashMap<Mac,Connect
         timeout(Duration::from_secs(5), uni.read_exact(&mut buf)).await??;
         mac_list.push(buf.into());
     }
+    //在上线之前告诉设备本rwebs会写上真实的访问者，设备收到后才相信请求头里的Rweb-Client/Rweb-Session
+    if let Ok(mut uni) = conn.open_uni().await && write_features(&mut uni,FEATURE_VISITOR).await.is_ok(){
+        uni.finish().unwrap_or_default();
+    }
     let mut peers_s = peers.write().await;
     for mac in mac_list.iter(){
         if let Some(_) = peers_s.get(mac){
@@ -158,39 +340,257 @@ async fn handle_incomming(incoming:Incoming,peers:Arc<RwLock<HashMap<Mac,Connect
             return Err(RwebError::new(10402, "node_mac already online").into());
         }
     }
+    let mut health_s = quic_server.health.write().await;
     for mac in mac_list.iter(){
         peers_s.insert(mac.clone(), conn.clone());
+        health_s.remove(mac);//上一个连接留下的状态作废
     }
+    drop(health_s);
     drop(peers_s);
     log::info!("node_mac online:{}",mac_list.iter().map(|m|m.to_string()).collect::<Vec<String>>().join(","));
-    #[cfg(feature="p2p")]
+    let mac_list = Arc::new(RwLock::new(mac_list));
+    let health = quic_server.health.clone();
     select! {
-        _ = handle_bi(connection.clone(), peers_bi.clone(), mac_list[0])=>{},//p2p连接？
+        _ = handle_bi(conn.clone(), quic_server, mac_list.clone())=>{},
+        _ = listen_uni(conn.clone(), peers.clone(), health.clone(), mac_list.clone())=>{},
         _ = conn.closed()=>{}
-    }    
-    #[cfg(not(feature="p2p"))]
-    conn.closed().await;
+    }
+    let mac_list = mac_list.read().await;
     log::info!("node_mac offline:{}",mac_list.iter().map(|m|m.to_string()).collect::<Vec<String>>().join(","));
     let mut peers_s = peers.write().await;
+    let mut health = health.write().await;
     for mac in mac_list.iter(){
-        peers_s.remove(mac);
+        if peers_s.get(mac).is_some_and(|c|c.stable_id() == conn.stable_id()){
+            peers_s.remove(mac);
+            health.remove(mac);
+        }
     }
     drop(peers_s);
     Ok(())
 }
 
+//设备注册之后的uni流：mac列表或目标状态
+async fn listen_uni(conn:Connection,peers:Arc<RwLock<HashMap<Mac,Connection>>>,health:Arc<RwLock<HashMap<Mac,TargetHealth>>>,mac_list:Arc<RwLock<Vec<Mac>>>)->Result<(),Box<dyn Error+Send+Sync>>{
+    loop{
+        let mut uni = conn.accept_uni().await?;
+        match timeout(Duration::from_secs(5), read_uni(&mut uni)).await{
+            Ok(Ok(UniMessage::MacList(new_list)))=>update_mac_list(&conn, &peers, &health, &mac_list, new_list).await,
+            Ok(Ok(UniMessage::Health(report)))=>update_health(&health, &mac_list, report).await,
+            _=>{}//读不完整的忽略，不影响连接
+        }
+    }
+}
+
+//目标状态只接受这个连接的mac列表里的mac，uni流按打开的顺序处理，设备先发的新mac列表先生效
+async fn update_health(health:&RwLock<HashMap<Mac,TargetHealth>>,mac_list:&RwLock<Vec<Mac>>,report:Vec<(Mac,TargetHealth)>){
+    let mac_list = mac_list.read().await;
+    let mut health = health.write().await;
+    for (mac,h) in report{
+        if !mac_list.contains(&mac){
+            log::debug!("target health ignored, not on this connection:{}",mac);
+            continue;
+        }
+        if health.insert(mac, h) != Some(h){
+            log::info!("target health:{} {}",mac,h);
+        }
+    }
+}
+
+//设备换了代理表时在新的uni流上再发一次mac列表：不在新列表里的下线，新增的上线，
+//新增的已在别的连接上时跳过，并在rwebs打开的uni流上告诉设备
+async fn update_mac_list(conn:&Connection,peers:&RwLock<HashMap<Mac,Connection>>,health:&RwLock<HashMap<Mac,TargetHealth>>,mac_list:&RwLock<Vec<Mac>>,new_list:Vec<Mac>){
+    let mut peers_s = peers.write().await;
+    let mut health = health.write().await;
+    let mut mac_list = mac_list.write().await;
+    for mac in mac_list.iter().filter(|m|!new_list.contains(m)){
+        if peers_s.get(mac).is_some_and(|c|c.stable_id() == conn.stable_id()){
+            peers_s.remove(mac);
+            health.remove(mac);
+        }
+    }
+    let mut accepted = Vec::with_capacity(new_list.len());
+    let mut rejected = vec![];
+    for mac in new_list{
+        match peers_s.get(&mac){
+            Some(c) if c.stable_id() != conn.stable_id()=>{
+                log::warn!("node_mac already online:{}",mac);
+                rejected.push(mac);
+            },
+            Some(_)=>accepted.push(mac),//本来就在这个连接上
+            None=>{
+                peers_s.insert(mac, conn.clone());
+                health.remove(&mac);
+                accepted.push(mac);
+            }
+        }
+    }
+    log::info!("node_mac update:{} -> {}",mac_list.iter().map(|m|m.to_string()).collect::<Vec<String>>().join(","),accepted.iter().map(|m|m.to_string()).collect::<Vec<String>>().join(","));
+    *mac_list = accepted;
+    drop((peers_s,health,mac_list));
+    if !rejected.is_empty(){
+        let conn = conn.clone();
+        tokio::spawn(async move{//不在这里等设备读
+            if let Ok(mut uni) = conn.open_uni().await && write_rejected(&mut uni,&rejected).await.is_ok(){
+                uni.finish().unwrap_or_default();
+            }
+        });
+    }
+}
+
+fn server_config(h3:Option<(&str,&str)>)->Result<ServerConfig,Box<dyn Error>>{
+    let web = match h3{
+        Some((priv_key,cert_der))=>{
+            let web_key = rustls::pki_types::PrivateKeyDer::from_pem_slice(priv_key.as_bytes())?;
+            let web_certs = extract_full_pem_certificates(cert_der).into_iter().filter_map(|s|rustls::pki_types::CertificateDer::from_pem_slice(s.as_bytes()).ok()).collect::<Vec<_>>();
+            Some((web_certs,web_key))
+        },
+        None=>None
+    };
+    configure_host_server(CER_BIN.as_bytes(),KEY_BIN.as_bytes(),web)
+}
+
 pub fn make_server_udp_endpoint(addr:SocketAddr, cert_der:&[u8], priv_key:&[u8]) -> Result<Endpoint, Box<dyn Error>> {
-    Ok(Endpoint::server( configure_host_server(cert_der,priv_key)?, addr)?)
+    Ok(Endpoint::server( configure_host_server(cert_der,priv_key,None)?, addr)?)
 }
 
-fn configure_host_server(cert_der:&[u8],priv_key:&[u8]) -> Result<ServerConfig, Box<dyn Error>> {
+//web不为空时按ALPN选择证书：h3用https证书，设备用内置证书
+//不带ALPN的设备由OptionalAlpn交给不要求ALPN的配置
+fn configure_host_server(cert_der:&[u8],priv_key:&[u8],web:Option<(Vec<CertificateDer<'static>>,PrivateKeyDer<'static>)>) -> Result<ServerConfig, Box<dyn Error>> {
     let priv_key = rustls::pki_types::PrivateKeyDer::from_pem_slice(priv_key)?;//  ::from_pem_file(priv_key)?;
     let cert_chain = vec![rustls::pki_types::CertificateDer::from_pem_slice(cert_der)?];//from_pem_file(cert_der)?];
-    let mut server_config = ServerConfig::with_single_cert(cert_chain, priv_key)?;
+    let mut server_config = match web{
+        Some((web_certs,web_key))=>{
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let device = Arc::new(CertifiedKey::new(cert_chain, provider.key_provider.load_private_key(priv_key)?));
+            let resolver = AlpnCertResolver{
+                device:device.clone(),
+                web:Arc::new(CertifiedKey::new(web_certs, provider.key_provider.load_private_key(web_key)?)),
+            };
+            let mut crypto = rustls::ServerConfig::builder_with_provider(provider.clone())
+                .with_protocol_versions(&[&rustls::version::TLS13])?
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(resolver));
+            crypto.alpn_protocols = vec![ALPN_H3.to_vec(),ALPN_RWEB.to_vec()];
+            let plain = rustls::ServerConfig::builder_with_provider(provider)
+                .with_protocol_versions(&[&rustls::version::TLS13])?
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(AlpnCertResolver{device:device.clone(),web:device}));//只有设备
+            ServerConfig::with_crypto(Arc::new(OptionalAlpn{alpn:Arc::new(QuicServerConfig::try_from(crypto)?),plain:Arc::new(QuicServerConfig::try_from(plain)?)}))
+        },
+        None=>ServerConfig::with_single_cert(cert_chain, priv_key)?
+    };
     Arc::get_mut(&mut server_config.transport).ok_or("none mutable")?
         .keep_alive_interval(Some(std::time::Duration::from_millis(KEEPALIVE_INTERVAL_MILLIS)))
         .max_idle_timeout(Some(quinn::IdleTimeout::from(VarInt::from_u32(IDLE_TIMEOUT_MILLIS))))
         .max_concurrent_bidi_streams(10000_u16.into())
         .max_concurrent_uni_streams(10000_u16.into());
     Ok(server_config)
-}
\ No newline at end of file
+}
+
+#[derive(Debug)]
+struct AlpnCertResolver{
+    device:Arc<CertifiedKey>,
+    web:Arc<CertifiedKey>,
+}
+
+impl ResolvesServerCert for AlpnCertResolver{
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let h3 = client_hello.alpn().map(|mut alpn|alpn.any(|p|p == ALPN_H3)).unwrap_or(false);
+        Some(if h3 {self.web.clone()} else {self.device.clone()})
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::testutil;
+    use tokio::net::TcpListener;
+
+    fn protocol(conn:&Connection)->Option<Vec<u8>>{
+        conn.handshake_data().and_then(|d|d.downcast::<HandshakeData>().ok()).and_then(|h|h.protocol)
+    }
+
+    //开启h3后浏览器按ALPN走h3，带ALPN_RWEB和不带ALPN的设备都能连上
+    #[tokio::test]
+    async fn h3_optional_alpn(){
+        let server = QuicServer::default();
+        let port = testutil::start(&server,true);
+        let plain = testutil::connect(port,&[]).await.unwrap();
+        assert_eq!(protocol(&plain),None);
+        let rweb = testutil::connect(port,&[ALPN_RWEB]).await.unwrap();
+        assert_eq!(protocol(&rweb).as_deref(),Some(ALPN_RWEB));
+        let h3 = testutil::connect(port,&[ALPN_H3]).await.unwrap();
+        assert_eq!(protocol(&h3).as_deref(),Some(ALPN_H3));
+        assert!(testutil::connect(port,&[b"other"]).await.is_err());
+
+        let mac:Mac = "aabbccdd2701".parse().unwrap();
+        let _device = testutil::device(&server,port,&[mac],|_,_|"HTTP/1.1 204 No Content\r\n\r\n".to_string()).await;
+        let mut stream = server.open_stream(mac).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(get_header(&mut stream).await.unwrap().uri,"204");
+    }
+
+    //反向转发只放行配置给本连接设备的服务
+    #[tokio::test]
+    async fn reverse_allowed(){
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move{
+            while let Ok((mut tcp,_)) = target.accept().await{
+                tcp.write_all(b"pong").await.unwrap_or_default();
+            }
+        });
+        let mac:Mac = "aabbccdd3101".parse().unwrap();
+        let other:Mac = "aabbccdd3102".parse().unwrap();
+        let server = QuicServer::default().with_reverse(vec![
+            ReverseRule{mac,name:"syslog".to_string(),target:target_addr.to_string()},
+            ReverseRule{mac:other,name:"syslog".to_string(),target:target_addr.to_string()},
+        ]);
+        let port = testutil::start(&server,false);
+        let conn = testutil::device(&server,port,&[mac],|_,_|String::new()).await;
+        let open = |mac:Mac,name:&'static str|{
+            let conn = conn.clone();
+            async move{
+                let (mut send,mut recv) = conn.open_bi().await.unwrap();
+                let header:Vec<u8> = Header::new_reverse(mac,name).into();
+                send.write_all(&header).await.unwrap();
+                let status = get_header(&mut recv).await.unwrap().uri;
+                (status,recv)
+            }
+        };
+        let (status,mut recv) = open(mac,"syslog").await;
+        assert_eq!(status,"200");
+        let mut buf = [0;4];
+        recv.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf,b"pong");
+        assert_eq!(open(mac,"ssh").await.0,"403");
+        assert_eq!(open(other,"syslog").await.0,"403");
+    }
+
+    //中转只允许配置过的(请求方,目标)，目标设备收到说明请求方的RELAY头
+    #[tokio::test]
+    async fn relay_allowed(){
+        let a:Mac = "aabbccdd3201".parse().unwrap();
+        let b:Mac = "aabbccdd3202".parse().unwrap();
+        let c:Mac = "aabbccdd3203".parse().unwrap();
+        let server = QuicServer::default().with_relay(vec![RelayRule{from:a,to:b}]);
+        let port = testutil::start(&server,false);
+        let _target = testutil::device(&server,port,&[b],|_,header|format!("HTTP/1.1 200 OK\r\n\r\n{} {}",header.method,header.get("mac").cloned().unwrap_or_default())).await;
+        let relay = |conn:Connection|async move{
+            let (mut send,mut recv) = conn.open_bi().await.unwrap();
+            let header:Vec<u8> = Header::new_relay(b).into();
+            send.write_all(&header).await.unwrap();
+            let status = get_header(&mut recv).await.unwrap().uri;
+            let mut body = String::new();
+            if status == "200"{
+                get_header(&mut recv).await.unwrap();
+                recv.read_to_string(&mut body).await.unwrap();
+            }
+            (status,body)
+        };
+        let device_a = testutil::device(&server,port,&[a],|_,_|String::new()).await;
+        assert_eq!(relay(device_a).await,("200".to_string(),format!("{} {}",METHOD_RELAY,a)));
+        let device_c = testutil::device(&server,port,&[c],|_,_|String::new()).await;
+        assert_eq!(relay(device_c).await,("403".to_string(),String::new()));
+    }
+}
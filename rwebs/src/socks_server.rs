@@ -0,0 +1,107 @@
+use std::{collections::HashMap, error::Error, net::SocketAddr};
+use rweb_common::{io::{header::Visitor, socks5}, mac::Mac, RwebError};
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::quic_server::QuicServer;
+
+//socks5密码：devices里有的设备只能用自己的密码，其他设备用password，都没有时不接受socks5
+#[derive(Debug, Clone, Default)]
+pub struct SocksAuth {
+    pub password: Option<String>,
+    pub devices: HashMap<Mac, String>,
+}
+
+impl SocksAuth {
+    pub fn enabled(&self) -> bool {
+        self.password.is_some() || !self.devices.is_empty()
+    }
+
+    //json对象，键为设备标签，值为密码
+    pub fn load_devices(path: &str) -> Result<HashMap<Mac, String>, Box<dyn Error+Send+Sync>> {
+        let devices: HashMap<String, String> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        devices.into_iter().map(|(mac, pass)| Ok((mac.parse()?, pass))).collect()
+    }
+
+    pub fn check(&self, mac: Mac, pass: &str) -> bool {
+        match self.devices.get(&mac).or(self.password.as_ref()) {
+            Some(password) => socks5::password_eq(password.as_bytes(), pass.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+//socks5前端，用户名为设备标签，密码见SocksAuth
+//CONNECT转到设备已有的CONNECT路径；设备端不支持数据报，UDP ASSOCIATE返回命令不支持
+pub async fn serve_socks5<T: AsyncRead + AsyncWrite + Unpin>(mut stream: T, quic_server: QuicServer, auth: &SocksAuth, visitor: Visitor) -> Result<(), Box<dyn Error+Send+Sync>> {
+    let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+    let methods = socks5::read_greeting(&mut stream).await?;
+    if !auth.enabled() || !methods.contains(&socks5::METHOD_USERPASS) {
+        socks5::write_method(&mut stream, socks5::METHOD_NO_ACCEPTABLE).await?;
+        return Err("socks5 need username/password".into());
+    }
+    socks5::write_method(&mut stream, socks5::METHOD_USERPASS).await?;
+    let (user, pass) = socks5::read_userpass(&mut stream).await?;
+    let mac: Result<Mac, RwebError> = user.split('.').next().unwrap_or_default().try_into();
+    let mac = match mac {
+        Ok(mac) if auth.check(mac, &pass) => mac,
+        _ => {
+            socks5::write_auth_status(&mut stream, false).await?;
+            return Err(format!("socks5 auth failed, user:{}", user).into());
+        }
+    };
+    socks5::write_auth_status(&mut stream, true).await?;
+    let (cmd, addr) = socks5::read_request(&mut stream).await?;
+    log::info!("socks5 cmd: {}, addr: {}, mac: {}", cmd, addr, mac);
+    match cmd {
+        socks5::CMD_CONNECT => {
+            match quic_server.open_connect(mac, &addr.to_string(), Some(&visitor)).await {
+                Ok(mut device_stream) => {
+                    socks5::write_reply(&mut stream, socks5::REP_SUCCEEDED, unspecified).await?;
+                    tokio::io::copy_bidirectional(&mut stream, &mut device_stream).await?;
+                },
+                Err(e) => {
+                    let rep = if e.code == 504 { socks5::REP_HOST_UNREACHABLE } else { socks5::REP_GENERAL_FAILURE };//504为设备连接目标失败，其他为设备未连接或连接不可用
+                    socks5::write_reply(&mut stream, rep, unspecified).await?;
+                    return Err(e.into());
+                }
+            }
+        },
+        _ => {
+            socks5::write_reply(&mut stream, socks5::REP_CMD_NOT_SUPPORTED, unspecified).await?;
+            return Err(format!("socks5 cmd {} not support", cmd).into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use rweb_common::io::socks5::SocksAddr;
+    use crate::testutil;
+
+    async fn connect(server: &QuicServer, auth: &SocksAuth, user: &str, pass: &str) -> Result<(), RwebError> {
+        let (mut client, server_io) = tokio::io::duplex(1024);
+        let (server, auth) = (server.clone(), auth.clone());
+        tokio::spawn(async move {
+            serve_socks5(server_io, server, &auth, Visitor { ip: IpAddr::V4(Ipv4Addr::LOCALHOST), session: 1 }).await.unwrap_or_default();
+        });
+        socks5::client_connect(&mut client, user, pass, &SocksAddr::Domain("abc.com".to_string(), 443)).await
+    }
+
+    //有自己密码的设备不能用共用密码，设备不在线时返回一般错误
+    #[tokio::test]
+    async fn socks_auth() {
+        let server = QuicServer::default();
+        let port = testutil::start(&server, false);
+        let (shared, own): (Mac, Mac) = ("aabbccdd2801".parse().unwrap(), "aabbccdd2802".parse().unwrap());
+        let _device = testutil::device(&server, port, &[shared, own], |_, _| "HTTP/1.1 200 Connection Established\r\n\r\n".to_string()).await;
+        assert_eq!(connect(&server, &SocksAuth::default(), "aabbccdd2801", "pw").await.unwrap_err().code, 7006);
+        let auth = SocksAuth { password: Some("shared".to_string()), devices: HashMap::from([(own, "own".to_string())]) };
+        connect(&server, &auth, "aabbccdd2801", "shared").await.unwrap();
+        assert_eq!(connect(&server, &auth, "aabbccdd2801", "own").await.unwrap_err().code, 7007);
+        assert_eq!(connect(&server, &auth, "aabbccdd2802.abc.com", "shared").await.unwrap_err().code, 7007);
+        connect(&server, &auth, "aabbccdd2802.abc.com", "own").await.unwrap();
+        assert_eq!(connect(&server, &auth, "aabbccdd2803", "shared").await.unwrap_err().code, 7100 + socks5::REP_GENERAL_FAILURE as i32);
+    }
+}
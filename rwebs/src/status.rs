@@ -0,0 +1,86 @@
+use std::{error::Error, net::SocketAddr};
+use rweb_common::get_header;
+use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}};
+use crate::quic_server::QuicServer;
+
+//--status-addr上的状态页，GET /devices返回在线设备和目标状态的json
+//没有鉴权，只应监听在回环或内网地址上
+pub async fn run_status(addr:SocketAddr,quic_server:QuicServer)->Result<(),Box<dyn Error+Send+Sync>>{
+    let listener = TcpListener::bind(addr).await.map_err(|e|format!("status addr {}:{}",addr,e))?;
+    log::info!("status page listen:{}",addr);
+    serve_status(listener,quic_server).await
+}
+
+//在已经绑定好的listener上运行，测试时用端口0绑定后交给这里
+pub async fn serve_status(listener:TcpListener,quic_server:QuicServer)->Result<(),Box<dyn Error+Send+Sync>>{
+    loop{
+        let (stream,_) = listener.accept().await?;
+        let quic_server = quic_server.clone();
+        tokio::spawn(async move{
+            if let Err(e) = handle_status(stream,quic_server).await{
+                log::debug!("status page error:{}",e);
+            }
+        });
+    }
+}
+
+async fn handle_status(mut stream:TcpStream,quic_server:QuicServer)->Result<(),Box<dyn Error+Send+Sync>>{
+    let header = get_header(&mut stream).await?;
+    let (status,body) = match (header.method.as_str(),header.uri.as_str()){
+        ("GET","/devices")=>("200 OK",devices_json(&quic_server).await),
+        _=>("404 Not Found","{}".to_string()),
+    };
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",status,body.len(),body);
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+//[{"mac":"aabbcc00020c","health":"up"},..]
+pub async fn devices_json(quic_server:&QuicServer)->String{
+    let mut devices = quic_server.devices().await;
+    devices.sort_by_key(|(mac,_)|mac.to_string());
+    let devices:Vec<String> = devices.into_iter().map(|(mac,health)|format!(r#"{{"mac":"{}","health":"{}"}}"#,mac,health)).collect();
+    format!("[{}]",devices.join(","))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use rweb_common::{health::TargetHealth, io::header::write_health, mac::Mac};
+    use tokio::{io::AsyncReadExt, time::{sleep, Duration}};
+
+    //设备报告的目标状态出现在状态页上，不在这个连接上的mac报告的不算
+    #[tokio::test]
+    async fn status_devices(){
+        let server = QuicServer::default();
+        let port = crate::testutil::start(&server,false);
+        let (mac_a,mac_b):(Mac,Mac) = ("aabbccdd3301".parse().unwrap(),"aabbccdd3302".parse().unwrap());
+        let a = crate::testutil::device(&server,port,&[mac_a],|_,_|String::new()).await;
+        crate::testutil::device(&server,port,&[mac_b],|_,_|String::new()).await;
+        let mut uni = a.open_uni().await.unwrap();
+        write_health(&mut uni,&[(mac_a,TargetHealth::Up),(mac_b,TargetHealth::Down)]).await.unwrap();
+        uni.finish().unwrap();
+        for _ in 0..50{
+            if server.target_health(mac_a).await == TargetHealth::Up{
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(server.target_health(mac_b).await,TargetHealth::Unknown);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_status(listener,server.clone()));
+        let get = |path:&'static str|async move{
+            let mut s = TcpStream::connect(addr).await.unwrap();
+            s.write_all(format!("GET {} HTTP/1.1\r\nHost: status\r\n\r\n",path).as_bytes()).await.unwrap();
+            let mut resp = String::new();
+            s.read_to_string(&mut resp).await.unwrap();
+            resp
+        };
+        let resp = get("/devices").await;
+        assert!(resp.starts_with("HTTP/1.1 200"));
+        assert!(resp.ends_with(&format!(r#"[{{"mac":"{}","health":"up"}},{{"mac":"{}","health":"unknown"}}]"#,mac_a,mac_b)),"{}",resp);
+        assert!(get("/").await.starts_with("HTTP/1.1 404"));
+    }
+}
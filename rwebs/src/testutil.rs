@@ -0,0 +1,65 @@
+use std::{net::SocketAddr, sync::Arc};
+use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint};
+use rustls::{pki_types::{pem::PemObject, CertificateDer}, RootCertStore};
+use rweb_common::{get_header, key::{CER_BIN, KEY_BIN}, mac::Mac, Header};
+use tokio::{io::AsyncWriteExt, time::{sleep, timeout, Duration}};
+use crate::quic_server::QuicServer;
+
+//在127.0.0.1的随机端口上启动rwebs，h3为true时用内置证书同时提供http/3，返回端口
+pub(crate) fn start(server:&QuicServer,h3:bool)->u16{
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let server = server.clone();
+    tokio::spawn(async move{
+        server.start_on(socket,h3.then_some((KEY_BIN,CER_BIN))).await.unwrap_or_default();
+    });
+    port
+}
+
+//信任内置证书，alpn为空时和旧版设备一样不带ALPN
+pub(crate) async fn connect(port:u16,alpn:&[&[u8]])->Result<Connection,quinn::ConnectionError>{
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_slice(CER_BIN.as_bytes()).unwrap()).unwrap();
+    let mut crypto = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13]).unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    crypto.alpn_protocols = alpn.iter().map(|a|a.to_vec()).collect();
+    let endpoint = Endpoint::client(SocketAddr::from(([127,0,0,1],0))).unwrap();
+    let config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto).unwrap()));
+    endpoint.connect_with(config,SocketAddr::from(([127,0,0,1],port)),"reform").unwrap().await
+}
+
+//模拟设备：注册mac_list，每条bi流读出mac和请求头交给respond，写回它返回的内容后结束这条流
+pub(crate) async fn device(server:&QuicServer,port:u16,mac_list:&[Mac],respond:fn(Mac,Header)->String)->Connection{
+    let conn = connect(port,&[]).await.unwrap();
+    let mut uni = conn.open_uni().await.unwrap();
+    uni.write_u16(mac_list.len() as u16).await.unwrap();
+    for mac in mac_list{
+        uni.write_all(mac.as_ref()).await.unwrap();
+    }
+    uni.finish().unwrap();
+    let c = conn.clone();
+    tokio::spawn(async move{
+        while let Ok((mut send,mut recv)) = c.accept_bi().await{
+            tokio::spawn(async move{
+                let mut mac = [0;6];
+                recv.read_exact(&mut mac).await.unwrap();
+                if let Ok(header) = get_header(&mut recv).await{
+                    send.write_all(respond(mac.into(),header).as_bytes()).await.unwrap_or_default();
+                }
+                send.finish().unwrap_or_default();
+            });
+        }
+    });
+    timeout(Duration::from_secs(5),async{
+        loop{
+            let online = server.devices().await;
+            if mac_list.iter().all(|m|online.iter().any(|(o,_)|o == m)){
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    }).await.unwrap();
+    conn
+}